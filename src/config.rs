@@ -0,0 +1,491 @@
+//! Deployment configuration files, which describe the destinations
+//! of a deployment as named groups that get deployed one after the
+//! other, each with its own policies.
+//!
+//! A configuration file is written in TOML and looks like this:
+//!
+//! ```toml
+//! [[group]]
+//! name = "db"
+//! hosts = ["nixos://db1", "nixos://db2"]
+//! max-parallel = 1
+//!
+//! [[group]]
+//! name = "app"
+//! hosts = ["app1", "app2", "app3"]
+//! gate = "preflight"
+//! strategy = "parallel"
+//! max-parallel = 2
+//!
+//! [[group]]
+//! name = "legacy"
+//! hosts = [
+//!   { destination = "nixos://old-box", nix-options = { sandbox = "false" } },
+//!   { destination = "nixos://degraded-box", preflight-check = "skip" },
+//!   { destination = "nixos://ancient-box", health-check = "failed-units" },
+//!   { destination = "nixos://kiosk", specialisation = "kiosk-mode" },
+//!   { destination = "nixos://far-away", copy-timeout = "30m", tags = ["remote"] },
+//! ]
+//! ```
+//!
+//! Groups are deployed in the order in which they appear in the file,
+//! and a group only gets deployed if all the groups before it were
+//! deployed successfully. A deploy can be limited to some of the
+//! groups, or to the hosts with some tags (see [`Config::select`]).
+
+use crate::{
+    copy::CopyMethod, snapshot::Snapshot, Behavior, CheckBehavior, Destination, Gate, HealthCheck,
+    Strategy,
+};
+use anyhow::Context;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    num::NonZeroUsize,
+    path::Path,
+    time::Duration,
+};
+
+/// A deployment configuration.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// The groups of destinations, in the order they get deployed in.
+    #[serde(rename = "group")]
+    pub groups: Vec<Group>,
+}
+
+/// A named group of destinations that get deployed together.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Group {
+    /// The name of the group, used in log messages.
+    pub name: String,
+
+    /// The destinations in the group.
+    pub hosts: Vec<Host>,
+
+    /// How many destinations in the group get deployed to at the
+    /// same time. Unless a different `strategy` is set, destinations
+    /// get deployed to in batches of this size, and each batch only
+    /// starts once the previous one succeeded. By default, all
+    /// destinations are deployed to at once.
+    pub max_parallel: Option<NonZeroUsize>,
+
+    /// How deploying to the group's destinations gets spread out
+    /// over time (defaults to the commandline setting).
+    pub strategy: Option<Strategy>,
+
+    /// When to start activating the configuration on the group's
+    /// destinations (defaults to the commandline setting).
+    pub gate: Option<Gate>,
+
+    /// Whether to check the health of the group's destinations
+    /// before deploying (defaults to the commandline setting).
+    pub preflight_check: Option<CheckBehavior>,
+
+    /// Whether to test the configuration on the group's destinations
+    /// before installing it as the boot configuration (defaults to
+    /// the commandline setting).
+    pub test: Option<Behavior>,
+}
+
+/// A destination, along with the settings that apply only to it.
+///
+/// In a configuration file, a host is either just a destination
+/// string, or a table with a `destination` key and any overrides.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "HostSpec")]
+pub struct Host {
+    /// Where to deploy to.
+    pub destination: Destination,
+
+    /// Extra commandline arguments for "nix build" that replace the
+    /// ones given on the commandline, for this host only.
+    pub build_cmdline: Option<Vec<String>>,
+
+    /// Nix options (passed as `--option NAME VALUE`) that get set
+    /// when building for this host only, in addition to the
+    /// commandline arguments.
+    pub nix_options: BTreeMap<String, String>,
+
+    /// Whether to check the health of this host before deploying
+    /// (defaults to the group's setting).
+    pub preflight_check: Option<CheckBehavior>,
+
+    /// How to check the health of this host (defaults to the
+    /// commandline setting).
+    pub health_check: Option<HealthCheck>,
+
+    /// Whether to test the configuration on this host before
+    /// installing it as the boot configuration (defaults to the
+    /// group's setting).
+    pub test: Option<Behavior>,
+
+    /// The specialisation of the configuration that gets activated
+    /// on this host (defaults to the commandline setting).
+    pub specialisation: Option<String>,
+
+    /// The profile (under `system-profiles`) that the configuration
+    /// gets installed into on this host (defaults to the commandline
+    /// setting).
+    pub profile_name: Option<String>,
+
+    /// Whether to try out the boot loader update before setting the
+    /// system profile on this host (defaults to the commandline
+    /// setting).
+    pub boot_dry_run: Option<Behavior>,
+
+    /// How to copy the flake closure to this host (defaults to the
+    /// commandline setting).
+    pub copy_method: Option<CopyMethod>,
+
+    /// The filesystem snapshots to take on this host before
+    /// activating the configuration (replacing the ones given on the
+    /// commandline).
+    pub snapshots: Option<Vec<Snapshot>>,
+
+    /// The command that takes this host out of service before
+    /// activating the configuration (defaults to the commandline
+    /// setting).
+    pub drain: Option<String>,
+
+    /// The command that puts this host back into service once the
+    /// configuration is active and healthy (defaults to the
+    /// commandline setting).
+    pub undrain: Option<String>,
+
+    /// How long copying the flake closure to this host may take
+    /// before it is retried (defaults to the commandline setting).
+    pub copy_timeout: Option<Duration>,
+
+    /// Free-form tags that select the host when deploying only some
+    /// of the hosts.
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HostSpec {
+    Destination(Destination),
+    Table(Box<HostTable>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct HostTable {
+    destination: Destination,
+    build_cmdline: Option<Vec<String>>,
+    #[serde(default)]
+    nix_options: BTreeMap<String, String>,
+    preflight_check: Option<CheckBehavior>,
+    health_check: Option<HealthCheck>,
+    test: Option<Behavior>,
+    #[serde(default, deserialize_with = "deserialize_path_component")]
+    specialisation: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_path_component")]
+    profile_name: Option<String>,
+    boot_dry_run: Option<Behavior>,
+    copy_method: Option<CopyMethod>,
+    snapshots: Option<Vec<Snapshot>>,
+    drain: Option<String>,
+    undrain: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    copy_timeout: Option<Duration>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Deserializes a human-readable duration, like "90s" or "1h 30m".
+fn deserialize_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Deserializes a name that gets used as a single path component on
+/// the destination, like a profile name.
+fn deserialize_path_component<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    crate::path_component(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl From<HostSpec> for Host {
+    fn from(spec: HostSpec) -> Self {
+        match spec {
+            HostSpec::Destination(destination) => Host {
+                destination,
+                build_cmdline: None,
+                nix_options: BTreeMap::new(),
+                preflight_check: None,
+                health_check: None,
+                test: None,
+                specialisation: None,
+                profile_name: None,
+                boot_dry_run: None,
+                copy_method: None,
+                snapshots: None,
+                drain: None,
+                undrain: None,
+                copy_timeout: None,
+                tags: vec![],
+            },
+            HostSpec::Table(table) => {
+                let table = *table;
+                Host {
+                    destination: table.destination,
+                    build_cmdline: table.build_cmdline,
+                    nix_options: table.nix_options,
+                    preflight_check: table.preflight_check,
+                    health_check: table.health_check,
+                    test: table.test,
+                    specialisation: table.specialisation,
+                    profile_name: table.profile_name,
+                    boot_dry_run: table.boot_dry_run,
+                    copy_method: table.copy_method,
+                    snapshots: table.snapshots,
+                    drain: table.drain,
+                    undrain: table.undrain,
+                    copy_timeout: table.copy_timeout,
+                    tags: table.tags,
+                }
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates a configuration file.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {path:?}"))?;
+        contents
+            .parse()
+            .with_context(|| format!("Invalid config file {path:?}"))
+    }
+
+    /// Expands the hosts of every group whose destination is an SRV
+    /// record into the hosts that the record points to, each with
+    /// the same settings.
+    pub async fn expand_destinations(mut self) -> Result<Self, anyhow::Error> {
+        for group in &mut self.groups {
+            let mut hosts = vec![];
+            for host in &group.hosts {
+                for destination in host.destination.expand().await? {
+                    hosts.push(Host {
+                        destination,
+                        ..host.clone()
+                    });
+                }
+            }
+            group.hosts = hosts;
+        }
+        Ok(self)
+    }
+
+    /// Limits the configuration to the groups named in `groups` (if
+    /// any are given), and within those to the hosts that carry at
+    /// least one of the `tags` (if any are given). Groups left
+    /// without hosts get dropped.
+    pub fn select(mut self, groups: &[String], tags: &[String]) -> Result<Self, anyhow::Error> {
+        for name in groups {
+            if !self.groups.iter().any(|group| &group.name == name) {
+                anyhow::bail!("There is no group {name:?}");
+            }
+        }
+        self.groups
+            .retain(|group| groups.is_empty() || groups.contains(&group.name));
+        for group in &mut self.groups {
+            group
+                .hosts
+                .retain(|host| tags.is_empty() || host.tags.iter().any(|tag| tags.contains(tag)));
+        }
+        self.groups.retain(|group| !group.hosts.is_empty());
+        if self.groups.is_empty() {
+            anyhow::bail!("No hosts match the selected groups and tags");
+        }
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut names = HashSet::new();
+        for group in &self.groups {
+            if !names.insert(&group.name) {
+                anyhow::bail!("Group {:?} is defined more than once", group.name);
+            }
+            if group.hosts.is_empty() {
+                anyhow::bail!("Group {:?} has no hosts", group.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use crate::{copy::CopyMethod, Behavior, CheckBehavior, Gate, HealthCheck, Strategy};
+    use std::time::Duration;
+
+    #[test]
+    fn parses_groups_in_order() {
+        let config: Config = r#"
+            [[group]]
+            name = "db"
+            hosts = ["nixos://db1", "db2"]
+            max-parallel = 1
+            preflight-check = "skip"
+
+            [[group]]
+            name = "app"
+            hosts = ["app1"]
+            gate = "preflight"
+            strategy = "serial"
+            preflight-check = "warn"
+        "#
+        .parse()
+        .unwrap();
+        let names: Vec<&str> = config.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, vec!["db", "app"]);
+        assert_eq!(config.groups[0].hosts[1].destination.hostname, "db2");
+        assert_eq!(config.groups[0].max_parallel.map(|n| n.get()), Some(1));
+        assert_eq!(config.groups[0].preflight_check, Some(CheckBehavior::Skip));
+        assert_eq!(config.groups[1].gate, Some(Gate::Preflight));
+        assert_eq!(config.groups[1].strategy, Some(Strategy::Serial));
+        assert_eq!(config.groups[1].preflight_check, Some(CheckBehavior::Warn));
+        assert_eq!(config.groups[1].test, None);
+    }
+
+    #[test]
+    fn parses_host_overrides() {
+        let config: Config = r#"
+            [[group]]
+            name = "legacy"
+            hosts = [
+              { destination = "plain", specialisation = "kiosk", profile-name = "kiosk", snapshots = ["zfs:rpool/root"] },
+              { destination = "nixos://old/cfg", build-cmdline = ["-v"], nix-options = { sandbox = "false" }, copy-method = "tarball" },
+              { destination = "degraded", preflight-check = "skip", test = "skip", health-check = "failed-units", boot-dry-run = "skip", drain = "lb drain $DEPLOY_FLAKE_HOST" },
+            ]
+        "#
+        .parse()
+        .unwrap();
+        let hosts = &config.groups[0].hosts;
+        assert_eq!(hosts[0].build_cmdline, None);
+        assert_eq!(hosts[0].specialisation.as_deref(), Some("kiosk"));
+        assert_eq!(hosts[0].profile_name.as_deref(), Some("kiosk"));
+        assert_eq!(hosts[1].specialisation, None);
+        assert_eq!(
+            hosts[0].snapshots.as_ref().unwrap()[0].to_string(),
+            "zfs:rpool/root"
+        );
+        assert_eq!(hosts[1].snapshots, None);
+        assert!(hosts[0].nix_options.is_empty());
+        assert_eq!(hosts[1].destination.config_name.as_deref(), Some("cfg"));
+        assert_eq!(hosts[1].build_cmdline, Some(vec!["-v".to_string()]));
+        assert_eq!(hosts[1].nix_options["sandbox"], "false");
+        assert_eq!(hosts[1].test, None);
+        assert_eq!(hosts[1].copy_method, Some(CopyMethod::Tarball));
+        assert_eq!(hosts[0].copy_method, None);
+        assert_eq!(hosts[2].preflight_check, Some(CheckBehavior::Skip));
+        assert_eq!(hosts[2].test, Some(Behavior::Skip));
+        assert_eq!(hosts[2].health_check, Some(HealthCheck::FailedUnits));
+        assert_eq!(hosts[2].boot_dry_run, Some(Behavior::Skip));
+        assert_eq!(
+            hosts[2].drain.as_deref(),
+            Some("lb drain $DEPLOY_FLAKE_HOST")
+        );
+        assert_eq!(hosts[2].undrain, None);
+        assert_eq!(hosts[0].copy_timeout, None);
+        assert!(hosts[0].tags.is_empty());
+    }
+
+    #[test]
+    fn selects_groups_and_tags() {
+        let config: Config = r#"
+            [[group]]
+            name = "db"
+            hosts = [{ destination = "db1", tags = ["eu"] }, { destination = "db2", tags = ["us"], copy-timeout = "30m" }]
+
+            [[group]]
+            name = "app"
+            hosts = [{ destination = "app1", tags = ["us"] }]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.groups[0].hosts[1].copy_timeout,
+            Some(Duration::from_secs(30 * 60))
+        );
+
+        let eu = config.clone().select(&[], &["eu".to_string()]).unwrap();
+        assert_eq!(eu.groups.len(), 1);
+        assert_eq!(eu.groups[0].hosts[0].destination.hostname, "db1");
+
+        let us_apps = config
+            .clone()
+            .select(&["app".to_string()], &["us".to_string()])
+            .unwrap();
+        assert_eq!(us_apps.groups[0].name, "app");
+        assert_eq!(us_apps.groups[0].hosts.len(), 1);
+
+        assert!(config.clone().select(&["web".to_string()], &[]).is_err());
+        assert!(config.select(&[], &["asia".to_string()]).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_and_empty_groups() {
+        assert!(r#"
+            [[group]]
+            name = "db"
+            hosts = ["db1"]
+            [[group]]
+            name = "db"
+            hosts = ["db2"]
+        "#
+        .parse::<Config>()
+        .is_err());
+        assert!(r#"
+            [[group]]
+            name = "db"
+            hosts = []
+        "#
+        .parse::<Config>()
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_paths_as_names() {
+        assert!(r#"
+            [[group]]
+            name = "db"
+            hosts = [{ destination = "db1", profile-name = "../system" }]
+        "#
+        .parse::<Config>()
+        .is_err());
+        assert!(r#"
+            [[group]]
+            name = "db"
+            hosts = [{ destination = "db1", specialisation = ".." }]
+        "#
+        .parse::<Config>()
+        .is_err());
+    }
+}
@@ -0,0 +1,656 @@
+//! Ways of getting the closure of a store path into a destination's
+//! nix store.
+//!
+//! Every transport implements [`ClosureCopier`]; [`CopyMethod`]
+//! selects one of the built-in transports. Library users with more
+//! exotic transports can implement [`ClosureCopier`] themselves.
+
+use crate::{
+    bracketed_host, read_and_log_messages,
+    subprocess::{RingBuffer, Stream, SubprocessLogger},
+    transient_failure_from_output, LocalNix, Nixos, OutputLine, RemoteOptions, TransientFailure,
+};
+use anyhow::{bail, Context};
+use futures::future::BoxFuture;
+use indicatif::ProgressStyle;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tracing as log;
+use tracing::{instrument, Instrument};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// Copies the closure of a store path to a destination system.
+pub trait ClosureCopier: fmt::Debug + Send + Sync {
+    /// Copies the closure of `path` into the nix store of `to`.
+    fn copy_closure<'a>(
+        &'a self,
+        path: &'a Path,
+        to: &'a Nixos,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+}
+
+/// The built-in ways of copying a closure to a destination.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyMethod {
+    /// Use `nix-copy-closure`.
+    #[default]
+    NixCopyClosure,
+
+    /// Use `nix copy --to ssh-ng://destination`.
+    NixCopy,
+
+    /// Push the closure to a binary cache, then have the destination
+    /// substitute it from there.
+    Substitute,
+
+    /// Export the paths missing on the destination with
+    /// `nix-store --export` and import them there over the
+    /// destination's ssh connection.
+    Tarball,
+}
+
+impl CopyMethod {
+    /// Returns the copier for this method. `cache` is the binary
+    /// cache that the `substitute` method pushes to.
+    pub fn copier(
+        self,
+        cache: Option<&str>,
+        options: &RemoteOptions,
+    ) -> Result<Box<dyn ClosureCopier>, anyhow::Error> {
+        let options = options.clone();
+        Ok(match self {
+            CopyMethod::NixCopyClosure => Box::new(NixCopyClosure { options }),
+            CopyMethod::NixCopy => Box::new(NixCopy { options }),
+            CopyMethod::Substitute => Box::new(Substitute {
+                cache: cache
+                    .context("Copying via substitution needs a binary cache")?
+                    .to_string(),
+                options,
+            }),
+            CopyMethod::Tarball => Box::new(Tarball { options }),
+        })
+    }
+}
+
+/// Copies closures with `nix-copy-closure`.
+#[derive(Debug, Clone, Default)]
+pub struct NixCopyClosure {
+    pub options: RemoteOptions,
+}
+
+impl ClosureCopier for NixCopyClosure {
+    fn copy_closure<'a>(
+        &'a self,
+        path: &'a Path,
+        to: &'a Nixos,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            if let Some(root) = &to.options().remote_store {
+                bail!("nix-copy-closure can not copy to the store in {root:?}, copy with `nix copy` instead");
+            }
+            let mut cmd = Command::from(self.options.local_nix.command("nix-copy-closure"));
+            if self.options.substitute_on_destination {
+                cmd.arg("--use-substitutes");
+            }
+            cmd.arg(bracketed_host(to.host()).as_ref()).arg(path);
+            run_local(
+                cmd,
+                to.options(),
+                to.port(),
+                Some(to.control_socket()),
+                false,
+            )
+            .await
+        })
+    }
+}
+
+/// Copies closures with `nix copy`, over the `ssh-ng` protocol.
+#[derive(Debug, Clone, Default)]
+pub struct NixCopy {
+    pub options: RemoteOptions,
+}
+
+impl ClosureCopier for NixCopy {
+    fn copy_closure<'a>(
+        &'a self,
+        path: &'a Path,
+        to: &'a Nixos,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut cmd = Command::from(self.options.local_nix.command("nix"));
+            cmd.args(["copy", "--to"]).arg(to.ssh_ng_store());
+            if self.options.substitute_on_destination {
+                cmd.arg("--substitute-on-destination");
+            }
+            cmd.arg(path);
+            run_local(
+                cmd,
+                to.options(),
+                to.port(),
+                Some(to.control_socket()),
+                true,
+            )
+            .await
+        })
+    }
+}
+
+/// Pushes closures to a binary cache, then substitutes them on the
+/// destination. The destination must trust the cache's signatures.
+#[derive(Debug, Clone)]
+pub struct Substitute {
+    /// The URL of the binary cache, as understood by `nix copy`.
+    pub cache: String,
+
+    pub options: RemoteOptions,
+}
+
+impl ClosureCopier for Substitute {
+    fn copy_closure<'a>(
+        &'a self,
+        path: &'a Path,
+        to: &'a Nixos,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut cmd = Command::from(self.options.local_nix.command("nix"));
+            cmd.args(["copy", "--to", &self.cache]).arg(path);
+            run_local(cmd, &self.options, None, None, true)
+                .await
+                .with_context(|| format!("Pushing to {}", self.cache))?;
+            let mut substitute = vec!["nix".to_string()];
+            substitute.extend(to.feature_args().await?);
+            substitute.push("copy".to_string());
+            substitute.extend(to.store_args());
+            substitute.extend([
+                "--from".to_string(),
+                self.cache.clone(),
+                path.to_string_lossy().to_string(),
+            ]);
+            to.exec(&substitute, None)
+                .await
+                .with_context(|| format!("Substituting from {}", self.cache))
+        })
+    }
+}
+
+/// Exports the paths that are missing on the destination and imports
+/// them there, over the destination's ssh connection. The remote
+/// user must be trusted by the destination's nix daemon.
+#[derive(Debug, Clone, Default)]
+pub struct Tarball {
+    pub options: RemoteOptions,
+}
+
+impl ClosureCopier for Tarball {
+    fn copy_closure<'a>(
+        &'a self,
+        path: &'a Path,
+        to: &'a Nixos,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let requisites = requisites(path, &self.options.local_nix).await?;
+            let missing = to.os().missing_store_paths(&requisites).await?;
+            if missing.is_empty() {
+                return Ok(());
+            }
+            // `--requisites` lists paths before the paths referring
+            // to them, which is the order they must be imported in:
+            let paths: Vec<&PathBuf> = requisites
+                .iter()
+                .filter(|path| missing.contains(path))
+                .collect();
+            log::event!(log::Level::DEBUG, paths = paths.len(), "Exporting");
+            let mut export = Command::from(self.options.local_nix.command("nix-store"))
+                .arg("--export")
+                .args(paths)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .context("Could not execute nix-store --export")?;
+            let mut stdout = export.stdout.take().unwrap();
+            let stderr_read = tokio::task::spawn(
+                read_and_log_messages(Stream::Stderr, export.stderr.take().unwrap(), &self.options)
+                    .instrument(log::Span::current()),
+            );
+            let mut import = vec!["nix-store".to_string()];
+            import.extend(to.store_args());
+            import.push("--import".to_string());
+            to.exec(&import, Some(&mut stdout))
+                .await
+                .context("Importing the closure")?;
+            let (status, _) = futures::join!(export.wait(), stderr_read);
+            if !status?.success() {
+                bail!("nix-store --export failed");
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Copies the closure of a store path from one system to another, via
+/// `nix copy` over the `ssh-ng` protocol. The data flows through the
+/// machine running deploy-flake, so the two systems don't need to be
+/// able to reach each other.
+#[instrument(level = "DEBUG", skip(options), err)]
+pub async fn copy_between(
+    path: &Path,
+    from: &Nixos,
+    to: &Nixos,
+    options: &RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    // Both systems get reached with the same ssh options, so they
+    // can only differ in port if one of them uses the default port:
+    let port = match (from.port(), to.port()) {
+        (Some(from_port), Some(to_port)) if from_port != to_port => {
+            bail!("Can not copy between {from:?} and {to:?}, which listen on different ssh ports")
+        }
+        (from_port, to_port) => from_port.or(to_port),
+    };
+    if from.options().jump_host != to.options().jump_host {
+        bail!("Can not copy between {from:?} and {to:?}, which are reached through different jump hosts");
+    }
+    let mut cmd = Command::from(options.local_nix.command("nix"));
+    cmd.args(["copy", "--from"])
+        .arg(from.ssh_ng_store())
+        .arg("--to")
+        .arg(to.ssh_ng_store());
+    if options.substitute_on_destination {
+        cmd.arg("--substitute-on-destination");
+    }
+    cmd.arg(path);
+    run_local(cmd, to.options(), port, None, true).await
+}
+
+/// Copies the closure of a store path from one system straight to
+/// another, by having `from` push it with `nix copy` over the
+/// `ssh-ng` protocol, so that the data doesn't flow through the
+/// machine running deploy-flake. `from` must be able to reach `to`
+/// with ssh on its own, e.g. with a key of its own.
+#[instrument(level = "DEBUG", skip(options), err)]
+pub async fn copy_from_system(
+    path: &Path,
+    from: &Nixos,
+    to: &Nixos,
+    options: &RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let push = push_args(
+        path,
+        [from.feature_args().await?, from.store_args()].concat(),
+        &to.ssh_ng_store(),
+        to.port(),
+        options.substitute_on_destination,
+    );
+    from.exec(&push, None)
+        .await
+        .with_context(|| format!("Pushing {path:?} from {:?}", from.host()))
+}
+
+/// Returns the command line that pushes the closure of `path` from a
+/// system whose nix commands take `nix_args` (like the features they
+/// need enabled, or the store they use) to `to_store` (listening on
+/// the given ssh port, if any).
+fn push_args(
+    path: &Path,
+    nix_args: Vec<String>,
+    to_store: &str,
+    port: Option<u16>,
+    substitute_on_destination: bool,
+) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(port) = port {
+        args.extend(["env".to_string(), format!("NIX_SSHOPTS=-p {port}")]);
+    }
+    args.push("nix".to_string());
+    args.extend(nix_args);
+    args.push("copy".to_string());
+    args.extend(["--to".to_string(), to_store.to_string()]);
+    if substitute_on_destination {
+        args.push("--substitute-on-destination".to_string());
+    }
+    args.push(path.to_string_lossy().into_owned());
+    args
+}
+
+/// Returns the closure of a store path, in dependency order.
+async fn requisites(path: &Path, local_nix: &LocalNix) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let output = Command::from(local_nix.command("nix-store"))
+        .args(["--query", "--requisites"])
+        .arg(path)
+        .output()
+        .await
+        .context("Could not execute nix-store --query")?;
+    if !output.status.success() {
+        bail!(
+            "nix-store --query failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Runs a local command that talks to the destination via ssh (on
+/// the given port, if any), logging its output. `options` must be the
+/// ones that the destination gets connected with, so that the command
+/// reaches it the same way (e.g. through its jump host). If the command is a
+/// `nix copy`, `copy_progress` has it report its progress, which gets
+/// shown on the current span's progress bar.
+#[instrument(level = "DEBUG", skip(options), err)]
+pub(crate) async fn run_local(
+    mut cmd: Command,
+    options: &RemoteOptions,
+    port: Option<u16>,
+    control_socket: Option<&Path>,
+    copy_progress: bool,
+) -> Result<(), anyhow::Error> {
+    let ssh_options = local_ssh_options(options, port, control_socket);
+    if !ssh_options.is_empty() {
+        cmd.env("NIX_SSHOPTS", ssh_options.join(" "));
+    }
+    if options.non_interactive {
+        cmd.stdin(Stdio::null());
+    }
+    if copy_progress {
+        cmd.args(["--log-format", "internal-json"]);
+    }
+    cmd.stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = cmd.spawn()?;
+    let stdout_read = tokio::task::spawn(
+        read_and_log_messages(Stream::Stdout, child.stdout.take().unwrap(), options)
+            .instrument(log::Span::current()),
+    );
+
+    let stderr = child.stderr.take().unwrap();
+    let messages = RingBuffer::new(KEPT_STDERR_LINES);
+    let logger = SubprocessLogger::for_options(options).with_sink(messages.clone());
+    let stderr_read = if copy_progress {
+        tokio::task::spawn(
+            read_and_show_copy_progress(stderr, logger).instrument(log::Span::current()),
+        )
+    } else {
+        tokio::task::spawn(
+            logger
+                .read(Stream::Stderr, stderr)
+                .instrument(log::Span::current()),
+        )
+    };
+
+    let outcomes = futures::join!(child.wait(), stdout_read, stderr_read);
+    let result = outcomes.0?;
+    if !result.success() {
+        let failed = format!("{:?} failed with {result}", cmd.as_std().get_program());
+        // Copies mostly fail because the connection dropped, which
+        // retrying can get past, but not e.g. because of a missing
+        // path or a full disk:
+        return Err(match transient_failure_from_output(&messages.lines()) {
+            Some(line) => anyhow::Error::new(TransientFailure(line.to_string())).context(failed),
+            None => anyhow::anyhow!(failed),
+        });
+    }
+    Ok(())
+}
+
+/// How many of the last lines that a local command prints to stderr
+/// get kept to tell why it failed.
+const KEPT_STDERR_LINES: usize = 100;
+
+/// Returns the options for the ssh that a local command talks to the
+/// destination with. With the `control_socket` of the destination's
+/// connection, that ssh runs over the connection instead of
+/// connecting (and authenticating) again; if the connection is gone,
+/// it connects by itself.
+fn local_ssh_options(
+    options: &RemoteOptions,
+    port: Option<u16>,
+    control_socket: Option<&Path>,
+) -> Vec<String> {
+    let mut ssh_options = options.ssh_options();
+    if let Some(port) = port {
+        ssh_options.extend(["-p".to_string(), port.to_string()]);
+    }
+    if let Some(socket) = control_socket {
+        ssh_options.extend([
+            "-o".to_string(),
+            format!("ControlPath={}", socket.display()),
+        ]);
+    }
+    ssh_options
+}
+
+/// The type of nix activities that copy a single store path.
+const ACTIVITY_COPY_PATH: u64 = 100;
+
+/// The type of nix results that report an activity's progress.
+const RESULT_PROGRESS: u64 = 105;
+
+/// A line of `nix --log-format internal-json` output, without the
+/// `@nix ` prefix.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum NixLogLine {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        fields: Vec<serde_json::Value>,
+    },
+    Msg {
+        msg: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The progress of a `nix copy`, as reported in its `--log-format
+/// internal-json` output.
+#[derive(Debug, Default)]
+struct CopyProgress {
+    /// The bytes that each activity copying a store path has copied,
+    /// and the bytes it expects to copy.
+    paths: HashMap<u64, (u64, u64)>,
+}
+
+impl CopyProgress {
+    /// Updates the progress from a line of output, returning the
+    /// message that the line carries, if any. Lines that aren't in
+    /// nix's JSON format are messages in their entirety.
+    fn update(&mut self, line: &str) -> Option<String> {
+        let Some(json) = line.strip_prefix("@nix ") else {
+            return Some(line.to_string());
+        };
+        match serde_json::from_str(json) {
+            Ok(NixLogLine::Start { id, kind }) if kind == ACTIVITY_COPY_PATH => {
+                self.paths.insert(id, (0, 0));
+                None
+            }
+            Ok(NixLogLine::Result { id, kind, fields }) if kind == RESULT_PROGRESS => {
+                if let (Some(path), [done, expected, ..]) = (self.paths.get_mut(&id), &fields[..]) {
+                    *path = (
+                        done.as_u64().unwrap_or_default(),
+                        expected.as_u64().unwrap_or_default(),
+                    );
+                }
+                None
+            }
+            Ok(NixLogLine::Msg { msg }) => Some(msg),
+            Ok(_) => None,
+            Err(_) => Some(line.to_string()),
+        }
+    }
+
+    /// Returns the bytes copied so far, and the bytes expected to be
+    /// copied by the paths that started copying.
+    fn bytes(&self) -> (u64, u64) {
+        self.paths.values().fold((0, 0), |(done, expected), path| {
+            (done + path.0, expected + path.1)
+        })
+    }
+
+    /// Returns how many paths finished copying, out of how many
+    /// started.
+    fn paths(&self) -> (usize, usize) {
+        let done = self
+            .paths
+            .values()
+            .filter(|(done, expected)| *expected > 0 && done >= expected)
+            .count();
+        (done, self.paths.len())
+    }
+}
+
+/// Reads the `--log-format internal-json` output of a `nix copy`,
+/// showing its progress on the current span's progress bar, and
+/// handing the messages in it to `logger`.
+async fn read_and_show_copy_progress(
+    r: impl AsyncRead + Unpin,
+    mut logger: SubprocessLogger,
+) -> Result<(), anyhow::Error> {
+    let span = log::Span::current();
+    span.pb_set_style(
+        &ProgressStyle::with_template(
+            "{span_child_prefix}{spinner} {span_name}{{{span_fields}}} {wide_bar} {binary_bytes}/{binary_total_bytes} {msg} (ETA {eta})",
+        )
+        .expect("The progress bar template is valid"),
+    );
+    let mut progress = CopyProgress::default();
+    let mut lines = BufReader::new(r).lines();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .context("Unable to read next line")?
+    {
+        if let Some(message) = progress.update(&line) {
+            logger
+                .handle(&OutputLine {
+                    stream: Stream::Stderr,
+                    line: message,
+                })
+                .await?;
+            continue;
+        }
+        let (done, expected) = progress.bytes();
+        let (paths_done, paths) = progress.paths();
+        span.pb_set_length(expected);
+        span.pb_set_position(done);
+        span.pb_set_message(&format!("{paths_done}/{paths} paths"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{local_ssh_options, push_args, CopyProgress};
+    use crate::RemoteOptions;
+    use std::path::Path;
+
+    #[test]
+    fn pushing() {
+        let path = Path::new("/nix/store/abc-nixos-system-web2");
+        assert_eq!(
+            push_args(path, vec![], "ssh-ng://web2", None, false),
+            vec![
+                "nix",
+                "copy",
+                "--to",
+                "ssh-ng://web2",
+                "/nix/store/abc-nixos-system-web2"
+            ]
+        );
+        assert_eq!(
+            push_args(
+                path,
+                vec![
+                    "--extra-experimental-features".to_string(),
+                    "nix-command".to_string(),
+                    "--store".to_string(),
+                    "/mnt".to_string()
+                ],
+                "ssh-ng://web2",
+                Some(2222),
+                true
+            ),
+            vec![
+                "env",
+                "NIX_SSHOPTS=-p 2222",
+                "nix",
+                "--extra-experimental-features",
+                "nix-command",
+                "--store",
+                "/mnt",
+                "copy",
+                "--to",
+                "ssh-ng://web2",
+                "--substitute-on-destination",
+                "/nix/store/abc-nixos-system-web2"
+            ]
+        );
+    }
+
+    #[test]
+    fn reusing_the_connection() {
+        let options = RemoteOptions::default();
+        assert!(local_ssh_options(&options, None, None).is_empty());
+        assert_eq!(
+            local_ssh_options(
+                &options,
+                Some(2222),
+                Some(Path::new("/tmp/.ssh-connection1"))
+            ),
+            vec!["-p", "2222", "-o", "ControlPath=/tmp/.ssh-connection1"]
+        );
+    }
+
+    #[test]
+    fn copy_progress_parsing() {
+        let mut progress = CopyProgress::default();
+        assert_eq!(
+            progress.update("warning: ignoring untrusted substituter"),
+            Some("warning: ignoring untrusted substituter".to_string())
+        );
+        assert_eq!(progress.update(r#"@nix {"action":"start","id":1,"level":3,"type":103,"text":"","fields":[],"parent":0}"#), None);
+        assert_eq!(progress.update(r#"@nix {"action":"start","id":2,"level":3,"type":100,"text":"copying path '/nix/store/abc-hello'","fields":["/nix/store/abc-hello","local","ssh-ng://db1"],"parent":1}"#), None);
+        assert_eq!(progress.update(r#"@nix {"action":"start","id":3,"level":3,"type":100,"text":"copying path '/nix/store/def-world'","fields":["/nix/store/def-world","local","ssh-ng://db1"],"parent":1}"#), None);
+        assert_eq!(
+            progress
+                .update(r#"@nix {"action":"result","id":2,"type":105,"fields":[1000,1000,0,0]}"#),
+            None
+        );
+        assert_eq!(
+            progress
+                .update(r#"@nix {"action":"result","id":3,"type":105,"fields":[500,3000,0,0]}"#),
+            None
+        );
+        assert_eq!(
+            progress.update(r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,1,0]}"#),
+            None
+        );
+        assert_eq!(progress.update(r#"@nix {"action":"stop","id":2}"#), None);
+        assert_eq!(
+            progress
+                .update(r#"@nix {"action":"msg","level":0,"msg":"error: unexpected end-of-file"}"#),
+            Some("error: unexpected end-of-file".to_string())
+        );
+        assert_eq!(progress.bytes(), (1500, 4000));
+        assert_eq!(progress.paths(), (1, 2));
+    }
+}
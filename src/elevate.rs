@@ -0,0 +1,147 @@
+//! Running commands on a destination with superuser privileges.
+
+use std::{fmt, str::FromStr};
+
+/// Asks for the password that elevating privileges on a destination
+/// takes.
+pub trait PasswordPrompt: fmt::Debug + Send + Sync {
+    /// Returns the password for `host`. This may block, e.g. while
+    /// the user types it in.
+    fn password(&self, host: &str) -> Result<String, anyhow::Error>;
+}
+
+/// The ways of running commands with superuser privileges on a
+/// destination, by prefixing their command lines.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Elevation {
+    /// Use `sudo`.
+    #[default]
+    Sudo,
+
+    /// Use OpenBSD's `doas`.
+    Doas,
+
+    /// Use systemd's `run0`.
+    Run0,
+
+    /// Run commands as the user that deploy-flake logs in as, which
+    /// must be root already.
+    None,
+
+    /// Prefix commands with this command line. Parsing one rejects
+    /// an empty command line; an empty one runs commands as-is, like
+    /// [`Elevation::None`].
+    Custom(Vec<String>),
+}
+
+impl Elevation {
+    /// Returns the command line that runs the rest of the command
+    /// line with superuser privileges. It is never empty. With
+    /// `non_interactive`, it fails instead of prompting for a
+    /// password (where the way of elevating privileges allows that).
+    pub fn prefix(&self, non_interactive: bool) -> Vec<String> {
+        let (prefix, non_interactive_flag): (Vec<&str>, _) = match self {
+            Elevation::Sudo => (vec!["sudo"], Some("-n")),
+            Elevation::Doas => (vec!["doas"], Some("-n")),
+            Elevation::Run0 => (vec!["run0"], Some("--no-ask-password")),
+            Elevation::Custom(prefix) if !prefix.is_empty() => {
+                (prefix.iter().map(String::as_str).collect(), None)
+            }
+            // Commands need a program to run, and env runs the rest
+            // of the command line as-is:
+            Elevation::None | Elevation::Custom(_) => (vec!["env"], None),
+        };
+        prefix
+            .into_iter()
+            .chain(non_interactive_flag.filter(|_| non_interactive))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Returns the command line that runs the rest of the command
+    /// line with superuser privileges, reading the password for that
+    /// from the first line of its stdin, if this way of elevating
+    /// privileges can do that.
+    pub fn password_prefix(&self) -> Option<Vec<String>> {
+        match self {
+            // An empty prompt keeps sudo from printing one to stderr:
+            Elevation::Sudo => Some(["sudo", "-S", "-p", ""].map(String::from).to_vec()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Elevation {
+    type Err = anyhow::Error;
+
+    /// Parses one of the built-in names (`sudo`, `doas`, `run0` or
+    /// `none`), or else a custom command line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "sudo" => Elevation::Sudo,
+            "doas" => Elevation::Doas,
+            "run0" => Elevation::Run0,
+            "none" => Elevation::None,
+            custom => {
+                let prefix: Vec<String> = custom.split_whitespace().map(String::from).collect();
+                if prefix.is_empty() {
+                    anyhow::bail!("The command that elevates privileges can not be empty");
+                }
+                Elevation::Custom(prefix)
+            }
+        })
+    }
+}
+
+impl fmt::Display for Elevation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Elevation::Sudo => write!(f, "sudo"),
+            Elevation::Doas => write!(f, "doas"),
+            Elevation::Run0 => write!(f, "run0"),
+            Elevation::None => write!(f, "none"),
+            Elevation::Custom(prefix) => write!(f, "{}", prefix.join(" ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Elevation;
+    use test_case::test_case;
+
+    #[test_case("sudo", false => vec!["sudo"]; "sudo")]
+    #[test_case("sudo", true => vec!["sudo", "-n"]; "non-interactive sudo")]
+    #[test_case("doas", true => vec!["doas", "-n"]; "non-interactive doas")]
+    #[test_case("run0", true => vec!["run0", "--no-ask-password"]; "non-interactive run0")]
+    #[test_case("none", true => vec!["env"]; "none")]
+    #[test_case("pfexec -P all", true => vec!["pfexec", "-P", "all"]; "custom")]
+    fn prefixes(elevation: &str, non_interactive: bool) -> Vec<String> {
+        let elevation: Elevation = elevation.parse().unwrap();
+        assert_eq!(
+            elevation.to_string().parse::<Elevation>().unwrap(),
+            elevation
+        );
+        elevation.prefix(non_interactive)
+    }
+
+    #[test]
+    fn password_prefixes() {
+        assert_eq!(
+            Elevation::Sudo.password_prefix(),
+            Some(vec![
+                "sudo".to_string(),
+                "-S".to_string(),
+                "-p".to_string(),
+                String::new()
+            ])
+        );
+        assert_eq!(Elevation::Doas.password_prefix(), None);
+    }
+
+    #[test]
+    fn empty_custom_command() {
+        assert!(" ".parse::<Elevation>().is_err());
+        assert_eq!(Elevation::Custom(vec![]).prefix(false), vec!["env"]);
+    }
+}
@@ -0,0 +1,160 @@
+//! A cache of the facts gathered about destinations, kept on the
+//! machine running deploy-flake, so that repeatedly deploying to a
+//! large fleet doesn't probe every host for the same facts every
+//! time.
+//!
+//! Only the facts that rarely change (the hostname, nix version and
+//! features, and architecture) get cached. A cached entry is valid for a limited
+//! time, and only while the destination runs the same system that it
+//! ran when the entry was made: activating a configuration may well
+//! change the nix version.
+
+use crate::HostFacts;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use tracing as log;
+
+/// Where and for how long host facts get cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FactsCache {
+    /// The directory holding one cache file per destination.
+    pub dir: PathBuf,
+
+    /// How long cached facts remain valid.
+    pub ttl: Duration,
+
+    /// Ignore cached facts, gathering them anew (and caching the
+    /// newly-gathered facts).
+    pub refresh: bool,
+}
+
+/// The facts cached about a destination.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CachedFacts {
+    /// When the facts were gathered, in seconds since the UNIX epoch.
+    pub gathered_at: u64,
+
+    /// The store path of the system that the destination ran when
+    /// the facts were gathered.
+    pub current_system: Option<PathBuf>,
+
+    pub hostname: String,
+    pub nix_version: String,
+    pub architecture: String,
+
+    /// Missing from entries cached by older versions of deploy-flake.
+    #[serde(default)]
+    pub nix_features: Vec<String>,
+}
+
+impl FactsCache {
+    /// Returns the default cache directory, under `$XDG_CACHE_HOME`
+    /// (or `~/.cache`), if either is set.
+    pub fn default_dir() -> Option<PathBuf> {
+        let cache_home = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(cache_home.join("deploy-flake").join("facts"))
+    }
+
+    /// Returns the path of the cache file for a destination.
+    fn path(&self, host: &str) -> PathBuf {
+        let name: String = host
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '@' => c,
+                _ => '_',
+            })
+            .collect();
+        self.dir.join(format!("{name}.json"))
+    }
+
+    /// Returns the facts cached about a destination, unless there
+    /// are none or they have expired.
+    pub fn load(&self, host: &str) -> Option<CachedFacts> {
+        if self.refresh {
+            return None;
+        }
+        let contents = std::fs::read(self.path(host)).ok()?;
+        let cached: CachedFacts = serde_json::from_slice(&contents).ok()?;
+        let age = unix_time().saturating_sub(cached.gathered_at);
+        (age < self.ttl.as_secs()).then_some(cached)
+    }
+
+    /// Caches the facts gathered about a destination. Caching is
+    /// best-effort: failures only get logged.
+    pub fn store(&self, host: &str, facts: &HostFacts) {
+        let cached = CachedFacts {
+            gathered_at: unix_time(),
+            current_system: facts.current_system.clone(),
+            hostname: facts.hostname.clone(),
+            nix_version: facts.nix_version.clone(),
+            architecture: facts.architecture.clone(),
+            nix_features: facts.nix_features.clone(),
+        };
+        let path = self.path(host);
+        let result = std::fs::create_dir_all(&self.dir)
+            .and_then(|()| Ok(serde_json::to_vec(&cached)?))
+            .and_then(|contents| std::fs::write(&path, contents));
+        if let Err(error) = result {
+            log::event!(log::Level::DEBUG, %error, ?path, "Could not cache host facts");
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::FactsCache;
+    use crate::HostFacts;
+    use std::{path::PathBuf, time::Duration};
+
+    #[test]
+    fn caching() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FactsCache {
+            dir: dir.path().join("facts"),
+            ttl: Duration::from_secs(3600),
+            refresh: false,
+        };
+        let facts = HostFacts {
+            hostname: "db1".to_string(),
+            nix_version: "2.18.1".to_string(),
+            architecture: "x86_64".to_string(),
+            nix_features: vec!["flakes".to_string()],
+            free_store_bytes: 12345678,
+            free_boot_bytes: None,
+            current_system: Some(PathBuf::from("/nix/store/abc-nixos-system-db1")),
+            current_generation: Some(42),
+            nixos_version: None,
+        };
+        assert_eq!(cache.load("root@db1"), None);
+        cache.store("root@db1", &facts);
+        let cached = cache.load("root@db1").unwrap();
+        assert_eq!(cached.nix_version, "2.18.1");
+        assert_eq!(cached.current_system, facts.current_system);
+        assert_eq!(cache.load("root@db2"), None);
+
+        let refreshing = FactsCache {
+            refresh: true,
+            ..cache.clone()
+        };
+        assert_eq!(refreshing.load("root@db1"), None);
+        let expired = FactsCache {
+            ttl: Duration::ZERO,
+            ..cache.clone()
+        };
+        assert_eq!(expired.load("root@db1"), None);
+    }
+}
@@ -0,0 +1,265 @@
+//! Inventories of what a fleet of destinations runs: the system that
+//! each destination runs, and the deploy that deploy-flake last
+//! activated there, according to its provenance record. Inventories
+//! can be saved and compared against later ones, to see how the fleet
+//! changed in between.
+
+use crate::Nixos;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+/// A deploy, as recorded in a destination's provenance record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Deployed {
+    /// The system configuration that the deploy activated.
+    pub path: PathBuf,
+
+    /// The ID of the deploy run. Deploy IDs are ULIDs, so later
+    /// deploys have IDs that sort after those of earlier ones.
+    pub deploy_id: String,
+
+    /// Where the configuration came from (see
+    /// [`crate::Flake::provenance`]).
+    pub source: String,
+}
+
+/// What a destination runs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HostState {
+    /// The store path of the system that the destination runs.
+    pub current_system: Option<PathBuf>,
+
+    /// The last deploy that deploy-flake made to the destination.
+    pub deployed: Option<Deployed>,
+}
+
+impl HostState {
+    /// Finds out what the system `on` runs.
+    pub async fn gather(on: &Nixos) -> Result<Self, anyhow::Error> {
+        let current_system = on.facts().await?.current_system.clone();
+        let deployed = on.os().last_deploy().await?;
+        Ok(Self {
+            current_system,
+            deployed,
+        })
+    }
+
+    /// Returns whether the destination runs something other than
+    /// what deploy-flake last activated there, e.g. because someone
+    /// rolled it back by hand.
+    pub fn drifted(&self) -> bool {
+        self.deployed
+            .as_ref()
+            .is_some_and(|deployed| Some(&deployed.path) != self.current_system.as_ref())
+    }
+
+    fn source(&self) -> Option<&str> {
+        self.deployed
+            .as_ref()
+            .map(|deployed| deployed.source.as_str())
+    }
+}
+
+/// What every destination in a fleet runs, by destination.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Inventory {
+    pub hosts: BTreeMap<String, HostState>,
+}
+
+impl Inventory {
+    /// Reads an inventory saved with [`Inventory::save`].
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read(path).with_context(|| format!("Could not read {path:?}"))?;
+        serde_json::from_slice(&contents).with_context(|| format!("Could not parse {path:?}"))
+    }
+
+    /// Writes the inventory to a JSON file.
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Could not write {path:?}"))
+    }
+
+    /// Returns the source of the fleet's most recent deploy.
+    fn newest_source(&self) -> Option<&str> {
+        self.hosts
+            .values()
+            .filter_map(|host| host.deployed.as_ref())
+            .max_by(|a, b| a.deploy_id.cmp(&b.deploy_id))
+            .map(|deployed| deployed.source.as_str())
+    }
+
+    /// Describes which destinations run which source, the newest
+    /// source first. Destinations that run an older source are
+    /// behind; those that don't run what deploy-flake last activated
+    /// there have drifted.
+    pub fn render(&self) -> String {
+        let newest = self.newest_source();
+        let mut by_source: BTreeMap<Option<&str>, Vec<(&String, &HostState)>> = BTreeMap::new();
+        for (name, host) in &self.hosts {
+            by_source
+                .entry(host.source())
+                .or_default()
+                .push((name, host));
+        }
+        let mut groups: Vec<_> = by_source.into_iter().collect();
+        groups.sort_by_key(|(source, hosts)| {
+            (
+                *source != newest,
+                source.is_none(),
+                std::cmp::Reverse(hosts.len()),
+            )
+        });
+        let mut out = String::new();
+        for (source, hosts) in groups {
+            let _ = match source {
+                Some(source) if Some(source) == newest => writeln!(out, "{source} (newest):"),
+                Some(source) => writeln!(out, "{source} (behind):"),
+                None => writeln!(out, "not deployed by deploy-flake:"),
+            };
+            for (name, host) in hosts {
+                let system = host
+                    .current_system
+                    .as_deref()
+                    .map_or("unknown system".into(), Path::to_string_lossy);
+                let _ = match &host.deployed {
+                    Some(deployed) if host.drifted() => writeln!(
+                        out,
+                        "  {name}: {system} (drifted, deploy {} activated {})",
+                        deployed.deploy_id,
+                        deployed.path.display()
+                    ),
+                    _ => writeln!(out, "  {name}: {system}"),
+                };
+            }
+        }
+        out
+    }
+
+    /// Describes how the fleet changed since the `earlier` inventory.
+    pub fn changes_since(&self, earlier: &Inventory) -> Vec<String> {
+        let mut changes = vec![];
+        for (name, before) in &earlier.hosts {
+            let Some(now) = self.hosts.get(name) else {
+                changes.push(format!("{name}: gone"));
+                continue;
+            };
+            let describe = |source: Option<&str>| source.unwrap_or("not deployed").to_string();
+            if before.source() != now.source() {
+                changes.push(format!(
+                    "{name}: {} -> {}",
+                    describe(before.source()),
+                    describe(now.source())
+                ));
+            } else if before.current_system != now.current_system {
+                changes.push(format!(
+                    "{name}: system {:?} -> {:?}",
+                    before.current_system, now.current_system
+                ));
+            }
+        }
+        for name in self.hosts.keys() {
+            if !earlier.hosts.contains_key(name) {
+                changes.push(format!("{name}: new"));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Deployed, HostState, Inventory};
+    use std::path::PathBuf;
+
+    fn host(system: &str, deployed: Option<(&str, &str, &str)>) -> HostState {
+        HostState {
+            current_system: Some(PathBuf::from(system)),
+            deployed: deployed.map(|(path, deploy_id, source)| Deployed {
+                path: PathBuf::from(path),
+                deploy_id: deploy_id.to_string(),
+                source: source.to_string(),
+            }),
+        }
+    }
+
+    fn inventory(hosts: Vec<(&str, HostState)>) -> Inventory {
+        Inventory {
+            hosts: hosts
+                .into_iter()
+                .map(|(name, host)| (name.to_string(), host))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn rendering() {
+        let fleet = inventory(vec![
+            (
+                "db1",
+                host("/nix/store/a", Some(("/nix/store/a", "01HA", "rev a"))),
+            ),
+            (
+                "db2",
+                host("/nix/store/b", Some(("/nix/store/b", "01HB", "rev b"))),
+            ),
+            (
+                "db3",
+                host("/nix/store/x", Some(("/nix/store/c", "01HB", "rev b"))),
+            ),
+            ("web1", host("/nix/store/w", None)),
+        ]);
+        assert!(fleet.hosts["db3"].drifted());
+        assert!(!fleet.hosts["web1"].drifted());
+        assert_eq!(
+            fleet.render(),
+            "rev b (newest):\n  db2: /nix/store/b\n  db3: /nix/store/x (drifted, deploy 01HB activated /nix/store/c)\n\
+             rev a (behind):\n  db1: /nix/store/a\n\
+             not deployed by deploy-flake:\n  web1: /nix/store/w\n"
+        );
+    }
+
+    #[test]
+    fn changes() {
+        let before = inventory(vec![
+            (
+                "db1",
+                host("/nix/store/a", Some(("/nix/store/a", "01HA", "rev a"))),
+            ),
+            (
+                "db2",
+                host("/nix/store/a", Some(("/nix/store/a", "01HA", "rev a"))),
+            ),
+            (
+                "db3",
+                host("/nix/store/a", Some(("/nix/store/a", "01HA", "rev a"))),
+            ),
+        ]);
+        let after = inventory(vec![
+            (
+                "db1",
+                host("/nix/store/b", Some(("/nix/store/b", "01HB", "rev b"))),
+            ),
+            (
+                "db2",
+                host("/nix/store/x", Some(("/nix/store/a", "01HA", "rev a"))),
+            ),
+            ("web1", host("/nix/store/w", None)),
+        ]);
+        assert_eq!(
+            after.changes_since(&before),
+            vec![
+                "db1: rev a -> rev b",
+                "db2: system Some(\"/nix/store/a\") -> Some(\"/nix/store/x\")",
+                "db3: gone",
+                "web1: new",
+            ]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Inspecting the git repository that a flake lives in: checking that
+//! its revision was signed by someone who is allowed to deploy it,
+//! and whether it is a shallow clone.
+
+use anyhow::{bail, Context};
+use std::{path::Path, process::Command};
+use tracing as log;
+
+/// Checks that the revision of the git repository in `dir` carries a
+/// good signature by one of the `allowed` keys, either on the commit
+/// itself or on a tag pointing at it. Keys are given as GnuPG
+/// fingerprints or as SSH key fingerprints (like `SHA256:...`).
+/// Returns the fingerprint of the key that signed the revision.
+pub fn verify_signed_revision(
+    dir: &Path,
+    revision: &str,
+    allowed: &[String],
+) -> Result<String, anyhow::Error> {
+    let tags = git(dir, &["tag", "--points-at", revision])?;
+    let candidates = std::iter::once(("verify-commit", revision))
+        .chain(tags.lines().map(|tag| ("verify-tag", tag)));
+    let mut signers = vec![];
+    for (verify, object) in candidates {
+        let output = Command::new("git")
+            .args([verify, "--raw", object])
+            .current_dir(dir)
+            .output()
+            .context("Could not execute git")?;
+        if !output.status.success() {
+            log::event!(log::Level::DEBUG, object, "No good signature");
+            continue;
+        }
+        let found = signers_from_output(&String::from_utf8_lossy(&output.stderr));
+        if let Some(signer) = found.iter().find(|signer| is_allowed(signer, allowed)) {
+            log::event!(log::Level::INFO, object, signer, "Revision is signed");
+            return Ok(signer.clone());
+        }
+        signers.extend(found);
+    }
+    if signers.is_empty() {
+        bail!("Revision {revision} carries no good signature");
+    }
+    bail!(
+        "Revision {revision} is signed only by keys that are not allowed: {}",
+        signers.join(", ")
+    );
+}
+
+/// Returns whether `dir` is in a shallow clone of a git repository.
+/// Directories that aren't in a git repository are not shallow.
+pub fn is_shallow(dir: &Path) -> bool {
+    git(dir, &["rev-parse", "--is-shallow-repository"])
+        .map(|output| output.trim() == "true")
+        .unwrap_or(false)
+}
+
+/// Runs a git command in `dir`, returning its output.
+fn git(dir: &Path, args: &[&str]) -> Result<String, anyhow::Error> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .context("Could not execute git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns whether a signing key's fingerprint is in the allowed set.
+/// GnuPG fingerprints compare case-insensitively and may be given
+/// with spaces.
+fn is_allowed(signer: &str, allowed: &[String]) -> bool {
+    allowed.iter().any(|key| {
+        let key: String = key.split_whitespace().collect();
+        if key.starts_with("SHA256:") {
+            key == signer
+        } else {
+            key.eq_ignore_ascii_case(signer)
+        }
+    })
+}
+
+/// Extracts the fingerprints of the keys that made good signatures
+/// from the `--raw` output of `git verify-commit` or `git
+/// verify-tag`: GnuPG status lines for GnuPG signatures, and
+/// ssh-keygen's messages for SSH signatures.
+fn signers_from_output(output: &str) -> Vec<String> {
+    let mut signers = vec![];
+    for line in output.lines() {
+        if let Some(rest) = line.strip_prefix("[GNUPG:] VALIDSIG ") {
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            // The signing (sub)key's fingerprint comes first, the
+            // primary key's fingerprint last:
+            signers.extend(fields.first().map(|s| s.to_string()));
+            if fields.len() >= 10 && fields[9] != fields[0] {
+                signers.push(fields[9].to_string());
+            }
+        } else if line.starts_with("Good \"git\" signature") {
+            signers.extend(
+                line.split_whitespace()
+                    .find(|word| word.starts_with("SHA256:"))
+                    .map(String::from),
+            );
+        }
+    }
+    signers
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_allowed, signers_from_output};
+
+    #[test]
+    fn gnupg_signers() {
+        let output = "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG 0123456789ABCDEF Jane Doe <jane@example.com>\n[GNUPG:] VALIDSIG 1111222233334444555566667777888899990000 2024-01-15 1705312345 0 4 0 22 8 00 AAAABBBBCCCCDDDDEEEEFFFF0000111122223333\n";
+        assert_eq!(
+            signers_from_output(output),
+            vec![
+                "1111222233334444555566667777888899990000",
+                "AAAABBBBCCCCDDDDEEEEFFFF0000111122223333"
+            ]
+        );
+        assert!(is_allowed(
+            "AAAABBBBCCCCDDDDEEEEFFFF0000111122223333",
+            &["aaaa bbbb cccc dddd eeee ffff 0000 1111 2222 3333".to_string()]
+        ));
+    }
+
+    #[test]
+    fn ssh_signers() {
+        let output =
+            "Good \"git\" signature for jane@example.com with ED25519 key SHA256:abcDEF123+/xyz\n";
+        assert_eq!(signers_from_output(output), vec!["SHA256:abcDEF123+/xyz"]);
+        assert!(is_allowed(
+            "SHA256:abcDEF123+/xyz",
+            &["SHA256:abcDEF123+/xyz".to_string()]
+        ));
+        assert!(!is_allowed(
+            "SHA256:abcDEF123+/xyz",
+            &["SHA256:ABCdef123+/xyz".to_string()]
+        ));
+        assert!(signers_from_output("error: no signature found\n").is_empty());
+    }
+}
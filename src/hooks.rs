@@ -0,0 +1,294 @@
+//! Commands that run on the machine running deploy-flake at certain
+//! points of deploying to a destination, like taking the destination
+//! out of a load balancer before activating a configuration on it.
+//!
+//! Programs that embed deploy-flake can hook into a deploy with
+//! code, too: every phase of deploying to a destination runs in
+//! [`in_phase`], which awaits the [`PhaseHooks`] they implement (and
+//! set in [`RemoteOptions::phase_hooks`]) whenever the destination
+//! enters or leaves the phase, and which has them called for every
+//! line that a subprocess prints during it.
+
+use crate::{
+    copy::run_local,
+    status,
+    subprocess::{LineSink, Stream},
+    Nixos, OutputLine, RemoteOptions,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use std::{
+    fmt,
+    future::Future,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::process::Command;
+use tracing::{instrument, Instrument};
+
+/// The environment variable that tells a hook which host it runs for.
+pub const HOST_ENV_VAR: &str = "DEPLOY_FLAKE_HOST";
+
+/// The environment variable that tells a hook which user deploy-flake
+/// logs in to the host as, if the destination names one.
+pub const USER_ENV_VAR: &str = "DEPLOY_FLAKE_USER";
+
+/// Returns the environment that tells a hook about `host` (which may
+/// be given as `user@host`).
+fn hook_env(host: &str) -> Vec<(&'static str, &str)> {
+    match host.split_once('@') {
+        Some((user, host)) => vec![(HOST_ENV_VAR, host), (USER_ENV_VAR, user)],
+        None => vec![(HOST_ENV_VAR, host)],
+    }
+}
+
+/// Runs a hook command for a destination with `sh -c`, logging its
+/// output. The host that the hook runs for is passed in
+/// [`HOST_ENV_VAR`], and the user in [`USER_ENV_VAR`].
+#[instrument(level = "DEBUG", err)]
+pub async fn run_hook(command: &str, on: &Nixos) -> Result<(), anyhow::Error> {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command])
+        .envs(hook_env(on.host()))
+        .stdin(Stdio::null());
+    run_local(cmd, on.options(), None, None, false)
+        .await
+        .with_context(|| format!("Hook {command:?} failed"))
+}
+
+/// Hook points in deploying to a destination. All of them do nothing
+/// by default.
+///
+/// The phase hooks get awaited on the task that works on the
+/// destination: a hook that waits (say, for an operator's go-ahead)
+/// pauses the deploy to that destination until it returns, and a hook
+/// that fails fails the phase.
+#[async_trait]
+pub trait PhaseHooks: fmt::Debug + Send + Sync + 'static {
+    /// Called when a destination enters a phase of the deploy. If it
+    /// fails, the phase doesn't run.
+    async fn on_phase_start(&self, _phase: &Phase) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Called when a destination leaves a phase of the deploy, with
+    /// the time it spent in that phase. If it fails, so does the
+    /// phase.
+    async fn on_phase_end(&self, _phase: &Phase, _elapsed: Duration) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Called for every line that a subprocess prints to `stream`,
+    /// along with the phase that the subprocess runs in, if any. This
+    /// gets called while reading the subprocess's output, so it must
+    /// not block.
+    fn on_subprocess_line(&self, _phase: Option<&Phase>, _stream: Stream, _line: &str) {}
+}
+
+tokio::task_local! {
+    /// The phase that the task is in, see [`in_phase`].
+    static CURRENT_PHASE: Phase;
+}
+
+/// Runs `f` as the phase `name` of deploying to `host`: in a span
+/// that every log line from the phase carries the name of, and
+/// between the [`on_phase_start`](PhaseHooks::on_phase_start) and
+/// [`on_phase_end`](PhaseHooks::on_phase_end) of the hooks in
+/// `options`, if any. Subprocesses that get started during the phase
+/// hand their lines to the hooks along with the phase.
+pub async fn in_phase<T>(
+    name: &'static str,
+    host: &str,
+    options: &RemoteOptions,
+    f: impl Future<Output = Result<T, anyhow::Error>>,
+) -> Result<T, anyhow::Error> {
+    let phase = Phase {
+        name: name.to_string(),
+        host: Some(host.to_string()),
+        deploy_id: options.deploy_id.clone(),
+    };
+    let Some(hooks) = options.phase_hooks.as_deref() else {
+        return f.instrument(status::phase(name)).await;
+    };
+    let run = async {
+        hooks
+            .on_phase_start(&phase)
+            .await
+            .with_context(|| format!("The hook starting {name} failed"))?;
+        let started = Instant::now();
+        let result = f.await;
+        let ended = hooks
+            .on_phase_end(&phase, started.elapsed())
+            .await
+            .with_context(|| format!("The hook ending {name} failed"));
+        let value = result?;
+        ended?;
+        Ok(value)
+    };
+    CURRENT_PHASE
+        .scope(phase.clone(), run)
+        .instrument(status::phase(name))
+        .await
+}
+
+/// A phase of deploying to a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phase {
+    /// The name of the phase, like "build" or "activate".
+    pub name: String,
+
+    /// The destination that the phase deploys to, if known.
+    pub host: Option<String>,
+
+    /// The ID of the deploy run, if known.
+    pub deploy_id: Option<String>,
+}
+
+/// Hands the lines that a subprocess prints to
+/// [`PhaseHooks::on_subprocess_line`], along with the phase that the
+/// subprocess got started in.
+#[derive(Debug)]
+pub(crate) struct HookSink {
+    hooks: Arc<dyn PhaseHooks>,
+    phase: Option<Phase>,
+}
+
+impl HookSink {
+    /// Returns a sink for a subprocess that starts in the current
+    /// task's phase, if any.
+    pub(crate) fn new(hooks: Arc<dyn PhaseHooks>) -> Self {
+        Self {
+            hooks,
+            phase: CURRENT_PHASE.try_with(Phase::clone).ok(),
+        }
+    }
+}
+
+impl LineSink for HookSink {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        self.hooks
+            .on_subprocess_line(self.phase.as_ref(), line.stream, &line.line);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hook_env, in_phase, Phase, PhaseHooks};
+    use crate::{
+        subprocess::{Stream, SubprocessLogger},
+        RemoteOptions,
+    };
+    use async_trait::async_trait;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use test_case::test_case;
+
+    #[derive(Default, Clone, Debug)]
+    struct Recorder {
+        events: Arc<Mutex<Vec<String>>>,
+        refuse: Option<&'static str>,
+    }
+
+    impl Recorder {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[async_trait]
+    impl PhaseHooks for Recorder {
+        async fn on_phase_start(&self, phase: &Phase) -> Result<(), anyhow::Error> {
+            let host = phase.host.as_deref().unwrap_or("?");
+            self.record(format!("start {} {host}", phase.name));
+            if self.refuse == Some("start") {
+                anyhow::bail!("refused");
+            }
+            Ok(())
+        }
+
+        async fn on_phase_end(
+            &self,
+            phase: &Phase,
+            _elapsed: Duration,
+        ) -> Result<(), anyhow::Error> {
+            self.record(format!("end {}", phase.name));
+            if self.refuse == Some("end") {
+                anyhow::bail!("refused");
+            }
+            Ok(())
+        }
+
+        fn on_subprocess_line(&self, phase: Option<&Phase>, stream: Stream, line: &str) {
+            let name = phase.map_or("-", |phase| phase.name.as_str());
+            self.record(format!("{name} {} {line}", stream.name()));
+        }
+    }
+
+    #[test_case("web1" => vec![("DEPLOY_FLAKE_HOST", "web1")]; "bare host")]
+    #[test_case("root@web1" => vec![("DEPLOY_FLAKE_HOST", "web1"), ("DEPLOY_FLAKE_USER", "root")]; "with a user")]
+    fn hook_environment(host: &str) -> Vec<(&'static str, &str)> {
+        hook_env(host)
+    }
+
+    #[tokio::test]
+    async fn calls_line_hooks() {
+        let recorder = Recorder::default();
+        let options = RemoteOptions {
+            phase_hooks: Some(Arc::new(recorder.clone())),
+            ..RemoteOptions::default()
+        };
+        in_phase("build", "db1", &options, async {
+            SubprocessLogger::for_options(&options)
+                .read(
+                    Stream::Stderr,
+                    &b"building '/nix/store/aaa-foo.drv'...\n"[..],
+                )
+                .await
+        })
+        .await
+        .unwrap();
+        SubprocessLogger::for_options(&options)
+            .read(Stream::Stdout, &b"outside\n"[..])
+            .await
+            .unwrap();
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec![
+                "start build db1",
+                "build stderr building '/nix/store/aaa-foo.drv'...",
+                "end build",
+                "- stdout outside",
+            ]
+        );
+    }
+
+    #[test_case(None => "start test db1, ran, end test"; "passing")]
+    #[test_case(Some("start") => "start test db1, The hook starting test failed"; "refused start")]
+    #[test_case(Some("end") => "start test db1, ran, end test, The hook ending test failed"; "refused end")]
+    #[tokio::test]
+    async fn calls_phase_hooks(refuse: Option<&'static str>) -> String {
+        let recorder = Recorder {
+            refuse,
+            ..Recorder::default()
+        };
+        let options = RemoteOptions {
+            phase_hooks: Some(Arc::new(recorder.clone())),
+            ..RemoteOptions::default()
+        };
+        let result = in_phase("test", "db1", &options, async {
+            recorder.record("ran".to_string());
+            Ok(())
+        })
+        .await;
+        if let Err(error) = result {
+            recorder.record(error.to_string());
+        }
+        let events = recorder.events.lock().unwrap().join(", ");
+        events
+    }
+}
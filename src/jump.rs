@@ -0,0 +1,195 @@
+//! Jump hosts (bastions) that destinations are reached through.
+//!
+//! Each jump host gets connected to once, and the connections to all
+//! destinations behind it (along with the ssh invocations that copy
+//! closures to them) get multiplexed through that one connection,
+//! instead of each opening their own connection to the jump host.
+
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tempfile::TempDir;
+use tokio::sync::OnceCell;
+use tracing as log;
+
+/// The connections to jump hosts, shared between all destinations of
+/// a run.
+pub struct JumpHosts {
+    /// The directory holding the control sockets of the connections
+    /// and the ssh configurations that tunnel through them, created
+    /// once the first jump host gets connected to.
+    dir: OnceCell<TempDir>,
+
+    /// How ssh treats the jump hosts' host keys: the run's policy,
+    /// never the one that a destination behind them asks for.
+    host_key_policy: Option<crate::HostKeyPolicy>,
+
+    connections: Mutex<HashMap<String, Arc<OnceCell<JumpHost>>>>,
+}
+
+/// An established connection to a jump host.
+struct JumpHost {
+    /// Keeps the connection (and so its control socket) open.
+    _session: openssh::Session,
+
+    /// An ssh configuration file that tunnels connections through the
+    /// jump host's connection.
+    config: PathBuf,
+}
+
+impl Default for JumpHosts {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl fmt::Debug for JumpHosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let connections = self.connections.lock().unwrap();
+        f.debug_struct("JumpHosts")
+            .field("dir", &self.dir.get().map(TempDir::path))
+            .field("host_key_policy", &self.host_key_policy)
+            .field("connections", &connections.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Drop for JumpHosts {
+    fn drop(&mut self) {
+        // Closing the connections needs their control sockets, so
+        // they must go before the directory holding them:
+        if let Ok(connections) = self.connections.get_mut() {
+            connections.clear();
+        }
+    }
+}
+
+impl JumpHosts {
+    /// Returns the (not yet connected) jump hosts of a run, whose
+    /// host keys get checked according to `host_key_policy`.
+    pub fn new(host_key_policy: Option<crate::HostKeyPolicy>) -> Self {
+        Self {
+            dir: OnceCell::new(),
+            host_key_policy,
+            connections: Default::default(),
+        }
+    }
+
+    /// Returns the ssh configuration file that reaches hosts through
+    /// the jump host, connecting to it unless it is connected already.
+    pub async fn config_for(
+        &self,
+        jump_host: &str,
+        options: &crate::RemoteOptions,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let cell = self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(jump_host.to_string())
+            .or_default()
+            .clone();
+        let connection = cell
+            .get_or_try_init(|| self.connect(jump_host, options))
+            .await?;
+        Ok(connection.config.clone())
+    }
+
+    /// Returns the ssh configuration file for a jump host that is
+    /// connected already.
+    pub fn connected_config(&self, jump_host: &str) -> Option<PathBuf> {
+        let connections = self.connections.lock().unwrap();
+        Some(connections.get(jump_host)?.get()?.config.clone())
+    }
+
+    async fn connect(
+        &self,
+        jump_host: &str,
+        options: &crate::RemoteOptions,
+    ) -> Result<JumpHost, anyhow::Error> {
+        log::event!(log::Level::DEBUG, jump_host, "Connecting to jump host");
+        let dir = self
+            .dir
+            .get_or_try_init(|| async {
+                tempfile::Builder::new()
+                    .prefix("deploy-flake-jump-")
+                    .tempdir()
+            })
+            .await
+            .context("Could not create a directory for the jump hosts' control sockets")?
+            .path();
+        let mut builder = openssh::SessionBuilder::default();
+        builder
+            .known_hosts_check(self.host_key_policy.unwrap_or_default().known_hosts())
+            .control_directory(dir);
+        if let Some(keep_alive) = options.keep_alive {
+            builder.server_alive_interval(keep_alive);
+        }
+        let ssh_config = options.ssh_config.as_ref().map(|config| config.file());
+        if let Some(config) = ssh_config {
+            builder.config_file(config);
+        }
+        let session = builder
+            .connect(jump_host)
+            .await
+            .with_context(|| format!("Connecting to jump host {jump_host:?}"))?;
+        let name: String = jump_host
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let config = dir.join(format!("{name}.config"));
+        std::fs::write(
+            &config,
+            tunnel_config(session.control_socket(), jump_host, ssh_config),
+        )
+        .with_context(|| format!("Could not write {config:?}"))?;
+        Ok(JumpHost {
+            _session: session,
+            config,
+        })
+    }
+}
+
+/// Returns an ssh configuration that tunnels connections through the
+/// connection to `jump_host` whose control socket is at `socket`, and
+/// otherwise uses the ssh configuration `config`, or the user's and
+/// the system's if there is none.
+fn tunnel_config(socket: &Path, jump_host: &str, config: Option<&Path>) -> String {
+    // ssh uses the first value it finds for an option, so the
+    // tunnel takes precedence over any ProxyJump in the included
+    // configuration:
+    format!(
+        "ProxyCommand ssh -S \"{}\" -W %h:%p {jump_host}\n{}",
+        socket.display(),
+        crate::ssh_config::includes(config)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::tunnel_config;
+    use std::path::Path;
+
+    #[test]
+    fn tunneling() {
+        assert_eq!(
+            tunnel_config(Path::new("/tmp/j/.ssh-connection1"), "admin@bastion", None),
+            "ProxyCommand ssh -S \"/tmp/j/.ssh-connection1\" -W %h:%p admin@bastion\n\
+             Include ~/.ssh/config\n\
+             Include /etc/ssh/ssh_config\n"
+        );
+        assert_eq!(
+            tunnel_config(
+                Path::new("/tmp/j/.ssh-connection1"),
+                "admin@bastion",
+                Some(Path::new("/tmp/c/config"))
+            ),
+            "ProxyCommand ssh -S \"/tmp/j/.ssh-connection1\" -W %h:%p admin@bastion\n\
+             Include \"/tmp/c/config\"\n"
+        );
+    }
+}
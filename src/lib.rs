@@ -1,70 +1,677 @@
-use log::Instrument;
-use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::io::AsyncRead;
 use tracing::instrument;
+
+// Connections to destinations go through OpenSSH's control sockets,
+// which are Unix domain sockets:
+#[cfg(not(unix))]
+compile_error!("deploy-flake needs a unix-like system; on Windows, build and run it inside WSL");
+
+pub mod config;
+pub mod copy;
+pub mod elevate;
+pub mod facts;
+pub mod fleet;
+pub mod git;
+pub mod hooks;
+pub mod jump;
 mod nix;
 mod os;
+pub mod plan;
+pub mod report;
+pub mod retry;
+pub mod snapshot;
+pub mod ssh_config;
+pub mod status;
+pub mod subprocess;
+pub mod supervise;
+mod system_ssh;
+pub mod tunnel;
 use tracing as log;
 
 pub(crate) use os::{NixOperatingSystem, Verb};
 
-use anyhow::{anyhow, bail, Context};
-use os::Nixos;
+use anyhow::{anyhow, Context};
+pub use nix::LocalNix;
+pub use os::{HostFacts, Nixos, UnitChanges};
+use serde::Deserialize;
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     fmt,
+    future::Future,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
-use tokio::process::Command;
+use subprocess::Stream;
 use url::Url;
 
 /// The tracing target that's used to log messages emitted by
 /// subprocesses.
 pub const SUBPROCESS_LOG_TARGET: &str = "subprocess_log";
 
+/// The slowest transfer rate (in bytes per second) that we assume
+/// when computing a copy timeout from the size of a closure.
+pub const ASSUMED_MIN_COPY_BANDWIDTH: u64 = 1024 * 1024;
+
+/// The time added on top of the bandwidth-derived copy timeout, to
+/// account for connection setup and nix's own overhead.
+pub const COPY_TIMEOUT_SLACK: Duration = Duration::from_secs(30);
+
+/// Returns a timeout that a copy of `bytes` bytes should reasonably
+/// complete in, assuming a link at least as fast as
+/// [`ASSUMED_MIN_COPY_BANDWIDTH`].
+pub fn copy_timeout_for_size(bytes: u64) -> Duration {
+    Duration::from_secs(bytes / ASSUMED_MIN_COPY_BANDWIDTH) + COPY_TIMEOUT_SLACK
+}
+
 /// All the important bits about a nix flake reference.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Flake {
-    /// The path that the flake source code lives in.
-    dir: PathBuf,
+    /// The local directory that the flake source code lives in,
+    /// unless the flake was given by a remote reference.
+    dir: Option<PathBuf>,
+
+    /// The locked reference that a remote flake can be fetched by
+    /// (like `github:owner/repo/<rev>`).
+    reference: Option<String>,
 
     /// The path that the flake derivation lives in, via `nix info`
     resolved_path: PathBuf,
+
+    /// The git revision of the flake, unless it has uncommitted
+    /// changes (or isn't in a git repository).
+    revision: Option<String>,
+
+    /// The git revision that the flake's uncommitted changes are
+    /// based on, if nix reports it.
+    dirty_revision: Option<String>,
+
+    /// The type of the flake's source, like "git" or "path".
+    source_type: Option<String>,
+
+    /// The NAR hash of the flake's source tree, which identifies it
+    /// exactly, whether it's in git or not.
+    nar_hash: Option<String>,
+
+    /// Whether the flake lives in a shallow git clone.
+    shallow: bool,
+}
+
+/// Something about a flake's source that makes it hard to tell later
+/// what exactly was deployed.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum SourceWarning {
+    /// The flake has uncommitted changes, which are based on the
+    /// given revision (if nix knows it).
+    Dirty { revision: Option<String> },
+
+    /// The flake lives in a shallow clone, whose history may be
+    /// missing the revision's ancestry.
+    Shallow,
+
+    /// The flake isn't in a git repository at all.
+    NotGit { source_type: String },
+}
+
+impl SourceWarning {
+    /// Returns a short, stable name for the kind of warning.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourceWarning::Dirty { .. } => "dirty",
+            SourceWarning::Shallow => "shallow",
+            SourceWarning::NotGit { .. } => "not-git",
+        }
+    }
+}
+
+impl fmt::Display for SourceWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceWarning::Dirty {
+                revision: Some(revision),
+            } => write!(f, "The flake has uncommitted changes on top of {revision}"),
+            SourceWarning::Dirty { revision: None } => {
+                write!(f, "The flake has uncommitted changes")
+            }
+            SourceWarning::Shallow => write!(f, "The flake is in a shallow git clone"),
+            SourceWarning::NotGit { source_type } => {
+                write!(
+                    f,
+                    "The flake is not in a git repository (source type {source_type:?})"
+                )
+            }
+        }
+    }
 }
 
-/// Read from an AsyncRead stream and log each line as INFO-level messages.
-pub(crate) async fn read_and_log_messages(
-    stream: &str,
+/// Options controlling how the phases of a deployment get retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeployOptions {
+    /// How copying a closure to a destination gets retried. Only
+    /// copies that exceed the attempt timeout get retried; without
+    /// one, a timeout gets derived from the size of the closure.
+    pub copy_retry: retry::RetryPolicy,
+
+    /// How building a configuration gets retried, when it failed for
+    /// a transient reason (see [`TransientFailure`]).
+    pub build_retry: retry::RetryPolicy,
+
+    /// The most bytes that copying a closure to a destination may
+    /// transfer. Larger copies fail before they start.
+    pub max_transfer_size: Option<ByteSize>,
+
+    /// Whether to check that a destination has enough free disk space
+    /// for a closure before copying it there, and whether running out
+    /// of space stops the copy.
+    pub check_free_space: CheckBehavior,
+}
+
+impl Default for DeployOptions {
+    fn default() -> Self {
+        Self {
+            copy_retry: Default::default(),
+            build_retry: retry::RetryPolicy::default().with_max_retries(2),
+            max_transfer_size: None,
+            check_free_space: CheckBehavior::Run,
+        }
+    }
+}
+
+/// Where a system configuration gets built.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum BuildOn {
+    /// On the destination, from a copy of the flake's source.
+    #[default]
+    Target,
+
+    /// On the machine running deploy-flake. Only the built system
+    /// gets copied to the destination, so the destination never has
+    /// to evaluate the configuration.
+    Local,
+
+    /// On another host (reached over ssh, like a destination), from
+    /// a copy of the flake's source. The built system gets copied
+    /// from there to the destination.
+    Host(String),
+}
+
+impl FromStr for BuildOn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "target" => Ok(BuildOn::Target),
+            "local" => Ok(BuildOn::Local),
+            "" => Err(anyhow!("Must be \"target\", \"local\" or a host name")),
+            host => Ok(BuildOn::Host(host.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for BuildOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildOn::Target => write!(f, "target"),
+            BuildOn::Local => write!(f, "local"),
+            BuildOn::Host(host) => write!(f, "{host}"),
+        }
+    }
+}
+
+/// Options controlling how a system configuration gets built.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Extra commandline arguments passed to the "nix build" command.
+    pub cmdline: Vec<String>,
+
+    /// Whether to keep building independent derivations when one of
+    /// them fails, so that all failures can be reported at once.
+    pub keep_going: bool,
+
+    /// The maximum number of build jobs that nix runs in parallel.
+    pub max_jobs: Option<u32>,
+
+    /// The number of CPU cores that each build job may use.
+    pub cores: Option<u32>,
+
+    /// Whether to print the full build logs of each derivation
+    /// (nix's `-L`), rather than just nix's progress messages.
+    pub print_build_logs: bool,
+
+    /// The number of log lines that nix shows for failed builds.
+    pub log_lines: Option<u32>,
+
+    /// Nix options that get set for the build, as `--option NAME VALUE`.
+    pub nix_options: BTreeMap<String, String>,
+}
+
+impl BuildOptions {
+    /// Returns the arguments to pass to "nix build" in addition to
+    /// the ones that deploy-flake always passes.
+    pub fn nix_args(&self) -> Vec<String> {
+        let mut args = self.cmdline.clone();
+        if self.print_build_logs {
+            args.push("-L".to_string());
+        }
+        if let Some(log_lines) = self.log_lines {
+            args.extend(["--log-lines".to_string(), log_lines.to_string()]);
+        }
+        if self.keep_going {
+            args.push("--keep-going".to_string());
+        }
+        if let Some(max_jobs) = self.max_jobs {
+            args.extend(["--max-jobs".to_string(), max_jobs.to_string()]);
+        }
+        if let Some(cores) = self.cores {
+            args.extend(["--cores".to_string(), cores.to_string()]);
+        }
+        for (name, value) in &self.nix_options {
+            args.extend(["--option".to_string(), name.clone(), value.clone()]);
+        }
+        args
+    }
+}
+
+/// Settings for how commands get run on a destination system.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteOptions {
+    /// The ID of the deploy run, recorded in the destination's
+    /// journal when a configuration gets activated there.
+    pub deploy_id: Option<String>,
+
+    /// How commands get superuser privileges on the destination.
+    pub elevation: elevate::Elevation,
+
+    /// Asks for the password that elevating privileges takes, if
+    /// the destination needs one. Elevated commands then read it
+    /// from their stdin.
+    pub elevation_password: Option<Arc<dyn elevate::PasswordPrompt>>,
+
+    /// Gets awaited whenever the destination enters or leaves a phase
+    /// of the deploy, see [`hooks::in_phase`].
+    pub phase_hooks: Option<Arc<dyn hooks::PhaseHooks>>,
+
+    /// Never prompt for input: remote commands get no stdin, sudo
+    /// fails instead of asking for a password and ssh runs in batch
+    /// mode, so that anything requiring interaction fails right away.
+    pub non_interactive: bool,
+
+    /// How often ssh checks that an idle connection is still alive,
+    /// so that connections that are shared between several
+    /// operations don't get dropped by firewalls in between.
+    pub keep_alive: Option<Duration>,
+
+    /// Where facts gathered about the destination get cached, if
+    /// anywhere.
+    pub facts_cache: Option<facts::FactsCache>,
+
+    /// The nix that local commands talking to the destination (like
+    /// copying closures to it) use.
+    pub local_nix: LocalNix,
+
+    /// The levels that the output of commands gets logged at.
+    pub log_levels: SubprocessLogLevels,
+
+    /// Log every command that runs with superuser privileges on the
+    /// destination to its journal (tagged `deploy-flake-audit`)
+    /// before running it.
+    pub audit: bool,
+
+    /// The root directory of the nix store that deploy-flake uses on
+    /// the destination, if it isn't the default one (as understood by
+    /// nix's `--store` option: with `/mnt`, store paths live in
+    /// `/mnt/nix/store`).
+    pub remote_store: Option<PathBuf>,
+
+    /// Have the destination substitute the paths it can from its
+    /// binary caches when a closure gets copied to it with
+    /// `nix-copy-closure` or `nix copy`, so that only the paths that
+    /// aren't in any cache get sent from here.
+    pub substitute_on_destination: bool,
+
+    /// The environment variables that activating a configuration (and
+    /// running its pre-activation checks) may see, if not
+    /// [`DEFAULT_ACTIVATION_ENV`]. All others get removed.
+    pub activation_env: Option<Vec<String>>,
+
+    /// How ssh treats host keys that it doesn't know yet. By default,
+    /// it refuses to connect (or, with [`RemoteOptions::system_ssh`],
+    /// does what the system's ssh configuration says).
+    pub host_key_policy: Option<HostKeyPolicy>,
+
+    /// The ssh options and configuration file that every ssh
+    /// connection uses, if they differ from ssh's defaults.
+    pub ssh_config: Option<Arc<ssh_config::SshConfig>>,
+
+    /// The jump host that the destination is reached through, if
+    /// any, like `admin@bastion.example.com`.
+    pub jump_host: Option<String>,
+
+    /// The connections to jump hosts, shared between all the
+    /// destinations that are reached through them.
+    pub jump_hosts: Arc<jump::JumpHosts>,
+
+    /// Connect to the destination with the system's ssh, deferring
+    /// entirely to its configuration, instead of with the options
+    /// that the openssh crate overrides it with.
+    pub system_ssh: bool,
+
+    /// How long to wait for a destination that dials in through a
+    /// reverse tunnel to do so, before giving up on it.
+    pub tunnel_wait: Duration,
+
+    /// Accepts the connections of destinations that dial in to
+    /// deploy-flake itself, instead of through an ssh server's
+    /// reverse tunnels.
+    pub tunnel_broker: Option<Arc<tunnel::Broker>>,
+}
+
+/// The environment variables that activating a configuration may see
+/// by default.
+pub const DEFAULT_ACTIVATION_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "TERM",
+    "LANG",
+    "LC_ALL",
+    "LOCALE_ARCHIVE",
+    "TZDIR",
+];
+
+impl RemoteOptions {
+    /// Returns the environment variables that activating a
+    /// configuration may see.
+    pub fn activation_env(&self) -> Vec<&str> {
+        match &self.activation_env {
+            Some(names) => names.iter().map(String::as_str).collect(),
+            None => DEFAULT_ACTIVATION_ENV.to_vec(),
+        }
+    }
+    /// Returns the options for ssh invocations that don't go through
+    /// the ssh session of a destination (e.g. by nix-copy-closure,
+    /// via `NIX_SSHOPTS`).
+    pub fn ssh_options(&self) -> Vec<String> {
+        let mut opts = vec![];
+        if self.non_interactive {
+            opts.extend(["-o".to_string(), "BatchMode=yes".to_string()]);
+        }
+        if let Some(policy) = self.host_key_policy {
+            opts.extend(["-o".to_string(), policy.ssh_option()]);
+        }
+        if let Some(keep_alive) = self.keep_alive {
+            opts.extend([
+                "-o".to_string(),
+                format!("ServerAliveInterval={}", keep_alive.as_secs().max(1)),
+            ]);
+        }
+        let jump_config = self
+            .jump_host
+            .as_deref()
+            .and_then(|jump_host| self.jump_hosts.connected_config(jump_host));
+        // The jump host's configuration includes the run's:
+        let config = jump_config.or_else(|| {
+            self.ssh_config
+                .as_ref()
+                .map(|config| config.file().to_owned())
+        });
+        if let Some(config) = config {
+            opts.extend(["-F".to_string(), config.to_string_lossy().into_owned()]);
+        }
+        opts
+    }
+}
+
+/// The levels that lines printed by subprocesses get logged at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubprocessLogLevels {
+    /// The level for lines printed to stdout.
+    pub stdout: log::Level,
+
+    /// The level for lines printed to stderr.
+    pub stderr: log::Level,
+
+    /// The level for nix's `warning:` lines, on either stream.
+    pub warnings: log::Level,
+}
+
+impl Default for SubprocessLogLevels {
+    fn default() -> Self {
+        Self {
+            stdout: log::Level::INFO,
+            stderr: log::Level::INFO,
+            warnings: log::Level::INFO,
+        }
+    }
+}
+
+impl SubprocessLogLevels {
+    /// Returns the level that a line printed to `stream` gets logged
+    /// at.
+    pub fn level_for(&self, stream: Stream, line: &str) -> log::Level {
+        if line.trim_start().starts_with("warning:") {
+            self.warnings
+        } else if stream == Stream::Stderr {
+            self.stderr
+        } else {
+            self.stdout
+        }
+    }
+
+    /// Logs a line printed to `stream` by a subprocess.
+    fn log(&self, stream: Stream, line: &str) {
+        let tag = stream.tag();
+        macro_rules! log_at {
+            ($level:expr) => {
+                log::event!(target: SUBPROCESS_LOG_TARGET, $level, "{tag} {line}")
+            };
+        }
+        match self.level_for(stream, line) {
+            log::Level::ERROR => log_at!(log::Level::ERROR),
+            log::Level::WARN => log_at!(log::Level::WARN),
+            log::Level::INFO => log_at!(log::Level::INFO),
+            log::Level::DEBUG => log_at!(log::Level::DEBUG),
+            log::Level::TRACE => log_at!(log::Level::TRACE),
+        }
+    }
+}
+
+/// Read from an AsyncRead stream and log each line at the level
+/// that `options` assign to it.
+pub(crate) fn read_and_log_messages(
+    stream: Stream,
     r: impl AsyncRead + Unpin,
-) -> Result<(), anyhow::Error> {
-    let br = BufReader::new(r);
-    let mut lines = br.lines();
-    while let Some(line) = lines
-        .next_line()
-        .await
-        .context("Unable to read next line")?
-    {
-        log::event!(
-            target: SUBPROCESS_LOG_TARGET,
-            log::Level::INFO,
-            "{stream} {line}"
-        );
+    options: &RemoteOptions,
+) -> impl Future<Output = Result<(), anyhow::Error>> {
+    subprocess::SubprocessLogger::for_options(options).read(stream, r)
+}
+
+/// A line that a command printed, forwarded as the command runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputLine {
+    /// The stream that the line was printed to.
+    pub stream: Stream,
+
+    pub line: String,
+}
+
+/// Read from an AsyncRead stream, log each line at the level that
+/// `options` assign to it and forward it to `to`, if given.
+pub(crate) fn read_log_and_forward_messages(
+    stream: Stream,
+    r: impl AsyncRead + Unpin,
+    options: &RemoteOptions,
+    to: Option<tokio::sync::mpsc::UnboundedSender<OutputLine>>,
+) -> impl Future<Output = Result<(), anyhow::Error>> {
+    let mut logger = subprocess::SubprocessLogger::for_options(options);
+    if let Some(to) = to {
+        logger = logger.with_sink(to);
+    }
+    logger.read(stream, r)
+}
+
+/// Read from an AsyncRead stream, log each line at the level that
+/// `options` assign to it and return all the lines that were read.
+pub(crate) fn read_log_and_collect_messages(
+    stream: Stream,
+    r: impl AsyncRead + Unpin,
+    options: &RemoteOptions,
+) -> impl Future<Output = Result<Vec<String>, anyhow::Error>> {
+    let collected = subprocess::Collector::default();
+    let read = subprocess::SubprocessLogger::for_options(options)
+        .with_sink(collected.clone())
+        .read(stream, r);
+    async move {
+        read.await?;
+        Ok(collected.lines())
     }
-    Ok(())
 }
 
 impl Flake {
     /// Construct a new flake reference from a source path.
     #[instrument(level = "DEBUG", err)]
     pub fn from_path<P: fmt::Debug + AsRef<Path>>(dir: P) -> Result<Self, anyhow::Error> {
+        Self::from_path_using(dir, &LocalNix::System)
+    }
+
+    /// Like [`Flake::from_path`], but looks the flake up with the given nix.
+    #[instrument(level = "DEBUG", err)]
+    pub fn from_path_using<P: fmt::Debug + AsRef<Path>>(
+        dir: P,
+        local_nix: &LocalNix,
+    ) -> Result<Self, anyhow::Error> {
         let dir = dir.as_ref().to_owned();
-        let info = nix::FlakeInfo::from_path(&dir).with_context(|| format!("Flake {:?}", &dir))?;
+        let info = nix::FlakeInfo::from_path(&dir, local_nix)
+            .with_context(|| format!("Flake {:?}", &dir))?;
+        let shallow = git::is_shallow(&dir);
         Ok(Flake {
-            dir,
-            resolved_path: info.path,
+            dir: Some(dir),
+            shallow,
+            ..Self::from_info(info, None)
         })
     }
 
+    /// Construct a new flake from a flake reference, which is either
+    /// a local directory or a remote reference that nix understands
+    /// (like `github:owner/repo?ref=main` or `git+ssh://...`). Remote
+    /// flakes get fetched into the local store.
+    #[instrument(level = "DEBUG", err)]
+    pub fn from_ref(reference: &str, local_nix: &LocalNix) -> Result<Self, anyhow::Error> {
+        if Path::new(reference).is_dir() {
+            return Self::from_path_using(reference, local_nix);
+        }
+        let info = nix::FlakeInfo::from_ref(reference, local_nix)
+            .with_context(|| format!("Flake {reference:?}"))?;
+        let locked = info
+            .url
+            .clone()
+            .with_context(|| format!("nix reported no locked URL for flake {reference:?}"))?;
+        Ok(Self::from_info(info, Some(locked)))
+    }
+
+    fn from_info(info: nix::FlakeInfo, reference: Option<String>) -> Self {
+        let (source_type, nar_hash) = match info.locked {
+            Some(locked) => (Some(locked.source_type), locked.nar_hash),
+            None => (None, None),
+        };
+        Flake {
+            dir: None,
+            reference,
+            resolved_path: info.path,
+            revision: info.revision,
+            dirty_revision: info.dirty_revision,
+            source_type,
+            nar_hash,
+            shallow: false,
+        }
+    }
+
+    /// Returns the locked reference that the flake can be fetched
+    /// by, if it was given by a remote reference.
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    /// Returns the flake, but referring to it by its store path
+    /// only, for building it on systems that can't fetch it
+    /// themselves: its store path must get copied there instead.
+    pub fn without_reference(self) -> Self {
+        Self {
+            reference: None,
+            ..self
+        }
+    }
+
+    /// Returns the git revision of the flake, unless it has
+    /// uncommitted changes.
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    /// Returns the NAR hash of the flake's source tree, if nix
+    /// reported it.
+    pub fn nar_hash(&self) -> Option<&str> {
+        self.nar_hash.as_deref()
+    }
+
+    /// Returns what makes the flake's source hard to trace back to
+    /// a revision later on.
+    pub fn source_warnings(&self) -> Vec<SourceWarning> {
+        let mut warnings = vec![];
+        match (&self.revision, self.source_type.as_deref()) {
+            (Some(_), _) => {}
+            (None, Some("git") | None) => warnings.push(SourceWarning::Dirty {
+                revision: self.dirty_revision.clone(),
+            }),
+            (None, Some(source_type)) => warnings.push(SourceWarning::NotGit {
+                source_type: source_type.to_owned(),
+            }),
+        }
+        if self.shallow {
+            warnings.push(SourceWarning::Shallow);
+        }
+        warnings
+    }
+
+    /// Describes the source that the flake was built from, for the
+    /// provenance record: its git revision (or the dirty revision
+    /// it's based on) and the NAR hash of its source tree.
+    pub fn provenance(&self) -> String {
+        let mut provenance = match (&self.revision, &self.dirty_revision) {
+            (Some(revision), _) => format!("revision {revision}"),
+            (None, Some(dirty)) => format!("revision {dirty}"),
+            (None, None) => match self.source_type.as_deref() {
+                Some("git") | None => "uncommitted changes".to_string(),
+                Some(source_type) => format!("{source_type} source"),
+            },
+        };
+        if let Some(nar_hash) = &self.nar_hash {
+            provenance.push_str(&format!(", narHash {nar_hash}"));
+        }
+        provenance
+    }
+
+    /// Checks that the flake's git revision is signed by one of the
+    /// `allowed` keys (see [`git::verify_signed_revision`]).
+    #[instrument(level = "DEBUG", err)]
+    pub fn verify_signed(&self, allowed: &[String]) -> Result<(), anyhow::Error> {
+        let dir = self.dir.as_ref().with_context(|| {
+            format!(
+                "Flake {:?} is not in a local git checkout, so its signature can't be checked",
+                self.reference
+            )
+        })?;
+        let revision = self.revision().with_context(|| {
+            format!("Flake {dir:?} has uncommitted changes, so it can't be signed")
+        })?;
+        git::verify_signed_revision(dir, revision, allowed)?;
+        Ok(())
+    }
+
     /// Returns the store path of the flake as a utf-8 string.
     pub fn resolved_path(&self) -> &str {
         self.resolved_path
@@ -72,95 +679,1169 @@ impl Flake {
             .expect("Resolved flake path must be utf-8 clean")
     }
 
+    /// Returns what flake fragments refer to the flake by: its
+    /// locked reference if it has one, its store path otherwise.
+    fn installable_base(&self) -> &str {
+        self.reference().unwrap_or_else(|| self.resolved_path())
+    }
+
+    /// Returns the destinations that the flake declares in its
+    /// `deploy-flake.hosts` output: a list of destination strings
+    /// (like `nixos://web1/webserver`), or of attribute sets with a
+    /// `hostname` and optionally a `flavor`, `port` and `config`.
+    #[instrument(level = "DEBUG", err)]
+    pub async fn inventory(&self, local_nix: &LocalNix) -> Result<Vec<Destination>, anyhow::Error> {
+        let installable = format!("{}#deploy-flake.hosts", self.installable_base());
+        let hosts: Vec<InventoryHost> = nix::eval_json(&installable, local_nix).await?;
+        hosts
+            .into_iter()
+            .map(InventoryHost::into_destination)
+            .collect()
+    }
+
     /// Returns a flake fragment to a NixOS system configuration for the given hostname.
     pub fn nixos_system_config(&self, hostname: &str) -> String {
         format!(
             "{}#nixosConfigurations.{}.config.system.build.toplevel",
-            self.resolved_path(),
+            self.installable_base(),
             hostname
         )
     }
 
-    /// Copies the store path closure to the destination host.
-    #[instrument(skip(self), fields(to), err)]
-    pub async fn copy_closure(&self, to: &str) -> Result<(), anyhow::Error> {
-        let mut cmd = Command::new("nix-copy-closure");
-        cmd.args([to, self.resolved_path()]);
-        cmd.stderr(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped());
+    /// Returns a flake fragment to the system configuration for the
+    /// given hostname, for a flavor of operating system.
+    pub fn system_config(&self, flavor: Flavor, hostname: &str) -> String {
+        match flavor {
+            Flavor::Nixos => self.nixos_system_config(hostname),
+            Flavor::Darwin => self.darwin_system_config(hostname),
+        }
+    }
+
+    /// Returns a flake fragment to a nix-darwin system configuration for the given hostname.
+    pub fn darwin_system_config(&self, hostname: &str) -> String {
+        format!(
+            "{}#darwinConfigurations.{}.system",
+            self.installable_base(),
+            hostname
+        )
+    }
+
+    #[instrument(err, skip(options))]
+    pub async fn build(
+        &self,
+        on: Arc<Nixos>,
+        config_name: Option<&str>,
+        options: &BuildOptions,
+    ) -> Result<SystemConfiguration, anyhow::Error> {
+        let (path, system_name) = on.os().build_flake(self, config_name, options).await?;
+        Ok(SystemConfiguration::existing(on, path, system_name)
+            .with_source(Some(self.provenance())))
+    }
+
+    /// Finds out what building the flake's system configuration for
+    /// the system `on` would do, without building anything: on
+    /// `build_host` if given, or on the machine running deploy-flake
+    /// otherwise.
+    #[instrument(err, skip(options))]
+    pub async fn dry_build(
+        &self,
+        on: &Nixos,
+        build_host: Option<&Nixos>,
+        config_name: Option<&str>,
+        options: &BuildOptions,
+    ) -> Result<DryBuild, anyhow::Error> {
+        let system_name = match config_name {
+            Some(name) => name.to_owned(),
+            None => on.facts().await?.hostname.clone(),
+        };
+        let installable = self.system_config(on.flavor(), &system_name);
+        let output = match build_host {
+            Some(build_host) => build_host.dry_build(&installable, options).await?,
+            None => nix::dry_build(&installable, options, &on.options().local_nix).await?,
+        };
+        Ok(DryBuild::from_output(installable, &output))
+    }
+
+    /// Estimates what deploying the flake's system configuration to
+    /// the system `on` would take, without building or copying
+    /// anything. Builds get estimated on the machine running
+    /// deploy-flake.
+    #[instrument(err, skip(options))]
+    pub async fn estimate(
+        &self,
+        on: &Nixos,
+        config_name: Option<&str>,
+        options: &BuildOptions,
+    ) -> Result<Estimate, anyhow::Error> {
+        let facts = on.facts().await?;
+        let system_name = config_name.unwrap_or(&facts.hostname);
+        let installable = self.system_config(on.flavor(), system_name);
+        let local_nix = &on.options().local_nix;
+        let system: PathBuf = nix::eval_json(&format!("{installable}.outPath"), local_nix).await?;
+        let dry_build = DryBuild::from_output(
+            installable.clone(),
+            &nix::dry_build(&installable, options, local_nix).await?,
+        );
+        let built = dry_build.will_build.is_empty() && dry_build.will_fetch.is_empty();
+        let transfer = if on
+            .os()
+            .missing_store_paths(std::slice::from_ref(&system))
+            .await?
+            .is_empty()
+        {
+            Some(TransferSize::default())
+        } else if built {
+            Some(transfer_size(&system, on).await?)
+        } else {
+            // Paths that aren't built yet have no size to go by:
+            None
+        };
+        Ok(Estimate {
+            changes: facts.current_system.as_ref() != Some(&system),
+            system,
+            dry_build,
+            transfer,
+        })
+    }
+
+    /// Builds the flake's system configuration for the system `on`
+    /// on the machine running deploy-flake, with the given nix. The
+    /// built configuration must get copied to the system before it
+    /// can be activated there.
+    #[instrument(err, skip(options))]
+    pub async fn build_locally(
+        &self,
+        on: Arc<Nixos>,
+        config_name: Option<&str>,
+        options: &BuildOptions,
+        local_nix: &LocalNix,
+    ) -> Result<SystemConfiguration, anyhow::Error> {
+        let system_name = match config_name {
+            Some(name) => name.to_owned(),
+            None => on.facts().await?.hostname.clone(),
+        };
+        let installable = self.system_config(on.flavor(), &system_name);
+        let path = nix::build(&installable, options, local_nix, on.options())
+            .await
+            .context("Could not build the flake locally")?;
+        Ok(SystemConfiguration::existing(on, path, system_name)
+            .with_source(Some(self.provenance())))
+    }
+}
+
+/// What building a system configuration would do, as reported by
+/// `nix build --dry-run`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DryBuild {
+    /// The flake fragment that would get built.
+    pub installable: String,
+
+    /// The derivations that would get built.
+    pub will_build: Vec<String>,
+
+    /// The store paths that would get fetched from substituters.
+    pub will_fetch: Vec<String>,
+}
+
+impl DryBuild {
+    /// Parses the output of `nix build --dry-run`.
+    fn from_output(installable: String, output: &[String]) -> Self {
+        let mut dry_build = DryBuild {
+            installable,
+            ..Default::default()
+        };
+        let mut section = None;
+        for line in output {
+            if line.ends_with("will be built:") {
+                section = Some(&mut dry_build.will_build);
+            } else if line.contains("will be fetched") {
+                section = Some(&mut dry_build.will_fetch);
+            } else if let (Some(paths), Some(path)) = (section.as_mut(), line.strip_prefix("  ")) {
+                paths.push(path.trim().to_string());
+            } else {
+                section = None;
+            }
+        }
+        dry_build
+    }
+}
+
+impl fmt::Display for DryBuild {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  builds {}", self.installable)?;
+        for (what, paths) in [("build", &self.will_build), ("fetch", &self.will_fetch)] {
+            writeln!(f, "  would {what} {} path(s)", paths.len())?;
+            for path in paths {
+                writeln!(f, "    {path}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An estimate of what deploying a system configuration to a
+/// destination would take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    /// The store path of the system configuration.
+    pub system: PathBuf,
+
+    /// What building it would do.
+    pub dry_build: DryBuild,
+
+    /// What copying it to the destination would transfer, unless
+    /// that can't be known before it is built.
+    pub transfer: Option<TransferSize>,
+
+    /// Whether activating it would change the system that the
+    /// destination runs.
+    pub changes: bool,
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  builds {} ({})",
+            self.dry_build.installable,
+            self.system.display()
+        )?;
+        writeln!(
+            f,
+            "  would build {} and fetch {} path(s)",
+            self.dry_build.will_build.len(),
+            self.dry_build.will_fetch.len()
+        )?;
+        match self.transfer {
+            Some(size) if size.paths == 0 => writeln!(f, "  would copy nothing")?,
+            Some(size) => writeln!(f, "  would copy {size}")?,
+            None => writeln!(f, "  would copy an unknown amount, until it is built")?,
+        }
+        if self.changes {
+            writeln!(f, "  would change the running system")
+        } else {
+            writeln!(f, "  runs this configuration already")
+        }
+    }
+}
+
+/// Checks whether the system `on` is healthy enough to be deployed
+/// to, waiting at most `timeout` for it to settle.
+#[instrument(level = "DEBUG", skip(on), err)]
+pub async fn check_system_health(
+    on: &Nixos,
+    method: HealthCheck,
+    timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    on.os().preflight_check_system(method, timeout).await
+}
+
+/// Removes the GC root with the given name from the system `on`, if
+/// it exists.
+#[instrument(level = "DEBUG", skip(on), err)]
+pub async fn remove_gc_root(on: &Nixos, name: &str) -> Result<(), anyhow::Error> {
+    on.os().remove_gc_root(name).await
+}
+
+/// Checks that the state of the system `on` is consistent: that it
+/// runs the system profile's current generation, boots it by
+/// default, has no failed units, and runs the configuration that
+/// deploy-flake last activated on it. Returns the inconsistencies.
+#[instrument(level = "DEBUG", skip(on), err)]
+pub async fn verify_system(on: &Nixos) -> Result<Vec<String>, anyhow::Error> {
+    on.os().inconsistencies().await
+}
+
+/// A number of bytes. Parses from a number with an optional binary
+/// unit, like `500M`, `1.5GiB` or `2T`, and displays in the largest
+/// unit that fits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ByteSize(pub u64);
+
+/// The binary units that [`ByteSize`] knows, from the largest down.
+const BYTE_UNITS: &[(&str, u64)] = &[
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number
+            .parse()
+            .with_context(|| format!("Can not parse {s:?} as a size"))?;
+        let unit = unit.trim();
+        let factor = match unit {
+            "" | "B" => 1,
+            _ => BYTE_UNITS
+                .iter()
+                .find(|(name, _)| {
+                    let prefix = &name[..1];
+                    [prefix.to_string(), format!("{prefix}B"), name.to_string()]
+                        .iter()
+                        .any(|spelling| spelling.eq_ignore_ascii_case(unit))
+                })
+                .map(|(_, factor)| *factor)
+                .with_context(|| format!("Unknown size unit {unit:?} in {s:?}"))?,
+        };
+        Ok(ByteSize((number * factor as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match BYTE_UNITS.iter().find(|(_, factor)| self.0 >= *factor) {
+            Some((unit, factor)) => write!(f, "{:.1} {unit}", self.0 as f64 / *factor as f64),
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
+
+/// Which old generations of a profile to delete once a new
+/// configuration is installed as the boot configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pruning {
+    /// Keep this many of the newest generations, counting the
+    /// current one.
+    KeepGenerations(u64),
+
+    /// Delete the generations that are older than this many days.
+    OlderThanDays(u64),
+}
+
+impl Pruning {
+    /// Returns the pruning that deletes generations older than
+    /// `age`, which must be a whole number of days.
+    pub fn older_than(age: Duration) -> Result<Self, anyhow::Error> {
+        const DAY: u64 = 24 * 60 * 60;
+        let seconds = age.as_secs();
+        if seconds == 0 || !seconds.is_multiple_of(DAY) || age.subsec_nanos() != 0 {
+            anyhow::bail!(
+                "Generations can only be deleted by age in whole days, not {}",
+                humantime::format_duration(age)
+            );
+        }
+        Ok(Pruning::OlderThanDays(seconds / DAY))
+    }
+
+    /// Returns the argument of `nix-env --delete-generations` that
+    /// selects the generations to delete.
+    pub(crate) fn generations(&self) -> String {
+        match self {
+            Pruning::KeepGenerations(count) => format!("+{count}"),
+            Pruning::OlderThanDays(days) => format!("{days}d"),
+        }
+    }
+}
+
+/// What copying the closure of a store path to a destination
+/// transfers: the paths in it that the destination doesn't have yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransferSize {
+    /// The number of paths to transfer.
+    pub paths: usize,
+
+    /// The size of those paths, in bytes.
+    pub bytes: u64,
+}
+
+impl fmt::Display for TransferSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} paths / {}", self.paths, ByteSize(self.bytes))
+    }
+}
+
+/// Returns the paths in the closure of a store path that are not yet
+/// present on the destination system, and their size.
+#[instrument(level = "DEBUG", skip(to), err)]
+pub async fn transfer_size(path: &Path, to: &Nixos) -> Result<TransferSize, anyhow::Error> {
+    let closure = nix::PathInfo::closure_of(path, &to.options().local_nix).await?;
+    let paths: Vec<PathBuf> = closure.iter().map(|info| info.path.clone()).collect();
+    let missing = to.os().missing_store_paths(&paths).await?;
+    Ok(closure
+        .iter()
+        .filter(|info| missing.contains(&info.path))
+        .fold(TransferSize::default(), |size, info| TransferSize {
+            paths: size.paths + 1,
+            bytes: size.bytes + info.nar_size,
+        }))
+}
+
+/// Checks that the destination has room for the closure of a store
+/// path: that its nix store's filesystem can hold the paths that need
+/// to be transferred, and that its /boot can hold the kernel and
+/// initrd of the configuration at `path`, unless the running system
+/// has them already. Only configurations built here can be looked at
+/// before they're copied, so /boot doesn't get checked for others.
+#[instrument(level = "DEBUG", skip(to), err)]
+pub async fn check_free_space(
+    path: &Path,
+    to: &Nixos,
+    transfer: TransferSize,
+) -> Result<(), anyhow::Error> {
+    let facts = to.facts().await?;
+    check_space("/nix/store", transfer.bytes, facts.free_store_bytes)?;
+    let Some(free) = facts.free_boot_bytes else {
+        return Ok(());
+    };
+    if tokio::fs::symlink_metadata(path).await.is_err() {
+        log::info!(
+            ?path,
+            "Not checking the space on /boot, since the configuration wasn't built here"
+        );
+        return Ok(());
+    }
+    let mut needed = 0;
+    for name in ["kernel", "initrd"] {
+        // Only NixOS configurations have these:
+        let Ok(file) = tokio::fs::canonicalize(path.join(name)).await else {
+            continue;
+        };
+        if to.running_file(name).await?.as_ref() != Some(&file) {
+            needed += tokio::fs::metadata(&file)
+                .await
+                .with_context(|| format!("Could not determine the size of {file:?}"))?
+                .len();
+        }
+    }
+    check_space("/boot", needed, free)
+}
+
+/// The oldest version of nix that can build flakes.
+pub const FLAKES_NIX_VERSION: &str = "2.4";
+
+/// Checks that the system described by `facts` runs at least version
+/// `min` of nix.
+pub fn check_nix_version(facts: &HostFacts, min: &str) -> Result<(), anyhow::Error> {
+    if !version_at_least(&facts.nix_version, min) {
+        anyhow::bail!(
+            "{} runs nix {:?}, but needs nix {min} or later",
+            facts.hostname,
+            facts.nix_version
+        );
+    }
+    Ok(())
+}
+
+/// Returns whether the dotted version `version` (like `2.18.1`) is
+/// at least `min`. Anything after the digits of a part (like the
+/// `pre` in `2.19pre`) gets ignored.
+fn version_at_least(version: &str, min: &str) -> bool {
+    let parts = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|part| {
+                let digits = part
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(part.len());
+                part[..digits].parse().unwrap_or(0)
+            })
+            .collect()
+    };
+    let (version, min) = (parts(version), parts(min));
+    for i in 0..version.len().max(min.len()) {
+        let (have, need) = (
+            version.get(i).copied().unwrap_or(0),
+            min.get(i).copied().unwrap_or(0),
+        );
+        if have != need {
+            return have > need;
+        }
+    }
+    true
+}
+
+/// Returns the platform (like `x86_64-linux`) that the system
+/// configuration at `path` on the machine running deploy-flake was
+/// built for, if it says.
+pub async fn built_platform(path: &Path) -> Option<String> {
+    let platform = tokio::fs::read_to_string(path.join("system")).await.ok()?;
+    Some(platform.trim().to_string())
+}
+
+/// Checks that the configuration at `path` was built for a
+/// `platform` that a system of the given flavor, described by
+/// `facts`, can run. Configurations that don't say what platform they
+/// were built for pass.
+pub fn check_platform(
+    path: &Path,
+    platform: Option<&str>,
+    flavor: Flavor,
+    facts: &HostFacts,
+) -> Result<(), anyhow::Error> {
+    let Some(platform) = platform else {
+        return Ok(());
+    };
+    if !runs_on(platform, &facts.architecture, flavor) {
+        anyhow::bail!(
+            "{path:?} was built for {platform}, but {} is a {} {} machine. Pass --force-platform to deploy it anyway",
+            facts.hostname,
+            facts.architecture,
+            flavor.nix_os()
+        );
+    }
+    Ok(())
+}
+
+/// Returns whether a machine of the given flavor whose `uname -m` is
+/// `machine` can run binaries for the nix platform `platform`.
+fn runs_on(platform: &str, machine: &str, flavor: Flavor) -> bool {
+    let (cpu, os) = platform.split_once('-').unwrap_or((platform, ""));
+    // uname and nix don't always agree on the names of CPUs:
+    let machine = match machine {
+        "arm64" => "aarch64",
+        "amd64" => "x86_64",
+        "i386" | "i586" => "i686",
+        "ppc64le" => "powerpc64le",
+        "ppc64" => "powerpc64",
+        "ppc" => "powerpc",
+        machine => machine,
+    };
+    // x86_64 machines run i686 binaries, too:
+    let runs_cpu = cpu == machine || (machine == "x86_64" && cpu == "i686");
+    runs_cpu && os == flavor.nix_os()
+}
+
+/// Fails if `needed` bytes don't fit into the `free` bytes of the
+/// filesystem holding `dir`.
+fn check_space(dir: &str, needed: u64, free: u64) -> Result<(), anyhow::Error> {
+    if needed > free {
+        anyhow::bail!(
+            "{dir} needs {} of space, but only {} are free. Make room (say, with nix-collect-garbage -d), or pass --preflight-check=warn to try anyway",
+            ByteSize(needed),
+            ByteSize(free)
+        );
+    }
+    Ok(())
+}
+
+/// Returns the NixOS release (like "23.11") that a NixOS version
+/// string (like "23.11.20240115.b8dd8be (Tapir)") belongs to.
+pub fn nixos_release(version: &str) -> Option<&str> {
+    let (year, rest) = version.split_once('.')?;
+    let month_len = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    if year.is_empty() || !year.chars().all(|c| c.is_ascii_digit()) || month_len == 0 {
+        return None;
+    }
+    Some(&version[..year.len() + 1 + month_len])
+}
+
+/// Checks that a name (like a profile or specialisation name) can be
+/// used as a single component of a path on the destination, so it
+/// can't point outside the directory it gets joined onto.
+pub fn path_component(name: &str) -> Result<String, anyhow::Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        anyhow::bail!("{name:?} is not a valid name: it must not be empty, \".\" or \"..\", nor contain a \"/\"");
+    }
+    Ok(name.to_string())
+}
+
+/// The error returned when an operation failed for a reason that is
+/// likely to go away when retrying it, like a network blip.
+#[derive(Debug)]
+pub struct TransientFailure(pub String);
+
+impl fmt::Display for TransientFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transient failure: {}", self.0)
+    }
+}
+
+impl std::error::Error for TransientFailure {}
+
+/// Returns the first line of nix (or ssh) output that indicates a
+/// failure which is likely to go away when retrying, like a timeout
+/// talking to a substituter or a dropped connection.
+pub(crate) fn transient_failure_from_output(output: &[String]) -> Option<&str> {
+    const MARKERS: &[&str] = &[
+        "unexpected end-of-file",
+        "Timeout was reached",
+        "Connection timed out",
+        "Connection reset by peer",
+        "Connection closed by",
+        "Broken pipe",
+        "Could not resolve host",
+        "HTTP error 502",
+        "HTTP error 503",
+        "HTTP error 504",
+    ];
+    output
+        .iter()
+        .find(|line| MARKERS.iter().any(|marker| line.contains(marker)))
+        .map(String::as_str)
+}
+
+/// The error returned when activating a configuration failed because
+/// some of its units failed to start.
+#[derive(Debug)]
+pub struct UnitsFailed {
+    /// The units that failed.
+    pub units: Vec<String>,
+
+    /// What systemd and the journal had to say about the units.
+    pub details: String,
+}
+
+impl UnitsFailed {
+    /// Returns whether every unit that failed matches one of the
+    /// `patterns`, in which `*` matches any number of characters and
+    /// `?` matches a single one.
+    pub fn all_match(&self, patterns: &[String]) -> bool {
+        !self.units.is_empty()
+            && self.units.iter().all(|unit| {
+                patterns
+                    .iter()
+                    .any(|pattern| unit_matches(pattern.as_bytes(), unit.as_bytes()))
+            })
+    }
+}
+
+impl fmt::Display for UnitsFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed units: {}\n{}",
+            self.units.join(", "),
+            self.details
+        )
+    }
+}
+
+impl std::error::Error for UnitsFailed {}
+
+/// Returns whether a unit name matches a glob pattern.
+fn unit_matches(pattern: &[u8], unit: &[u8]) -> bool {
+    match (pattern.split_first(), unit.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            unit_matches(rest, unit) || (!unit.is_empty() && unit_matches(pattern, &unit[1..]))
+        }
+        (Some((b'?', rest)), Some((_, unit_rest))) => unit_matches(rest, unit_rest),
+        (Some((p, rest)), Some((u, unit_rest))) if p == u => unit_matches(rest, unit_rest),
+        _ => false,
+    }
+}
+
+/// The name of the GC root that records the system configuration
+/// that was current before the last activation.
+const PREVIOUS_GC_ROOT: &str = "previous";
+
+/// The name of the GC root that pins the system configuration that
+/// was promoted to be known-good. Nothing that deploy-flake does
+/// removes it, other than promoting another configuration.
+const KNOWN_GOOD_GC_ROOT: &str = "known-good";
+
+/// A rollback to the configuration that a system ran before a new one
+/// got tested on it. The system performs the rollback on its own,
+/// unless the new configuration gets confirmed in time.
+#[derive(Debug)]
+pub struct PendingRollback {
+    system: Arc<Nixos>,
+    name: String,
+    deadline: tokio::time::Instant,
+}
+
+impl PendingRollback {
+    /// Confirms the new configuration by connecting to the system
+    /// anew and cancelling the rollback over that connection. If that
+    /// doesn't succeed before the rollback is due, the system gets
+    /// rolled back right away (if it can still be reached at all).
+    #[instrument(level = "DEBUG", err)]
+    pub async fn confirm(self) -> Result<(), anyhow::Error> {
+        let remaining = self
+            .deadline
+            .saturating_duration_since(tokio::time::Instant::now());
+        let confirmed = tokio::time::timeout(remaining, async {
+            let system = self
+                .system
+                .flavor()
+                .connect(
+                    self.system.host(),
+                    self.system.port(),
+                    self.system.options().clone(),
+                )
+                .await?;
+            system.os().cancel_rollback(&self.name).await?;
+            system.close().await
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out reconnecting")));
+        if let Err(e) = confirmed {
+            log::event!(log::Level::WARN, error=%e, "Could not confirm the new configuration, rolling back");
+            if let Err(error) = self.system.os().trigger_rollback(&self.name).await {
+                log::event!(log::Level::DEBUG, %error, "Could not roll back right away, leaving it to the system");
+            }
+            return Err(e.context("Could not confirm the new configuration; the system rolls back to its previous configuration"));
+        }
+        Ok(())
+    }
+}
+
+/// Represents a "built" system configuration on a system that is ready to be activated.
+pub struct SystemConfiguration {
+    path: PathBuf,
+    system: Arc<Nixos>,
+    system_name: String,
+    specialisation: Option<String>,
+    profile_name: Option<String>,
+    generation: Option<u64>,
+    source: Option<String>,
+    test_timeout: Option<Duration>,
+}
+
+impl SystemConfiguration {
+    /// Refers to a system configuration that was built on the system
+    /// earlier, e.g. in a previous invocation.
+    pub fn existing(on: Arc<Nixos>, path: PathBuf, system_name: String) -> Self {
+        SystemConfiguration {
+            path,
+            system: on,
+            system_name,
+            specialisation: None,
+            profile_name: None,
+            generation: None,
+            source: None,
+            test_timeout: None,
+        }
+    }
+
+    /// Records the source that the configuration was built from (see
+    /// [`Flake::provenance`]), for the provenance record.
+    pub fn with_source(self, source: Option<String>) -> Self {
+        Self { source, ..self }
+    }
+
+    /// Returns the source that the configuration was built from, if
+    /// it is known.
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// Selects a specialisation of the configuration that gets
+    /// activated when testing it, instead of the configuration itself.
+    pub fn with_specialisation(self, specialisation: Option<String>) -> Self {
+        Self {
+            specialisation,
+            ..self
+        }
+    }
+
+    /// Selects a profile (under `system-profiles`) that the
+    /// configuration gets installed into, instead of the "system"
+    /// profile.
+    pub fn with_profile_name(self, profile_name: Option<String>) -> Self {
+        Self {
+            profile_name,
+            ..self
+        }
+    }
+
+    /// Refers to the configuration over another connection to its
+    /// system, like one that was made after the system rebooted.
+    pub fn with_system(self, system: Arc<Nixos>) -> Self {
+        Self { system, ..self }
+    }
+
+    /// Limits how long testing (or switching to) the configuration
+    /// may take, after which the activation gets stopped and fails.
+    pub fn with_test_timeout(self, test_timeout: Option<Duration>) -> Self {
+        Self {
+            test_timeout,
+            ..self
+        }
+    }
+
+    /// Returns whether the system already runs the configuration (or
+    /// its selected specialisation), with the configuration as the
+    /// current generation of the system profile, so that activating
+    /// it would change nothing.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn is_current(&self) -> Result<bool, anyhow::Error> {
+        if self.profile_name.is_some() {
+            // The named profile's generations aren't compared:
+            return Ok(false);
+        }
+        Ok(self.system.runs(&self.activation_path()).await?
+            && self.system.os().current_generation().await?.as_ref() == Some(&self.path))
+    }
+
+    /// Returns the path of the configuration that gets activated
+    /// when testing it: the selected specialisation, if any.
+    fn activation_path(&self) -> PathBuf {
+        match &self.specialisation {
+            Some(name) => self.path.join("specialisation").join(name),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Schedules the system to roll back to the configuration that it
+    /// currently runs, `within` from now, unless the rollback gets
+    /// confirmed away. Testing this configuration and confirming it
+    /// must both happen in that time.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn schedule_rollback(
+        &self,
+        within: Duration,
+    ) -> Result<PendingRollback, anyhow::Error> {
+        let previous = self
+            .system
+            .facts()
+            .await?
+            .current_system
+            .clone()
+            .with_context(|| {
+                format!(
+                    "{:?} runs no system configuration to roll back to",
+                    self.system
+                )
+            })?;
+        let id = match &self.system.options().deploy_id {
+            Some(deploy_id) => deploy_id.to_lowercase(),
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs()
+                .to_string(),
+        };
+        let name = format!("deploy-flake-rollback-{id}");
+        let deadline = tokio::time::Instant::now() + within;
+        self.system
+            .os()
+            .schedule_rollback(&previous, &self.activation_path(), &name, within)
+            .await?;
+        log::event!(
+            log::Level::DEBUG,
+            ?previous,
+            unit = name,
+            "Scheduled rollback"
+        );
+        Ok(PendingRollback {
+            system: self.system.clone(),
+            name,
+            deadline,
+        })
+    }
+
+    #[instrument(skip(self) err)]
+    pub async fn test_config(&self) -> Result<(), anyhow::Error> {
+        self.system
+            .os()
+            .test_config(&self.activation_path(), self.test_timeout)
+            .await
+    }
+
+    /// Activates the configuration on the running system and
+    /// installs it as the boot configuration in one go, like
+    /// `nixos-rebuild switch`. The profile must already be set.
+    #[instrument(skip(self) err)]
+    pub async fn switch_config(&self) -> Result<(), anyhow::Error> {
+        self.system
+            .os()
+            .switch_config(&self.activation_path(), self.test_timeout)
+            .await
+    }
+
+    /// Stops testing (or switching to) the configuration, if that is
+    /// running in the background on its system, e.g. because the
+    /// deploy got interrupted.
+    #[instrument(skip(self) err)]
+    pub async fn stop_activation(&self) -> Result<(), anyhow::Error> {
+        self.system
+            .os()
+            .stop_activation(&self.activation_path())
+            .await
+    }
+
+    /// Reports the changes to units that activating the
+    /// configuration would make, without changing anything.
+    #[instrument(skip(self) err)]
+    pub async fn dry_activate(&self) -> Result<UnitChanges, anyhow::Error> {
+        self.system.os().dry_activate(&self.path).await
+    }
+
+    /// Describes how the packages in the configuration differ from
+    /// those of the running system, if it has `nvd` installed.
+    #[instrument(skip(self) err)]
+    pub async fn package_diff(&self) -> Result<Option<String>, anyhow::Error> {
+        self.system.os().package_diff(&self.path).await
+    }
+
+    #[instrument(skip(self) err)]
+    pub async fn boot_config(&self) -> Result<(), anyhow::Error> {
+        self.boot_dry_run(None).await?;
+        self.set_profile(None).await?;
+        self.update_boot(None).await
+    }
+
+    /// Tries out installing the configuration as the boot
+    /// configuration, before the profile gets changed. Like the other
+    /// steps of installing it, it stops once `timeout` (if any) is up.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn boot_dry_run(&self, timeout: Option<Duration>) -> Result<(), anyhow::Error> {
+        log::event!(
+            log::Level::DEBUG,
+            "Attempting to activate boot configuration (dry-run)"
+        );
+        self.system
+            .os()
+            .update_boot_for_config(&self.path, timeout)
+            .await
+            .context("Trial run of boot activation failed. No cleanup necessary.")
+    }
+
+    /// Makes the configuration the current generation of its
+    /// profile. A configuration that is an existing generation of the
+    /// "system" profile becomes current again, instead of getting
+    /// added as a new generation.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn set_profile(&self, timeout: Option<Duration>) -> Result<(), anyhow::Error> {
+        log::event!(log::Level::DEBUG, "Setting system profile");
+        match self.generation {
+            Some(number) => self.system.os().switch_generation(number, timeout).await,
+            None => {
+                self.system
+                    .os()
+                    .set_as_current_generation(&self.path, self.profile_name.as_deref(), timeout)
+                    .await
+            }
+        }
+        .context("You may have to check the system profile generation to clean up.")
+    }
+
+    /// Installs the configuration as the default boot entry. The
+    /// profile must already point to it.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn update_boot(&self, timeout: Option<Duration>) -> Result<(), anyhow::Error> {
+        self.system.os().update_boot_for_config(&self.path, timeout).await
+            .context("Actually setting the boot configuration failed. To clean up, you'll have to reset the system profile.")
+    }
+
+    /// Deletes the old generations of the configuration's profile
+    /// that `pruning` selects, and installs the boot configuration
+    /// anew, so that the boot menu no longer lists them. The profile
+    /// must already point to the configuration.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn prune_generations(&self, pruning: Pruning) -> Result<(), anyhow::Error> {
+        self.system
+            .os()
+            .delete_generations(self.profile_name.as_deref(), pruning)
+            .await?;
+        self.system.os().update_boot_for_config(&self.path, None).await
+            .context("Old generations were deleted, but the boot menu may still list them. Installing the boot configuration again (e.g. with the next deploy) cleans it up.")
+    }
+
+    /// Deletes everything from the system's nix store that no GC root
+    /// (like a profile generation) keeps alive.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn collect_garbage(&self) -> Result<(), anyhow::Error> {
+        self.system.os().collect_garbage().await
+    }
+
+    /// Registers the configuration as a GC root with the given name
+    /// on the system, so that it survives garbage collection until
+    /// it is activated.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn add_gc_root(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.system.os().add_gc_root(&self.path, name).await
+    }
+
+    /// Records in the system's journal that the configuration is
+    /// about to get activated, along with the deploy run's ID and
+    /// the source it was built from.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn record_provenance(&self) -> Result<(), anyhow::Error> {
+        self.system
+            .os()
+            .record_provenance(&self.path, self.source.as_deref())
+            .await
+    }
+
+    /// Records the system profile's current generation as the one
+    /// to roll back to, before this configuration gets activated.
+    /// Deploying the configuration that is already current leaves
+    /// the recorded generation alone.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn record_previous_system(&self) -> Result<(), anyhow::Error> {
+        match self.system.os().current_generation().await? {
+            Some(current) if current != self.path => {
+                log::event!(log::Level::DEBUG, previous=?current, "Recording the previous system");
+                self.system
+                    .os()
+                    .add_gc_root(&current, PREVIOUS_GC_ROOT)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes the given filesystem snapshots on the system, returning
+    /// the names of the snapshots it took.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn take_snapshots(
+        &self,
+        snapshots: &[snapshot::Snapshot],
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let name = format!("deploy-flake-{timestamp}");
+        let mut taken = vec![];
+        for snapshot in snapshots {
+            let snapshot_name = self.system.os().take_snapshot(snapshot, &name).await?;
+            log::event!(log::Level::INFO, snapshot = snapshot_name, "Took snapshot");
+            taken.push(snapshot_name);
+        }
+        Ok(taken)
+    }
 
-        let mut child = cmd.spawn()?;
-        let stdout_read = tokio::task::spawn(
-            read_and_log_messages("O", child.stdout.take().unwrap())
-                .instrument(log::Span::current()),
-        );
+    /// Returns the system configuration that was current before the
+    /// last one that deploy-flake activated on the system.
+    #[instrument(level="DEBUG" err)]
+    pub async fn previous(on: Arc<Nixos>) -> Result<Self, anyhow::Error> {
+        let path = on
+            .os()
+            .gc_root(PREVIOUS_GC_ROOT)
+            .await?
+            .with_context(|| format!("No previously-deployed system is recorded on {on:?}"))?;
+        let system_name = on.facts().await?.hostname.clone();
+        Ok(Self::existing(on, path, system_name))
+    }
 
-        let stderr_read = tokio::task::spawn(
-            read_and_log_messages("E", child.stderr.take().unwrap())
-                .instrument(log::Span::current()),
-        );
+    /// Returns the system configuration that was last promoted to be
+    /// known-good on the system (see [`SystemConfiguration::pin_known_good`]).
+    #[instrument(level="DEBUG" err)]
+    pub async fn known_good(on: Arc<Nixos>) -> Result<Self, anyhow::Error> {
+        let path = on
+            .os()
+            .gc_root(KNOWN_GOOD_GC_ROOT)
+            .await?
+            .with_context(|| format!("No known-good system is pinned on {on:?}"))?;
+        let system_name = on.facts().await?.hostname.clone();
+        Ok(Self::existing(on, path, system_name))
+    }
 
-        let outcomes = futures::join!(cmd.status(), stdout_read, stderr_read);
-        let result = outcomes.0?;
-        if !result.success() {
-            bail!("nix-copy-closure failed");
+    /// Promotes the system configuration that the system currently
+    /// runs to be known-good, pinning it with a GC root, once it has
+    /// been running for at least `min_age`. Returns the pinned
+    /// configuration.
+    #[instrument(level="DEBUG" err)]
+    pub async fn pin_known_good(on: Arc<Nixos>, min_age: Duration) -> Result<Self, anyhow::Error> {
+        let current = on
+            .facts()
+            .await?
+            .current_system
+            .clone()
+            .with_context(|| format!("{on:?} runs no system configuration to pin"))?;
+        let since = on
+            .os()
+            .current_system_since()
+            .await?
+            .with_context(|| format!("Could not tell when {on:?} activated {current:?}"))?;
+        let age = since.elapsed().unwrap_or_default();
+        if age < min_age {
+            anyhow::bail!(
+                "{current:?} has only been running for {}, not yet {}",
+                humantime::format_duration(Duration::from_secs(age.as_secs())),
+                humantime::format_duration(min_age)
+            );
         }
-        Ok(())
+        on.os().add_gc_root(&current, KNOWN_GOOD_GC_ROOT).await?;
+        let system_name = on.facts().await?.hostname.clone();
+        Ok(Self::existing(on, current, system_name))
     }
 
-    #[instrument(err, skip(build_cmdline))]
-    pub async fn build(
-        &self,
-        on: Arc<Nixos>,
-        config_name: Option<&str>,
-        build_cmdline: Vec<String>,
-    ) -> Result<SystemConfiguration, anyhow::Error> {
-        let (path, system_name) = on.build_flake(self, config_name, build_cmdline).await?;
-        Ok(SystemConfiguration {
-            path,
-            system: on,
-            system_name,
+    /// Returns the generation of the system's "system" profile
+    /// before the current one. Activating it resets the profile to
+    /// that generation.
+    #[instrument(level="DEBUG" err)]
+    pub async fn previous_generation(on: Arc<Nixos>) -> Result<Self, anyhow::Error> {
+        let (number, path) = on.os().previous_generation().await?.with_context(|| {
+            format!("There is no system generation before the current one on {on:?}")
+        })?;
+        let system_name = on.facts().await?.hostname.clone();
+        Ok(Self {
+            generation: Some(number),
+            ..Self::existing(on, path, system_name)
         })
     }
-}
-
-/// Represents a "built" system configuration on a system that is ready to be activated.
-pub struct SystemConfiguration {
-    path: PathBuf,
-    system: Arc<Nixos>,
-    system_name: String,
-}
 
-impl SystemConfiguration {
-    #[instrument(skip(self) err)]
-    pub async fn test_config(&self) -> Result<(), anyhow::Error> {
-        self.system.test_config(&self.path).await
+    /// Returns the NixOS version of the configuration, if it records
+    /// one.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn nixos_version(&self) -> Result<Option<String>, anyhow::Error> {
+        self.system.os().nixos_version(&self.path).await
     }
 
-    #[instrument(skip(self) err)]
-    pub async fn boot_config(&self) -> Result<(), anyhow::Error> {
-        log::event!(
-            log::Level::DEBUG,
-            "Attempting to activate boot configuration (dry-run)"
-        );
-        self.system
-            .update_boot_for_config(&self.path)
-            .await
-            .context("Trial run of boot activation failed. No cleanup necessary.")?;
+    /// Returns why the system would have to reboot to run the
+    /// configuration: the components (like `kernel`) that differ from
+    /// the booted system's.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn reboot_reasons(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.system.os().reboot_reasons(&self.path).await
+    }
 
-        log::event!(log::Level::DEBUG, "Setting system profile");
-        self.system
-            .set_as_current_generation(&self.path)
-            .await
-            .context("You may have to check the system profile generation to clean up.")?;
+    /// Returns the changes in the versions of notable components
+    /// (like the kernel) between the `running` system and the
+    /// configuration.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn version_changes(
+        &self,
+        running: &Path,
+    ) -> Result<Vec<report::VersionChange>, anyhow::Error> {
+        let (from, to) = futures::try_join!(
+            self.system.component_versions(running),
+            self.system.component_versions(&self.path)
+        )?;
+        Ok(from
+            .into_iter()
+            .zip(to)
+            .filter(|((_, from), (_, to))| from != to)
+            .map(|((component, from), (_, to))| report::VersionChange {
+                component: component.to_string(),
+                from,
+                to,
+            })
+            .collect())
+    }
 
-        self.system.update_boot_for_config(&self.path).await
-            .context("Actually setting the boot configuration failed. To clean up, you'll have to reset the system profile.")
+    /// Checks that the configuration's closure is still present in
+    /// the system's nix store.
+    #[instrument(level="DEBUG", skip(self) err)]
+    pub async fn check_present(&self) -> Result<(), anyhow::Error> {
+        let missing = self
+            .system
+            .os()
+            .missing_store_paths(std::slice::from_ref(&self.path))
+            .await?;
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "System configuration {:?} is no longer present on {:?}",
+                self.path,
+                self.system
+            );
+        }
+        Ok(())
     }
 
     #[instrument(level="DEBUG", skip(self) err)]
-    pub async fn preflight_check_system(&self) -> Result<(), anyhow::Error> {
-        self.system.preflight_check_system().await
+    pub async fn preflight_check_system(
+        &self,
+        method: HealthCheck,
+        timeout: Duration,
+    ) -> Result<(), anyhow::Error> {
+        self.system
+            .os()
+            .preflight_check_system(method, timeout)
+            .await
     }
 
     #[instrument(level="DEBUG", skip(self) err)]
@@ -169,6 +1850,7 @@ impl SystemConfiguration {
         script: Option<&Path>,
     ) -> Result<(), anyhow::Error> {
         self.system
+            .os()
             .preflight_check_closure(&self.path, script)
             .await
     }
@@ -189,12 +1871,285 @@ impl SystemConfiguration {
     }
 }
 
+/// Whether to run an optional step of the deployment.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Behavior {
+    Run,
+    Skip,
+}
+
+/// How a configuration gets activated on a destination.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Activation {
+    /// Activate the configuration on the running system, and only
+    /// install it as the boot configuration if that worked.
+    #[default]
+    #[value(alias = "both")]
+    #[serde(alias = "both")]
+    TestThenBoot,
+
+    /// Set the profile, then activate the configuration and install
+    /// it as the boot configuration in a single step.
+    Switch,
+
+    /// Only install the configuration as the boot configuration,
+    /// leaving the running system alone.
+    #[value(alias = "boot")]
+    #[serde(alias = "boot")]
+    BootOnly,
+
+    /// Only activate the configuration on the running system, leaving
+    /// the profile and boot configuration alone.
+    #[value(alias = "test")]
+    #[serde(alias = "test")]
+    TestOnly,
+}
+
+impl Activation {
+    /// Returns whether the activation changes the running system.
+    pub fn changes_running_system(self) -> bool {
+        self != Activation::BootOnly
+    }
+
+    /// Returns whether the activation installs the configuration as
+    /// the boot configuration after testing it (or without testing).
+    pub fn installs_boot_config(self) -> bool {
+        matches!(self, Activation::TestThenBoot | Activation::BootOnly)
+    }
+}
+
+/// Whether to reboot a destination once a configuration is installed
+/// as its boot configuration.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reboot {
+    /// Leave rebooting to the user.
+    #[default]
+    Never,
+
+    /// Always reboot.
+    Always,
+
+    /// Reboot if the configuration has a different kernel, initrd,
+    /// kernel modules or systemd than the system that was booted.
+    IfNeeded,
+}
+
+/// How a destination reboots into a configuration.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RebootMethod {
+    /// Reboot through the firmware.
+    #[default]
+    Reboot,
+
+    /// Restart only userspace with `systemctl soft-reboot`, keeping
+    /// the running kernel. Needs systemd 254 or later.
+    SoftReboot,
+
+    /// Boot the configuration's kernel directly with kexec, skipping
+    /// the firmware. Needs kexec-tools on the destination.
+    Kexec,
+}
+
+impl RebootMethod {
+    /// Returns whether rebooting this way takes the configuration's
+    /// component `reason` (as returned by
+    /// [`SystemConfiguration::reboot_reasons`]) into effect.
+    pub fn applies(self, reason: &str) -> bool {
+        self != RebootMethod::SoftReboot || reason == "systemd"
+    }
+}
+
+/// How often to try reconnecting to a rebooting system.
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reboots the system `on` into the configuration at `derivation`,
+/// which must be its boot configuration already, and waits at most
+/// `timeout` for it to come back. Returns a new connection to the
+/// rebooted system, once it is certain that the system runs the
+/// configuration.
+#[instrument(level = "DEBUG", skip(on), err)]
+pub async fn reboot(
+    on: &Nixos,
+    method: RebootMethod,
+    derivation: &Path,
+    timeout: Duration,
+) -> Result<Arc<Nixos>, anyhow::Error> {
+    let boot_id = on.os().boot_id().await?;
+    on.os().reboot(method, derivation).await?;
+    let system = tokio::time::timeout(timeout, async {
+        loop {
+            tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+            let system = match on
+                .flavor()
+                .connect(on.host(), on.port(), on.options().clone())
+                .await
+            {
+                Ok(system) => system,
+                Err(error) => {
+                    log::event!(log::Level::DEBUG, error = %format!("{error:#}"), "Not back yet");
+                    continue;
+                }
+            };
+            // Until it goes down, the system is still reachable as it
+            // was booted before:
+            let new_boot_id = system.os().boot_id().await;
+            match new_boot_id {
+                Ok(new_boot_id) if new_boot_id != boot_id => return system,
+                Ok(_) => log::event!(log::Level::DEBUG, "Not rebooted yet"),
+                Err(error) => {
+                    log::event!(log::Level::DEBUG, error = %format!("{error:#}"), "Not back yet")
+                }
+            }
+            let _ = system.close().await;
+        }
+    })
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "{on:?} did not come back within {} of rebooting",
+            humantime::format_duration(timeout)
+        )
+    })?;
+    // A soft reboot restarts into the current system, a full one into
+    // the boot configuration (or whatever the boot loader picked):
+    let runs = match method {
+        RebootMethod::SoftReboot => system.runs(derivation).await?,
+        RebootMethod::Reboot | RebootMethod::Kexec => system.booted(derivation).await?,
+    };
+    if !runs {
+        anyhow::bail!("{on:?} came back from rebooting, but does not run {derivation:?}");
+    }
+    Ok(system)
+}
+
+/// Whether to run a check before deploying, and whether failing it
+/// stops the deploy.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckBehavior {
+    /// Run the check, and don't deploy if it fails.
+    Run,
+
+    /// Run the check, but only warn if it fails.
+    Warn,
+
+    /// Don't run the check.
+    Skip,
+}
+
+impl FromStr for Behavior {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Behavior::Skip),
+            "run" => Ok(Behavior::Run),
+            _ => anyhow::bail!("Unknown behavior {s:?}"),
+        }
+    }
+}
+
+/// How to check whether a system is healthy before deploying to it.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthCheck {
+    /// Ask `systemctl is-system-running`, and look for failed units
+    /// instead if the system doesn't support that.
+    #[default]
+    Auto,
+
+    /// Only ask `systemctl is-system-running`.
+    IsSystemRunning,
+
+    /// Only look for failed units.
+    FailedUnits,
+}
+
+/// When to start activating a configuration on a set of destinations.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Gate {
+    /// Activate each destination as soon as it is ready.
+    None,
+
+    /// Activate only once every destination passed its preflight checks.
+    Preflight,
+}
+
+/// How deploying to a set of destinations gets spread out over time.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Deploy to all destinations at the same time (or to at most
+    /// max-parallel of them, starting on the next destination
+    /// whenever one is done).
+    Parallel,
+
+    /// Deploy to one destination after the other, stopping at the
+    /// first one that fails.
+    Serial,
+
+    /// Deploy in waves of max-parallel destinations, starting each
+    /// wave only once the previous one succeeded.
+    Rolling,
+}
+
+impl Strategy {
+    /// Returns the strategy to use when none was chosen: deploying
+    /// in waves if a wave size is given, in parallel otherwise.
+    pub fn default_for(max_parallel: Option<NonZeroUsize>) -> Self {
+        match max_parallel {
+            Some(_) => Strategy::Rolling,
+            None => Strategy::Parallel,
+        }
+    }
+
+    /// Returns how many of `count` destinations go into each wave,
+    /// and how many destinations of a wave get deployed to at the
+    /// same time.
+    pub fn waves(
+        self,
+        count: usize,
+        max_parallel: Option<NonZeroUsize>,
+    ) -> Result<(usize, usize), anyhow::Error> {
+        let all = count.max(1);
+        match self {
+            Strategy::Parallel => Ok((all, max_parallel.map_or(all, NonZeroUsize::get))),
+            Strategy::Serial => Ok((1, 1)),
+            Strategy::Rolling => {
+                let size = max_parallel
+                    .context("Deploying in rolling waves needs a max-parallel wave size")?
+                    .get();
+                Ok((size, size))
+            }
+        }
+    }
+}
+
 /// The kind of operating system we deploy to
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum Flavor {
     /// NixOS, the default.
     #[default]
     Nixos,
+
+    /// macOS, managed with nix-darwin.
+    Darwin,
+}
+
+impl Flavor {
+    /// Returns the name that nix platforms (like `x86_64-linux`) give
+    /// the flavor's operating system.
+    pub fn nix_os(self) -> &'static str {
+        match self {
+            Flavor::Nixos => "linux",
+            Flavor::Darwin => "darwin",
+        }
+    }
 }
 
 impl FromStr for Flavor {
@@ -203,8 +2158,9 @@ impl FromStr for Flavor {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "nixos" => Ok(Flavor::Nixos),
+            "darwin" => Ok(Flavor::Darwin),
             s => Err(anyhow!(
-                "Can not parse {:?} - only \"nixos\" is a valid flavor",
+                "Can not parse {:?} - only \"nixos\" and \"darwin\" are valid flavors",
                 s
             )),
         }
@@ -215,23 +2171,308 @@ impl fmt::Display for Flavor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Flavor::Nixos => write!(f, "nixos"),
+            Flavor::Darwin => write!(f, "darwin"),
         }
     }
 }
 
 impl Flavor {
-    pub fn on_connection(&self, host: &str, connection: openssh::Session) -> Arc<Nixos> {
+    pub fn on_connection(
+        &self,
+        host: &str,
+        connection: openssh::Session,
+        options: RemoteOptions,
+    ) -> Arc<Nixos> {
+        Arc::new(Nixos::from_session(host.to_owned(), connection, options).with_flavor(*self))
+    }
+
+    /// Connects to a host running this flavor of operating system,
+    /// on the given ssh port if any, in the "connect" phase of
+    /// deploying to it.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        options: RemoteOptions,
+    ) -> Result<Arc<Nixos>, anyhow::Error> {
+        let phase_options = options.clone();
+        hooks::in_phase("connect", host, &phase_options, async {
+            Ok(Arc::new(
+                Nixos::connect(host, port, options)
+                    .await?
+                    .with_flavor(*self),
+            ))
+        })
+        .await
+    }
+}
+
+/// How ssh treats the keys of hosts that it connects to.
+#[derive(clap::ValueEnum, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum HostKeyPolicy {
+    /// Refuse to connect to hosts whose key isn't known yet.
+    #[default]
+    Strict,
+
+    /// Remember the keys of hosts that aren't known yet, but refuse
+    /// to connect to hosts whose key changed.
+    AcceptNew,
+
+    /// Connect to hosts no matter what their key is.
+    Off,
+}
+
+impl HostKeyPolicy {
+    /// Returns the policy as the openssh crate knows it.
+    pub fn known_hosts(self) -> openssh::KnownHosts {
         match self {
-            Flavor::Nixos => Arc::new(Nixos::new(host.to_owned(), connection)),
+            HostKeyPolicy::Strict => openssh::KnownHosts::Strict,
+            HostKeyPolicy::AcceptNew => openssh::KnownHosts::Add,
+            HostKeyPolicy::Off => openssh::KnownHosts::Accept,
         }
     }
+
+    /// Returns the policy as an option for the ssh command line.
+    pub fn ssh_option(self) -> String {
+        let value = match self {
+            HostKeyPolicy::Strict => "yes",
+            HostKeyPolicy::AcceptNew => "accept-new",
+            HostKeyPolicy::Off => "no",
+        };
+        format!("StrictHostKeyChecking={value}")
+    }
+}
+
+impl fmt::Display for HostKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no variant is skipped");
+        write!(f, "{}", value.get_name())
+    }
+}
+
+/// How a destination's hostname turns into the hosts that get
+/// deployed to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Discovery {
+    /// The hostname names the one host to deploy to.
+    #[default]
+    Host,
+
+    /// The hostname is a DNS SRV record (like
+    /// `_deploy._tcp.example.com`), whose targets are the hosts to
+    /// deploy to.
+    Srv,
 }
 
 #[derive(Debug, Clone)]
 pub struct Destination {
     pub os_flavor: Flavor,
     pub hostname: String,
+    /// The ssh port to connect to, unless it's the default one.
+    pub port: Option<u16>,
     pub config_name: Option<String>,
+    pub discovery: Discovery,
+    /// The local port that the destination's reverse tunnel listens
+    /// on, if it dials in instead of being connected to directly
+    /// (see [`tunnel`]).
+    pub tunnel: Option<u16>,
+    /// How ssh treats the destination's host key, if not as
+    /// [`RemoteOptions::host_key_policy`] says.
+    pub host_key_policy: Option<HostKeyPolicy>,
+    /// The jump host that the destination is reached through, if not
+    /// the one in [`RemoteOptions::jump_host`].
+    pub via: Option<String>,
+}
+
+impl Destination {
+    /// Returns the `[user@]host` and port that ssh connects to for
+    /// this destination: for one that dials in, that is the local
+    /// end of its reverse tunnel.
+    pub fn ssh_target(&self) -> (String, Option<u16>) {
+        let Some(port) = self.tunnel else {
+            return (self.hostname.clone(), self.port);
+        };
+        let target = match self.hostname.split_once('@') {
+            Some((username, _)) => format!("{username}@{}", tunnel::TUNNEL_HOST),
+            None => tunnel::TUNNEL_HOST.to_string(),
+        };
+        (target, Some(port))
+    }
+
+    /// Returns the name that ssh looks the destination's host key up
+    /// under, if it isn't the host it connects to: a destination that
+    /// dials in gets connected to on the local end of its tunnel, but
+    /// its key must not get mixed up with the keys of the other
+    /// destinations that do that.
+    pub fn host_key_alias(&self) -> Option<&str> {
+        self.tunnel?;
+        Some(match self.hostname.split_once('@') {
+            Some((_, host)) => host,
+            None => &self.hostname,
+        })
+    }
+
+    /// Returns the destination for a target of this destination's
+    /// SRV record, reached on the record's port unless this
+    /// destination names one. The target "." means that there is no
+    /// such service, so it has none.
+    fn srv_target(&self, username: Option<&str>, target: &str, port: u16) -> Option<Destination> {
+        let target = target.trim_end_matches('.');
+        if target.is_empty() {
+            return None;
+        }
+        Some(Destination {
+            os_flavor: self.os_flavor,
+            hostname: match username {
+                Some(username) => format!("{username}@{target}"),
+                None => target.to_string(),
+            },
+            port: self.port.or(Some(port)),
+            tunnel: None,
+            host_key_policy: self.host_key_policy,
+            via: self.via.clone(),
+            config_name: self.config_name.clone(),
+            discovery: Discovery::Host,
+        })
+    }
+
+    /// Returns the concrete destinations that this destination
+    /// stands for: itself, or the targets of its SRV record.
+    pub async fn expand(&self) -> Result<Vec<Destination>, anyhow::Error> {
+        if self.discovery == Discovery::Host {
+            return Ok(vec![self.clone()]);
+        }
+        let (username, name) = match self.hostname.split_once('@') {
+            Some((username, name)) => (Some(username), name),
+            None => (None, self.hostname.as_str()),
+        };
+        let resolver = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+            .context("Could not set up a DNS resolver")?;
+        let lookup = resolver
+            .srv_lookup(name)
+            .await
+            .with_context(|| format!("Could not look up SRV record {name:?}"))?;
+        let mut records: Vec<_> = lookup.iter().collect();
+        records.sort_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())));
+        let destinations: Vec<Destination> = records
+            .into_iter()
+            .filter_map(|srv| self.srv_target(username, &srv.target().to_utf8(), srv.port()))
+            .collect();
+        if destinations.is_empty() {
+            anyhow::bail!("SRV record {name:?} has no targets");
+        }
+        log::debug!(
+            srv = name,
+            hosts = destinations.len(),
+            "Expanded SRV record"
+        );
+        Ok(destinations)
+    }
+}
+
+/// A destination as declared in a flake's `deploy-flake.hosts`
+/// output.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum InventoryHost {
+    Destination(String),
+    Attrs {
+        hostname: String,
+        #[serde(default)]
+        flavor: Option<String>,
+        #[serde(default)]
+        port: Option<u16>,
+        #[serde(default)]
+        config: Option<String>,
+        #[serde(default)]
+        tunnel: Option<u16>,
+    },
+}
+
+impl InventoryHost {
+    fn into_destination(self) -> Result<Destination, anyhow::Error> {
+        match self {
+            InventoryHost::Destination(destination) => destination.parse(),
+            InventoryHost::Attrs {
+                hostname,
+                flavor,
+                port,
+                config,
+                tunnel,
+            } => Ok(Destination {
+                os_flavor: match flavor {
+                    Some(flavor) => flavor.parse()?,
+                    None => Flavor::default(),
+                },
+                hostname,
+                port,
+                config_name: config,
+                discovery: Discovery::Host,
+                tunnel,
+                host_key_policy: None,
+                via: None,
+            }),
+        }
+    }
+}
+
+/// Returns a `[user@]host` with the host in brackets if it is an
+/// IPv6 address, as URLs (and nix's store URLs) need it.
+pub fn bracketed_host(hostname: &str) -> Cow<'_, str> {
+    let (user, host) = match hostname.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, hostname),
+    };
+    if !host.contains(':') || host.starts_with('[') {
+        return Cow::Borrowed(hostname);
+    }
+    match user {
+        Some(user) => Cow::Owned(format!("{user}@[{host}]")),
+        None => Cow::Owned(format!("[{host}]")),
+    }
+}
+
+/// Expands every destination into the concrete destinations it
+/// stands for (see [`Destination::expand`]), keeping their order.
+pub async fn expand_destinations(
+    destinations: Vec<Destination>,
+) -> Result<Vec<Destination>, anyhow::Error> {
+    let expanded =
+        futures::future::try_join_all(destinations.iter().map(Destination::expand)).await?;
+    Ok(expanded.into_iter().flatten().collect())
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.os_flavor)?;
+        if self.discovery == Discovery::Srv {
+            write!(f, "+srv")?;
+        }
+        write!(f, "://{}", bracketed_host(&self.hostname))?;
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        if let Some(config_name) = &self.config_name {
+            write!(f, "/{config_name}")?;
+        }
+        let params = [
+            self.tunnel.map(|port| format!("tunnel={port}")),
+            self.host_key_policy
+                .map(|policy| format!("host-key-policy={policy}")),
+            self.via.as_ref().map(|via| format!("via={via}")),
+        ];
+        for (i, param) in params.iter().flatten().enumerate() {
+            write!(f, "{}{param}", if i == 0 { '?' } else { '&' })?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 impl FromStr for Destination {
@@ -240,20 +2481,65 @@ impl FromStr for Destination {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if let Ok(url) = Url::parse(s) {
             // we have a URL, let's see if it matches something we can deal with:
-            match (url.scheme(), url.host_str(), url.path(), url.username()) {
-                ("nixos", Some(host), path, username) => {
+            let (scheme, discovery) = match url.scheme().strip_suffix("+srv") {
+                Some(scheme) => (scheme, Discovery::Srv),
+                None => (url.scheme(), Discovery::Host),
+            };
+            let os_flavor = scheme.parse::<Flavor>();
+            match (os_flavor, url.host(), url.path(), url.username()) {
+                (Ok(os_flavor), Some(host), path, username) => {
+                    // IPv6 addresses lose their brackets, the way ssh
+                    // expects them:
+                    let host = match host {
+                        url::Host::Ipv6(address) => address.to_string(),
+                        host => host.to_string(),
+                    };
                     let hostname = if username.is_empty() {
-                        host.to_string()
+                        host
                     } else {
                         format!("{username}@{host}")
                     };
+                    let mut tunnel = None;
+                    let mut host_key_policy = None;
+                    let mut via = None;
+                    for (key, value) in url.query_pairs() {
+                        match &*key {
+                            "via" => via = Some(value.into_owned()),
+                            "host-key-policy" => {
+                                host_key_policy = Some(
+                                    clap::ValueEnum::from_str(&value, false).map_err(|_| {
+                                        anyhow::anyhow!("Invalid host key policy {value:?} in {s}")
+                                    })?,
+                                )
+                            }
+                            "tunnel" => {
+                                tunnel = Some(value.parse().with_context(|| {
+                                    format!("Invalid tunnel port {value:?} in {s}")
+                                })?)
+                            }
+                            _ => anyhow::bail!("Unknown parameter {key:?} in {s}"),
+                        }
+                    }
+                    if tunnel.is_some() && discovery == Discovery::Srv {
+                        anyhow::bail!("SRV records can not dial in through a tunnel: {s}");
+                    }
+                    if tunnel.is_some() && via.is_some() {
+                        anyhow::bail!(
+                            "Destinations that dial in can not be reached via a jump host: {s}"
+                        );
+                    }
                     Ok(Destination {
-                        os_flavor: Flavor::Nixos,
+                        os_flavor,
                         hostname,
+                        port: url.port(),
                         config_name: path
                             .strip_prefix('/')
                             .filter(|path| !path.is_empty())
                             .map(String::from),
+                        discovery,
+                        tunnel,
+                        host_key_policy,
+                        via,
                     })
                 }
                 _ => anyhow::bail!("Unable to parse {s}"),
@@ -262,7 +2548,12 @@ impl FromStr for Destination {
             Ok(Destination {
                 os_flavor: Flavor::Nixos,
                 hostname: s.to_string(),
+                port: None,
                 config_name: None,
+                discovery: Discovery::Host,
+                tunnel: None,
+                host_key_policy: None,
+                via: None,
             })
         }
     }
@@ -270,15 +2561,355 @@ impl FromStr for Destination {
 
 #[cfg(test)]
 mod test {
-    use super::Destination;
+    use super::{
+        bracketed_host, check_space, copy_timeout_for_size, nix::FlakeInfo, nixos_release,
+        path_component, runs_on, transient_failure_from_output, version_at_least, ByteSize,
+        Destination, DryBuild, Estimate, Flake, Flavor, InventoryHost, Pruning, RebootMethod,
+        SourceWarning, Strategy, Stream, SubprocessLogLevels, UnitsFailed, COPY_TIMEOUT_SLACK,
+    };
+    use std::time::Duration;
     use test_case::test_case;
+    use tracing::Level;
+
+    #[test]
+    fn transient_failure_detection() {
+        let output: Vec<String> = [
+            "error: builder for '/nix/store/aaa-foo.drv' failed with exit code 1;",
+            "error: unable to download 'https://cache.nixos.org/aaa.narinfo': Timeout was reached",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            transient_failure_from_output(&output),
+            Some(output[1].as_str())
+        );
+        assert_eq!(transient_failure_from_output(&output[..1]), None);
+    }
+
+    #[test_case("1000" => 1000; "plain bytes")]
+    #[test_case("512B" => 512; "bytes")]
+    #[test_case("500M" => 500 << 20; "short unit")]
+    #[test_case("2 GiB" => 2 << 30; "long unit")]
+    #[test_case("1.5gb" => 3 << 29; "fraction")]
+    #[test_case("1T" => 1 << 40; "terabytes")]
+    fn byte_size_parsing(s: &str) -> u64 {
+        s.parse::<ByteSize>().unwrap().0
+    }
+
+    #[test_case(Pruning::KeepGenerations(5) => "+5"; "keeping generations")]
+    #[test_case(Pruning::older_than(Duration::from_secs(30 * 24 * 60 * 60)).unwrap() => "30d"; "by age")]
+    fn pruning(pruning: Pruning) -> String {
+        pruning.generations()
+    }
+
+    #[test]
+    fn pruning_by_partial_days() {
+        assert!(Pruning::older_than(Duration::from_secs(36 * 60 * 60)).is_err());
+        assert!(Pruning::older_than(Duration::ZERO).is_err());
+    }
+
+    #[test_case("2.18.1", "2.4" => true; "newer")]
+    #[test_case("2.4", "2.4.0" => true; "same")]
+    #[test_case("2.3.16", "2.4" => false; "older")]
+    #[test_case("2.19pre20231020", "2.19" => true; "prerelease")]
+    #[test_case("", "2.4" => false; "unknown")]
+    fn nix_versions(version: &str, min: &str) -> bool {
+        version_at_least(version, min)
+    }
+
+    #[test_case("x86_64-linux", "x86_64", Flavor::Nixos => true; "same platform")]
+    #[test_case("x86_64-linux", "aarch64", Flavor::Nixos => false; "other cpu")]
+    #[test_case("x86_64-darwin", "x86_64", Flavor::Nixos => false; "other os")]
+    #[test_case("aarch64-darwin", "arm64", Flavor::Darwin => true; "apple silicon")]
+    #[test_case("i686-linux", "x86_64", Flavor::Nixos => true; "32 bits on 64")]
+    #[test_case("powerpc64le-linux", "ppc64le", Flavor::Nixos => true; "uname cpu names")]
+    fn platforms(platform: &str, machine: &str, flavor: Flavor) -> bool {
+        runs_on(platform, machine, flavor)
+    }
+
+    #[test_case(RebootMethod::Reboot, "kernel" => true; "reboot")]
+    #[test_case(RebootMethod::Kexec, "initrd" => true; "kexec")]
+    #[test_case(RebootMethod::SoftReboot, "systemd" => true; "soft reboot")]
+    #[test_case(RebootMethod::SoftReboot, "kernel-modules" => false; "soft reboot keeps the kernel")]
+    fn reboot_methods(method: RebootMethod, reason: &str) -> bool {
+        method.applies(reason)
+    }
+
+    #[test_case(100, 200 => true; "fits")]
+    #[test_case(200, 200 => true; "fits exactly")]
+    #[test_case(201, 200 => false; "too large")]
+    fn free_space(needed: u64, free: u64) -> bool {
+        check_space("/boot", needed, free).is_ok()
+    }
+
+    #[test]
+    fn byte_size_errors_and_display() {
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("12 parsecs".parse::<ByteSize>().is_err());
+        assert_eq!(ByteSize(512).to_string(), "512 B");
+        assert_eq!(ByteSize(3 << 29).to_string(), "1.5 GiB");
+    }
+
+    #[test_case(Stream::Stdout, "building '/nix/store/aaa-foo.drv'...", Level::DEBUG ; "stdout")]
+    #[test_case(Stream::Stderr, "copying path '/nix/store/aaa-foo'", Level::WARN ; "stderr")]
+    #[test_case(Stream::Stderr, "warning: Git tree is dirty", Level::ERROR ; "nix warning")]
+    fn subprocess_log_levels(stream: Stream, line: &str, expected: Level) {
+        let levels = SubprocessLogLevels {
+            stdout: Level::DEBUG,
+            stderr: Level::WARN,
+            warnings: Level::ERROR,
+        };
+        assert_eq!(levels.level_for(stream, line), expected);
+    }
+
+    #[test_case(0, COPY_TIMEOUT_SLACK ; "empty closure")]
+    #[test_case(512 * 1024, COPY_TIMEOUT_SLACK ; "less than a second's worth")]
+    #[test_case(300 * 1024 * 1024, COPY_TIMEOUT_SLACK + Duration::from_secs(300) ; "large closure")]
+    fn copy_timeout_scaling(bytes: u64, expected: Duration) {
+        assert_eq!(copy_timeout_for_size(bytes), expected);
+    }
+
+    #[test_case("23.11.20240115.b8dd8be (Tapir)", Some("23.11") ; "stable release")]
+    #[test_case("24.05pre-git", Some("24.05") ; "pre-release")]
+    #[test_case("unknown", None ; "garbage")]
+    #[test_case("24.11.git.abcdef", Some("24.11") ; "git checkout")]
+    #[test_case("", None ; "empty")]
+    fn nixos_release_parsing(version: &str, expected: Option<&str>) {
+        assert_eq!(nixos_release(version), expected);
+    }
+
+    #[test_case("kiosk", true ; "plain name")]
+    #[test_case("kiosk-mode.v2", true ; "dots inside")]
+    #[test_case("", false ; "empty")]
+    #[test_case(".", false ; "current dir")]
+    #[test_case("..", false ; "parent dir")]
+    #[test_case("../system", false ; "parent path")]
+    #[test_case("a/b", false ; "nested path")]
+    fn path_components(name: &str, valid: bool) {
+        assert_eq!(path_component(name).is_ok(), valid);
+    }
 
     #[test_case("nixos://foo", true ; "when both operands are negative")]
     #[test_case("fleepybeepo://foo", false ; "invalid flavor")]
     #[test_case("nixos:///foo", false ; "invalid hostname")]
     #[test_case("nixos://foobar@foo", true ; "with a username")]
     #[test_case("nixos://foobar@foo/configname", true ; "with a config name")]
+    #[test_case("nixos+srv://_deploy._tcp.example.com", true ; "SRV record")]
+    #[test_case("darwin://mac-mini", true ; "nix-darwin")]
+    #[test_case("nixos://root@foo:2222/web", true ; "with a port")]
+    #[test_case("nixos://[2001:db8::1]:2222", true ; "IPv6 literal")]
+    #[test_case("fleepybeepo+srv://_deploy._tcp.example.com", false ; "SRV record with invalid flavor")]
+    #[test_case("nixos://root@device1?tunnel=2201", true ; "behind a tunnel")]
+    #[test_case("nixos://device1?tunnel=ssh", false ; "invalid tunnel port")]
+    #[test_case("nixos://device1?tunel=2201", false ; "unknown parameter")]
+    #[test_case("nixos://device1?host-key-policy=lax", false ; "invalid host key policy")]
+    #[test_case("nixos://db1?via=admin@bastion.example.com", true ; "via a jump host")]
+    #[test_case("nixos://device1?tunnel=2201&via=bastion", false ; "tunnel via a jump host")]
+    #[test_case("nixos+srv://_deploy._tcp.example.com?tunnel=2201", false ; "SRV record behind a tunnel")]
     fn destination_parsing(input: &str, parses: bool) {
         assert_eq!(input.parse::<Destination>().is_ok(), parses);
     }
+
+    #[test_case("foo", "nixos://foo" ; "bare hostname")]
+    #[test_case("nixos://foobar@foo/configname", "nixos://foobar@foo/configname" ; "full URL")]
+    #[test_case("nixos+srv://root@_deploy._tcp.example.com/web", "nixos+srv://root@_deploy._tcp.example.com/web" ; "SRV record")]
+    #[test_case("darwin://mac-mini/studio", "darwin://mac-mini/studio" ; "nix-darwin")]
+    #[test_case("nixos://root@foo:2222/web", "nixos://root@foo:2222/web" ; "with a port")]
+    #[test_case("nixos://root@[2001:db8::1]:2222", "nixos://root@[2001:db8::1]:2222" ; "IPv6 literal")]
+    #[test_case("nixos://root@device1/edge?tunnel=2201", "nixos://root@device1/edge?tunnel=2201" ; "behind a tunnel")]
+    #[test_case("nixos://new-host?host-key-policy=accept-new", "nixos://new-host?host-key-policy=accept-new" ; "with a host key policy")]
+    #[test_case("nixos://device1?host-key-policy=off&tunnel=2201", "nixos://device1?tunnel=2201&host-key-policy=off" ; "with several parameters")]
+    #[test_case("nixos://root@db1/db?via=admin@bastion.example.com", "nixos://root@db1/db?via=admin@bastion.example.com" ; "via a jump host")]
+    fn destination_display_roundtrips(input: &str, displayed: &str) {
+        let dest = input.parse::<Destination>().unwrap();
+        assert_eq!(dest.to_string(), displayed);
+        let reparsed = displayed.parse::<Destination>().unwrap();
+        assert_eq!(reparsed.hostname, dest.hostname);
+        assert_eq!(reparsed.config_name, dest.config_name);
+        assert_eq!(reparsed.discovery, dest.discovery);
+        assert_eq!(reparsed.os_flavor, dest.os_flavor);
+        assert_eq!(reparsed.port, dest.port);
+        assert_eq!(reparsed.tunnel, dest.tunnel);
+        assert_eq!(reparsed.host_key_policy, dest.host_key_policy);
+        assert_eq!(reparsed.via, dest.via);
+    }
+
+    #[test_case("nixos+srv://_deploy._tcp.example.com/web", "web1.example.com.", 2222 => Some("nixos://web1.example.com:2222/web".to_string()) ; "record's port")]
+    #[test_case("nixos+srv://root@_deploy._tcp.example.com:22", "web1.example.com.", 2222 => Some("nixos://root@web1.example.com:22".to_string()) ; "destination's port")]
+    #[test_case("nixos+srv://_deploy._tcp.example.com", ".", 0 => None ; "no service")]
+    fn srv_targets(input: &str, target: &str, port: u16) -> Option<String> {
+        let dest: Destination = input.parse().unwrap();
+        let (username, _) = dest.hostname.split_once('@').unzip();
+        dest.srv_target(username, target, port)
+            .map(|dest| dest.to_string())
+    }
+
+    #[test]
+    fn tunnel_destination() {
+        let dest: Destination = "nixos://root@device1/edge?tunnel=2201".parse().unwrap();
+        assert_eq!(dest.hostname, "root@device1");
+        assert_eq!(dest.config_name.as_deref(), Some("edge"));
+        assert_eq!(
+            dest.ssh_target(),
+            ("root@localhost".to_string(), Some(2201))
+        );
+        assert_eq!(dest.host_key_alias(), Some("device1"));
+        let direct: Destination = "nixos://root@device1/edge".parse().unwrap();
+        assert_eq!(direct.host_key_alias(), None);
+    }
+
+    #[test]
+    fn inventory_parsing() {
+        let hosts: Vec<InventoryHost> = serde_json::from_str(
+            r#"["nixos://web1/webserver", {"hostname": "root@mac-mini", "flavor": "darwin", "port": 2222, "config": "studio"}]"#,
+        )
+        .unwrap();
+        let destinations: Vec<Destination> = hosts
+            .into_iter()
+            .map(InventoryHost::into_destination)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(destinations[0].hostname, "web1");
+        assert_eq!(destinations[0].config_name.as_deref(), Some("webserver"));
+        assert_eq!(
+            destinations[1].to_string(),
+            "darwin://root@mac-mini:2222/studio"
+        );
+    }
+
+    #[test]
+    fn ipv6_destination() {
+        let dest: Destination = "nixos://root@[2001:db8::1]:2222".parse().unwrap();
+        assert_eq!(dest.hostname, "root@2001:db8::1");
+        assert_eq!(dest.port, Some(2222));
+        assert_eq!(bracketed_host(&dest.hostname), "root@[2001:db8::1]");
+        assert_eq!(bracketed_host("root@foo"), "root@foo");
+    }
+
+    #[test]
+    fn flake_source_warnings() {
+        let info: FlakeInfo = serde_json::from_str(
+            r#"{"path":"/nix/store/aaa-source","dirtyRevision":"0123abc-dirty","locked":{"type":"git","narHash":"sha256-xyz=","dirtyRev":"0123abc-dirty"}}"#,
+        )
+        .unwrap();
+        let locked = info.locked.unwrap();
+        let flake = Flake {
+            dir: Some(".".into()),
+            reference: None,
+            resolved_path: info.path,
+            revision: info.revision,
+            dirty_revision: info.dirty_revision,
+            source_type: Some(locked.source_type),
+            nar_hash: locked.nar_hash,
+            shallow: true,
+        };
+        assert_eq!(
+            flake.source_warnings(),
+            vec![
+                SourceWarning::Dirty {
+                    revision: Some("0123abc-dirty".to_string())
+                },
+                SourceWarning::Shallow
+            ]
+        );
+        assert_eq!(
+            flake.provenance(),
+            "revision 0123abc-dirty, narHash sha256-xyz="
+        );
+
+        let path = Flake {
+            source_type: Some("path".to_string()),
+            dirty_revision: None,
+            shallow: false,
+            ..flake
+        };
+        assert_eq!(
+            path.source_warnings(),
+            vec![SourceWarning::NotGit {
+                source_type: "path".to_string()
+            }]
+        );
+        assert_eq!(path.provenance(), "path source, narHash sha256-xyz=");
+    }
+
+    #[test_case(Strategy::Parallel, None, Some((5, 5)) ; "parallel")]
+    #[test_case(Strategy::Parallel, Some(2), Some((5, 2)) ; "bounded parallel")]
+    #[test_case(Strategy::Serial, Some(2), Some((1, 1)) ; "serial")]
+    #[test_case(Strategy::Rolling, Some(2), Some((2, 2)) ; "rolling")]
+    #[test_case(Strategy::Rolling, None, None ; "rolling without wave size")]
+    fn strategy_waves(
+        strategy: Strategy,
+        max_parallel: Option<usize>,
+        waves: Option<(usize, usize)>,
+    ) {
+        let max_parallel = max_parallel.and_then(std::num::NonZeroUsize::new);
+        assert_eq!(strategy.waves(5, max_parallel).ok(), waves);
+    }
+
+    #[test]
+    fn dry_build_parsing() {
+        let output: Vec<String> = [
+            "these 2 derivations will be built:",
+            "  /nix/store/aaa-etc.drv",
+            "  /nix/store/bbb-nixos-system-db1.drv",
+            "this path will be fetched (1.00 MiB download, 4.00 MiB unpacked):",
+            "  /nix/store/ccc-hello",
+            "warning: Git tree is dirty",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let dry_build = DryBuild::from_output("flake#db1".to_string(), &output);
+        assert_eq!(
+            dry_build.will_build,
+            vec![
+                "/nix/store/aaa-etc.drv",
+                "/nix/store/bbb-nixos-system-db1.drv"
+            ]
+        );
+        assert_eq!(dry_build.will_fetch, vec!["/nix/store/ccc-hello"]);
+        assert_eq!(
+            DryBuild::from_output(String::new(), &[]),
+            DryBuild::default()
+        );
+    }
+
+    #[test]
+    fn estimate_display() {
+        let estimate = Estimate {
+            system: std::path::PathBuf::from("/nix/store/bbb-nixos-system-db1"),
+            dry_build: DryBuild {
+                installable: "flake#db1".to_string(),
+                will_build: vec!["/nix/store/aaa-etc.drv".to_string()],
+                will_fetch: vec![],
+            },
+            transfer: None,
+            changes: true,
+        };
+        assert_eq!(
+            estimate.to_string(),
+            "  builds flake#db1 (/nix/store/bbb-nixos-system-db1)\n  \
+             would build 1 and fetch 0 path(s)\n  \
+             would copy an unknown amount, until it is built\n  \
+             would change the running system\n"
+        );
+    }
+
+    #[test_case(&["flaky.service", "acme-example.com.service"], true ; "exact")]
+    #[test_case(&["flaky*", "acme-*.service"], true ; "wildcards")]
+    #[test_case(&["*.servic?"], true ; "single character")]
+    #[test_case(&["acme-*.service"], false ; "one unit matches nothing")]
+    #[test_case(&[], false ; "no patterns")]
+    fn failed_units_matching(patterns: &[&str], all_match: bool) {
+        let failed = UnitsFailed {
+            units: vec![
+                "flaky.service".to_string(),
+                "acme-example.com.service".to_string(),
+            ],
+            details: String::new(),
+        };
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        assert_eq!(failed.all_match(&patterns), all_match);
+    }
 }
@@ -1,56 +1,805 @@
 use tokio::task;
 use tracing as log;
 use tracing::instrument;
+use tracing::Instrument;
 
 use anyhow::Context;
 use clap::Parser;
-use deploy_flake::{Destination, Flake};
-use openssh::{KnownHosts, Session};
-use std::{path::PathBuf, str::FromStr};
+use deploy_flake::{
+    config::{Config, Host},
+    copy::{copy_between, copy_from_system, ClosureCopier, CopyMethod},
+    elevate::{Elevation, PasswordPrompt},
+    expand_destinations,
+    facts::FactsCache,
+    fleet::{HostState, Inventory},
+    hooks::{in_phase, run_hook},
+    jump::JumpHosts,
+    nixos_release,
+    plan::{Plan, StagedHost},
+    report::{CompletedStep, HostReport, Outcome, Report, Step},
+    retry::{retrying, RetryPolicy},
+    snapshot::Snapshot,
+    ssh_config::SshConfig,
+    status::{StatusLayer, STATUS_TARGET},
+    supervise::{join_error, supervise, Cancelled, Panicked},
+    tunnel::{wait_for_tunnel, Broker},
+    Activation, Behavior, BuildOn, BuildOptions, ByteSize, CheckBehavior, DeployOptions,
+    Destination, Flake, Flavor, Gate, HealthCheck, HostKeyPolicy, LocalNix, Nixos, OutputLine,
+    Pruning, Reboot, RebootMethod, RemoteOptions, Strategy, SubprocessLogLevels,
+    SystemConfiguration, TransferSize, TransientFailure, UnitChanges, UnitsFailed,
+    FLAKES_NIX_VERSION,
+};
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{BufRead, IsTerminal, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{Mutex, Notify, Semaphore};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
 #[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
-enum Behavior {
-    Run,
-    Skip,
+enum PrintBuildLogs {
+    /// Print build logs only if stderr is not a terminal.
+    Auto,
+    Always,
+    Never,
 }
 
-impl FromStr for Behavior {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "skip" => Ok(Behavior::Skip),
-            "run" => Ok(Behavior::Run),
-            _ => anyhow::bail!("Unknown behavior {s:?}"),
+impl PrintBuildLogs {
+    fn enabled(&self) -> bool {
+        match self {
+            PrintBuildLogs::Auto => !std::io::stderr().is_terminal(),
+            PrintBuildLogs::Always => true,
+            PrintBuildLogs::Never => false,
         }
     }
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum LogFormat {
+    /// Human-readable log lines.
+    Text,
+    /// One JSON object per line, including the fields of every span
+    /// (e.g. the host and phase) that the event happened in.
+    Json,
+}
+
 #[derive(Parser, Debug)]
-#[clap(author = "Andreas Fuchs <asf@boinkor.net>")]
+#[clap(
+    author = "Andreas Fuchs <asf@boinkor.net>",
+    args_conflicts_with_subcommands = true
+)]
 struct Opts {
-    /// The flake source code directory to deploy.
+    #[clap(flatten)]
+    target: TargetArgs,
+
+    #[clap(flatten)]
+    prepare: PrepareArgs,
+
+    #[clap(flatten)]
+    activate: ActivateArgs,
+
+    #[clap(flatten)]
+    deploy: DeployArgs,
+
+    /// Never wait for input from the terminal: anything that would
+    /// need it (a sudo password, an ssh password or host key
+    /// confirmation, or a confirmation prompt) fails immediately
+    /// instead. Useful in CI.
+    #[clap(long, global = true)]
+    non_interactive: bool,
+
+    /// How commands that need superuser privileges get them on the
+    /// destinations: `sudo`, `doas`, `run0`, `none` (if deploy-flake
+    /// logs in as root), or a custom command line that runs the
+    /// command following it as root.
+    #[clap(long, value_name = "METHOD", default_value = "sudo", global = true)]
+    elevation: Elevation,
+
+    /// Ask for the sudo password of each destination on the
+    /// terminal (once per destination), for destinations where sudo
+    /// needs one. Only works with `--elevation=sudo`.
+    #[clap(long, global = true, conflicts_with = "non_interactive")]
+    ask_sudo_password: bool,
+
+    /// An environment variable that activating a configuration (and
+    /// its pre-activation checks) gets to see; all others get
+    /// removed. Can be given multiple times, and replaces the default
+    /// list (PATH, HOME, USER, LOGNAME, TERM, LANG, LC_ALL,
+    /// LOCALE_ARCHIVE and TZDIR) when given.
+    #[clap(long, value_name = "NAME", global = true)]
+    activation_env: Vec<String>,
+
+    /// How often ssh checks that an idle connection to a destination
+    /// is still alive, e.g. while a long build runs in between
+    /// operations on it.
+    #[clap(long, value_name = "DURATION", global = true)]
+    ssh_keep_alive: Option<humantime::Duration>,
+
+    /// What ssh does with host keys that it doesn't know yet:
+    /// "strict" refuses to connect, "accept-new" remembers them (but
+    /// still refuses keys that changed), and "off" connects no
+    /// matter what. Defaults to "strict", or with `--system-ssh`, to
+    /// what the ssh configuration says. Destinations can override it
+    /// with a `?host-key-policy=POLICY` parameter.
+    #[clap(long, value_name = "POLICY", value_enum, global = true)]
+    host_key_policy: Option<HostKeyPolicy>,
+
+    /// An ssh option (like `IdentityFile=~/.ssh/deploy`, as given to
+    /// `ssh -o`) for every ssh connection that deploy-flake makes,
+    /// including those that copy closures. Can be given multiple
+    /// times; takes precedence over the ssh configuration.
+    #[clap(long, value_name = "OPTION", global = true)]
+    ssh_opt: Vec<String>,
+
+    /// Read this ssh configuration file instead of `~/.ssh/config`
+    /// (and the system-wide one), as with `ssh -F`.
+    #[clap(long, value_name = "FILE", global = true)]
+    ssh_config: Option<PathBuf>,
+
+    /// Reach the destinations through this jump host (like
+    /// `admin@bastion.example.com`). deploy-flake connects to the
+    /// jump host once, and tunnels the connections to all
+    /// destinations through that connection. Destinations can name
+    /// their own jump host with a `?via=HOST` parameter.
+    #[clap(long, value_name = "HOST", global = true)]
+    ssh_jump: Option<String>,
+
+    /// Connect to the destinations with the system's ssh, deferring
+    /// entirely to its configuration (ProxyCommand, Match blocks,
+    /// GSSAPI, password prompts and so on) instead of overriding
+    /// parts of it. Use ProxyJump in the ssh configuration instead
+    /// of `--ssh-jump` with it.
+    #[clap(long, global = true, conflicts_with = "ssh_jump")]
+    system_ssh: bool,
+
+    /// How long to wait for destinations that dial in through a
+    /// reverse tunnel (like `nixos://device1?tunnel=2201`) to do so.
+    /// By default, those that haven't dialed in yet fail right away.
+    #[clap(long, value_name = "DURATION", default_value = "0s", global = true)]
+    tunnel_wait: humantime::Duration,
+
+    /// Let destinations that dial in (like
+    /// `nixos://device1?tunnel=2201`) do so with plain connections to
+    /// their ssh server, which deploy-flake accepts on their tunnel
+    /// port on this address (like `0.0.0.0`), instead of with reverse
+    /// tunnels through an ssh server. Give them time to dial in with
+    /// `--tunnel-wait`.
+    #[clap(long, value_name = "ADDRESS", global = true)]
+    tunnel_listen: Option<std::net::IpAddr>,
+
+    /// How long the hostname, nix version and architecture gathered
+    /// about a destination get cached for (in
+    /// `$XDG_CACHE_HOME/deploy-flake/facts`). A zero duration turns
+    /// caching off.
+    #[clap(long, value_name = "DURATION", default_value = "1h", global = true)]
+    facts_ttl: humantime::Duration,
+
+    /// Gather facts about the destinations anew instead of using
+    /// cached ones.
+    #[clap(long, global = true)]
+    refresh_facts: bool,
+
+    /// Log every command that deploy-flake runs as root on a
+    /// destination to that destination's journal (tagged
+    /// `deploy-flake-audit`), along with the deploy ID.
+    #[clap(long, global = true)]
+    audit_remote_commands: bool,
+
+    /// The root of the nix store to deploy into on the destinations,
+    /// for destinations whose store isn't in `/nix/store` (it gets
+    /// passed to remote nix commands as `--store`, so with `/mnt`,
+    /// store paths and profiles live in `/mnt/nix`). Configurations
+    /// in such a store can't be tested on the running system, and
+    /// get installed as the boot configuration with `nixos-enter`.
+    #[clap(long, value_name = "DIR", global = true)]
+    remote_store: Option<PathBuf>,
+
+    /// When copying closures with `nix-copy-closure` or `nix copy`,
+    /// let the destinations fetch the paths they can from their own
+    /// binary caches (like cache.nixos.org), so that only the rest
+    /// gets sent over the uplink of the machine running deploy-flake.
+    #[clap(long, global = true)]
+    substitute_on_destination: bool,
+
+    /// The level that lines printed to stdout by commands get logged
+    /// at. Together with RUST_LOG, this controls how noisy builds are.
+    #[clap(long, value_name = "LEVEL", default_value_t = log::Level::INFO, global = true)]
+    stdout_log_level: log::Level,
+
+    /// The level that lines printed to stderr by commands get logged at.
+    #[clap(long, value_name = "LEVEL", default_value_t = log::Level::INFO, global = true)]
+    stderr_log_level: log::Level,
+
+    /// The level that nix's "warning:" lines get logged at.
+    #[clap(long, value_name = "LEVEL", default_value_t = log::Level::INFO, global = true)]
+    warning_log_level: log::Level,
+
+    /// The format that log messages get written in.
+    #[clap(long, require_equals = true, value_name = "FORMAT", default_value_t = LogFormat::Text, value_enum, global = true)]
+    log_format: LogFormat,
+
+    /// Write machine-readable progress events (as line-delimited
+    /// JSON) to this open file descriptor, e.g. for CI dashboards
+    /// that track the deploy as it happens.
+    #[clap(long, value_name = "FD", global = true)]
+    status_fd: Option<i32>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Copy, build and preflight-check the flake on every
+    /// destination and register the built configurations as GC
+    /// roots, recording them in a plan file for `activate`.
+    Stage {
+        /// The file that the plan gets written to.
+        #[clap(long, value_name = "FILE")]
+        plan: PathBuf,
+
+        /// Sign the plan with this minisign secret key, so that
+        /// `activate --verify-key` can check that it wasn't tampered
+        /// with. Needs the `minisign` tool.
+        #[clap(long, value_name = "FILE")]
+        sign_key: Option<PathBuf>,
+
+        #[clap(flatten)]
+        target: TargetArgs,
+
+        #[clap(flatten)]
+        prepare: PrepareArgs,
+    },
+
+    /// Activate the configurations recorded in a plan file by
+    /// `stage`. Destinations that were already activated are
+    /// skipped, so an interrupted activation can be resumed by
+    /// running this again.
+    ///
+    /// With `--path` instead, copy a system configuration that is
+    /// already built (e.g. a known-good one, for disaster recovery)
+    /// to the destinations and activate it there, without evaluating
+    /// or building any flake.
+    Activate {
+        /// The plan file written by `stage`.
+        #[clap(long, value_name = "FILE", required_unless_present = "path")]
+        plan: Option<PathBuf>,
+
+        /// Only activate the plan if it was signed with the secret key
+        /// belonging to this minisign public key.
+        #[clap(long, value_name = "FILE", requires = "plan")]
+        verify_key: Option<PathBuf>,
+
+        /// The store path of a built system configuration, like
+        /// `/nix/store/...-nixos-system-host`, to activate instead of
+        /// a plan.
+        #[clap(
+            long,
+            value_name = "STORE_PATH",
+            conflicts_with = "plan",
+            requires = "to"
+        )]
+        path: Option<PathBuf>,
+
+        /// The destinations to activate the store path on.
+        #[clap(long, num_args = 1.., value_name = "DESTINATION", requires = "path")]
+        to: Vec<Destination>,
+
+        #[clap(flatten)]
+        copy_retry: CopyRetryArgs,
+
+        /// Refuse to copy the store path to a destination if the paths
+        /// it is missing add up to more than this size, like `2GiB`.
+        #[clap(long, value_name = "SIZE", requires = "path")]
+        max_transfer_size: Option<ByteSize>,
+
+        /// How to copy the store path to the destinations.
+        #[clap(long, visible_alias = "copy-backend", require_equals=true, value_name = "METHOD", default_value_t = CopyMethod::NixCopyClosure, value_enum)]
+        copy_method: CopyMethod,
+
+        /// The binary cache that `--copy-method=substitute` pushes to
+        /// and substitutes from, e.g. one that already has the store
+        /// path.
+        #[clap(long, value_name = "URL", requires = "path")]
+        copy_cache: Option<String>,
+
+        /// For fleets of identical machines: only copy the store path
+        /// to the first destination from here, and have every
+        /// destination that has it push it on to another one with
+        /// `nix copy`, doubling the number of sources each round. The
+        /// destinations must be able to ssh to each other; those that
+        /// can't get the store path from another destination get it
+        /// from here instead.
+        #[clap(long, requires = "path")]
+        fanout: bool,
+
+        #[clap(flatten)]
+        activate: ActivateArgs,
+
+        #[clap(flatten)]
+        health: HealthCheckArgs,
+    },
+
+    /// Run a command on every destination, e.g. `deploy-flake exec
+    /// --to host1 host2 -- systemctl restart myapp`.
+    Exec {
+        /// The destinations to run the command on.
+        #[clap(long, num_args = 1.., value_name = "DESTINATION", required_unless_present = "config")]
+        to: Vec<Destination>,
+
+        /// Run the command on every destination in this deployment
+        /// configuration file, instead.
+        #[clap(long, value_name = "FILE", conflicts_with = "to")]
+        config: Option<PathBuf>,
+
+        /// How many destinations to run the command on at the same
+        /// time. By default, it runs on all of them at once.
+        #[clap(long, value_name = "N")]
+        max_parallel: Option<NonZeroUsize>,
+
+        /// A file whose contents get fed to the command's stdin on
+        /// every destination.
+        #[clap(long, value_name = "FILE")]
+        input: Option<PathBuf>,
+
+        /// Print one JSON object per line on stdout for every line
+        /// that the command prints on a destination, and for every
+        /// destination that it finished on.
+        #[clap(long)]
+        porcelain: bool,
+
+        /// The command to run, and its arguments.
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Build the flake's configuration on every destination and print
+    /// the changes to units that activating it would make, without
+    /// changing anything on the destinations.
+    DryActivate {
+        #[clap(flatten)]
+        target: TargetArgs,
+
+        #[clap(flatten)]
+        prepare: PrepareArgs,
+    },
+
+    /// Estimate what deploying the flake would take on every
+    /// destination, and in total: what would need building, how much
+    /// would get copied, and whether activating would change
+    /// anything. Builds and copies nothing.
+    Estimate {
+        #[clap(flatten)]
+        target: TargetArgs,
+
+        #[clap(flatten)]
+        prepare: PrepareArgs,
+
+        /// How many destinations to estimate for at the same time. By
+        /// default, all of them get estimated for at once.
+        #[clap(long, value_name = "N")]
+        max_parallel: Option<NonZeroUsize>,
+    },
+
+    /// Copy the closures of store paths to every destination, e.g. to
+    /// pre-seed a large dataset or toolchain before a deploy.
+    Copy {
+        /// The store paths to copy.
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// The destinations to copy the store paths to.
+        #[clap(long, num_args = 1.., value_name = "DESTINATION", required = true)]
+        to: Vec<Destination>,
+
+        /// How long copying to a destination may take before it is
+        /// retried. Defaults to a timeout derived from the size of the
+        /// paths that need to be transferred.
+        #[clap(long, value_name = "DURATION")]
+        copy_timeout: Option<humantime::Duration>,
+
+        #[clap(flatten)]
+        copy_retry: CopyRetryArgs,
+
+        /// Refuse to copy the store paths to a destination if the
+        /// paths it is missing add up to more than this size, like
+        /// `2GiB`.
+        #[clap(long, value_name = "SIZE")]
+        max_transfer_size: Option<ByteSize>,
+
+        /// How to copy the store paths to the destinations.
+        #[clap(long, visible_alias = "copy-backend", require_equals=true, value_name = "METHOD", default_value_t = CopyMethod::NixCopyClosure, value_enum)]
+        copy_method: CopyMethod,
+
+        /// The binary cache that `--copy-method=substitute` pushes to
+        /// and substitutes from.
+        #[clap(long, value_name = "URL")]
+        copy_cache: Option<String>,
+
+        /// Only copy the store paths to the first destination from
+        /// here, and have the destinations that have them push them
+        /// on to the others (see `activate --fanout`).
+        #[clap(long)]
+        fanout: bool,
+    },
+
+    /// Activate an earlier system configuration on every
+    /// destination: by default, the generation of the system profile
+    /// before the current one, resetting the profile to it, e.g.
+    /// `deploy-flake rollback nixos://host`.
+    Rollback {
+        /// The destinations to roll back.
+        #[clap(required = true)]
+        to: Vec<Destination>,
+
+        /// Which configuration to roll back to.
+        #[clap(long, value_name = "TARGET", default_value_t = RollbackTo::PreviousGeneration, value_enum)]
+        rollback_to: RollbackTo,
+
+        /// Ask for confirmation before testing and installing the
+        /// boot configuration on each destination.
+        #[clap(long, visible_alias = "confirm", conflicts_with = "non_interactive")]
+        ask: bool,
+
+        /// Don't ask for confirmation, even if `--ask` was given,
+        /// e.g. by a shell alias.
+        #[clap(long)]
+        yes: bool,
+
+        #[clap(flatten)]
+        activate: ActivateArgs,
+
+        #[clap(flatten)]
+        health: HealthCheckArgs,
+    },
+
+    /// Promote the configuration that every destination runs to be
+    /// known-good, pinning it so that it never gets garbage-collected
+    /// and can be rolled back to with `rollback
+    /// --rollback-to=known-good`. Destinations must be healthy and
+    /// have run their configuration for at least `--min-age`.
+    PinKnownGood {
+        /// The destinations whose configurations to pin.
+        #[clap(required = true)]
+        to: Vec<Destination>,
+
+        /// How long a destination must have been running its
+        /// configuration for it to count as known-good.
+        #[clap(long, value_name = "DURATION", default_value = "7d")]
+        min_age: humantime::Duration,
+
+        #[clap(flatten)]
+        health: HealthCheckArgs,
+    },
+
+    /// Check that the state of every destination is consistent: that
+    /// it runs the system profile's current generation, boots it by
+    /// default, has no failed units, and runs the configuration that
+    /// deploy-flake last activated there. Changes nothing.
+    Verify {
+        /// The destinations to check.
+        #[clap(required = true)]
+        to: Vec<Destination>,
+
+        /// How many destinations get checked at the same time. The
+        /// result for each destination gets printed as soon as it is
+        /// in.
+        #[clap(long, value_name = "N", default_value = "20")]
+        max_parallel: NonZeroUsize,
+
+        /// How long checking a single destination (including
+        /// connecting to it) may take before it counts as failed.
+        #[clap(long, value_name = "DURATION", default_value = "1m")]
+        timeout: humantime::Duration,
+
+        /// Print one JSON object per line on stdout for every
+        /// destination that got checked.
+        #[clap(long)]
+        porcelain: bool,
+    },
+
+    /// Show which flake source every destination runs, according to
+    /// the provenance records that deploys left there: destinations
+    /// running an older source than the latest deploy are behind,
+    /// and those running something other than what deploy-flake
+    /// activated there have drifted. Changes nothing.
+    FleetDiff {
+        /// The destinations to inventory.
+        #[clap(required = true)]
+        to: Vec<Destination>,
+
+        /// Also show how the fleet changed since the inventory saved
+        /// in this file (with `--save`).
+        #[clap(long, value_name = "FILE")]
+        since: Option<PathBuf>,
+
+        /// Save the inventory to this file as JSON, to compare
+        /// against later with `--since`.
+        #[clap(long, value_name = "FILE")]
+        save: Option<PathBuf>,
+
+        /// How many destinations get inventoried at the same time.
+        #[clap(long, value_name = "N", default_value = "20")]
+        max_parallel: NonZeroUsize,
+    },
+}
+
+// Arguments that only apply when deploying in one go.
+#[derive(clap::Args, Debug)]
+struct DeployArgs {
+    /// When to start activating the new configuration. With
+    /// `--gate=preflight`, the flake gets copied, built and
+    /// preflight-checked on every destination first, and only if all
+    /// of them succeed does any destination get activated.
+    #[clap(long, require_equals=true, value_name = "GATE", default_value_t = Gate::None, value_enum)]
+    gate: Gate,
+
+    /// How many destinations get deployed to at the same time. By
+    /// default, all destinations are deployed to at once.
+    #[clap(long, value_name = "N")]
+    max_parallel: Option<NonZeroUsize>,
+
+    /// How deploying gets spread out over the destinations: all at
+    /// once (or at most `--max-parallel` at a time), one after the
+    /// other, or in rolling waves of `--max-parallel` destinations,
+    /// each of which only starts once the previous wave succeeded.
+    /// Defaults to "rolling" if `--max-parallel` is given, and to
+    /// "parallel" otherwise.
+    #[clap(long, require_equals = true, value_name = "STRATEGY", value_enum)]
+    strategy: Option<Strategy>,
+
+    /// A deployment configuration file (in TOML format) that defines
+    /// groups of destinations. The groups get deployed one after the
+    /// other, in the order they are defined in, according to each
+    /// group's policies.
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["to", "all"])]
+    config: Option<PathBuf>,
+
+    /// Only deploy the group with this name from the configuration
+    /// file. Can be given multiple times.
+    #[clap(long = "group", value_name = "NAME", requires = "config")]
+    groups: Vec<String>,
+
+    /// Only deploy the hosts in the configuration file that carry
+    /// this tag. Can be given multiple times, to deploy the hosts
+    /// that carry any of the tags.
+    #[clap(long = "tag", value_name = "TAG", requires = "config")]
+    tags: Vec<String>,
+
+    /// Ask for confirmation before copying the flake to, testing the
+    /// configuration on, and installing the boot configuration on
+    /// each destination. Answering "no" skips the destination,
+    /// "all" continues without asking again, and "quit" aborts.
+    #[clap(long, visible_alias = "confirm", conflicts_with = "non_interactive")]
+    ask: bool,
+
+    /// Don't ask for confirmation, even if `--ask` was given, e.g. by
+    /// a shell alias.
+    #[clap(long)]
+    yes: bool,
+
+    /// Write a report of what happened on each destination (along
+    /// with the facts gathered about it) to this file, in JSON format.
+    #[clap(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Instead of deploying the flake, activate the system
+    /// configuration that was current on each destination before
+    /// deploy-flake last activated a configuration there.
+    #[clap(long, conflicts_with_all = ["config", "all"])]
+    rollback_to_last_deployed: bool,
+
+    /// Instead of deploying, print what deploying would do on each
+    /// destination: which configuration gets built, which paths nix
+    /// would build or fetch for it, and which activation steps would
+    /// run. Nothing gets built or activated, but the flake's source
+    /// gets copied to the build hosts that need it.
+    #[clap(long, conflicts_with_all = ["ask", "rollback_to_last_deployed"])]
+    dry_run: bool,
+
+    /// Before deploying anything, check the health of every
+    /// destination (like the preflight check does), and leave out
+    /// the ones that are unhealthy or unreachable, reporting them as
+    /// skipped. The deploy to the others goes ahead. At most
+    /// `--max-parallel` destinations get checked at a time, and
+    /// destinations whose preflight check is set to "skip" or "warn"
+    /// (say, in the configuration file) don't get left out.
+    #[clap(long)]
+    skip_unhealthy: bool,
+
+    /// With `--build-on=local`, only copy each built configuration to
+    /// the first destination that needs it from here, and have the
+    /// destinations that have it push it on to the others that
+    /// deploy the same configuration (see `activate --fanout`).
+    #[clap(long)]
+    fanout: bool,
+}
+
+// Arguments that select what gets deployed where.
+#[derive(clap::Args, Debug)]
+struct TargetArgs {
+    /// The flake to deploy: a source code directory, or a flake
+    /// reference like `github:owner/repo?ref=main` or
+    /// `git+ssh://git@example.com/repo`.
     #[clap(long, default_value = ".")]
-    flake: PathBuf,
+    flake: String,
 
     /// The destinations that will be deployed to.
     ///
     /// Each destination is either just a hostname, or a URL of the
-    /// form FLAVOR://HOSTNAME/[CONFIGURATION] where FLAVOR is
-    /// "nixos", and the optional CONFIGURATION specifies what
-    /// nixosConfiguration to build and deploy on the destination
-    /// (defaults to the hostname that the remote host reports).
+    /// form FLAVOR://HOSTNAME[:PORT]/[CONFIGURATION][?tunnel=PORT] where FLAVOR is
+    /// "nixos" or "darwin", and the optional CONFIGURATION specifies
+    /// what nixosConfiguration (or darwinConfiguration) to build and
+    /// deploy on the destination (defaults to the hostname that the
+    /// remote host reports). IPv6 addresses go in brackets, as in
+    /// `nixos://[2001:db8::1]:2222`. With a FLAVOR of "nixos+srv",
+    /// HOSTNAME names a DNS SRV record, and every host that the
+    /// record points to gets deployed to. With `?tunnel=PORT`, the
+    /// destination dials in through a reverse tunnel that listens on
+    /// that local port, instead of being connected to directly.
     #[clap(value_parser)]
     to: Vec<Destination>,
 
+    /// Deploy to every destination that the flake declares in its
+    /// `deploy-flake.hosts` output, instead of the ones given on
+    /// the commandline.
+    #[clap(long, conflicts_with = "to")]
+    all: bool,
+
+    /// Whether to check that every destination can be connected to
+    /// before doing anything else, failing right away (and listing
+    /// the unreachable destinations) if any of them can't.
+    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = Behavior::Skip, value_enum)]
+    precheck_connectivity: Behavior,
+
+    /// Run the local nix commands (looking up the flake, copying
+    /// closures) with the nix from the flake's devShell, so that
+    /// everyone deploying the flake uses the same nix version.
+    #[clap(long)]
+    use_flake_nix: bool,
+
+    /// Only deploy the flake if its git revision (or a tag pointing
+    /// at it) carries a good signature by one of the
+    /// `--allowed-signer` keys.
+    #[clap(long, requires = "allowed_signers")]
+    require_signed: bool,
+
+    /// The fingerprint of a key that may sign revisions deployed
+    /// with `--require-signed`: a GnuPG key fingerprint or an SSH key
+    /// fingerprint (like `SHA256:...`). Can be given multiple times.
+    #[clap(long = "allowed-signer", value_name = "FINGERPRINT")]
+    allowed_signers: Vec<String>,
+}
+
+impl TargetArgs {
+    /// Looks up the flake with the given nix, checking its signature
+    /// if required.
+    fn flake(&self, local_nix: &LocalNix) -> Result<Flake, anyhow::Error> {
+        let flake = Flake::from_ref(&self.flake, local_nix)?;
+        for warning in flake.source_warnings() {
+            log::warn!(
+                kind = warning.name(),
+                nar_hash = flake.nar_hash(),
+                "{warning}"
+            );
+        }
+        if self.require_signed {
+            flake.verify_signed(&self.allowed_signers)?;
+        }
+        Ok(flake)
+    }
+
+    /// Returns the concrete destinations to deploy to: the ones
+    /// given on the commandline, or the flake's inventory.
+    async fn destinations(
+        &self,
+        flake: &Flake,
+        local_nix: &LocalNix,
+    ) -> Result<Vec<Destination>, anyhow::Error> {
+        let destinations = if self.all {
+            let inventory = flake
+                .inventory(local_nix)
+                .await
+                .context("Could not read the flake's host inventory")?;
+            log::info!(hosts = inventory.len(), "Read the flake's host inventory");
+            inventory
+        } else {
+            self.to.clone()
+        };
+        expand_destinations(destinations).await
+    }
+
+    /// Returns the nix that local nix commands use. A flake in a
+    /// directory gets referred to by its absolute path, since those
+    /// commands don't all run in the current directory.
+    fn local_nix(&self) -> Result<LocalNix, anyhow::Error> {
+        if !self.use_flake_nix {
+            return Ok(LocalNix::System);
+        }
+        let flake = Path::new(&self.flake);
+        if !flake.is_dir() {
+            return Ok(LocalNix::DevShell(self.flake.clone()));
+        }
+        let flake = flake
+            .canonicalize()
+            .with_context(|| format!("Could not resolve {flake:?}"))?;
+        Ok(LocalNix::DevShell(flake.to_string_lossy().into_owned()))
+    }
+}
+
+// Arguments that control how copying to a destination gets retried.
+#[derive(clap::Args, Debug)]
+struct CopyRetryArgs {
+    /// How many times to retry copying to a destination when the
+    /// copy timed out or failed, e.g. because the connection dropped.
+    #[clap(long, value_name = "N", default_value_t = 3)]
+    copy_retries: usize,
+
+    /// How long to wait before the first retry of a copy. Later
+    /// retries wait exponentially longer.
+    #[clap(long, value_name = "DURATION", default_value = "1s")]
+    copy_backoff_base: humantime::Duration,
+
+    /// The longest time to wait between two attempts to copy.
+    #[clap(long, value_name = "DURATION", default_value = "1m")]
+    copy_backoff_max: humantime::Duration,
+}
+
+impl CopyRetryArgs {
+    /// Returns the retry policy for copies that may take at most
+    /// `timeout` each.
+    fn policy(&self, timeout: Option<humantime::Duration>) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.copy_retries,
+            attempt_timeout: timeout.map(Duration::from),
+            min_delay: self.copy_backoff_base.into(),
+            max_delay: self.copy_backoff_max.into(),
+        }
+    }
+}
+
+// Arguments that control how the health of a destination gets
+// checked.
+#[derive(clap::Args, Debug)]
+struct HealthCheckArgs {
+    /// How the health checks determine whether the target system
+    /// is healthy. The default, "auto", asks `systemctl
+    /// is-system-running` and falls back to looking for failed units
+    /// on systems that don't support that.
+    #[clap(long, require_equals=true, value_name = "METHOD", default_value_t = HealthCheck::Auto, value_enum)]
+    health_check: HealthCheck,
+
+    /// How long the health check waits for a system that is still
+    /// starting up to settle, before failing with the jobs and units
+    /// that keep it from settling.
+    #[clap(long, value_name = "DURATION", default_value = "5m")]
+    health_check_timeout: humantime::Duration,
+}
+
+// Arguments that control how the flake gets copied, built and
+// checked on each destination.
+#[derive(clap::Args, Debug)]
+struct PrepareArgs {
     /// Whether to run the "preflight" check, where deploy-flake
-    /// checks if the target system is healthy. Running it is usually
-    /// a good idea to do, but when updating boot config on a broken
-    /// system, it is necessary to skip.
-    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = Behavior::Run, value_enum)]
-    preflight_check: Behavior,
+    /// checks if the target system is healthy, and has enough free
+    /// space in /nix/store and /boot for the new configuration.
+    /// Running it is usually a good idea to do, but when updating
+    /// boot config on a broken system, it is necessary to skip. With
+    /// "warn", the check runs, but an unhealthy system only gets
+    /// reported (in the summary and the report) instead of stopping
+    /// the deploy.
+    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = CheckBehavior::Run, value_enum)]
+    preflight_check: CheckBehavior,
+
+    #[clap(flatten)]
+    health: HealthCheckArgs,
 
     /// A program contained in the new system closure, run on the
     /// system being deployed, that checks whether the system closure
@@ -61,13 +810,6 @@ struct Opts {
     #[clap(long, require_equals = true, value_name = "PROGRAM")]
     pre_activate_script: Option<PathBuf>,
 
-    /// Whether to run the "test" step, updating the system config
-    /// in-place before installing a new boot config. The default runs
-    /// the test step, use `--test=skip` to directly install the built
-    /// boot configuration.
-    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = Behavior::Run, value_enum)]
-    test: Behavior,
-
     /// Extra commandline arguments passed to the "nix build"
     /// command. Defaults to the arguments needed to activate the
     /// "flake" and "nix-command" features.
@@ -78,114 +820,3585 @@ struct Opts {
         default_value = "--extra-experimental-features nix-command --extra-experimental-features flakes"
     )]
     build_cmdline: Vec<String>,
+
+    /// Keep building other derivations when one of them fails, and
+    /// report every derivation that failed to build at the end.
+    #[clap(long)]
+    keep_going: bool,
+
+    /// The maximum number of build jobs that nix may run in parallel
+    /// on the build host. Defaults to the build host's nix settings.
+    #[clap(long, value_name = "N")]
+    max_jobs: Option<u32>,
+
+    /// The number of CPU cores each build job may use on the build
+    /// host (0 means all of them). Defaults to the build host's nix
+    /// settings.
+    #[clap(long, value_name = "N")]
+    cores: Option<u32>,
+
+    /// Whether to print the full build log of every derivation that
+    /// gets built. The default, "auto", prints them only when stderr
+    /// is not a terminal (e.g. in CI), and otherwise shows only nix's
+    /// progress messages.
+    #[clap(long, require_equals=true, value_name = "WHEN", default_value_t = PrintBuildLogs::Auto, value_enum)]
+    print_build_logs: PrintBuildLogs,
+
+    /// The number of log lines that nix shows for a failed build.
+    #[clap(long, value_name = "N")]
+    build_log_lines: Option<u32>,
+
+    /// How long copying the flake closure to a destination may take
+    /// before it is retried. Defaults to a timeout derived from the
+    /// size of the paths that need to be transferred.
+    #[clap(long, value_name = "DURATION")]
+    copy_timeout: Option<humantime::Duration>,
+
+    #[clap(flatten)]
+    copy_retry: CopyRetryArgs,
+
+    /// Refuse to copy a closure to a destination if the paths it is
+    /// missing add up to more than this size, like `2GiB`.
+    #[clap(long, value_name = "SIZE")]
+    max_transfer_size: Option<ByteSize>,
+
+    /// Copy configurations built here or on a build host to
+    /// destinations even if they were built for another platform than
+    /// the destination's (say, x86_64-linux for an aarch64 machine, or
+    /// x86_64-darwin for an x86_64 NixOS machine), e.g. for destinations
+    /// that can run the other platform's binaries under emulation.
+    #[clap(long)]
+    force_platform: bool,
+
+    /// Refuse to deploy to destinations whose nix is older than this
+    /// version, like `2.18`.
+    #[clap(long, value_name = "VERSION")]
+    min_nix_version: Option<String>,
+
+    /// How to copy the flake closure to the destinations:
+    /// `nix-copy-closure`, `nix copy` over ssh-ng, pushing it to a
+    /// binary cache that the destinations substitute from
+    /// (`substitute`, see `--copy-cache`), or exporting it with
+    /// `nix-store --export` and importing it over the destination's
+    /// ssh connection (`tarball`).
+    #[clap(long, visible_alias = "copy-backend", require_equals=true, value_name = "METHOD", default_value_t = CopyMethod::NixCopyClosure, value_enum)]
+    copy_method: CopyMethod,
+
+    /// The binary cache that `--copy-method=substitute` pushes to
+    /// and substitutes from.
+    #[clap(long, value_name = "URL")]
+    copy_cache: Option<String>,
+
+    /// How many times to retry building the configuration when it
+    /// failed for a transient reason, like a timeout talking to a
+    /// substituter or a dropped ssh connection.
+    #[clap(long, value_name = "N", default_value_t = 2)]
+    build_retries: usize,
+
+    /// How long building the configuration may take before the
+    /// build is abandoned. A build that times out gets retried like
+    /// one that failed for a transient reason (see
+    /// `--build-retries`).
+    #[clap(long, value_name = "DURATION")]
+    build_timeout: Option<humantime::Duration>,
+
+    /// Where to build the configuration: on each destination
+    /// (`target`), on the machine running deploy-flake (`local`),
+    /// or on another host given by name. Building elsewhere only
+    /// copies the built system to the destinations, which is useful
+    /// if they are too small to evaluate the configuration.
+    #[clap(long, value_name = "WHERE", default_value_t = BuildOn::Target)]
+    build_on: BuildOn,
+}
+
+// Arguments that control how a prepared configuration gets activated.
+#[derive(clap::Args, Debug)]
+struct ActivateArgs {
+    /// Whether to run the "test" step, updating the system config
+    /// in-place before installing a new boot config. The default runs
+    /// the test step, use `--test=skip` to directly install the built
+    /// boot configuration.
+    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = Behavior::Run, value_enum)]
+    test: Behavior,
+
+    /// How to activate the configuration: "test-then-boot" activates
+    /// it on the running system and only then installs it as the
+    /// boot configuration; "switch" sets the profile and does both in
+    /// a single step, like `nixos-rebuild switch`; "boot-only" (the
+    /// same as `--test=skip`) leaves the running system alone; and
+    /// "test-only" leaves the profile and boot configuration alone.
+    /// "both", "boot" and "test" are short for "test-then-boot",
+    /// "boot-only" and "test-only".
+    #[clap(long, visible_alias = "activate", value_name = "MODE", default_value_t = Activation::TestThenBoot, value_enum, conflicts_with = "test")]
+    activation: Activation,
+
+    /// Units that are known to sometimes fail to start on the first
+    /// try, like `flaky-*.service`. If the "test" step fails only
+    /// because units matching these patterns failed, it gets tried
+    /// once more before the destination counts as failed. Can be
+    /// given multiple times.
+    #[clap(long = "retry-test-on-unit", value_name = "PATTERN")]
+    retry_test_units: Vec<String>,
+
+    /// Before the "test" step, show which units activating the
+    /// configuration would stop, restart, reload or start, and (if
+    /// the destination has `nvd` installed) which packages change.
+    /// With `--ask`, the question whether to activate comes after.
+    #[clap(long)]
+    show_changes: bool,
+
+    /// How long to wait before trying the "test" step again.
+    #[clap(long, value_name = "DURATION", default_value = "10s")]
+    retry_test_delay: humantime::Duration,
+
+    /// Activate the configuration even on destinations that already
+    /// run it (as their current system and the current generation of
+    /// the system profile). Without this, those are left alone.
+    #[clap(long)]
+    force: bool,
+
+    /// How long the "test" step (or the "switch" step, see
+    /// `--activation`) may take. When it takes longer, the
+    /// destination stops the activation and the step fails.
+    #[clap(long, value_name = "DURATION")]
+    test_timeout: Option<humantime::Duration>,
+
+    /// How long installing the configuration as the boot
+    /// configuration (setting the system profile and updating the
+    /// boot loader) may take before it fails. On NixOS destinations,
+    /// the commands doing that get stopped then, too.
+    #[clap(long, value_name = "DURATION")]
+    activate_timeout: Option<humantime::Duration>,
+
+    /// The specialisation of the configuration to activate in the
+    /// "test" step, instead of the configuration itself.
+    #[clap(long, value_name = "NAME", value_parser = deploy_flake::path_component)]
+    specialisation: Option<String>,
+
+    /// Install the configuration into this profile (under
+    /// /nix/var/nix/profiles/system-profiles) instead of the "system"
+    /// profile.
+    #[clap(long, value_name = "NAME", value_parser = deploy_flake::path_component)]
+    profile_name: Option<String>,
+
+    /// Whether to check the system's health again after the "test"
+    /// step, and only change the system profile and boot
+    /// configuration if it is healthy. Uses the same method as the
+    /// preflight check (see `--health-check`).
+    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = Behavior::Skip, value_enum)]
+    post_test_check: Behavior,
+
+    /// Whether to try out the boot loader update before setting the
+    /// system profile, so that a failing boot loader update leaves
+    /// the profile alone. Skipping it speeds up deploys to hosts
+    /// whose boot loader update is slow, since it otherwise runs
+    /// twice.
+    #[clap(long, require_equals=true, value_name = "BEHAVIOR", default_missing_value = "run", default_value_t = Behavior::Run, value_enum)]
+    boot_dry_run: Behavior,
+
+    /// Take a filesystem snapshot before activating the
+    /// configuration, as a data-level rollback point. Given as
+    /// `zfs:DATASET`, `btrfs:SUBVOLUME-PATH` or `lvm:VG/LV`; can be
+    /// given multiple times.
+    #[clap(long = "snapshot", value_name = "KIND:SOURCE")]
+    snapshots: Vec<Snapshot>,
+
+    /// Roll the destination back to the configuration it ran before
+    /// unless deploy-flake can connect to it anew within this time
+    /// after the "test" step starts. The destination rolls back on
+    /// its own, so this protects against configurations that cut
+    /// off ssh access.
+    #[clap(long, value_name = "DURATION")]
+    confirm_timeout: Option<humantime::Duration>,
+
+    /// A shell command that takes a destination out of service (e.g.
+    /// out of a load balancer) before its configuration gets
+    /// activated. It runs locally, with the destination's hostname in
+    /// the DEPLOY_FLAKE_HOST environment variable.
+    #[clap(long, value_name = "COMMAND")]
+    drain_command: Option<String>,
+
+    /// A shell command that puts a destination back into service
+    /// once its new configuration is active and passed the health
+    /// check. Runs like `--drain-command`.
+    #[clap(long, value_name = "COMMAND")]
+    undrain_command: Option<String>,
+
+    /// Once the configuration is installed as the boot configuration,
+    /// delete all but the newest N generations of the profile
+    /// (counting the new one). At least 2 have to be kept, to be able
+    /// to roll back with the `rollback` subcommand.
+    #[clap(long, value_name = "N", group = "pruning", value_parser = clap::value_parser!(u64).range(2..))]
+    keep_generations: Option<u64>,
+
+    /// Once the configuration is installed as the boot configuration,
+    /// delete the generations of the profile that are older than
+    /// this, in whole days (like "30d").
+    #[clap(long, value_name = "DURATION", group = "pruning", value_parser = older_than)]
+    delete_older_than: Option<Pruning>,
+
+    /// After deleting old generations, collect garbage on the
+    /// destination, deleting the store paths that nothing uses any
+    /// more. Configurations that deploy-flake registered as GC roots
+    /// (like the known-good one) stay.
+    #[clap(long, requires = "pruning")]
+    collect_garbage: bool,
+
+    /// Whether to reboot destinations once the configuration is
+    /// installed as their boot configuration: "never", "always", or
+    /// "if-needed", if its kernel, initrd, kernel modules or systemd
+    /// differ from those of the system they booted. Once they come
+    /// back, the system health check runs again.
+    #[clap(long, value_name = "WHEN", default_value_t = Reboot::Never, value_enum)]
+    reboot: Reboot,
+
+    /// How long to wait for a destination to come back after
+    /// rebooting.
+    #[clap(long, value_name = "DURATION", default_value = "10m")]
+    reboot_timeout: humantime::Duration,
+
+    /// How to reboot destinations (see `--reboot`): "reboot" through
+    /// the firmware; "soft-reboot" restarts only userspace, and falls
+    /// back to a reboot when the kernel, initrd or kernel modules
+    /// changed; "kexec" boots the new kernel directly.
+    #[clap(long, value_name = "METHOD", default_value_t = RebootMethod::Reboot, value_enum)]
+    reboot_method: RebootMethod,
+}
+
+/// Parses the age of generations to delete.
+fn older_than(age: &str) -> Result<Pruning, anyhow::Error> {
+    Pruning::older_than(age.parse::<humantime::Duration>()?.into())
+}
+
+impl PrepareArgs {
+    fn options(&self) -> PrepareOptions {
+        PrepareOptions {
+            ask: None,
+            remote_options: RemoteOptions::default(),
+            deploy_options: DeployOptions {
+                copy_retry: self.copy_retry.policy(self.copy_timeout),
+                build_retry: RetryPolicy::default()
+                    .with_max_retries(self.build_retries)
+                    .with_attempt_timeout(self.build_timeout.map(Duration::from)),
+                max_transfer_size: self.max_transfer_size,
+                check_free_space: self.preflight_check,
+            },
+            copy_method: self.copy_method,
+            copy_cache: self.copy_cache.clone(),
+            build_on: self.build_on.clone(),
+            do_preflight: self.preflight_check,
+            force_platform: self.force_platform,
+            min_nix_version: self.min_nix_version.clone(),
+            health_check: self.health.health_check,
+            health_check_timeout: self.health.health_check_timeout.into(),
+            pre_activate_script: self.pre_activate_script.clone(),
+            build_options: BuildOptions {
+                cmdline: self.build_cmdline.clone(),
+                keep_going: self.keep_going,
+                max_jobs: self.max_jobs,
+                cores: self.cores,
+                print_build_logs: self.print_build_logs.enabled(),
+                log_lines: self.build_log_lines,
+                nix_options: Default::default(),
+            },
+            fanout: None,
+        }
+    }
+}
+
+impl ActivateArgs {
+    fn options(&self, health: &HealthCheckArgs) -> ActivateOptions {
+        ActivateOptions {
+            ask: None,
+            do_test: self.test,
+            activation: self.activation,
+            retry_test_units: self.retry_test_units.clone(),
+            retry_test_delay: self.retry_test_delay.into(),
+            test_timeout: self.test_timeout.map(Duration::from),
+            activate_timeout: self.activate_timeout.map(Duration::from),
+            show_changes: self.show_changes,
+            force: self.force,
+            specialisation: self.specialisation.clone(),
+            profile_name: self.profile_name.clone(),
+            post_test_check: self.post_test_check,
+            boot_dry_run: self.boot_dry_run,
+            snapshots: self.snapshots.clone(),
+            confirm_timeout: self.confirm_timeout.map(Duration::from),
+            drain: self.drain_command.clone(),
+            undrain: self.undrain_command.clone(),
+            pruning: self
+                .keep_generations
+                .map(Pruning::KeepGenerations)
+                .or(self.delete_older_than),
+            collect_garbage: self.collect_garbage,
+            reboot: self.reboot,
+            reboot_timeout: self.reboot_timeout.into(),
+            reboot_method: self.reboot_method,
+            health_check: health.health_check,
+            health_check_timeout: health.health_check_timeout.into(),
+        }
+    }
+}
+
+/// Opens the file descriptor that `--status-fd` names for writing
+/// status events.
+#[cfg(unix)]
+fn status_file(fd: i32) -> Result<std::fs::File, anyhow::Error> {
+    use std::os::unix::io::FromRawFd;
+    // Safety: the file descriptor was handed to us for writing
+    // status events, and nothing else in deploy-flake uses it.
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    if let Err(e) = file.metadata() {
+        // Closing a descriptor that isn't open would abort:
+        std::mem::forget(file);
+        return Err(e).with_context(|| format!("Status file descriptor {fd} is not open"));
+    }
+    Ok(file)
+}
+
+/// Opens the file descriptor that `--status-fd` names, which only
+/// unix-like systems have.
+#[cfg(not(unix))]
+fn status_file(fd: i32) -> Result<std::fs::File, anyhow::Error> {
+    anyhow::bail!("--status-fd={fd} needs a unix-like system with file descriptors")
 }
 
 #[instrument(err)]
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    let opts: Opts = Opts::parse();
+
     let indicatif_layer = tracing_indicatif::IndicatifLayer::new();
-    let filter = EnvFilter::builder()
-        .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
-        .from_env_lossy();
+    // Each log layer gets filtered separately, so that the status
+    // channel sees every phase no matter what RUST_LOG says:
+    let filter = || {
+        EnvFilter::builder()
+            .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
+            .from_env_lossy()
+    };
     let writer = indicatif_layer.get_stderr_writer();
-    let app_log_layer = tracing_subscriber::fmt::layer()
-        .with_target(false)
-        .compact()
-        .with_writer(writer.clone())
-        .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
-            metadata.target() != deploy_flake::SUBPROCESS_LOG_TARGET
-        }));
-    let subprocess_log_layer = tracing_subscriber::fmt::layer()
-        .with_target(false)
-        .with_level(false)
-        .compact()
-        .with_writer(writer.clone())
-        .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
-            metadata.target() == deploy_flake::SUBPROCESS_LOG_TARGET
-        }));
+    let (app_log_layer, subprocess_log_layer, json_log_layer) = match opts.log_format {
+        LogFormat::Text => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_target(false)
+                    .compact()
+                    .with_writer(writer.clone())
+                    .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                        metadata.target() != deploy_flake::SUBPROCESS_LOG_TARGET
+                            && metadata.target() != STATUS_TARGET
+                    }))
+                    .with_filter(filter()),
+            ),
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_target(false)
+                    .with_level(false)
+                    .compact()
+                    .with_writer(writer.clone())
+                    .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                        metadata.target() == deploy_flake::SUBPROCESS_LOG_TARGET
+                    }))
+                    .with_filter(filter()),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_span_list(true)
+                    .with_writer(writer.clone())
+                    .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                        metadata.target() != STATUS_TARGET
+                    }))
+                    .with_filter(filter()),
+            ),
+        ),
+    };
+    let status_layer = opts
+        .status_fd
+        .map(|fd| {
+            Ok::<_, anyhow::Error>(StatusLayer::new(status_file(fd)?).with_filter(
+                tracing_subscriber::filter::filter_fn(StatusLayer::is_interested),
+            ))
+        })
+        .transpose()?;
     tracing_subscriber::registry()
-        .with(filter)
         .with(app_log_layer)
         .with(subprocess_log_layer)
-        .with(indicatif_layer)
+        .with(json_log_layer)
+        .with(indicatif_layer.with_filter(filter()))
+        .with(status_layer)
         .init();
 
-    let opts: Opts = Opts::parse();
     log::trace!(cmdline = ?opts);
 
-    let flake = Flake::from_path(&opts.flake)?;
+    // Every log line, status event and report of this run carries
+    // the deploy ID, as does the record that activating a
+    // configuration leaves in the destination's journal:
+    let deploy_id = ulid::Ulid::new().to_string();
+    let span = log::info_span!("run", deploy_id);
+    if opts.ask_sudo_password && opts.elevation.password_prefix().is_none() {
+        anyhow::bail!(
+            "--ask-sudo-password can not give a password to --elevation={}",
+            opts.elevation
+        );
+    }
+    let remote_options = RemoteOptions {
+        deploy_id: Some(deploy_id),
+        elevation: opts.elevation.clone(),
+        elevation_password: opts
+            .ask_sudo_password
+            .then(|| Arc::new(TerminalPasswordPrompt::default()) as Arc<dyn PasswordPrompt>),
+        phase_hooks: None,
+        non_interactive: opts.non_interactive,
+        keep_alive: opts.ssh_keep_alive.map(Duration::from),
+        local_nix: LocalNix::System,
+        facts_cache: FactsCache::default_dir()
+            .filter(|_| !opts.facts_ttl.is_zero())
+            .map(|dir| FactsCache {
+                dir,
+                ttl: opts.facts_ttl.into(),
+                refresh: opts.refresh_facts,
+            }),
+        log_levels: SubprocessLogLevels {
+            stdout: opts.stdout_log_level,
+            stderr: opts.stderr_log_level,
+            warnings: opts.warning_log_level,
+        },
+        audit: opts.audit_remote_commands,
+        remote_store: opts.remote_store.clone(),
+        substitute_on_destination: opts.substitute_on_destination,
+        activation_env: Some(opts.activation_env.clone()).filter(|names| !names.is_empty()),
+        host_key_policy: opts.host_key_policy,
+        ssh_config: SshConfig::new(&opts.ssh_opt, opts.ssh_config.as_deref())?.map(Arc::new),
+        jump_host: opts.ssh_jump.clone(),
+        jump_hosts: Arc::new(JumpHosts::new(opts.host_key_policy)),
+        system_ssh: opts.system_ssh,
+        tunnel_wait: opts.tunnel_wait.into(),
+        tunnel_broker: opts
+            .tunnel_listen
+            .map(|address| Arc::new(Broker::new(address))),
+    };
+    async move {
+        match opts.command {
+            None if opts.deploy.rollback_to_last_deployed => {
+                rollback(
+                    expand_destinations(opts.target.to).await?,
+                    RollbackTo::LastDeployed,
+                    opts.activate.options(&opts.prepare.health),
+                    opts.deploy.ask && !opts.deploy.yes,
+                    remote_options,
+                )
+                .await
+            }
+            None => {
+                deploy(
+                    opts.target,
+                    opts.prepare,
+                    opts.activate,
+                    opts.deploy,
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::Stage {
+                plan,
+                sign_key,
+                target,
+                prepare,
+            }) => stage(target, prepare, &plan, sign_key.as_deref(), remote_options).await,
+            Some(Command::Activate {
+                plan: Some(plan),
+                verify_key,
+                activate,
+                health,
+                ..
+            }) => {
+                activate_plan(
+                    activate.options(&health),
+                    &plan,
+                    verify_key.as_deref(),
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::Activate {
+                path,
+                to,
+                copy_retry,
+                max_transfer_size,
+                copy_method,
+                copy_cache,
+                fanout,
+                activate,
+                health,
+                ..
+            }) => {
+                let path = path.expect("--path is required without --plan");
+                let copier: Arc<dyn ClosureCopier> = copy_method
+                    .copier(copy_cache.as_deref(), &remote_options)?
+                    .into();
+                activate_store_path(
+                    activate.options(&health),
+                    path,
+                    expand_destinations(to).await?,
+                    fanout,
+                    (
+                        DeployOptions {
+                            copy_retry: copy_retry.policy(None),
+                            max_transfer_size,
+                            ..Default::default()
+                        },
+                        copier,
+                    ),
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::Exec {
+                to,
+                config,
+                max_parallel,
+                input,
+                porcelain,
+                command,
+            }) => {
+                let destinations = match config {
+                    Some(config) => Config::load(&config)?
+                        .expand_destinations()
+                        .await?
+                        .groups
+                        .into_iter()
+                        .flat_map(|group| group.hosts)
+                        .map(|host| host.destination)
+                        .collect(),
+                    None => expand_destinations(to).await?,
+                };
+                let input = input
+                    .map(|path| {
+                        std::fs::read(&path).with_context(|| format!("Could not read {path:?}"))
+                    })
+                    .transpose()?;
+                exec(
+                    destinations,
+                    command,
+                    input,
+                    max_parallel,
+                    porcelain,
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::DryActivate { target, prepare }) => {
+                dry_activate(target, prepare, remote_options).await
+            }
+            Some(Command::Estimate {
+                target,
+                prepare,
+                max_parallel,
+            }) => estimate(target, prepare, max_parallel, remote_options).await,
+            Some(Command::Copy {
+                paths,
+                to,
+                copy_timeout,
+                copy_retry,
+                max_transfer_size,
+                copy_method,
+                copy_cache,
+                fanout,
+            }) => {
+                let copier: Arc<dyn ClosureCopier> = copy_method
+                    .copier(copy_cache.as_deref(), &remote_options)?
+                    .into();
+                copy(
+                    paths,
+                    expand_destinations(to).await?,
+                    fanout,
+                    (
+                        DeployOptions {
+                            copy_retry: copy_retry.policy(copy_timeout),
+                            max_transfer_size,
+                            ..Default::default()
+                        },
+                        copier,
+                    ),
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::Rollback {
+                to,
+                rollback_to,
+                ask,
+                yes,
+                activate,
+                health,
+            }) => {
+                rollback(
+                    expand_destinations(to).await?,
+                    rollback_to,
+                    activate.options(&health),
+                    ask && !yes,
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::PinKnownGood {
+                to,
+                min_age,
+                health,
+            }) => {
+                pin_known_good(
+                    expand_destinations(to).await?,
+                    min_age.into(),
+                    &health,
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::Verify {
+                to,
+                max_parallel,
+                timeout,
+                porcelain,
+            }) => {
+                verify(
+                    expand_destinations(to).await?,
+                    max_parallel,
+                    timeout.into(),
+                    porcelain,
+                    remote_options,
+                )
+                .await
+            }
+            Some(Command::FleetDiff {
+                to,
+                since,
+                save,
+                max_parallel,
+            }) => {
+                fleet_diff(
+                    expand_destinations(to).await?,
+                    since,
+                    save,
+                    max_parallel,
+                    remote_options,
+                )
+                .await
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Deploys the flake to every destination.
+async fn deploy(
+    target: TargetArgs,
+    prepare_args: PrepareArgs,
+    activate_args: ActivateArgs,
+    deploy_args: DeployArgs,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let gate = deploy_args.gate;
+    let max_parallel = deploy_args.max_parallel;
+    let strategy = deploy_args.strategy;
+    let deploy_id = remote_options.deploy_id.clone();
+    let config = match deploy_args.config.as_deref() {
+        Some(config) => Some(
+            Config::load(config)?
+                .select(&deploy_args.groups, &deploy_args.tags)?
+                .expand_destinations()
+                .await?,
+        ),
+        None => None,
+    };
+    let remote_options = RemoteOptions {
+        local_nix: target.local_nix()?,
+        ..remote_options
+    };
+    let flake = target.flake(&remote_options.local_nix)?;
     log::debug!(?flake, "Flake metadata");
+    let target = TargetArgs {
+        to: match config {
+            Some(_) => vec![],
+            None => {
+                target
+                    .destinations(&flake, &remote_options.local_nix)
+                    .await?
+            }
+        },
+        ..target
+    };
+    if target.precheck_connectivity == Behavior::Run {
+        let destinations: Vec<&Destination> = match &config {
+            None => target.to.iter().collect(),
+            Some(config) => config
+                .groups
+                .iter()
+                .flat_map(|group| group.hosts.iter().map(|host| &host.destination))
+                .collect(),
+        };
+        precheck_connectivity(&destinations, &remote_options).await?;
+    }
+    let prompter = (deploy_args.ask && !deploy_args.yes).then(|| Arc::new(Prompter::default()));
+    let prepare_options = PrepareOptions {
+        ask: prompter.clone(),
+        remote_options,
+        fanout: deploy_args.fanout.then(Default::default),
+        ..prepare_args.options()
+    };
+    let activate_options = ActivateOptions {
+        ask: prompter,
+        ..activate_args.options(&prepare_args.health)
+    };
 
-    let do_preflight = opts.preflight_check;
-    let do_test = opts.test;
-    let pre_activate_script = opts.pre_activate_script;
-    let build_cmdline = opts.build_cmdline.clone();
+    let mut groups = match config {
+        None => {
+            let prepare_options = Arc::new(prepare_options);
+            let activate_options = Arc::new(activate_options);
+            let hosts = target
+                .to
+                .into_iter()
+                .map(|destination| HostDeployment {
+                    report: Arc::new(std::sync::Mutex::new(HostReport::new(&destination))),
+                    destination,
+                    prepare_options: prepare_options.clone(),
+                    activate_options: activate_options.clone(),
+                })
+                .collect();
+            vec![GroupDeployment {
+                name: None,
+                hosts,
+                gate,
+                strategy: strategy.unwrap_or(Strategy::default_for(max_parallel)),
+                max_parallel,
+            }]
+        }
+        Some(config) => config
+            .groups
+            .into_iter()
+            .map(|group| {
+                let group_prepare_options = PrepareOptions {
+                    do_preflight: group
+                        .preflight_check
+                        .unwrap_or(prepare_options.do_preflight),
+                    ..prepare_options.clone()
+                };
+                let group_activate_options = ActivateOptions {
+                    do_test: group.test.unwrap_or(activate_options.do_test),
+                    ..activate_options.clone()
+                };
+                let hosts = group
+                    .hosts
+                    .iter()
+                    .map(|host| HostDeployment {
+                        destination: host.destination.clone(),
+                        report: Arc::new(std::sync::Mutex::new(HostReport::new(&host.destination))),
+                        prepare_options: Arc::new(group_prepare_options.for_host(host)),
+                        activate_options: Arc::new(group_activate_options.for_host(host)),
+                    })
+                    .collect();
+                let max_parallel = group.max_parallel.or(max_parallel);
+                GroupDeployment {
+                    name: Some(group.name),
+                    hosts,
+                    gate: group.gate.unwrap_or(gate),
+                    strategy: group
+                        .strategy
+                        .or(strategy)
+                        .unwrap_or(Strategy::default_for(max_parallel)),
+                    max_parallel,
+                }
+            })
+            .collect(),
+    };
+    if deploy_args.skip_unhealthy {
+        skip_unhealthy(&mut groups, max_parallel).await;
+    }
+    let reports: Vec<SharedHostReport> = groups
+        .iter()
+        .flat_map(|group| group.hosts.iter().map(|host| host.report.clone()))
+        .collect();
 
-    futures::future::try_join_all(opts.to.into_iter().map(|destination| {
-        let flake = flake.clone();
-        let build_cmdline = build_cmdline.clone();
-        let pre_activate_script = pre_activate_script.clone();
-        task::spawn(async move {
-            deploy(
+    if deploy_args.dry_run {
+        let hosts = groups.into_iter().flat_map(|group| group.hosts).collect();
+        return dry_run(&flake, hosts).await;
+    }
+    let watching = task::spawn(watch_for_interrupts());
+    let result = deploy_groups(&flake, groups).await;
+    watching.abort();
+    log::info!(target: STATUS_TARGET, succeeded = result.is_ok(), "finished");
+    if INTERRUPT.borrow().is_some() {
+        for report in &reports {
+            let report = report.lock().unwrap();
+            if let (Some(step), Some(state)) = (report.failed_step, &report.host_state) {
+                log::error!(
+                    destination = report.destination,
+                    step = step.name(),
+                    state,
+                    "Interrupted, leaving the destination partially deployed"
+                );
+            }
+        }
+    }
+
+    let report = Report {
+        deploy_id,
+        hosts: reports
+            .iter()
+            .map(|report| report.lock().unwrap().clone())
+            .collect(),
+    };
+    report.log_summary();
+    if let Some(report_file) = &deploy_args.report {
+        report.save(report_file)?;
+    }
+    result
+}
+
+/// A set of destinations that get deployed together, along with the
+/// policies to deploy them with.
+struct GroupDeployment {
+    /// The group's name, if it comes from a configuration file.
+    name: Option<String>,
+    hosts: Vec<HostDeployment>,
+    gate: Gate,
+    strategy: Strategy,
+    max_parallel: Option<NonZeroUsize>,
+}
+
+/// Deploys the groups one after the other, stopping at the first
+/// group that fails.
+async fn deploy_groups(flake: &Flake, groups: Vec<GroupDeployment>) -> Result<(), anyhow::Error> {
+    for group in groups {
+        let Some(name) = group.name else {
+            return deploy_batches(
                 flake,
-                destination,
-                do_preflight,
-                pre_activate_script,
-                do_test,
-                build_cmdline,
+                group.hosts,
+                group.gate,
+                group.strategy,
+                group.max_parallel,
             )
-            .await
-        })
-    }))
-    .await?;
-
+            .await;
+        };
+        let span = log::info_span!("group", name);
+        log::info!(parent: &span, hosts = group.hosts.len(), "Deploying group");
+        deploy_batches(
+            flake,
+            group.hosts,
+            group.gate,
+            group.strategy,
+            group.max_parallel,
+        )
+        .instrument(span)
+        .await
+        .with_context(|| {
+            format!("Deploying group {name:?} failed, not deploying any later groups")
+        })?;
+    }
     Ok(())
 }
 
-#[instrument(skip(flake, destination, pre_activate_script, do_test, build_cmdline), fields(flake=flake.resolved_path(), dest=destination.hostname) err)]
-async fn deploy(
-    flake: Flake,
-    destination: Destination,
-    do_preflight: Behavior,
-    pre_activate_script: Option<PathBuf>,
-    do_test: Behavior,
-    build_cmdline: Vec<String>,
-) -> Result<(), anyhow::Error> {
-    log::event!(log::Level::DEBUG, flake=?flake.resolved_path(), host=?destination.hostname, "Copying");
-    flake.copy_closure(&destination.hostname).await?;
+/// Checks the health of the destinations in the groups (at most
+/// `max_parallel` at a time), and drops the destinations that are
+/// unhealthy (or can't be reached) from them, recording them as
+/// skipped. Destinations whose preflight check is skipped don't get
+/// checked, and those whose preflight check only warns stay.
+async fn skip_unhealthy(groups: &mut [GroupDeployment], max_parallel: Option<NonZeroUsize>) {
+    let hosts: Vec<&HostDeployment> = groups.iter().flat_map(|group| &group.hosts).collect();
+    let limit = max_parallel.map_or(hosts.len(), NonZeroUsize::get).max(1);
+    let mut unhealthy: HashMap<usize, anyhow::Error> = futures::stream::iter(
+        hosts
+            .into_iter()
+            .enumerate()
+            .filter(|(_, host)| host.prepare_options.do_preflight != CheckBehavior::Skip),
+    )
+    .map(|(index, host)| {
+        let options = &host.prepare_options;
+        async move {
+            let healthy = async {
+                let system = connect(&host.destination, &options.remote_options).await?;
+                let healthy = in_phase(
+                    "preflight",
+                    system.host(),
+                    system.options(),
+                    deploy_flake::check_system_health(
+                        &system,
+                        options.health_check,
+                        options.health_check_timeout,
+                    ),
+                )
+                .await;
+                let _ = system.close().await;
+                healthy
+            }
+            .await;
+            (index, healthy)
+        }
+        .instrument(log::info_span!("health", host = host.destination.hostname))
+    })
+    .buffer_unordered(limit)
+    .filter_map(|(index, healthy)| futures::future::ready(Some(index).zip(healthy.err())))
+    .collect()
+    .await;
+    let mut index = 0;
+    for group in groups {
+        group.hosts.retain(|host| {
+            let checked = unhealthy.remove(&index);
+            index += 1;
+            match checked {
+                None => true,
+                // Its preflight check reports it as unhealthy later:
+                Some(e) if host.prepare_options.do_preflight == CheckBehavior::Warn => {
+                    log::debug!(host = host.destination.hostname, error = %format!("{e:#}"), "Keeping unhealthy destination");
+                    true
+                }
+                Some(e) => {
+                    log::warn!(host = host.destination.hostname, error = %format!("{e:#}"), "Skipping unhealthy destination");
+                    record_outcome::<()>(&host.report, &Err(Unhealthy(format!("{e:#}")).into()));
+                    false
+                }
+            }
+        });
+    }
+}
 
-    log::debug!("Connecting");
-    let flavor = destination.os_flavor.on_connection(
-        &destination.hostname,
-        Session::connect(&destination.hostname, KnownHosts::Strict)
-            .await
-            .with_context(|| format!("Connecting to {:?}", &destination.hostname))?,
+/// A host's report, filled in as the deployment progresses.
+type SharedHostReport = Arc<std::sync::Mutex<HostReport>>;
+
+/// Records the outcome of deploying to a destination in its report.
+fn record_outcome<T>(report: &SharedHostReport, result: &Result<T, anyhow::Error>) {
+    let mut report = report.lock().unwrap();
+    match result {
+        // A destination that was up to date already stays that way:
+        Ok(_) if report.outcome == Outcome::UpToDate => {}
+        Ok(_) => report.outcome = Outcome::Succeeded,
+        Err(e) if e.is::<Declined>() => report.outcome = Outcome::Skipped,
+        Err(e) if e.is::<Unhealthy>() => {
+            report.outcome = Outcome::Skipped;
+            report.error = Some(format!("{e:#}"));
+        }
+        Err(e) => {
+            report.outcome = Outcome::Failed;
+            report.error = Some(format!("{e:#}"));
+        }
+    }
+    log::info!(
+        target: STATUS_TARGET,
+        destination = report.destination,
+        outcome = report.outcome.name(),
+        error = report.error,
+        "outcome"
     );
-    log::event!(log::Level::DEBUG, config=?destination.config_name, "Building");
-    let built = flake
-        .build(flavor, destination.config_name.as_deref(), build_cmdline)
-        .await?;
+}
 
-    if do_preflight == Behavior::Run {
-        log::event!(log::Level::DEBUG, dest=?destination.hostname, "Checking system health");
-        built.preflight_check_system().await?;
-    } else {
-        log::event!(log::Level::DEBUG, dest=?destination.hostname, "Skipping system health check");
+/// Records the outcome of the destinations whose task panicked or got
+/// cancelled, which the task itself never got to.
+fn record_unfinished<T>(reports: &[SharedHostReport], results: &[Result<T, anyhow::Error>]) {
+    for (report, result) in reports.iter().zip(results) {
+        if matches!(result, Err(e) if e.is::<Panicked>() || e.is::<Cancelled>()) {
+            record_outcome(report, result);
+        }
     }
+}
 
-    built
-        .preflight_check_closure(pre_activate_script.as_deref())
-        .await?;
+/// A destination along with the settings to deploy to it with.
+#[derive(Clone)]
+struct HostDeployment {
+    destination: Destination,
+    report: SharedHostReport,
+    prepare_options: Arc<PrepareOptions>,
+    activate_options: Arc<ActivateOptions>,
+}
 
-    if do_test == Behavior::Run {
-        log::event!(log::Level::DEBUG, configuration=?built.configuration(), system_name=?built.for_system(), "Testing");
-        built.test_config().await?;
-    } else {
-        log::event!(log::Level::DEBUG, configuration=?built.configuration(), system_name=?built.for_system(), "Skipping test");
-    }
-    // TODO: rollbacks, maybe?
+/// Deploys the flake to the destinations in the waves that the
+/// strategy calls for. Each wave only starts once the previous one
+/// was deployed successfully.
+async fn deploy_batches(
+    flake: &Flake,
+    hosts: Vec<HostDeployment>,
+    gate: Gate,
+    strategy: Strategy,
+    max_parallel: Option<NonZeroUsize>,
+) -> Result<(), anyhow::Error> {
+    let (batch_size, parallel) = strategy.waves(hosts.len(), max_parallel)?;
+    let batches = hosts.len().div_ceil(batch_size);
+    for (number, batch) in hosts.chunks(batch_size).enumerate() {
+        if batches > 1 {
+            log::info!(
+                batch = number + 1,
+                of = batches,
+                destinations = batch.len(),
+                "Deploying batch"
+            );
+        }
+        deploy_batch(flake, batch.to_vec(), gate, parallel).await?;
+    }
+    Ok(())
+}
+
+/// Deploys the flake to all the destinations, working on at most
+/// `parallel` of them at the same time.
+async fn deploy_batch(
+    flake: &Flake,
+    hosts: Vec<HostDeployment>,
+    gate: Gate,
+    parallel: usize,
+) -> Result<(), anyhow::Error> {
+    let slots = Arc::new(Semaphore::new(parallel));
+    let reports: Vec<SharedHostReport> = hosts.iter().map(|host| host.report.clone()).collect();
+    match gate {
+        Gate::None => {
+            let results = supervise(hosts.into_iter().map(|host| {
+                let flake = flake.clone();
+                let slots = slots.clone();
+                (
+                    host.destination.hostname.clone(),
+                    async move {
+                        let _slot = slots.acquire().await?;
+                        let HostDeployment {
+                            destination,
+                            report,
+                            prepare_options,
+                            activate_options,
+                        } = host;
+                        let result = async {
+                            let built = unless_interrupted(prepare(
+                                flake,
+                                destination,
+                                &prepare_options,
+                                &report,
+                                DEPLOYING_GC_ROOT,
+                            ))
+                            .await?;
+                            activate_rooted(built, &activate_options, &report, DEPLOYING_GC_ROOT)
+                                .await
+                        }
+                        .await;
+                        record_outcome(&report, &result);
+                        result
+                    }
+                    .in_current_span(),
+                )
+            }))
+            .await;
+            record_unfinished(&reports, &results);
+            fail_if_any_failed(results, "Deploying")?;
+        }
+        Gate::Preflight => {
+            let results = supervise(hosts.into_iter().map(|host| {
+                let flake = flake.clone();
+                let slots = slots.clone();
+                (
+                    host.destination.hostname.clone(),
+                    async move {
+                        let _slot = slots.acquire().await?;
+                        let result = unless_interrupted(prepare(
+                            flake,
+                            host.destination,
+                            &host.prepare_options,
+                            &host.report,
+                            DEPLOYING_GC_ROOT,
+                        ))
+                        .await;
+                        if result.is_err() {
+                            record_outcome(&host.report, &result);
+                        }
+                        Ok::<_, anyhow::Error>((result?, host.activate_options, host.report))
+                    }
+                    .in_current_span(),
+                )
+            }))
+            .await;
+            record_unfinished(&reports, &results);
+            let prepared_on: Vec<Arc<Nixos>> = results
+                .iter()
+                .flatten()
+                .map(|(built, _, _)| built.on().clone())
+                .collect();
+            let prepared = match fail_if_any_failed(results, "Preparing") {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    // None of the prepared configurations get activated:
+                    futures::future::join_all(
+                        prepared_on
+                            .iter()
+                            .map(|system| release_gc_root(system, DEPLOYING_GC_ROOT)),
+                    )
+                    .await;
+                    return Err(e.context("Not activating the configuration on any destination"));
+                }
+            };
+            log::info!(
+                destinations = prepared.len(),
+                "All destinations passed preflight checks, activating"
+            );
+            let reports: Vec<SharedHostReport> = prepared
+                .iter()
+                .map(|(_, _, report)| report.clone())
+                .collect();
+            let results = supervise(prepared.into_iter().map(|(built, options, report)| {
+                let slots = slots.clone();
+                let destination = report.lock().unwrap().destination.clone();
+                (
+                    destination,
+                    async move {
+                        let _slot = slots.acquire().await?;
+                        let result =
+                            activate_rooted(built, &options, &report, DEPLOYING_GC_ROOT).await;
+                        record_outcome(&report, &result);
+                        result
+                    }
+                    .in_current_span(),
+                )
+            }))
+            .await;
+            record_unfinished(&reports, &results);
+            fail_if_any_failed(results, "Activating")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The name of the GC root that staged configurations get registered under.
+const STAGED_GC_ROOT: &str = "staged";
+
+/// The name of the GC root that keeps a configuration alive on its
+/// destination from when it gets there until its activation is over.
+const DEPLOYING_GC_ROOT: &str = "deploying";
+
+/// Prepares the flake on every destination and registers the built
+/// configurations as GC roots. Only if that succeeds everywhere does
+/// the plan get written.
+async fn stage(
+    target: TargetArgs,
+    prepare_args: PrepareArgs,
+    plan_file: &Path,
+    sign_key: Option<&Path>,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let remote_options = RemoteOptions {
+        local_nix: target.local_nix()?,
+        ..remote_options
+    };
+    let flake = target.flake(&remote_options.local_nix)?;
+    log::debug!(?flake, "Flake metadata");
+    let target = TargetArgs {
+        to: target
+            .destinations(&flake, &remote_options.local_nix)
+            .await?,
+        ..target
+    };
+    if target.precheck_connectivity == Behavior::Run {
+        precheck_connectivity(&target.to.iter().collect::<Vec<_>>(), &remote_options).await?;
+    }
+    let prepare_options = Arc::new(PrepareOptions {
+        remote_options,
+        ..prepare_args.options()
+    });
+
+    let results = supervise(target.to.into_iter().map(|destination| {
+        let flake = flake.clone();
+        let prepare_options = prepare_options.clone();
+        (destination.hostname.clone(), async move {
+            let spec = destination.to_string();
+            let report = Arc::new(std::sync::Mutex::new(HostReport::new(&destination)));
+            let built = prepare(
+                flake,
+                destination,
+                &prepare_options,
+                &report,
+                STAGED_GC_ROOT,
+            )
+            .await?;
+            Ok::<_, anyhow::Error>(StagedHost {
+                destination: spec,
+                system_name: built.for_system().to_string(),
+                configuration: built.configuration().to_owned(),
+                activated: false,
+            })
+        })
+    }))
+    .await;
+    let hosts = fail_if_any_failed(results, "Staging").context("Not writing a plan")?;
+    let mut plan = Plan {
+        flake: flake.resolved_path().to_string(),
+        source: Some(flake.provenance()),
+        hosts,
+        signature: None,
+    };
+    if let Some(sign_key) = sign_key {
+        plan.sign(sign_key)?;
+    }
+    plan.save(plan_file)?;
+    log::info!(destinations = plan.hosts.len(), plan_file = ?plan_file, "Staged configuration on all destinations");
+    Ok(())
+}
+
+/// Activates every not-yet-activated configuration in a plan,
+/// recording each successful activation in the plan file.
+async fn activate_plan(
+    activate_options: ActivateOptions,
+    plan_file: &Path,
+    verify_key: Option<&Path>,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let plan = Plan::load(plan_file)?;
+    if let Some(verify_key) = verify_key {
+        plan.verify(verify_key)
+            .with_context(|| format!("Not activating plan {plan_file:?}"))?;
+        log::info!(plan_file = ?plan_file, "Plan signature is valid");
+    }
+    let pending: Vec<StagedHost> = plan.pending().cloned().collect();
+    if pending.is_empty() {
+        log::info!(plan_file = ?plan_file, "All destinations in the plan are already activated");
+        return Ok(());
+    }
+    let source = plan.source.clone();
+    let plan = Arc::new(Mutex::new(plan));
+    let plan_file = Arc::new(plan_file.to_owned());
+    let activate_options = Arc::new(activate_options);
+    let remote_options = Arc::new(remote_options);
+
+    let results = supervise(pending.into_iter().map(|host| {
+        let remote_options = remote_options.clone();
+        let plan = plan.clone();
+        let plan_file = plan_file.clone();
+        let activate_options = activate_options.clone();
+        let source = source.clone();
+        let span = log::info_span!("staged", host = host.destination);
+        (
+            host.destination.clone(),
+            async move {
+                let destination: Destination = host.destination.parse()?;
+                let system = connect(&destination, &remote_options).await?;
+                let built =
+                    SystemConfiguration::existing(system, host.configuration, host.system_name)
+                        .with_source(source);
+                built.check_present().await?;
+                let system = built.on().clone();
+                let report = Arc::new(std::sync::Mutex::new(HostReport::new(&destination)));
+                activate(built, &activate_options, &report).await?;
+                let mut plan = plan.lock().await;
+                plan.mark_activated(&host.destination);
+                plan.save(&plan_file)?;
+                // The plan won't activate the configuration again, so
+                // it doesn't need to stay staged:
+                release_gc_root(&system, STAGED_GC_ROOT).await;
+                Ok(())
+            }
+            .instrument(span),
+        )
+    }))
+    .await;
+    fail_if_any_failed(results, "Activating")?;
+    Ok(())
+}
+
+/// The nix store that store paths given on the command line must be
+/// in.
+const NIX_STORE: &str = "/nix/store";
+
+/// Copies an already-built system configuration to every destination
+/// and activates it there.
+async fn activate_store_path(
+    activate_options: ActivateOptions,
+    path: PathBuf,
+    destinations: Vec<Destination>,
+    fanout: bool,
+    copying: (DeployOptions, Arc<dyn ClosureCopier>),
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    // Resolving symlinks (like `./result`) and `..` first makes sure
+    // that the path really is in the store:
+    let path = tokio::fs::canonicalize(&path)
+        .await
+        .with_context(|| format!("Could not resolve {path:?}"))?;
+    if !path.starts_with(NIX_STORE) {
+        anyhow::bail!("{path:?} is not a path in {NIX_STORE}");
+    }
+    let activate_options = Arc::new(activate_options);
+    let systems = copy_everywhere(
+        Arc::new(vec![path.clone()]),
+        &destinations,
+        fanout,
+        copying,
+        Arc::new(remote_options),
+    )
+    .await;
+
+    let results = supervise(
+        destinations
+            .into_iter()
+            .zip(systems)
+            .map(|(destination, system)| {
+                let path = path.clone();
+                let activate_options = activate_options.clone();
+                let span = log::info_span!("store-path", host = destination.hostname);
+                (
+                    destination.hostname.clone(),
+                    async move {
+                        let system_name = destination
+                            .config_name
+                            .clone()
+                            .unwrap_or_else(|| destination.hostname.clone());
+                        let built = SystemConfiguration::existing(system?, path, system_name);
+                        let on = built.on();
+                        in_phase(
+                            "gc-root",
+                            on.host(),
+                            on.options(),
+                            built.add_gc_root(DEPLOYING_GC_ROOT),
+                        )
+                        .await?;
+                        let report = Arc::new(std::sync::Mutex::new(HostReport::new(&destination)));
+                        activate_rooted(built, &activate_options, &report, DEPLOYING_GC_ROOT).await
+                    }
+                    .instrument(span),
+                )
+            }),
+    )
+    .await;
+    fail_if_any_failed(results, "Activating")?;
+    Ok(())
+}
+
+/// Builds the flake on every destination and prints the unit changes
+/// that activating it would make there.
+async fn dry_activate(
+    target: TargetArgs,
+    prepare_args: PrepareArgs,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let remote_options = RemoteOptions {
+        local_nix: target.local_nix()?,
+        ..remote_options
+    };
+    let flake = target.flake(&remote_options.local_nix)?;
+    let target = TargetArgs {
+        to: target
+            .destinations(&flake, &remote_options.local_nix)
+            .await?,
+        ..target
+    };
+    if target.precheck_connectivity == Behavior::Run {
+        precheck_connectivity(&target.to.iter().collect::<Vec<_>>(), &remote_options).await?;
+    }
+    let prepare_options = Arc::new(PrepareOptions {
+        remote_options,
+        ..prepare_args.options()
+    });
+    let results = supervise(target.to.iter().cloned().map(|destination| {
+        let flake = flake.clone();
+        let prepare_options = prepare_options.clone();
+        (destination.hostname.clone(), async move {
+            dry_activate_on(flake, destination, &prepare_options).await
+        })
+    }))
+    .await;
+    for (destination, result) in target.to.iter().zip(&results) {
+        if let Ok(changes) = result {
+            println!("{destination}:\n{changes}");
+        }
+    }
+    fail_if_any_failed(results, "Dry activation")?;
+    Ok(())
+}
+
+/// Builds the flake on a single destination and returns the unit
+/// changes that activating it would make.
+#[instrument(skip(flake, destination, options), fields(host=destination.hostname, config=destination.config_name) err)]
+async fn dry_activate_on(
+    flake: Flake,
+    destination: Destination,
+    options: &PrepareOptions,
+) -> Result<UnitChanges, anyhow::Error> {
+    let report = Arc::new(std::sync::Mutex::new(HostReport::new(&destination)));
+    let system = connect_to_build(&destination, options).await?;
+    let built = build_on(flake, &destination, system, options, &report).await?;
+    let on = built.on();
+    in_phase(
+        "dry-activate",
+        on.host(),
+        on.options(),
+        built.dry_activate(),
+    )
+    .await
+}
+
+/// Prints an estimate of what deploying the flake would take on every
+/// destination, and the totals for all of them, estimating for at
+/// most `max_parallel` destinations at the same time.
+async fn estimate(
+    target: TargetArgs,
+    prepare_args: PrepareArgs,
+    max_parallel: Option<NonZeroUsize>,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let remote_options = RemoteOptions {
+        local_nix: target.local_nix()?,
+        ..remote_options
+    };
+    let flake = target.flake(&remote_options.local_nix)?;
+    let destinations = target
+        .destinations(&flake, &remote_options.local_nix)
+        .await?;
+    let build_options = Arc::new(prepare_args.options().build_options);
+    let remote_options = Arc::new(remote_options);
+    let slots = Arc::new(Semaphore::new(
+        max_parallel.map_or(destinations.len().max(1), NonZeroUsize::get),
+    ));
+    let results = supervise(destinations.iter().cloned().map(|destination| {
+        let flake = flake.clone();
+        let build_options = build_options.clone();
+        let remote_options = remote_options.clone();
+        let slots = slots.clone();
+        let span = log::info_span!("estimate", host = destination.hostname);
+        (
+            destination.hostname.clone(),
+            async move {
+                let _slot = slots.acquire().await?;
+                let system = connect(&destination, &remote_options).await?;
+                flake
+                    .estimate(&system, destination.config_name.as_deref(), &build_options)
+                    .await
+            }
+            .instrument(span),
+        )
+    }))
+    .await;
+    let (mut building, mut changing, mut unknown) = (0, 0, 0);
+    let mut transfer = TransferSize::default();
+    for (destination, result) in destinations.iter().zip(&results) {
+        let Ok(estimate) = result else {
+            continue;
+        };
+        println!(
+            "{destination}:
+{estimate}"
+        );
+        building += usize::from(!estimate.dry_build.will_build.is_empty());
+        changing += usize::from(estimate.changes);
+        match estimate.transfer {
+            Some(size) => {
+                transfer.paths += size.paths;
+                transfer.bytes += size.bytes;
+            }
+            None => unknown += 1,
+        }
+    }
+    println!(
+        "In total: {building} host(s) need builds, {changing} would change, copying {transfer}"
+    );
+    if unknown > 0 {
+        println!("  plus whatever {unknown} host(s) need copied once built");
+    }
+    fail_if_any_failed(results, "Estimating")?;
+    Ok(())
+}
+
+/// Prints what deploying the flake would do on every destination.
+async fn dry_run(flake: &Flake, hosts: Vec<HostDeployment>) -> Result<(), anyhow::Error> {
+    let destinations: Vec<Destination> =
+        hosts.iter().map(|host| host.destination.clone()).collect();
+    let results = supervise(hosts.into_iter().map(|host| {
+        let flake = flake.clone();
+        (host.destination.hostname.clone(), async move {
+            dry_run_on(flake, &host).await
+        })
+    }))
+    .await;
+    for (destination, result) in destinations.iter().zip(&results) {
+        if let Ok(plan) = result {
+            println!("{destination}:\n{plan}");
+        }
+    }
+    fail_if_any_failed(results, "Dry run")?;
+    Ok(())
+}
+
+/// Finds out what deploying the flake to a destination would do. The
+/// only thing this changes is copying the flake's source to the build
+/// host, if it needs it to evaluate the configuration.
+#[instrument(skip(flake, host), fields(host=host.destination.hostname, config=host.destination.config_name) err)]
+async fn dry_run_on(flake: Flake, host: &HostDeployment) -> Result<String, anyhow::Error> {
+    let options = &host.prepare_options;
+    let system = connect(&host.destination, &options.remote_options).await?;
+    let (build_host, flake) = build_host_for(flake, &host.destination, &system, options).await?;
+    let mut plan = String::new();
+    if let (Some(build_host), None) = (&build_host, flake.reference()) {
+        let source = Path::new(flake.resolved_path());
+        let size = deploy_flake::transfer_size(source, build_host).await?;
+        if size.paths > 0 {
+            writeln!(plan, "  copies {size} of flake source to {build_host:?}")?;
+            let copier = options
+                .copy_method
+                .copier(options.copy_cache.as_deref(), &options.remote_options)?;
+            in_phase(
+                "copy",
+                build_host.host(),
+                build_host.options(),
+                copy_closure(source, build_host, &options.deploy_options, &*copier),
+            )
+            .await?;
+        }
+    }
+    let dry_build = in_phase(
+        "build",
+        system.host(),
+        system.options(),
+        flake.dry_build(
+            &system,
+            build_host.as_deref(),
+            host.destination.config_name.as_deref(),
+            &options.build_options,
+        ),
+    )
+    .await?;
+    write!(plan, "{dry_build}")?;
+    let steps = planned_steps(options, &host.activate_options);
+    writeln!(plan, "  would run: {}", steps.join(", "))?;
+    Ok(plan)
+}
+
+/// Returns the names of the steps that deploying with the given
+/// options runs, in order.
+fn planned_steps(prepare: &PrepareOptions, activate: &ActivateOptions) -> Vec<&'static str> {
+    let mut steps = vec![];
+    if prepare.do_preflight != CheckBehavior::Skip {
+        steps.push("preflight");
+    }
+    if !activate.snapshots.is_empty() {
+        steps.push(Step::Snapshot.name());
+    }
+    if activate.drain.is_some() {
+        steps.push(Step::Drain.name());
+    }
+    let activation = activate.activation();
+    if activation == Activation::Switch {
+        steps.extend([Step::SetProfile.name(), Step::Switch.name()]);
+    } else if activation.changes_running_system() {
+        steps.push(Step::Test.name());
+    }
+    if activation.changes_running_system() {
+        if activate.confirm_timeout.is_some() {
+            steps.push(Step::Confirm.name());
+        }
+        if activate.post_test_check == Behavior::Run {
+            steps.push(Step::HealthCheck.name());
+        }
+    }
+    if activate.undrain.is_some() {
+        steps.push(Step::Undrain.name());
+    }
+    if activation.installs_boot_config() {
+        if activate.boot_dry_run == Behavior::Run {
+            steps.push(Step::BootDryRun.name());
+        }
+        steps.extend([Step::SetProfile.name(), Step::UpdateBoot.name()]);
+    }
+    if activate.reboot != Reboot::Never
+        && (activation == Activation::Switch || activation.installs_boot_config())
+    {
+        steps.extend([Step::Reboot.name(), Step::RebootHealthCheck.name()]);
+    }
+    if activate.pruning.is_some()
+        && (activation == Activation::Switch || activation.installs_boot_config())
+    {
+        steps.push(Step::Prune.name());
+        if activate.collect_garbage {
+            steps.push(Step::CollectGarbage.name());
+        }
+    }
+    steps
+}
+
+/// Which system configuration a rollback activates.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum RollbackTo {
+    /// The configuration that was current before deploy-flake last
+    /// activated a configuration.
+    LastDeployed,
+
+    /// The generation of the system profile before the current one.
+    PreviousGeneration,
+
+    /// The configuration that was last promoted with
+    /// `pin-known-good`.
+    KnownGood,
+}
+
+impl RollbackTo {
+    async fn configuration(self, on: Arc<Nixos>) -> Result<SystemConfiguration, anyhow::Error> {
+        match self {
+            RollbackTo::LastDeployed => SystemConfiguration::previous(on).await,
+            RollbackTo::PreviousGeneration => SystemConfiguration::previous_generation(on).await,
+            RollbackTo::KnownGood => SystemConfiguration::known_good(on).await,
+        }
+    }
+}
+
+/// Activates an earlier system configuration on every destination.
+async fn rollback(
+    destinations: Vec<Destination>,
+    to: RollbackTo,
+    activate_options: ActivateOptions,
+    ask: bool,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let activate_options = Arc::new(ActivateOptions {
+        ask: ask.then(|| Arc::new(Prompter::default())),
+        // Rollbacks only ever concern the "system" profile:
+        profile_name: None,
+        ..activate_options
+    });
+    let remote_options = Arc::new(remote_options);
+    let results = supervise(destinations.into_iter().map(|destination| {
+        let activate_options = activate_options.clone();
+        let remote_options = remote_options.clone();
+        let span = log::info_span!("rollback", host = destination.hostname);
+        (
+            destination.hostname.clone(),
+            async move {
+                let system = connect(&destination, &remote_options).await?;
+                let previous = to.configuration(system).await?;
+                previous.check_present().await?;
+                log::info!(configuration=?previous.configuration(), "Rolling back");
+                let report = Arc::new(std::sync::Mutex::new(HostReport::new(&destination)));
+                activate(previous, &activate_options, &report).await
+            }
+            .instrument(span),
+        )
+    }))
+    .await;
+    fail_if_any_failed(results, "Rolling back")?;
+    Ok(())
+}
+
+/// Pins the configuration that every destination runs as known-good,
+/// if the destination is healthy and has run it for at least
+/// `min_age`.
+async fn pin_known_good(
+    destinations: Vec<Destination>,
+    min_age: Duration,
+    health: &HealthCheckArgs,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let (health_check, health_check_timeout) =
+        (health.health_check, health.health_check_timeout.into());
+    let remote_options = Arc::new(remote_options);
+    let results = supervise(destinations.into_iter().map(|destination| {
+        let remote_options = remote_options.clone();
+        let span = log::info_span!("pin", host = destination.hostname);
+        (
+            destination.hostname.clone(),
+            async move {
+                let system = connect(&destination, &remote_options).await?;
+                in_phase(
+                    "preflight",
+                    system.host(),
+                    system.options(),
+                    deploy_flake::check_system_health(&system, health_check, health_check_timeout),
+                )
+                .await?;
+                let pinned = SystemConfiguration::pin_known_good(system, min_age).await?;
+                log::info!(configuration=?pinned.configuration(), "Pinned as known-good");
+                Ok::<_, anyhow::Error>(())
+            }
+            .instrument(span),
+        )
+    }))
+    .await;
+    fail_if_any_failed(results, "Pinning")?;
+    Ok(())
+}
+
+/// Checks that the state of every destination is consistent, at
+/// most `max_parallel` destinations at a time, and prints what's
+/// inconsistent on each as soon as it is known. Unlike a deploy, a
+/// destination that can't be reached (in `timeout`) doesn't hold up
+/// the results for the others.
+async fn verify(
+    destinations: Vec<Destination>,
+    max_parallel: NonZeroUsize,
+    timeout: Duration,
+    porcelain: bool,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let remote_options = Arc::new(remote_options);
+    let checks = destinations.into_iter().map(|destination| {
+        let remote_options = remote_options.clone();
+        let span = log::info_span!("verify", host = destination.hostname);
+        let target = destination.clone();
+        let mut check = task::spawn(
+            async move {
+                let system = connect(&target, &remote_options).await?;
+                deploy_flake::verify_system(&system).await
+            }
+            .instrument(span),
+        );
+        async move {
+            let result = match tokio::time::timeout(timeout, &mut check).await {
+                Ok(joined) => joined.unwrap_or_else(|e| Err(join_error(e))),
+                Err(_) => {
+                    check.abort();
+                    Err(anyhow::anyhow!(
+                        "Timed out after {}",
+                        humantime::format_duration(timeout)
+                    ))
+                }
+            };
+            (destination, result)
+        }
+    });
+    let mut checks = futures::stream::iter(checks).buffer_unordered(max_parallel.get());
+    let mut results = vec![];
+    while let Some((destination, result)) = checks.next().await {
+        if porcelain {
+            print_porcelain(
+                &destination,
+                "checked",
+                match &result {
+                    Ok(problems) => serde_json::json!({
+                        "consistent": problems.is_empty(),
+                        "problems": problems,
+                    }),
+                    Err(e) => serde_json::json!({
+                        "consistent": null,
+                        "error": format!("{e:#}"),
+                    }),
+                },
+            );
+        }
+        let result = result.and_then(|problems| {
+            if problems.is_empty() {
+                return Ok(());
+            }
+            anyhow::bail!("Inconsistent state:\n  {}", problems.join("\n  "))
+        });
+        if !porcelain {
+            match &result {
+                Ok(()) => println!("{destination}: consistent"),
+                Err(e) => println!("{destination}: {e:#}"),
+            }
+        }
+        results.push(result);
+    }
+    fail_if_any_failed(results, "Verifying")?;
+    Ok(())
+}
+
+/// Shows which source every destination runs, and how that changed
+/// since an earlier inventory.
+async fn fleet_diff(
+    destinations: Vec<Destination>,
+    since: Option<PathBuf>,
+    save: Option<PathBuf>,
+    max_parallel: NonZeroUsize,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let earlier = since.as_deref().map(Inventory::load).transpose()?;
+    let remote_options = Arc::new(remote_options);
+    let gathered = destinations.into_iter().map(|destination| {
+        let remote_options = remote_options.clone();
+        let span = log::info_span!("fleet-diff", host = destination.hostname);
+        async move {
+            let state = async {
+                let system = connect(&destination, &remote_options).await?;
+                let state = HostState::gather(&system).await;
+                let _ = system.close().await;
+                state
+            }
+            .instrument(span)
+            .await;
+            (destination, state)
+        }
+    });
+    let mut gathered = futures::stream::iter(gathered).buffer_unordered(max_parallel.get());
+    let mut inventory = Inventory::default();
+    let mut results = vec![];
+    while let Some((destination, state)) = gathered.next().await {
+        results.push(state.map(|state| {
+            inventory.hosts.insert(destination.to_string(), state);
+        }));
+    }
+    print!("{}", inventory.render());
+    if let Some(earlier) = earlier {
+        println!("Changes since {:?}:", since.unwrap_or_default());
+        for change in inventory.changes_since(&earlier) {
+            println!("  {change}");
+        }
+    }
+    // A partial inventory would make the missing destinations look
+    // like they were gone the next time around:
+    fail_if_any_failed(results, "Inventorying")?;
+    if let Some(path) = save {
+        inventory.save(&path)?;
+    }
+    Ok(())
+}
+
+/// Runs a command on every destination, in batches of at most
+/// `max_parallel` destinations if given. Unlike a deploy, a failure
+/// on one destination doesn't stop the command from running on the
+/// others.
+async fn exec(
+    destinations: Vec<Destination>,
+    command: Vec<String>,
+    input: Option<Vec<u8>>,
+    max_parallel: Option<NonZeroUsize>,
+    porcelain: bool,
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let command = Arc::new(command);
+    let input = Arc::new(input);
+    let remote_options = Arc::new(remote_options);
+    let batch_size = max_parallel.map_or(destinations.len().max(1), NonZeroUsize::get);
+    let mut results = vec![];
+    for batch in destinations.chunks(batch_size) {
+        results.extend(
+            supervise(batch.iter().cloned().map(|destination| {
+                let command = command.clone();
+                let input = input.clone();
+                let remote_options = remote_options.clone();
+                (destination.hostname.clone(), async move {
+                    if !porcelain {
+                        return exec_on(
+                            destination,
+                            &command,
+                            input.as_deref(),
+                            None,
+                            &remote_options,
+                        )
+                        .await;
+                    }
+                    let (output, mut lines) = tokio::sync::mpsc::unbounded_channel();
+                    let printer = {
+                        let destination = destination.clone();
+                        task::spawn(async move {
+                            while let Some(OutputLine { stream, line }) = lines.recv().await {
+                                print_porcelain(
+                                    &destination,
+                                    "output",
+                                    serde_json::json!({"stream": stream.name(), "line": line}),
+                                );
+                            }
+                        })
+                    };
+                    let result = exec_on(
+                        destination.clone(),
+                        &command,
+                        input.as_deref(),
+                        Some(output),
+                        &remote_options,
+                    )
+                    .await;
+                    // All output comes before the destination finishes:
+                    let _ = printer.await;
+                    print_porcelain(
+                        &destination,
+                        "finished",
+                        match &result {
+                            Ok(()) => serde_json::json!({"succeeded": true}),
+                            Err(e) => serde_json::json!({
+                                "succeeded": false,
+                                "error": format!("{e:#}"),
+                            }),
+                        },
+                    );
+                    result
+                })
+            }))
+            .await,
+        );
+    }
+    fail_if_any_failed(results, "Running the command")?;
+    Ok(())
+}
+
+/// Runs a command on a single destination, forwarding the lines it
+/// prints to `output` if given.
+#[instrument(skip(destination, command, input, output, remote_options), fields(host=destination.hostname) err)]
+async fn exec_on(
+    destination: Destination,
+    command: &[String],
+    input: Option<&[u8]>,
+    output: Option<tokio::sync::mpsc::UnboundedSender<OutputLine>>,
+    remote_options: &RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let system = connect(&destination, remote_options).await?;
+    let mut input = input;
+    system
+        .exec_forwarding(
+            command,
+            input
+                .as_mut()
+                .map(|input| input as &mut (dyn tokio::io::AsyncRead + Unpin + Send)),
+            output,
+        )
+        .await?;
+    log::info!("Command succeeded");
+    Ok(())
+}
+
+/// Prints an event about a destination as a line of JSON on stdout,
+/// for `--porcelain` output. `fields` must be a JSON object.
+fn print_porcelain(destination: &Destination, event: &str, fields: serde_json::Value) {
+    let mut object = serde_json::Map::new();
+    object.insert("host".to_string(), destination.to_string().into());
+    object.insert("event".to_string(), event.into());
+    if let serde_json::Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+    println!("{}", serde_json::Value::Object(object));
+}
+
+/// Copies the closures of the store paths to every destination.
+async fn copy(
+    paths: Vec<PathBuf>,
+    destinations: Vec<Destination>,
+    fanout: bool,
+    copying: (DeployOptions, Arc<dyn ClosureCopier>),
+    remote_options: RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let results = copy_everywhere(
+        Arc::new(paths),
+        &destinations,
+        fanout,
+        copying,
+        Arc::new(remote_options),
+    )
+    .await;
+    fail_if_any_failed(results, "Copying")?;
+    Ok(())
+}
+
+/// Copies the closures of the store paths to every destination with
+/// the options and copier in `copying`, and returns the
+/// connections to the destinations in the same order, or what went
+/// wrong with them. With `fanout`, see [`Fanout`].
+async fn copy_everywhere(
+    paths: Arc<Vec<PathBuf>>,
+    destinations: &[Destination],
+    fanout: bool,
+    (deploy_options, copier): (DeployOptions, Arc<dyn ClosureCopier>),
+    remote_options: Arc<RemoteOptions>,
+) -> Vec<Result<Arc<Nixos>, anyhow::Error>> {
+    let fanout = fanout.then(|| Arc::new(Fanout::default()));
+    supervise(destinations.iter().cloned().map(|destination| {
+        let paths = paths.clone();
+        let fanout = fanout.clone();
+        let copier = copier.clone();
+        let remote_options = remote_options.clone();
+        let span = log::info_span!("copy", host = destination.hostname);
+        (
+            destination.hostname.clone(),
+            async move {
+                let system = connect(&destination, &remote_options)
+                    .await
+                    .inspect_err(|e| log::error!(error = %format!("{e:#}"), "Connecting failed"))?;
+                in_phase("copy", system.host(), system.options(), async {
+                    let Some(fanout) = &fanout else {
+                        return copy_paths(&paths, &system, &deploy_options, &*copier).await;
+                    };
+                    for path in paths.iter() {
+                        fanout
+                            .copy(path, &system, &deploy_options, &*copier, &remote_options)
+                            .await
+                            .with_context(|| format!("Copying {path:?}"))?;
+                    }
+                    Ok(())
+                })
+                .await?;
+                Ok(system)
+            }
+            .instrument(span),
+        )
+    }))
+    .await
+}
+
+/// Has `from` push the closure of a store path to `to` (see
+/// [`copy_from_system`]), retrying pushes that time out or fail for a
+/// transient reason. Without an attempt timeout in the copy retry
+/// policy, one gets derived from the size of what needs to be
+/// transferred, so that a stalled push can't hold up the deploy
+/// forever.
+async fn push_closure(
+    path: &Path,
+    from: &Nixos,
+    to: &Nixos,
+    options: &DeployOptions,
+    remote_options: &RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let size = transfer_size(path, to, options, false).await?;
+    let copy_retry = &options.copy_retry;
+    let copy_timeout = copy_retry
+        .attempt_timeout
+        .or_else(|| size.map(|size| deploy_flake::copy_timeout_for_size(size.bytes)));
+    retrying_copy(
+        "Pushing",
+        &copy_retry.with_attempt_timeout(copy_timeout),
+        || copy_from_system(path, from, to, remote_options),
+    )
+    .await
+}
+
+/// Runs the copy `f` under `policy`, retrying it when it fails with
+/// a [`TransientFailure`]. Every copy of a closure goes through here,
+/// so that they all retry the same failures.
+async fn retrying_copy<F, Fut>(what: &str, policy: &RetryPolicy, f: F) -> Result<(), anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<(), anyhow::Error>>,
+{
+    retrying(what, policy, |e| e.is::<TransientFailure>(), f).await
+}
+
+/// The destinations that already have closures, for `--fanout`: the
+/// first destination that needs a closure gets it from here, and
+/// from then on, every destination that has it pushes it on to one
+/// other destination at a time, so the number of destinations that
+/// have it doubles with every round.
+#[derive(Debug, Default)]
+struct Fanout {
+    sources: std::sync::Mutex<HashMap<PathBuf, Arc<Sources>>>,
+}
+
+/// The destinations that have a closure.
+#[derive(Debug, Default)]
+struct Sources {
+    state: std::sync::Mutex<SourcesState>,
+
+    /// Wakes up the destinations that wait for a source once one
+    /// becomes idle, or the copy from here is done.
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct SourcesState {
+    /// The destinations that have the closure and aren't pushing it
+    /// anywhere right now.
+    idle: Vec<Arc<Nixos>>,
+
+    /// Whether a destination is getting the closure from here.
+    seeding: bool,
+}
+
+impl Sources {
+    /// Waits until the closure can be copied to another destination,
+    /// and returns the destination to push it from, or `None` if it
+    /// needs to be copied from here.
+    async fn next(&self) -> Option<Arc<Nixos>> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(source) = state.idle.pop() {
+                    return Some(source);
+                }
+                if !state.seeding {
+                    state.seeding = true;
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Lets `system` push the closure to other destinations.
+    fn add(&self, system: Arc<Nixos>) {
+        self.state.lock().unwrap().idle.push(system);
+        self.changed.notify_waiters();
+    }
+
+    /// Records that the copy from here is done; if it failed, the
+    /// next destination copies from here instead.
+    fn seeded(&self) {
+        self.state.lock().unwrap().seeding = false;
+        self.changed.notify_waiters();
+    }
+}
+
+impl Fanout {
+    /// Copies the closure of a store path to a system, pushing it
+    /// from a destination that has it already if there is one. A
+    /// system that can't get it from there gets it from here instead.
+    async fn copy(
+        &self,
+        path: &Path,
+        system: &Arc<Nixos>,
+        deploy_options: &DeployOptions,
+        copier: &dyn ClosureCopier,
+        remote_options: &RemoteOptions,
+    ) -> Result<(), anyhow::Error> {
+        let sources = self
+            .sources
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .clone();
+        match sources.next().await {
+            None => {
+                let copied = copy_closure(path, system, deploy_options, copier).await;
+                if copied.is_ok() {
+                    sources.add(system.clone());
+                }
+                sources.seeded();
+                copied
+            }
+            Some(source) => {
+                let pushed =
+                    push_closure(path, &source, system, deploy_options, remote_options).await;
+                sources.add(source.clone());
+                match pushed {
+                    Ok(()) => log::info!(from = source.host(), "Copied"),
+                    Err(e) => {
+                        log::warn!(
+                            from = source.host(),
+                            error = %format!("{e:#}"),
+                            "Copying from another destination failed, copying from here"
+                        );
+                        copy_closure(path, system, deploy_options, copier).await?;
+                    }
+                }
+                sources.add(system.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Copies the closures of the store paths to a single system.
+#[instrument(skip_all, err)]
+async fn copy_paths(
+    paths: &[PathBuf],
+    system: &Nixos,
+    deploy_options: &DeployOptions,
+    copier: &dyn ClosureCopier,
+) -> Result<(), anyhow::Error> {
+    for path in paths {
+        copy_closure(path, system, deploy_options, copier)
+            .await
+            .with_context(|| format!("Copying {path:?}"))?;
+    }
+    log::info!(paths = paths.len(), "Copied");
+    Ok(())
+}
+
+/// Connects to the destination.
+async fn connect(
+    destination: &Destination,
+    remote_options: &RemoteOptions,
+) -> Result<Arc<Nixos>, anyhow::Error> {
+    log::debug!("Connecting");
+    let (host, port) = destination.ssh_target();
+    let port = match destination.tunnel {
+        Some(tunnel) => Some(dialed_in(tunnel, remote_options).await?),
+        None => port,
+    };
+    destination
+        .os_flavor
+        .connect(
+            &host,
+            port,
+            remote_options_for(destination, remote_options)?,
+        )
+        .await
+}
+
+/// Waits for a destination that dials in on the tunnel `port` to do
+/// so, and returns the local port that ssh connects to it on.
+async fn dialed_in(port: u16, remote_options: &RemoteOptions) -> Result<u16, anyhow::Error> {
+    match &remote_options.tunnel_broker {
+        Some(broker) => broker.open(port, remote_options.tunnel_wait).await,
+        None => {
+            wait_for_tunnel(port, remote_options.tunnel_wait).await?;
+            Ok(port)
+        }
+    }
+}
+
+/// Returns the remote options for connecting to a destination, with
+/// the ones that its parameters override. A destination whose host
+/// key ssh looks up under another name (see
+/// [`Destination::host_key_alias`]) gets an ssh configuration of its
+/// own that says so.
+fn remote_options_for(
+    destination: &Destination,
+    remote_options: &RemoteOptions,
+) -> Result<RemoteOptions, anyhow::Error> {
+    let ssh_config = match destination.host_key_alias() {
+        Some(alias) => SshConfig::new(
+            &[format!("HostKeyAlias {alias}")],
+            remote_options.ssh_config.as_deref().map(SshConfig::file),
+        )?
+        .map(Arc::new),
+        None => remote_options.ssh_config.clone(),
+    };
+    Ok(RemoteOptions {
+        host_key_policy: destination
+            .host_key_policy
+            .or(remote_options.host_key_policy),
+        jump_host: destination
+            .via
+            .clone()
+            .or_else(|| remote_options.jump_host.clone()),
+        ssh_config,
+        ..remote_options.clone()
+    })
+}
+
+/// How long connecting to a destination may take when checking
+/// whether it is reachable.
+const PRECHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks that every destination can be connected to, failing with a
+/// list of the ones that can't otherwise.
+async fn precheck_connectivity(
+    destinations: &[&Destination],
+    remote_options: &RemoteOptions,
+) -> Result<(), anyhow::Error> {
+    let unreachable: Vec<String> =
+        futures::future::join_all(destinations.iter().map(|destination| async move {
+            let hostname = &destination.hostname;
+            match tokio::time::timeout(PRECHECK_TIMEOUT, connect(destination, remote_options)).await
+            {
+                Ok(Ok(system)) => {
+                    let _ = system.close().await;
+                    None
+                }
+                Ok(Err(e)) => Some(format!("{hostname}: {e:#}")),
+                Err(_) => Some(format!("{hostname}: timed out")),
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    if !unreachable.is_empty() {
+        anyhow::bail!(
+            "{} of {} destinations are unreachable:\n  {}",
+            unreachable.len(),
+            destinations.len(),
+            unreachable.join("\n  ")
+        );
+    }
+    log::debug!(
+        destinations = destinations.len(),
+        "All destinations are reachable"
+    );
+    Ok(())
+}
+
+/// Returns the successful results, or an error counting the failed
+/// ones if any of the per-destination operations failed. (The
+/// individual errors have already been logged by the time this is
+/// called.)
+fn fail_if_any_failed<T>(
+    results: Vec<Result<T, anyhow::Error>>,
+    what: &str,
+) -> Result<Vec<T>, anyhow::Error> {
+    let total = results.len();
+    let mut succeeded = vec![];
+    let mut skipped = 0;
+    for result in results {
+        match result {
+            Ok(value) => succeeded.push(value),
+            Err(e) if e.is::<Declined>() => skipped += 1,
+            Err(_) => {}
+        }
+    }
+    if skipped > 0 {
+        log::warn!(skipped, "Skipped destinations at the user's request");
+    }
+    let failed = total - succeeded.len() - skipped;
+    if failed > 0 {
+        anyhow::bail!("{what} failed on {failed} of {total} destinations");
+    }
+    Ok(succeeded)
+}
+
+/// The error returned when the user declines to continue deploying
+/// to a destination.
+#[derive(Debug)]
+struct Declined;
+
+impl std::fmt::Display for Declined {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Skipped at the user's request")
+    }
+}
+
+impl std::error::Error for Declined {}
+
+/// The error that destinations left out by `--skip-unhealthy` are
+/// recorded with.
+#[derive(Debug)]
+struct Unhealthy(String);
+
+impl std::fmt::Display for Unhealthy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Skipped, since it is unhealthy: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unhealthy {}
+
+/// The error that work on a destination stops with when the deploy
+/// gets interrupted, naming the signal that interrupted it.
+#[derive(Debug)]
+struct Interrupted(&'static str);
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted by {}", self.0)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// The signal that interrupted the deploy, once one did.
+static INTERRUPT: std::sync::LazyLock<tokio::sync::watch::Sender<Option<&'static str>>> =
+    std::sync::LazyLock::new(|| tokio::sync::watch::Sender::new(None));
+
+/// The signals that interrupt a deploy: SIGINT and SIGTERM.
+#[cfg(unix)]
+struct Interrupts {
+    sigint: tokio::signal::unix::Signal,
+    sigterm: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Interrupts {
+    fn new() -> Result<Self, anyhow::Error> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt())?,
+            sigterm: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Waits for the next signal, returning its name.
+    async fn next(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigint.recv() => "SIGINT",
+            _ = self.sigterm.recv() => "SIGTERM",
+        }
+    }
+}
+
+/// The signals that interrupt a deploy: Ctrl-C, where there are no
+/// unix signals.
+#[cfg(not(unix))]
+struct Interrupts;
+
+#[cfg(not(unix))]
+impl Interrupts {
+    fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self)
+    }
+
+    /// Waits for the next Ctrl-C.
+    async fn next(&mut self) -> &'static str {
+        if tokio::signal::ctrl_c().await.is_err() {
+            // Without a way to notice interrupts, there are none:
+            std::future::pending::<()>().await;
+        }
+        "Ctrl-C"
+    }
+}
+
+/// Waits for SIGINT or SIGTERM, and then has the work on every
+/// destination stop at the next opportunity (see
+/// [`unless_interrupted`]). A second signal exits right away.
+async fn watch_for_interrupts() -> Result<(), anyhow::Error> {
+    let mut interrupts = Interrupts::new()?;
+    let signal = interrupts.next().await;
+    log::warn!(
+        signal,
+        "Interrupted, stopping the deploy (interrupt again to exit right away)"
+    );
+    INTERRUPT.send_replace(Some(signal));
+    let signal = interrupts.next().await;
+    log::error!(signal, "Interrupted again, exiting");
+    std::process::exit(130);
+}
+
+/// Runs `f` until it finishes or the deploy gets interrupted,
+/// whichever comes first. Local processes that `f` spawned get killed
+/// when it stops.
+async fn unless_interrupted<T>(
+    f: impl std::future::Future<Output = Result<T, anyhow::Error>>,
+) -> Result<T, anyhow::Error> {
+    let mut interrupt = INTERRUPT.subscribe();
+    tokio::select! {
+        biased;
+        signal = interrupt.wait_for(Option::is_some) => {
+            let signal = signal.ok().and_then(|signal| *signal).unwrap_or_default();
+            Err(anyhow::Error::new(Interrupted(signal)))
+        }
+        result = f => result,
+    }
+}
+
+/// An answer to a prompt in `--ask` mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Asks the user whether to proceed before each destructive step of
+/// the deployment. Only one question is asked at a time, even when
+/// deploying to multiple destinations in parallel.
+#[derive(Debug, Default)]
+struct Prompter {
+    /// An answer that applies to all further questions.
+    standing_answer: Mutex<Option<Answer>>,
+}
+
+impl Prompter {
+    /// Asks whether to perform `step` on `host`. Returns
+    /// [`Declined`] if the user says no, and an error if the user
+    /// wants to quit.
+    async fn confirm(&self, host: &str, step: &str) -> Result<(), anyhow::Error> {
+        self.confirm_showing(host, step, "").await
+    }
+
+    /// Like [`Prompter::confirm`], but shows `details` first (also
+    /// when the user already answered "all").
+    async fn confirm_showing(
+        &self,
+        host: &str,
+        step: &str,
+        details: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut standing_answer = self.standing_answer.lock().await;
+        let answer = match *standing_answer {
+            Some(answer) => {
+                if answer == Answer::All {
+                    tracing_indicatif::suspend_tracing_indicatif(|| eprint!("{details}"));
+                }
+                answer
+            }
+            None => {
+                let question = format!("{details}{host}: {step}? [y]es/[n]o/[a]ll/[q]uit: ");
+                task::spawn_blocking(move || {
+                    tracing_indicatif::suspend_tracing_indicatif(|| Self::ask(&question))
+                })
+                .await??
+            }
+        };
+        match answer {
+            Answer::Yes => Ok(()),
+            Answer::No => Err(Declined.into()),
+            Answer::All => {
+                *standing_answer = Some(Answer::All);
+                Ok(())
+            }
+            Answer::Quit => {
+                *standing_answer = Some(Answer::Quit);
+                anyhow::bail!("Deploy aborted at the user's request")
+            }
+        }
+    }
+
+    fn ask(question: &str) -> Result<Answer, std::io::Error> {
+        loop {
+            eprint!("{question}");
+            std::io::stderr().flush()?;
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line)? == 0 {
+                // stdin is closed, nobody can answer:
+                return Ok(Answer::Quit);
+            }
+            match line.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(Answer::Yes),
+                "n" | "no" => return Ok(Answer::No),
+                "a" | "all" => return Ok(Answer::All),
+                "q" | "quit" => return Ok(Answer::Quit),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Asks for the passwords that elevating privileges takes on the
+/// terminal, remembering them for the rest of the run.
+#[derive(Debug, Default)]
+struct TerminalPasswordPrompt {
+    passwords: std::sync::Mutex<HashMap<String, String>>,
+}
+
+impl PasswordPrompt for TerminalPasswordPrompt {
+    fn password(&self, host: &str) -> Result<String, anyhow::Error> {
+        // Holding the lock while asking means that only one question
+        // gets asked at a time, and only once per destination:
+        let mut passwords = self.passwords.lock().unwrap();
+        if let Some(password) = passwords.get(host) {
+            return Ok(password.clone());
+        }
+        let password = tracing_indicatif::suspend_tracing_indicatif(|| {
+            read_password(&format!("[sudo] password for {host}: "))
+        })
+        .with_context(|| format!("Could not ask for the sudo password for {host}"))?;
+        passwords.insert(host.to_string(), password.clone());
+        Ok(password)
+    }
+}
+
+/// Reads a line from the terminal without echoing it, after showing
+/// the prompt.
+fn read_password(prompt: &str) -> Result<String, anyhow::Error> {
+    let tty = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("No terminal to ask on")?;
+    let stty = |setting: &str| -> Result<(), anyhow::Error> {
+        let status = std::process::Command::new("stty")
+            .arg(setting)
+            .stdin(tty.try_clone()?)
+            .status()
+            .context("Could not run stty")?;
+        anyhow::ensure!(status.success(), "stty {setting} failed with {status}");
+        Ok(())
+    };
+    (&tty).write_all(prompt.as_bytes())?;
+    stty("-echo")?;
+    let mut line = String::new();
+    let read = std::io::BufReader::new(&tty).read_line(&mut line);
+    let restored = stty("echo");
+    (&tty).write_all(b"\n")?;
+    read?;
+    restored?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Settings for preparing the deployment on every destination.
+#[derive(Debug, Clone)]
+struct PrepareOptions {
+    ask: Option<Arc<Prompter>>,
+    remote_options: RemoteOptions,
+    deploy_options: DeployOptions,
+    copy_method: CopyMethod,
+    copy_cache: Option<String>,
+    build_on: BuildOn,
+    do_preflight: CheckBehavior,
+    force_platform: bool,
+    min_nix_version: Option<String>,
+    health_check: HealthCheck,
+    health_check_timeout: Duration,
+    pre_activate_script: Option<PathBuf>,
+    build_options: BuildOptions,
+    fanout: Option<Arc<Fanout>>,
+}
+
+impl PrepareOptions {
+    /// Returns the options with the overrides that the configuration
+    /// file specifies for the host applied.
+    fn for_host(&self, host: &Host) -> PrepareOptions {
+        let mut options = self.clone();
+        if let Some(preflight_check) = host.preflight_check {
+            options.do_preflight = preflight_check;
+        }
+        if let Some(health_check) = host.health_check {
+            options.health_check = health_check;
+        }
+        if let Some(copy_method) = host.copy_method {
+            options.copy_method = copy_method;
+        }
+        if let Some(copy_timeout) = host.copy_timeout {
+            options.deploy_options.copy_retry = options
+                .deploy_options
+                .copy_retry
+                .with_attempt_timeout(Some(copy_timeout));
+        }
+        if let Some(cmdline) = &host.build_cmdline {
+            options.build_options.cmdline = cmdline.clone();
+        }
+        options
+            .build_options
+            .nix_options
+            .extend(host.nix_options.clone());
+        options
+    }
+}
+
+/// Settings for activating the configuration on every destination.
+#[derive(Debug, Clone)]
+struct ActivateOptions {
+    ask: Option<Arc<Prompter>>,
+    do_test: Behavior,
+    activation: Activation,
+    retry_test_units: Vec<String>,
+    retry_test_delay: Duration,
+    test_timeout: Option<Duration>,
+    activate_timeout: Option<Duration>,
+    show_changes: bool,
+    force: bool,
+    specialisation: Option<String>,
+    profile_name: Option<String>,
+    post_test_check: Behavior,
+    boot_dry_run: Behavior,
+    snapshots: Vec<Snapshot>,
+    confirm_timeout: Option<Duration>,
+    drain: Option<String>,
+    undrain: Option<String>,
+    pruning: Option<Pruning>,
+    collect_garbage: bool,
+    reboot: Reboot,
+    reboot_timeout: Duration,
+    reboot_method: RebootMethod,
+    health_check: HealthCheck,
+    health_check_timeout: Duration,
+}
+
+impl ActivateOptions {
+    /// Returns how the configuration gets activated, taking into
+    /// account whether the "test" step gets skipped.
+    fn activation(&self) -> Activation {
+        match (self.activation, self.do_test) {
+            (Activation::TestThenBoot, Behavior::Skip) => Activation::BootOnly,
+            (activation, _) => activation,
+        }
+    }
+
+    /// Returns the options with the overrides that the configuration
+    /// file specifies for the host applied.
+    fn for_host(&self, host: &Host) -> ActivateOptions {
+        ActivateOptions {
+            do_test: host.test.unwrap_or(self.do_test),
+            health_check: host.health_check.unwrap_or(self.health_check),
+            boot_dry_run: host.boot_dry_run.unwrap_or(self.boot_dry_run),
+            snapshots: host
+                .snapshots
+                .clone()
+                .unwrap_or_else(|| self.snapshots.clone()),
+            drain: host.drain.clone().or_else(|| self.drain.clone()),
+            undrain: host.undrain.clone().or_else(|| self.undrain.clone()),
+            specialisation: host
+                .specialisation
+                .clone()
+                .or_else(|| self.specialisation.clone()),
+            profile_name: host
+                .profile_name
+                .clone()
+                .or_else(|| self.profile_name.clone()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Copies the flake to the destination, builds the system
+/// configuration there and checks whether it can be activated. Until
+/// it is activated, the GC root `gc_root` keeps the configuration
+/// from getting garbage-collected on the destination; it only stays
+/// if the configuration can be activated.
+#[instrument(skip(flake, destination, options, report), fields(flake=flake.resolved_path(), host=destination.hostname, config=destination.config_name) err)]
+async fn prepare(
+    flake: Flake,
+    destination: Destination,
+    options: &PrepareOptions,
+    report: &SharedHostReport,
+    gc_root: &str,
+) -> Result<SystemConfiguration, anyhow::Error> {
+    let system = connect_to_build(&destination, options).await?;
+
+    // The system's health doesn't depend on the build, so it gets
+    // checked while the configuration is being built:
+    let health_check = in_phase("preflight", system.host(), system.options(), async {
+        if options.do_preflight == CheckBehavior::Skip {
+            log::event!(log::Level::DEBUG, "Skipping system health check");
+            return Ok(());
+        }
+        log::event!(log::Level::DEBUG, "Checking system health");
+        let checked = deploy_flake::check_system_health(
+            &system,
+            options.health_check,
+            options.health_check_timeout,
+        )
+        .await;
+        match checked {
+            Err(e) if options.do_preflight == CheckBehavior::Warn => {
+                log::warn!(error = %format!("{e:#}"), "System is unhealthy, deploying anyway");
+                report
+                    .lock()
+                    .unwrap()
+                    .warnings
+                    .push(format!("Preflight check failed: {e:#}"));
+                Ok(())
+            }
+            checked => checked,
+        }
+    });
+    let (built, ()) = futures::try_join!(
+        build_on(flake, &destination, system.clone(), options, report),
+        health_check
+    )?;
+    let on = built.on();
+    in_phase(
+        "gc-root",
+        on.host(),
+        on.options(),
+        built.add_gc_root(gc_root),
+    )
+    .await?;
+
+    let checked = in_phase(
+        "preflight",
+        on.host(),
+        on.options(),
+        built.preflight_check_closure(options.pre_activate_script.as_deref()),
+    )
+    .await;
+    if checked.is_err() {
+        release_gc_root(built.on(), gc_root).await;
+    }
+    checked?;
+    Ok(built)
+}
+
+/// Removes a GC root that a configuration which won't get activated
+/// (any more) no longer needs, warning if that fails.
+async fn release_gc_root(system: &Nixos, name: &str) {
+    if let Err(error) = deploy_flake::remove_gc_root(system, name).await {
+        log::warn!(error = %format!("{error:#}"), root = name, "Could not remove the GC root of the deployment");
+    }
+}
+
+/// Connects to a destination, once the user agreed to copying the
+/// flake there if asked.
+async fn connect_to_build(
+    destination: &Destination,
+    options: &PrepareOptions,
+) -> Result<Arc<Nixos>, anyhow::Error> {
+    if let Some(prompter) = &options.ask {
+        prompter
+            .confirm(&destination.hostname, "Copy the flake")
+            .await?;
+    }
+    connect(destination, &options.remote_options).await
+}
+
+/// Copies the flake to the destination `flavor` and builds the
+/// system configuration there.
+async fn build_on(
+    flake: Flake,
+    destination: &Destination,
+    flavor: Arc<Nixos>,
+    options: &PrepareOptions,
+    report: &SharedHostReport,
+) -> Result<SystemConfiguration, anyhow::Error> {
+    let copier = options
+        .copy_method
+        .copier(options.copy_cache.as_deref(), &options.remote_options)?;
+    // Running out of space is checked for along with the preflight
+    // check, which the configuration file may override for the host:
+    let deploy_options = DeployOptions {
+        check_free_space: options.do_preflight,
+        ..options.deploy_options
+    };
+    let (build_host, flake) = build_host_for(flake, destination, &flavor, options).await?;
+    // The facts get gathered over the destination's ssh connection
+    // while the closure is being copied:
+    let (facts, copied) = in_phase("copy", flavor.host(), flavor.options(), async {
+        Ok(futures::join!(flavor.facts(), async {
+            match build_host.as_ref().filter(|_| flake.reference().is_none()) {
+                Some(build_host) => {
+                    copy_closure(
+                        Path::new(flake.resolved_path()),
+                        build_host,
+                        &deploy_options,
+                        &*copier,
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        }))
+    })
+    .await?;
+    copied?;
+    // The facts only serve the checks below and the report, so the
+    // deploy can go on without them:
+    let facts = match facts {
+        Ok(facts) => {
+            log::info!(
+                current_system = ?facts.current_system,
+                generation = facts.current_generation,
+                "Current system"
+            );
+            report.lock().unwrap().facts = Some(facts.clone());
+            // Copying and activating the configuration runs nix
+            // commands that older versions of nix don't have, even if
+            // nothing gets built on the destination:
+            deploy_flake::check_nix_version(facts, FLAKES_NIX_VERSION)
+                .context("Can not deploy flakes there: upgrade its nix")?;
+            if let Some(min) = &options.min_nix_version {
+                deploy_flake::check_nix_version(facts, min)?;
+            }
+            Some(facts)
+        }
+        Err(error) => {
+            log::warn!(
+                error = %format!("{error:#}"),
+                "Could not gather facts about the host, skipping the checks that need them"
+            );
+            None
+        }
+    };
+    if let Some(build_host) = &build_host {
+        match build_host.facts().await {
+            Ok(build_facts) => deploy_flake::check_nix_version(build_facts, FLAKES_NIX_VERSION).context(
+                "Can not build flakes there: upgrade its nix, or build somewhere else with --build-on",
+            )?,
+            Err(error) => log::warn!(
+                error = %format!("{error:#}"),
+                "Could not gather facts about the build host, not checking its nix version"
+            ),
+        }
+    }
+    log::event!(log::Level::DEBUG, config=?destination.config_name, "Building");
+    let config_name = destination.config_name.as_deref();
+    let built = in_phase(
+        "build",
+        flavor.host(),
+        flavor.options(),
+        retrying(
+            "Building",
+            &deploy_options.build_retry,
+            |e| e.is::<TransientFailure>(),
+            || async {
+                match &build_host {
+                    Some(build_host) => {
+                        // A build host builds the configuration named
+                        // after the destination, not after itself:
+                        let config_name = match config_name {
+                            Some(config_name) => config_name,
+                            None => &flavor.facts().await?.hostname,
+                        };
+                        flake
+                            .build(
+                                build_host.clone(),
+                                Some(config_name),
+                                &options.build_options,
+                            )
+                            .await
+                    }
+                    None => {
+                        flake
+                            .build_locally(
+                                flavor.clone(),
+                                config_name,
+                                &options.build_options,
+                                &options.remote_options.local_nix,
+                            )
+                            .await
+                    }
+                }
+            },
+        ),
+    )
+    .await?;
+    let built = match &options.build_on {
+        BuildOn::Target => built,
+        BuildOn::Local => {
+            if let Some(facts) = facts.filter(|_| !options.force_platform) {
+                let platform = deploy_flake::built_platform(built.configuration()).await;
+                deploy_flake::check_platform(
+                    built.configuration(),
+                    platform.as_deref(),
+                    flavor.flavor(),
+                    facts,
+                )?;
+            }
+            match &options.fanout {
+                Some(fanout) => {
+                    in_phase(
+                        "copy-system",
+                        flavor.host(),
+                        flavor.options(),
+                        fanout.copy(
+                            built.configuration(),
+                            &flavor,
+                            &deploy_options,
+                            &*copier,
+                            &options.remote_options,
+                        ),
+                    )
+                    .await?
+                }
+                None => {
+                    in_phase(
+                        "copy-system",
+                        flavor.host(),
+                        flavor.options(),
+                        copy_closure(built.configuration(), &flavor, &deploy_options, &*copier),
+                    )
+                    .await?
+                }
+            }
+            built
+        }
+        BuildOn::Host(_) => {
+            let path = built.configuration().to_owned();
+            if let Some(facts) = facts.filter(|_| !options.force_platform) {
+                let platform = built.on().built_platform(&path).await?;
+                deploy_flake::check_platform(&path, platform.as_deref(), flavor.flavor(), facts)?;
+            }
+            in_phase(
+                "copy-system",
+                flavor.host(),
+                flavor.options(),
+                retrying_copy("Copying", &deploy_options.copy_retry, || {
+                    copy_between(&path, built.on(), &flavor, &options.remote_options)
+                }),
+            )
+            .await
+            .context("Copying the built system from the build host failed")?;
+            SystemConfiguration::existing(flavor.clone(), path, built.for_system().to_string())
+                .with_source(built.source().map(String::from))
+        }
+    };
+    log::Span::current().record("config", built.for_system());
+    let nixos_version = built.nixos_version().await?;
+    warn_about_release_jump(
+        facts.and_then(|facts| facts.nixos_version.as_deref()),
+        nixos_version.as_deref(),
+    );
+    let version_changes = match facts.and_then(|facts| facts.current_system.as_ref()) {
+        Some(running) => built.version_changes(running).await?,
+        None => vec![],
+    };
+    for change in &version_changes {
+        log::info!(%change, "Activating the configuration changes a notable component");
+    }
+    {
+        let mut report = report.lock().unwrap();
+        report.system_name = Some(built.for_system().to_string());
+        report.configuration = Some(built.configuration().to_owned());
+        report.nixos_version = nixos_version;
+        report.version_changes = version_changes;
+    }
+    Ok(built)
+}
+
+/// Returns the system that builds a destination's configuration
+/// (connecting to it if it's a separate build host), unless it gets
+/// built locally, along with the flake to build there.
+async fn build_host_for(
+    flake: Flake,
+    destination: &Destination,
+    flavor: &Arc<Nixos>,
+    options: &PrepareOptions,
+) -> Result<(Option<Arc<Nixos>>, Flake), anyhow::Error> {
+    let build_host = match &options.build_on {
+        BuildOn::Target => Some(flavor.clone()),
+        BuildOn::Local => None,
+        BuildOn::Host(host) => {
+            Some(connect_build_host(host, destination, &options.remote_options).await?)
+        }
+    };
+
+    // A remote flake doesn't need copying to a build host that can
+    // fetch it itself:
+    let can_fetch = match (&build_host, flake.reference()) {
+        (Some(build_host), Some(reference)) => {
+            in_phase("fetch", flavor.host(), flavor.options(), async {
+                Ok(build_host
+                    .can_fetch_flake(reference, &options.build_options)
+                    .await)
+            })
+            .await?
+        }
+        _ => false,
+    };
+    let flake = if can_fetch {
+        flake
+    } else {
+        flake.without_reference()
+    };
+    Ok((build_host, flake))
+}
+
+/// Connects to the host that builds a destination's configuration.
+/// The build host gets treated as the same flavor of system as the
+/// destination, so that it builds the right kind of configuration.
+async fn connect_build_host(
+    host: &str,
+    destination: &Destination,
+    remote_options: &RemoteOptions,
+) -> Result<Arc<Nixos>, anyhow::Error> {
+    let build_host: Destination = host.parse()?;
+    log::debug!(
+        build_host = build_host.hostname,
+        "Connecting to the build host"
+    );
+    let (host, port) = build_host.ssh_target();
+    let port = match build_host.tunnel {
+        Some(tunnel) => Some(dialed_in(tunnel, remote_options).await?),
+        None => port,
+    };
+    destination
+        .os_flavor
+        .connect(
+            &host,
+            port,
+            remote_options_for(&build_host, remote_options)?,
+        )
+        .await
+}
+
+/// Warns if deploying a configuration would switch the destination
+/// to a different NixOS release, which is best followed by a reboot.
+fn warn_about_release_jump(running: Option<&str>, deploying: Option<&str>) {
+    let (Some(running), Some(deploying)) = (running, deploying) else {
+        return;
+    };
+    match (nixos_release(running), nixos_release(deploying)) {
+        (Some(from), Some(to)) if from != to => {
+            log::warn!(
+                running,
+                deploying,
+                "Deploying moves this host from NixOS {from} to NixOS {to}. Activating a new release in place can go wrong in surprising ways; consider rebooting the host once the deploy is done (say, with --reboot=always)."
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Returns how much copying the closure of `path` to `system`
+/// transfers, logging it, and fails if that is more than
+/// `--max-transfer-size` allows. That only gets measured if something
+/// needs it: the copy timeout (unless one is set), the size limit, or
+/// the free space check (if `check_free_space` says it runs).
+async fn transfer_size(
+    path: &Path,
+    system: &Nixos,
+    options: &DeployOptions,
+    check_free_space: bool,
+) -> Result<Option<TransferSize>, anyhow::Error> {
+    if options.copy_retry.attempt_timeout.is_some()
+        && options.max_transfer_size.is_none()
+        && !check_free_space
+    {
+        return Ok(None);
+    }
+    let size = deploy_flake::transfer_size(path, system).await?;
+    if size.paths > 0 {
+        log::event!(
+            log::Level::INFO,
+            paths = size.paths,
+            bytes = size.bytes,
+            "{size} need to be transferred"
+        );
+    }
+    if let Some(max) = options.max_transfer_size.filter(|max| size.bytes > max.0) {
+        anyhow::bail!(
+            "Copying {path:?} would transfer {size}, more than --max-transfer-size={max}"
+        );
+    }
+    Ok(Some(size))
+}
+
+/// Copies the closure of a store path to the destination, retrying
+/// if the copy takes longer than the retry policy's attempt timeout.
+/// Without an explicit timeout, one gets derived from the size of
+/// the paths that need to be transferred.
+async fn copy_closure(
+    path: &Path,
+    system: &Nixos,
+    options: &DeployOptions,
+    copier: &dyn ClosureCopier,
+) -> Result<(), anyhow::Error> {
+    let check_free_space = options.check_free_space != CheckBehavior::Skip;
+    let size = transfer_size(path, system, options, check_free_space).await?;
+    if let Some(size) = size.filter(|_| check_free_space) {
+        match deploy_flake::check_free_space(path, system, size).await {
+            Err(e) if options.check_free_space == CheckBehavior::Warn => {
+                log::warn!(error = %format!("{e:#}"), "Not enough free space, copying anyway");
+            }
+            checked => checked?,
+        }
+    }
+    let copy_retry = &options.copy_retry;
+    let copy_timeout = copy_retry.attempt_timeout.or_else(|| {
+        let size = size?;
+        let timeout = deploy_flake::copy_timeout_for_size(size.bytes);
+        log::event!(log::Level::DEBUG, bytes=size.bytes, timeout=%humantime::format_duration(timeout), "Computed copy timeout");
+        Some(timeout)
+    });
+    log::event!(log::Level::DEBUG, ?path, host=?system, ?copier, "Copying");
+    retrying_copy(
+        "Copying",
+        &copy_retry.with_attempt_timeout(copy_timeout),
+        || copier.copy_closure(path, system),
+    )
+    .await
+    .context("Copying the closure failed")
+}
+
+/// Activates a prepared system configuration (see [`activate`]), and
+/// then removes the GC root `gc_root` that kept it alive on its
+/// destination until then: if it got activated, the system profile
+/// keeps it alive.
+async fn activate_rooted(
+    built: SystemConfiguration,
+    options: &ActivateOptions,
+    report: &SharedHostReport,
+    gc_root: &str,
+) -> Result<(), anyhow::Error> {
+    let system = built.on().clone();
+    let activated = activate(built, options, report).await;
+    release_gc_root(&system, gc_root).await;
+    activated
+}
+
+/// Activates a prepared system configuration on its destination,
+/// recording the step that failed (if any) in the report. Then, the
+/// destination gets rebooted into the configuration if that's wanted,
+/// and old generations only get pruned once it is certain that the
+/// destination runs fine without them.
+#[instrument(skip(built, options, report), fields(host=?built.on(), config=built.for_system()) err)]
+async fn activate(
+    built: SystemConfiguration,
+    options: &ActivateOptions,
+    report: &SharedHostReport,
+) -> Result<(), anyhow::Error> {
+    let built = built
+        .with_specialisation(options.specialisation.clone())
+        .with_profile_name(options.profile_name.clone())
+        .with_test_timeout(options.test_timeout);
+    let system = built.on().clone();
+    let reboot = match activate_configuration(&built, options, report).await? {
+        Activated::Running => return Ok(()),
+        Activated::Installed(reboot) => reboot,
+    };
+    let built = match reboot {
+        None => built,
+        Some(method) => {
+            if let Some(prompter) = &options.ask {
+                prompter.confirm(&format!("{system:?}"), "Reboot").await?;
+            }
+            let rebooted = run_step(
+                &system,
+                Step::Reboot,
+                report,
+                deploy_flake::reboot(
+                    &system,
+                    method,
+                    built.configuration(),
+                    options.reboot_timeout,
+                ),
+            )
+            .await?;
+            run_step(
+                &rebooted,
+                Step::RebootHealthCheck,
+                report,
+                deploy_flake::check_system_health(
+                    &rebooted,
+                    options.health_check,
+                    options.health_check_timeout,
+                ),
+            )
+            .await?;
+            log::info!("Rebooted into the new configuration");
+            built.with_system(rebooted)
+        }
+    };
+    prune(&built, options, report).await
+}
+
+/// What activating a configuration did on its destination.
+enum Activated {
+    /// The configuration is (at most) running, but won't get booted.
+    Running,
+
+    /// The configuration got installed as the boot configuration,
+    /// and the destination should reboot into it like this, if at all.
+    Installed(Option<RebootMethod>),
+}
+
+/// Activates the configuration.
+async fn activate_configuration(
+    built: &SystemConfiguration,
+    options: &ActivateOptions,
+    report: &SharedHostReport,
+) -> Result<Activated, anyhow::Error> {
+    let host = format!("{:?}", built.on());
+    let activation = options.activation();
+    if activation == Activation::Switch && options.confirm_timeout.is_some() {
+        anyhow::bail!("Automatic rollbacks with --confirm-timeout can not undo a switch, use --activation=test-then-boot");
+    }
+    if activation == Activation::TestOnly && built.on().flavor() == Flavor::Darwin {
+        // darwin-rebuild has no way to activate a configuration only
+        // until the next reboot:
+        anyhow::bail!("nix-darwin can not activate a configuration without keeping it, use --activation=test-then-boot");
+    }
+    if !options.force && built.is_current().await? {
+        log::info!(configuration=?built.configuration(), "Already up to date, not activating");
+        report.lock().unwrap().outcome = Outcome::UpToDate;
+        return Ok(Activated::Running);
+    }
+    if let Err(error) = built.record_provenance().await {
+        // The record only helps tell deploys apart later, so it
+        // shouldn't keep the configuration from getting activated:
+        log::warn!(error = %format!("{error:#}"), "Could not record the deploy in the journal");
+    }
+    built.record_previous_system().await?;
+    if !options.snapshots.is_empty() {
+        let snapshots = run_step(
+            built.on(),
+            Step::Snapshot,
+            report,
+            built.take_snapshots(&options.snapshots),
+        )
+        .await?;
+        report.lock().unwrap().snapshots = snapshots;
+    }
+    if activation.changes_running_system() {
+        let changes = if options.show_changes {
+            in_phase(
+                "dry-activate",
+                built.on().host(),
+                built.on().options(),
+                show_changes(built),
+            )
+            .await?
+        } else {
+            String::new()
+        };
+        match &options.ask {
+            Some(prompter) => {
+                prompter
+                    .confirm_showing(
+                        &host,
+                        &format!(
+                            "Activate the configuration ({})",
+                            activation_step(activation).name()
+                        ),
+                        &changes,
+                    )
+                    .await?
+            }
+            None if !changes.is_empty() => {
+                tracing_indicatif::suspend_tracing_indicatif(|| eprint!("{changes}"))
+            }
+            None => {}
+        }
+    }
+    if let Some(drain) = &options.drain {
+        run_step(built.on(), Step::Drain, report, run_hook(drain, built.on())).await?;
+    }
+    let tested = async {
+        if activation.changes_running_system() {
+            log::event!(log::Level::DEBUG, configuration=?built.configuration(), system_name=?built.for_system(), ?activation, "Testing");
+            let rollback = match options.confirm_timeout {
+                Some(within) => Some(built.schedule_rollback(within).await?),
+                None => None,
+            };
+            if activation == Activation::Switch {
+                run_step(built.on(), Step::SetProfile, report, built.set_profile(None)).await?;
+            }
+            let tested = run_step(
+                built.on(),
+                activation_step(activation),
+                report,
+                test_config(built, activation, options),
+            )
+            .await;
+            if tested.as_ref().is_err_and(|e| e.is::<Interrupted>()) {
+                // The activation runs in the background on the
+                // destination, and would carry on without this:
+                if let Err(error) = built.stop_activation().await {
+                    log::warn!(%error, "Could not stop the activation");
+                }
+            }
+            tested?;
+            if let Some(rollback) = rollback {
+                run_step(built.on(), Step::Confirm, report, rollback.confirm()).await?;
+            }
+            if options.post_test_check == Behavior::Run {
+                run_step(
+                    built.on(),
+                    Step::HealthCheck,
+                    report,
+                    built.preflight_check_system(options.health_check, options.health_check_timeout),
+                )
+                .await?;
+            }
+        } else {
+            log::event!(log::Level::DEBUG, configuration=?built.configuration(), system_name=?built.for_system(), "Skipping test");
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if tested.is_err() && options.drain.is_some() {
+        log::warn!("Leaving the host drained, since the new configuration failed");
+    }
+    tested?;
+    if let Some(undrain) = &options.undrain {
+        run_step(
+            built.on(),
+            Step::Undrain,
+            report,
+            run_hook(undrain, built.on()),
+        )
+        .await?;
+    }
+    if !activation.installs_boot_config() {
+        log::event!(log::Level::INFO, configuration=?built.configuration(), system_name=?built.for_system(), ?activation, "Successfully activated");
+        if activation != Activation::Switch {
+            return Ok(Activated::Running);
+        }
+        return Ok(Activated::Installed(reboot_method(built, options).await?));
+    }
+    if let Some(prompter) = &options.ask {
+        prompter
+            .confirm(&host, "Install the boot configuration")
+            .await?;
+    }
     log::event!(log::Level::DEBUG, configuration=?built.configuration(), system_name=?built.for_system(), "Activating");
-    built.boot_config().await?;
+    let deadline = options
+        .activate_timeout
+        .map(|timeout| (tokio::time::Instant::now() + timeout, timeout));
+    if options.boot_dry_run == Behavior::Run {
+        run_step(
+            built.on(),
+            Step::BootDryRun,
+            report,
+            until(deadline, |timeout| built.boot_dry_run(timeout)),
+        )
+        .await?;
+    }
+    run_step(
+        built.on(),
+        Step::SetProfile,
+        report,
+        until(deadline, |timeout| built.set_profile(timeout)),
+    )
+    .await?;
+    run_step(
+        built.on(),
+        Step::UpdateBoot,
+        report,
+        until(deadline, |timeout| built.update_boot(timeout)),
+    )
+    .await?;
     log::event!(log::Level::INFO, configuration=?built.configuration(), system_name=?built.for_system(), "Successfully activated");
+    Ok(Activated::Installed(reboot_method(built, options).await?))
+}
+
+/// Returns how a destination should reboot into the configuration
+/// that was just installed as its boot configuration, if at all.
+async fn reboot_method(
+    built: &SystemConfiguration,
+    options: &ActivateOptions,
+) -> Result<Option<RebootMethod>, anyhow::Error> {
+    let reasons = match (options.reboot, options.reboot_method) {
+        (Reboot::Never, _) => return Ok(None),
+        (Reboot::Always, RebootMethod::Reboot | RebootMethod::Kexec) => vec![],
+        _ => built.reboot_reasons().await?,
+    };
+    if options.reboot == Reboot::IfNeeded {
+        if reasons.is_empty() {
+            log::info!("No reboot needed");
+            return Ok(None);
+        }
+        log::info!(?reasons, "Reboot needed");
+    }
+    let method = options.reboot_method;
+    if !reasons.iter().all(|reason| method.applies(reason)) {
+        log::info!(
+            ?reasons,
+            ?method,
+            "A soft reboot keeps the running kernel, rebooting fully"
+        );
+        return Ok(Some(RebootMethod::Reboot));
+    }
+    Ok(Some(method))
+}
+
+/// Deletes the old generations that `--keep-generations` or
+/// `--delete-older-than` select from a destination whose boot
+/// configuration was just installed, and collects garbage if asked.
+async fn prune(
+    built: &SystemConfiguration,
+    options: &ActivateOptions,
+    report: &SharedHostReport,
+) -> Result<(), anyhow::Error> {
+    let Some(pruning) = options.pruning else {
+        return Ok(());
+    };
+    run_step(
+        built.on(),
+        Step::Prune,
+        report,
+        built.prune_generations(pruning),
+    )
+    .await?;
+    if options.collect_garbage {
+        run_step(
+            built.on(),
+            Step::CollectGarbage,
+            report,
+            built.collect_garbage(),
+        )
+        .await?;
+    }
     Ok(())
 }
+
+/// Describes the unit and package changes that activating a
+/// configuration would make on its destination.
+async fn show_changes(built: &SystemConfiguration) -> Result<String, anyhow::Error> {
+    let host = built.on().host();
+    let mut changes = format!("{host}: activating the configuration would\n");
+    write!(changes, "{}", built.dry_activate().await?)?;
+    if let Some(diff) = built.package_diff().await? {
+        writeln!(changes, "{host}: package changes:")?;
+        for line in diff.lines() {
+            writeln!(changes, "  {line}")?;
+        }
+    }
+    Ok(changes)
+}
+
+/// Returns the step that activates a configuration on the running
+/// system.
+fn activation_step(activation: Activation) -> Step {
+    match activation {
+        Activation::Switch => Step::Switch,
+        _ => Step::Test,
+    }
+}
+
+/// Tests (or switches to) a configuration, trying once more if it
+/// failed only because units that are known to be flaky failed to
+/// start.
+async fn test_config(
+    built: &SystemConfiguration,
+    activation: Activation,
+    options: &ActivateOptions,
+) -> Result<(), anyhow::Error> {
+    let policy = RetryPolicy {
+        max_retries: usize::from(!options.retry_test_units.is_empty()),
+        attempt_timeout: None,
+        min_delay: options.retry_test_delay,
+        max_delay: options.retry_test_delay,
+    };
+    retrying(
+        "Testing",
+        &policy,
+        |e| {
+            e.downcast_ref::<UnitsFailed>()
+                .is_some_and(|failed| failed.all_match(&options.retry_test_units))
+        },
+        || async move {
+            match activation {
+                Activation::Switch => built.switch_config().await,
+                _ => built.test_config().await,
+            }
+        },
+    )
+    .await
+}
+
+/// Runs the operation that `f` returns, failing if it doesn't finish
+/// before the deadline (if any), which is given along with the
+/// timeout it was derived from. `f` gets the time that is left until
+/// the deadline, so that the commands the operation runs on the
+/// destination get stopped there, too.
+async fn until<T, Fut>(
+    deadline: Option<(tokio::time::Instant, Duration)>,
+    f: impl FnOnce(Option<Duration>) -> Fut,
+) -> Result<T, anyhow::Error>
+where
+    Fut: std::future::Future<Output = Result<T, anyhow::Error>>,
+{
+    match deadline {
+        None => f(None).await,
+        Some((deadline, timeout)) => {
+            let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+            tokio::time::timeout_at(deadline, f(Some(left)))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("Timed out after {}", humantime::format_duration(timeout))
+                })?
+        }
+    }
+}
+
+/// Runs a step of activating a configuration, recording in the
+/// report how long it took or, if it fails, the state that the
+/// destination is left in.
+async fn run_step<T>(
+    on: &Nixos,
+    step: Step,
+    report: &SharedHostReport,
+    f: impl std::future::Future<Output = Result<T, anyhow::Error>>,
+) -> Result<T, anyhow::Error> {
+    let started = std::time::Instant::now();
+    let result = unless_interrupted(in_phase(step.name(), on.host(), on.options(), f)).await;
+    let mut report = report.lock().unwrap();
+    if result.is_ok() {
+        let elapsed = started.elapsed();
+        log::debug!(step = step.name(), elapsed = %humantime::format_duration(elapsed), "Activation step completed");
+        report.completed_steps.push(CompletedStep {
+            step,
+            seconds: elapsed.as_secs_f64(),
+        });
+    } else {
+        let state = step.state_on_failure();
+        log::error!(step = step.name(), state, "Activation step failed");
+        report.failed_step = Some(step);
+        report.host_state = Some(state.to_string());
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{planned_steps, Opts};
+    use clap::Parser;
+    use test_case::test_case;
+
+    fn steps(args: &[&str]) -> Vec<&'static str> {
+        let opts = Opts::try_parse_from(
+            ["deploy-flake", "--preflight-check=skip"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap();
+        planned_steps(
+            &opts.prepare.options(),
+            &opts.activate.options(&opts.prepare.health),
+        )
+    }
+
+    #[test_case(&["--keep-generations=3"] => vec!["test", "boot-dry-run", "set-profile", "update-boot", "prune"]; "pruning")]
+    #[test_case(&["--delete-older-than=30d", "--collect-garbage"] => vec!["test", "boot-dry-run", "set-profile", "update-boot", "prune", "collect-garbage"]; "collecting garbage")]
+    #[test_case(&["--keep-generations=3", "--collect-garbage", "--reboot=always"] => vec!["test", "boot-dry-run", "set-profile", "update-boot", "reboot", "reboot-health-check", "prune", "collect-garbage"]; "pruning after rebooting")]
+    #[test_case(&["--keep-generations=3", "--activation=test"] => vec!["test"]; "nothing to prune")]
+    fn planned_pruning(args: &[&str]) -> Vec<&'static str> {
+        steps(args)
+    }
+
+    #[test_case(&["--keep-generations=1"]; "keeping one generation")]
+    #[test_case(&["--collect-garbage"]; "collecting garbage without pruning")]
+    fn rejected(args: &[&str]) {
+        assert!(Opts::try_parse_from(["deploy-flake"].iter().chain(args)).is_err());
+    }
+}
@@ -1,21 +1,102 @@
 use std::{
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
+use tokio::io::AsyncReadExt;
+use tracing::Instrument;
 
 use anyhow::Context;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::collections::HashMap;
+
+/// Which nix the nix commands that run on the machine running
+/// deploy-flake use.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub enum LocalNix {
+    /// The nix (and its tools) found on `PATH`.
+    #[default]
+    System,
+
+    /// The nix provided by the default devShell of the flake with
+    /// this reference (like an absolute directory path, since
+    /// commands may run in other directories), so that everyone
+    /// deploying the flake uses the same nix version. Commands run
+    /// via `nix develop --command` (see [`DEV_SHELL_SCRIPT`]).
+    DevShell(String),
+}
+
+/// Runs the command line in "$@" in the devShell of the flake in $0,
+/// with what the devShell's shellHook prints going to stderr, so that
+/// only the command's output ends up on stdout.
+const DEV_SHELL_SCRIPT: &str = r#"exec 3>&1 1>&2
+exec nix develop "$0" --command sh -c 'exec 1>&3 3>&-; exec "$@"' sh "$@""#;
+
+impl LocalNix {
+    /// Returns a command that runs `program` (like `nix` or
+    /// `nix-copy-closure`) with this nix.
+    pub fn command(&self, program: &str) -> Command {
+        match self {
+            LocalNix::System => Command::new(program),
+            LocalNix::DevShell(flake) => {
+                let mut cmd = Command::new("sh");
+                cmd.args(["-c", DEV_SHELL_SCRIPT, flake, program]);
+                cmd
+            }
+        }
+    }
+}
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct FlakeInfo {
     pub(crate) path: PathBuf,
+
+    /// The git revision of the flake, unless its tree is dirty.
+    #[serde(default)]
+    pub(crate) revision: Option<String>,
+
+    /// The git revision that a dirty tree is based on, suffixed with
+    /// `-dirty`. Only newer nix versions report it.
+    #[serde(default)]
+    pub(crate) dirty_revision: Option<String>,
+
+    /// The locked reference to the flake's source.
+    #[serde(default)]
+    pub(crate) locked: Option<LockedRef>,
+
+    /// The locked flake reference as a URL, which fetches exactly
+    /// this source.
+    #[serde(default)]
+    pub(crate) url: Option<String>,
+}
+
+/// The locked reference to a flake's source, as reported by `nix
+/// flake info --json`.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LockedRef {
+    /// The type of source, like "git" or "path".
+    #[serde(rename = "type")]
+    pub(crate) source_type: String,
+
+    /// The hash of the source tree's NAR serialization.
+    #[serde(default)]
+    pub(crate) nar_hash: Option<String>,
 }
 
 impl FlakeInfo {
-    pub(crate) fn from_path<P: AsRef<Path>>(p: P) -> Result<Self, anyhow::Error> {
-        let output = Command::new("nix")
+    pub(crate) fn from_path<P: AsRef<Path>>(
+        p: P,
+        local_nix: &LocalNix,
+    ) -> Result<Self, anyhow::Error> {
+        let path = p
+            .as_ref()
+            .canonicalize()
+            .context("Could not resolve the flake's directory")?;
+        let output = local_nix
+            .command("nix")
             .args(["flake", "info", "--json"])
-            .current_dir(p)
+            .arg(path)
             .output()
             .context("Could not execute nix flake info")?;
         if !output.status.success() {
@@ -26,4 +107,203 @@ impl FlakeInfo {
         }
         Ok(serde_json::from_slice(&output.stdout)?)
     }
+
+    /// Looks up an arbitrary flake reference (like
+    /// `github:owner/repo`), fetching it into the local store.
+    pub(crate) fn from_ref(reference: &str, local_nix: &LocalNix) -> Result<Self, anyhow::Error> {
+        let output = local_nix
+            .command("nix")
+            .args(["flake", "metadata", "--json", reference])
+            .output()
+            .context("Could not execute nix flake metadata")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "nix flake metadata failed:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+}
+
+/// Evaluates a flake attribute with `nix eval --json`, returning the
+/// deserialized result.
+pub(crate) async fn eval_json<T: DeserializeOwned>(
+    installable: &str,
+    local_nix: &LocalNix,
+) -> Result<T, anyhow::Error> {
+    let output = tokio::process::Command::from(local_nix.command("nix"))
+        .args(["eval", "--json", installable])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Could not execute nix eval")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "nix eval {installable} failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Could not parse the value of {installable}"))
+}
+
+/// Metadata about a single store path, via `nix path-info --json`.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PathInfo {
+    #[serde(default)]
+    pub(crate) path: PathBuf,
+    pub(crate) nar_size: u64,
+}
+
+/// The two shapes of output that `nix path-info --json` produces:
+/// older nix versions emit a list of objects, newer ones emit an
+/// object keyed by store path.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PathInfoOutput {
+    List(Vec<PathInfo>),
+    Map(HashMap<PathBuf, PathInfo>),
+}
+
+impl PathInfo {
+    /// Returns the path info of every store path in the closure of `path`.
+    pub(crate) async fn closure_of(
+        path: &Path,
+        local_nix: &LocalNix,
+    ) -> Result<Vec<Self>, anyhow::Error> {
+        let output = tokio::process::Command::from(local_nix.command("nix"))
+            .args(["path-info", "--recursive", "--json"])
+            .arg(path)
+            .output()
+            .await
+            .context("Could not execute nix path-info")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "nix path-info failed:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(match serde_json::from_slice(&output.stdout)? {
+            PathInfoOutput::List(infos) => infos,
+            PathInfoOutput::Map(infos) => infos
+                .into_iter()
+                .map(|(path, info)| PathInfo { path, ..info })
+                .collect(),
+        })
+    }
+}
+
+/// The part of `nix build --json` output that tells where a build
+/// result lives.
+#[derive(Deserialize, Debug)]
+struct BuildResult {
+    outputs: BuildOutputs,
+}
+
+#[derive(Deserialize, Debug)]
+struct BuildOutputs {
+    out: PathBuf,
+}
+
+/// Builds an installable on the machine running deploy-flake,
+/// logging nix's output, and returns the path of the build result.
+pub(crate) async fn build(
+    installable: &str,
+    options: &crate::BuildOptions,
+    local_nix: &LocalNix,
+    remote_options: &crate::RemoteOptions,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut child = tokio::process::Command::from(local_nix.command("nix"))
+        .args(["build", "--no-link", "--json"])
+        .args(options.nix_args())
+        .arg(installable)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Could not execute nix build")?;
+    let stderr_read = tokio::task::spawn(
+        crate::read_and_log_messages(
+            crate::subprocess::Stream::Stderr,
+            child.stderr.take().unwrap(),
+            remote_options,
+        )
+        .instrument(tracing::Span::current()),
+    );
+    let mut stdout = vec![];
+    let mut child_stdout = child.stdout.take().unwrap();
+    let (status, _, read) = futures::join!(
+        child.wait(),
+        stderr_read,
+        child_stdout.read_to_end(&mut stdout)
+    );
+    read?;
+    if !status?.success() {
+        anyhow::bail!("nix build failed");
+    }
+    let mut results: Vec<BuildResult> = serde_json::from_slice(&stdout)?;
+    match (results.pop(), results.is_empty()) {
+        (Some(result), true) => Ok(result.outputs.out),
+        _ => anyhow::bail!("nix build did not return exactly one result"),
+    }
+}
+
+/// Runs `nix build --dry-run` for an installable locally, returning
+/// its output.
+pub(crate) async fn dry_build(
+    installable: &str,
+    options: &crate::BuildOptions,
+    local_nix: &LocalNix,
+) -> Result<Vec<String>, anyhow::Error> {
+    let output = tokio::process::Command::from(local_nix.command("nix"))
+        .args(["build", "--dry-run", "--no-link"])
+        .args(options.nix_args())
+        .arg(installable)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Could not execute nix build --dry-run")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix build --dry-run failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::DEV_SHELL_SCRIPT;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn dev_shell_hook_output() {
+        // A nix that runs `nix develop FLAKE --command ...` after
+        // printing something, like a shellHook might:
+        let dir = tempfile::tempdir().unwrap();
+        let nix = dir.path().join("nix");
+        std::fs::write(&nix, "#!/bin/sh\nshift 3\necho hook\nexec \"$@\"\n").unwrap();
+        std::fs::set_permissions(&nix, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = format!(
+            "{}:{}",
+            dir.path().display(),
+            std::env::var("PATH").unwrap()
+        );
+        let output = std::process::Command::new("sh")
+            .args(["-c", DEV_SHELL_SCRIPT, "/src/flake", "echo", "output"])
+            .env("PATH", path)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "output\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "hook\n");
+    }
 }
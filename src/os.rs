@@ -1,5 +1,7 @@
+mod darwin;
 mod nixos;
 
+use serde::Serialize;
 use std::{
     fmt,
     path::{Path, PathBuf},
@@ -7,16 +9,111 @@ use std::{
 
 pub use nixos::Nixos;
 
+/// Facts about a system, gathered once per connection and then
+/// reused by every phase of the deployment.
+#[derive(Serialize, PartialEq, Eq, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct HostFacts {
+    /// The hostname that the system reports.
+    pub hostname: String,
+
+    /// The version of nix installed on the system.
+    pub nix_version: String,
+
+    /// The system's CPU architecture, as reported by `uname -m`.
+    pub architecture: String,
+
+    /// The experimental features (like `flakes`) that the system's
+    /// nix configuration enables. Empty if nix can't tell, e.g.
+    /// because it doesn't enable `nix-command`.
+    pub nix_features: Vec<String>,
+
+    /// The free disk space (in bytes) on the nix store's filesystem.
+    pub free_store_bytes: u64,
+
+    /// The free disk space (in bytes) on /boot's filesystem, if the
+    /// system has a /boot.
+    pub free_boot_bytes: Option<u64>,
+
+    /// The store path of the currently running system, if any.
+    pub current_system: Option<PathBuf>,
+
+    /// The number of the "system" profile's current generation, if
+    /// there is one.
+    pub current_generation: Option<u64>,
+
+    /// The NixOS version of the currently running system, if known.
+    pub nixos_version: Option<String>,
+}
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Verb {
     Test,
+    Switch,
     Build,
     Boot,
+    DryActivate,
+}
+
+/// The changes to systemd units that activating a configuration
+/// would make, as reported by a dry activation.
+#[derive(Serialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UnitChanges {
+    /// Units that would be stopped.
+    pub stop: Vec<String>,
+
+    /// Units that changed but would not be stopped.
+    pub not_stopped: Vec<String>,
+
+    /// Units that would be restarted.
+    pub restart: Vec<String>,
+
+    /// Units that would be reloaded.
+    pub reload: Vec<String>,
+
+    /// Units that would be started.
+    pub start: Vec<String>,
+}
+
+impl UnitChanges {
+    /// Returns whether activating the configuration would leave all
+    /// units alone.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
 }
 
-pub(crate) trait NixOperatingSystem: fmt::Debug {
-    /// Checks if the target system is able to be deployed to.
-    async fn preflight_check_system(&self) -> Result<(), anyhow::Error>;
+impl fmt::Display for UnitChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "  no unit changes");
+        }
+        for (what, units) in [
+            ("stop", &self.stop),
+            ("not stop (changed)", &self.not_stopped),
+            ("restart", &self.restart),
+            ("reload", &self.reload),
+            ("start", &self.start),
+        ] {
+            if !units.is_empty() {
+                writeln!(f, "  would {what}: {}", units.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+pub(crate) trait NixOperatingSystem: fmt::Debug + Send + Sync {
+    /// Checks if the target system is able to be deployed to,
+    /// waiting at most `timeout` for a system that is still starting
+    /// up to settle.
+    async fn preflight_check_system(
+        &self,
+        method: crate::HealthCheck,
+        timeout: std::time::Duration,
+    ) -> Result<(), anyhow::Error>;
 
     /// Checks if the built closure can be deployed to the system.
     async fn preflight_check_closure(
@@ -32,16 +129,173 @@ pub(crate) trait NixOperatingSystem: fmt::Debug {
         &self,
         flake: &crate::Flake,
         config_name: Option<&str>,
-        build_cmdline: Vec<String>,
+        options: &crate::BuildOptions,
     ) -> Result<(PathBuf, String), anyhow::Error>;
 
-    /// Sets the built system as the current "system" profile
-    /// generation, without activation.
-    async fn set_as_current_generation(&self, derivation: &Path) -> Result<(), anyhow::Error>;
+    /// Returns those of the given store paths that are not valid
+    /// (i.e., not present) in the system's nix store.
+    async fn missing_store_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, anyhow::Error>;
+
+    /// Registers the derivation as a GC root with the given name, so
+    /// that it survives garbage collection on the system.
+    async fn add_gc_root(&self, derivation: &Path, name: &str) -> Result<(), anyhow::Error>;
+
+    /// Returns the store path that the GC root with the given name
+    /// points to, if it exists.
+    async fn gc_root(&self, name: &str) -> Result<Option<PathBuf>, anyhow::Error>;
+
+    /// Removes the GC root with the given name, if it exists.
+    async fn remove_gc_root(&self, name: &str) -> Result<(), anyhow::Error>;
+
+    /// Returns the store path of the current "system" profile
+    /// generation, if there is one.
+    async fn current_generation(&self) -> Result<Option<PathBuf>, anyhow::Error>;
+
+    /// Returns when the running system configuration was installed
+    /// as the "system" profile's current generation, if the system
+    /// runs that generation.
+    async fn current_system_since(&self) -> Result<Option<std::time::SystemTime>, anyhow::Error>;
+
+    /// Returns the number and store path of the "system" profile
+    /// generation before the current one, if there is one.
+    async fn previous_generation(&self) -> Result<Option<(u64, PathBuf)>, anyhow::Error>;
+
+    /// Points the "system" profile back at an existing generation,
+    /// without activation, stopping once `timeout` (if any) is up.
+    async fn switch_generation(
+        &self,
+        number: u64,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Deletes the generations of the "system" profile (or the named
+    /// system profile) that `pruning` selects. The current generation
+    /// always stays.
+    async fn delete_generations(
+        &self,
+        profile_name: Option<&str>,
+        pruning: crate::Pruning,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Deletes the store paths that no GC root keeps alive.
+    async fn collect_garbage(&self) -> Result<(), anyhow::Error>;
+
+    /// Returns the components of the configuration (like `kernel`)
+    /// that only take effect once the system reboots into it, because
+    /// they differ from the booted system's.
+    async fn reboot_reasons(&self, derivation: &Path) -> Result<Vec<String>, anyhow::Error>;
 
-    /// Test the flake's system configuration on the live system.
-    async fn test_config(&self, derivation: &Path) -> Result<(), anyhow::Error>;
+    /// Returns an identifier that changes whenever the system boots
+    /// (or only restarts userspace, with a soft reboot).
+    async fn boot_id(&self) -> Result<String, anyhow::Error>;
 
-    /// Update the system's boot menu to include the configuration as the default boot entry.
-    async fn update_boot_for_config(&self, derivation: &Path) -> Result<(), anyhow::Error>;
+    /// Reboots the system into the configuration, which must be its
+    /// boot configuration already. Returns before the system goes
+    /// down.
+    async fn reboot(
+        &self,
+        method: crate::RebootMethod,
+        derivation: &Path,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Records in the system's journal that the configuration is
+    /// about to get activated, by which deploy run, and from which
+    /// source (see [`crate::Flake::provenance`]).
+    async fn record_provenance(
+        &self,
+        derivation: &Path,
+        source: Option<&str>,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Takes a filesystem snapshot called `name`, returning the
+    /// snapshot's full name.
+    async fn take_snapshot(
+        &self,
+        snapshot: &crate::snapshot::Snapshot,
+        name: &str,
+    ) -> Result<String, anyhow::Error>;
+
+    /// Returns the NixOS version of a built system configuration, if
+    /// it records one.
+    async fn nixos_version(&self, derivation: &Path) -> Result<Option<String>, anyhow::Error>;
+
+    /// Sets the built system as the current generation of the
+    /// "system" profile (or of the named profile under
+    /// `system-profiles`), without activation, stopping once `timeout`
+    /// (if any) is up.
+    async fn set_as_current_generation(
+        &self,
+        derivation: &Path,
+        profile_name: Option<&str>,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Schedules activating the `previous` configuration on the live
+    /// system after `delay`, as a unit called `name`, unless the
+    /// rollback gets cancelled before then. The rollback never runs
+    /// while the configuration `from` is being activated.
+    async fn schedule_rollback(
+        &self,
+        previous: &Path,
+        from: &Path,
+        name: &str,
+        delay: std::time::Duration,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Cancels a rollback scheduled with
+    /// [`NixOperatingSystem::schedule_rollback`].
+    async fn cancel_rollback(&self, name: &str) -> Result<(), anyhow::Error>;
+
+    /// Performs a rollback scheduled with
+    /// [`NixOperatingSystem::schedule_rollback`] right away.
+    async fn trigger_rollback(&self, name: &str) -> Result<(), anyhow::Error>;
+
+    /// Test the flake's system configuration on the live system,
+    /// stopping the activation if it takes longer than `timeout`.
+    async fn test_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Activate the flake's system configuration on the live system
+    /// and install it as the default boot entry in one go, stopping
+    /// the activation if it takes longer than `timeout`. The
+    /// configuration must already be the system profile's current
+    /// generation.
+    async fn switch_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Stops activating the configuration, if it is being tested
+    /// or switched to in the background.
+    async fn stop_activation(&self, derivation: &Path) -> Result<(), anyhow::Error>;
+
+    /// Reports the changes to units that activating the
+    /// configuration would make, without changing anything.
+    async fn dry_activate(&self, derivation: &Path) -> Result<UnitChanges, anyhow::Error>;
+
+    /// Describes how the packages in the configuration differ from
+    /// those of the running system, if the system has `nvd`
+    /// installed.
+    async fn package_diff(&self, derivation: &Path) -> Result<Option<String>, anyhow::Error>;
+
+    /// Checks that the system's state is consistent: that it runs
+    /// the system profile's current generation, and so on. Returns
+    /// the inconsistencies it found.
+    async fn inconsistencies(&self) -> Result<Vec<String>, anyhow::Error>;
+
+    /// Returns the last deploy that deploy-flake made to the system,
+    /// according to the provenance record that it left there.
+    async fn last_deploy(&self) -> Result<Option<crate::fleet::Deployed>, anyhow::Error>;
+
+    /// Update the system's boot menu to include the configuration as the default boot entry,
+    /// stopping once `timeout` (if any) is up.
+    async fn update_boot_for_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<(), anyhow::Error>;
 }
@@ -0,0 +1,263 @@
+use anyhow::Context;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing as log;
+use tracing::instrument;
+
+use super::nixos::{NixosSystem, SYSTEM_PROFILE};
+use crate::{snapshot::Snapshot, HealthCheck, NixOperatingSystem, Nixos, UnitChanges};
+use async_trait::async_trait;
+
+/// A nix-darwin system, reached over the connection of a [`Nixos`]
+/// with the [`Darwin`](crate::Flavor::Darwin) flavor.
+///
+/// nix-darwin has no boot loader to update and no separate "test"
+/// activation: a configuration gets activated with `darwin-rebuild
+/// activate`, and stays active until another one gets activated.
+#[derive(Debug)]
+pub(crate) struct Darwin<'a>(pub(crate) &'a Nixos);
+
+#[async_trait]
+impl NixOperatingSystem for Darwin<'_> {
+    #[instrument(level = "INFO", err)]
+    async fn preflight_check_system(
+        &self,
+        method: HealthCheck,
+        _timeout: Duration,
+    ) -> Result<(), anyhow::Error> {
+        log::event!(
+            log::Level::DEBUG,
+            ?method,
+            "nix-darwin has no system health check, skipping it"
+        );
+        Ok(())
+    }
+
+    async fn preflight_check_closure(
+        &self,
+        derivation: &Path,
+        script: Option<&Path>,
+    ) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0)
+            .preflight_check_closure(derivation, script)
+            .await
+    }
+
+    async fn build_flake(
+        &self,
+        flake: &crate::Flake,
+        config_name: Option<&str>,
+        options: &crate::BuildOptions,
+    ) -> Result<(PathBuf, String), anyhow::Error> {
+        self.0
+            .build_config(config_name, options, |hostname| {
+                flake.darwin_system_config(hostname)
+            })
+            .await
+    }
+
+    async fn missing_store_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, anyhow::Error> {
+        NixosSystem(self.0).missing_store_paths(paths).await
+    }
+
+    async fn add_gc_root(&self, derivation: &Path, name: &str) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0).add_gc_root(derivation, name).await
+    }
+
+    async fn gc_root(&self, name: &str) -> Result<Option<PathBuf>, anyhow::Error> {
+        NixosSystem(self.0).gc_root(name).await
+    }
+
+    async fn remove_gc_root(&self, name: &str) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0).remove_gc_root(name).await
+    }
+
+    async fn current_generation(&self) -> Result<Option<PathBuf>, anyhow::Error> {
+        NixosSystem(self.0).current_generation().await
+    }
+
+    async fn current_system_since(&self) -> Result<Option<SystemTime>, anyhow::Error> {
+        // macOS's stat takes BSD-style arguments:
+        self.0.profile_since(&["-f", "%m"]).await
+    }
+
+    async fn previous_generation(&self) -> Result<Option<(u64, PathBuf)>, anyhow::Error> {
+        NixosSystem(self.0).previous_generation().await
+    }
+
+    async fn switch_generation(
+        &self,
+        number: u64,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0).switch_generation(number, timeout).await
+    }
+
+    async fn delete_generations(
+        &self,
+        profile_name: Option<&str>,
+        pruning: crate::Pruning,
+    ) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0)
+            .delete_generations(profile_name, pruning)
+            .await
+    }
+
+    async fn collect_garbage(&self) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0).collect_garbage().await
+    }
+
+    async fn reboot_reasons(&self, _derivation: &Path) -> Result<Vec<String>, anyhow::Error> {
+        // A nix-darwin configuration is all activated in place:
+        Ok(vec![])
+    }
+
+    async fn boot_id(&self) -> Result<String, anyhow::Error> {
+        anyhow::bail!("Rebooting is not supported on nix-darwin")
+    }
+
+    async fn reboot(
+        &self,
+        _method: crate::RebootMethod,
+        _derivation: &Path,
+    ) -> Result<(), anyhow::Error> {
+        anyhow::bail!("Rebooting is not supported on nix-darwin")
+    }
+
+    async fn record_provenance(
+        &self,
+        derivation: &Path,
+        source: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        NixosSystem(self.0)
+            .record_provenance(derivation, source)
+            .await
+    }
+
+    async fn take_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        name: &str,
+    ) -> Result<String, anyhow::Error> {
+        NixosSystem(self.0).take_snapshot(snapshot, name).await
+    }
+
+    async fn nixos_version(&self, _derivation: &Path) -> Result<Option<String>, anyhow::Error> {
+        Ok(None)
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn set_as_current_generation(
+        &self,
+        derivation: &Path,
+        profile_name: Option<&str>,
+        _timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(name) = profile_name {
+            anyhow::bail!("nix-darwin does not support named system profiles like {name:?}");
+        }
+        // macOS has no `timeout` command, so only deploy-flake stops
+        // waiting for this once the time is up:
+        let mut cmd = self.0.elevated();
+        cmd.args(["nix-env", "-p", SYSTEM_PROFILE, "--set"])
+            .arg(derivation.to_string_lossy());
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not set {derivation:?} as the current generation"))
+    }
+
+    async fn schedule_rollback(
+        &self,
+        _previous: &Path,
+        _from: &Path,
+        _name: &str,
+        _delay: Duration,
+    ) -> Result<(), anyhow::Error> {
+        anyhow::bail!("Automatic rollbacks are not supported on nix-darwin")
+    }
+
+    async fn cancel_rollback(&self, _name: &str) -> Result<(), anyhow::Error> {
+        anyhow::bail!("Automatic rollbacks are not supported on nix-darwin")
+    }
+
+    async fn trigger_rollback(&self, _name: &str) -> Result<(), anyhow::Error> {
+        anyhow::bail!("Automatic rollbacks are not supported on nix-darwin")
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn test_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        // darwin-rebuild activates the system configuration that it
+        // is a part of:
+        self.0.log_activation_env().await;
+        let mut cmd = self.0.elevated();
+        self.0.clean_env(&mut cmd);
+        cmd.arg(derivation.join("sw/bin/darwin-rebuild").to_string_lossy())
+            .arg("activate");
+        let activation = self.0.run_command(cmd);
+        let result = match timeout {
+            None => activation.await,
+            Some(timeout) => tokio::time::timeout(timeout, activation)
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("Timed out after {}", humantime::format_duration(timeout))
+                })?,
+        };
+        result.with_context(|| format!("Activating the system closure {derivation:?} failed"))
+    }
+
+    async fn switch_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        // With no boot entries to update, switching is the same as
+        // testing:
+        self.test_config(derivation, timeout).await
+    }
+
+    async fn stop_activation(&self, _derivation: &Path) -> Result<(), anyhow::Error> {
+        // darwin-rebuild runs in the foreground of its ssh session,
+        // so it stops along with that:
+        Ok(())
+    }
+
+    async fn dry_activate(&self, derivation: &Path) -> Result<UnitChanges, anyhow::Error> {
+        anyhow::bail!("nix-darwin can not dry-activate {derivation:?}")
+    }
+
+    async fn package_diff(&self, derivation: &Path) -> Result<Option<String>, anyhow::Error> {
+        NixosSystem(self.0).package_diff(derivation).await
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn inconsistencies(&self) -> Result<Vec<String>, anyhow::Error> {
+        // nix-darwin has no boot entries or failed units to check:
+        Ok(self.0.profile_inconsistency().await?.into_iter().collect())
+    }
+
+    async fn last_deploy(&self) -> Result<Option<crate::fleet::Deployed>, anyhow::Error> {
+        // The provenance record goes to macOS's unified log, which
+        // deploy-flake doesn't read back:
+        Ok(None)
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn update_boot_for_config(
+        &self,
+        derivation: &Path,
+        _timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        log::event!(
+            log::Level::DEBUG,
+            "nix-darwin has no boot entries to update"
+        );
+        Ok(())
+    }
+}
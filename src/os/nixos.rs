@@ -1,7 +1,10 @@
-use crate::read_and_log_messages;
+use crate::{
+    read_and_log_messages, read_log_and_collect_messages, read_log_and_forward_messages, OutputLine,
+};
 use anyhow::Context;
 use openssh::{Command, Stdio};
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use tracing as log;
 use tracing::instrument;
 use tracing::Instrument;
@@ -10,34 +13,1151 @@ use core::fmt;
 use serde::Deserialize;
 use std::{
     borrow::Cow,
+    collections::HashSet,
+    ops::{Deref, DerefMut},
     path::{Path, PathBuf},
-    process::Output,
+    process::{ExitStatus, Output},
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use crate::{NixOperatingSystem, Verb};
+use super::darwin::Darwin;
+use crate::{
+    bracketed_host, fleet::Deployed, snapshot::Snapshot, subprocess::Stream,
+    transient_failure_from_output, Flavor, HealthCheck, HostFacts, NixOperatingSystem,
+    RemoteOptions, TransientFailure, UnitChanges, UnitsFailed, Verb,
+};
+use async_trait::async_trait;
 
-/// A nixos operating system instance.
+/// A nixos operating system instance. Its flavor picks how
+/// configurations get built and activated on it.
 pub struct Nixos {
     host: String,
+    port: Option<u16>,
     session: openssh::Session,
+    /// The master connection that the session runs over, if it was
+    /// made with the system's ssh. It must outlive the session.
+    master: Option<crate::system_ssh::Master>,
+    options: RemoteOptions,
+    flavor: Flavor,
+    facts: tokio::sync::OnceCell<HostFacts>,
+    /// Whether elevating privileges takes a password, once that has
+    /// been found out.
+    elevation_needs_password: tokio::sync::OnceCell<bool>,
+    channels: Semaphore,
+}
+
+/// A command to run on the system, which may need the password for
+/// elevating privileges fed to its stdin first.
+#[derive(Debug)]
+pub(super) struct RemoteCommand<'s> {
+    cmd: Command<'s>,
+
+    /// Whether the command reads the password from the first line of
+    /// its stdin.
+    needs_password: bool,
+}
+
+impl<'s> From<Command<'s>> for RemoteCommand<'s> {
+    fn from(cmd: Command<'s>) -> Self {
+        Self {
+            cmd,
+            needs_password: false,
+        }
+    }
+}
+
+impl<'s> Deref for RemoteCommand<'s> {
+    type Target = Command<'s>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cmd
+    }
+}
+
+impl DerefMut for RemoteCommand<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.cmd
+    }
 }
 
+/// How many commands may run on a system at the same time. They all
+/// share the one ssh connection to the system, each using a channel
+/// of its own, so this stays well below sshd's default `MaxSessions`
+/// of 10.
+const MAX_CHANNELS: usize = 4;
+
 pub const DEFAULT_PREFLIGHT_SCRIPT_NAME: &str = "pre-activate-safety-checks";
 
-fn strip_shell_output(output: Output) -> String {
-    let len = &output.stdout.len();
-    let last_byte = output.stdout[len - 1];
-    if last_byte == b'\n' {
-        String::from_utf8_lossy(&output.stdout[..(len - 1)]).to_string()
-    } else {
-        String::from_utf8_lossy(&output.stdout).to_string()
+/// The directory in which deploy-flake registers its GC roots.
+pub const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots/deploy-flake";
+
+/// The profile whose generations are the system configurations.
+pub(super) const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// The script that logs an elevated command to the journal and then
+/// runs it, when auditing remote commands.
+const AUDIT_SCRIPT: &str = r#"logger -t deploy-flake-audit -- "deploy $0: $*"; exec "$@""#;
+
+/// The script that runs "$@" with only those environment variables
+/// that $0 names (separated by spaces).
+const CLEAN_ENV_SCRIPT: &str = r#"for name in $(env | sed -n 's/^\([A-Za-z_][A-Za-z0-9_]*\)=.*/\1/p'); do case " $0 " in *" $name "*) ;; *) unset "$name" 2>/dev/null || true ;; esac; done; exec "$@""#;
+
+/// The script that resolves the symlink `$2` within the root
+/// directory `$1`, printing the path (relative to the root) that it
+/// ultimately points to, if that exists.
+const RESOLVE_IN_ROOT_SCRIPT: &str = r#"root=$1 link=$2 hops=0
+while [ -L "$root$link" ] && [ $hops -lt 40 ]; do
+  target=$(readlink "$root$link") || exit 1
+  case $target in /*) link=$target ;; *) link=$(dirname "$link")/$target ;; esac
+  hops=$((hops + 1))
+done
+[ -e "$root$link" ] && printf '%s\n' "$link""#;
+
+/// The script that runs its arguments as a command in `/tmp`.
+const IN_TMP_SCRIPT: &str = r#"cd /tmp && exec "$@""#;
+
+/// The symlink to the system configuration that is currently active.
+pub(super) const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// The symlink to the system configuration that the system booted.
+const BOOTED_SYSTEM: &str = "/run/booted-system";
+
+/// The components of a system configuration whose version changes
+/// are worth pointing out, since they often mean that the system
+/// needs a reboot or that connections to it get dropped: their name,
+/// the path in a system configuration that leads into their package,
+/// and the package's name.
+const NOTABLE_COMPONENTS: &[(&str, &str, &str)] = &[
+    ("kernel", "kernel", "linux"),
+    ("systemd", "sw/bin/systemctl", "systemd"),
+    ("openssh", "sw/bin/ssh", "openssh"),
+];
+
+/// The script that prints the boot loader entry that the system
+/// boots by default, with systemd-boot or GRUB. GRUB boots the first
+/// entry of its configuration by default.
+const DEFAULT_BOOT_ENTRY_SCRIPT: &str = r#"if [ -f /boot/loader/loader.conf ]; then
+  entry=$(sed -n 's/^default[[:space:]]*//p' /boot/loader/loader.conf)
+  cat "/boot/loader/entries/$entry"
+elif [ -f /boot/grub/grub.cfg ]; then
+  cat /boot/grub/grub.cfg
+fi"#;
+
+/// The directory holding the named system profiles, which the boot
+/// loader offers in addition to the "system" profile.
+const SYSTEM_PROFILES_DIR: &str = "/nix/var/nix/profiles/system-profiles";
+
+/// The script that gathers the [`HostFacts`] that may get cached,
+/// printing one fact per line.
+const STABLE_FACTS_SCRIPT: &str = "hostname
+nix --version
+uname -m
+nix show-config 2>/dev/null | sed -n 's/^experimental-features = //p' | grep . || echo";
+
+/// The script that gathers the [`HostFacts`] that never get cached,
+/// printing one fact per line.
+const CURRENT_FACTS_SCRIPT: &str =
+    "df -Pk /nix/store | awk 'NR == 2 { printf \"%d\\n\", $4 * 1024 }'
+df -Pk /boot 2>/dev/null | awk 'NR == 2 { free = $4 * 1024 } END { if (free == \"\") print \"\"; else printf \"%d\\n\", free }'
+readlink /run/current-system || echo
+readlink /nix/var/nix/profiles/system || echo
+cat /run/current-system/nixos-version 2>/dev/null || echo";
+
+/// Returns the arguments that make the rest of a command line get
+/// terminated once `timeout` is up (and killed if it doesn't stop
+/// soon after), along with the commands it started.
+fn timeout_args(timeout: Duration) -> Vec<String> {
+    vec![
+        "timeout".to_string(),
+        "--kill-after=10".to_string(),
+        timeout.as_secs().max(1).to_string(),
+    ]
+}
+
+/// Returns the command line that finds out whether elevating
+/// privileges takes a password, by failing if it does.
+fn elevation_probe(options: &RemoteOptions) -> Vec<String> {
+    let mut probe = options.elevation.prefix(true);
+    probe.push("true".to_string());
+    probe
+}
+
+/// Returns the command line that the rest of a command line runs
+/// behind to get superuser privileges (see [`Nixos::elevated`]), and
+/// whether it reads the password for that from its stdin: only if a
+/// password can be asked for, and elevating privileges takes one (or
+/// `needs_password` isn't known yet).
+fn elevated_command_line(
+    options: &RemoteOptions,
+    needs_password: Option<bool>,
+) -> (Vec<String>, bool) {
+    let password_prefix = options
+        .elevation_password
+        .as_ref()
+        .filter(|_| needs_password != Some(false))
+        .and_then(|_| options.elevation.password_prefix());
+    let reads_password = password_prefix.is_some();
+    let mut command_line =
+        password_prefix.unwrap_or_else(|| options.elevation.prefix(options.non_interactive));
+    if options.audit {
+        // The arguments that get added to the command end up in
+        // "$@", and the deploy ID in $0:
+        let deploy_id = options.deploy_id.as_deref().unwrap_or("unknown");
+        command_line.extend(["sh", "-c", AUDIT_SCRIPT, deploy_id].map(String::from));
+    }
+    (command_line, reads_password)
+}
+
+/// Returns the name of the transient unit that activates a
+/// configuration with a verb.
+fn activation_unit_name(verb: Verb, derivation: &Path) -> Result<String, anyhow::Error> {
+    Ok(format!(
+        "{}--{}.service",
+        Nixos::verb_command(verb),
+        Nixos::flake_base_name(derivation)?
+    ))
+}
+
+/// Returns the `systemd-run` argument that orders a rollback after
+/// the units that activate the configuration it rolls back from, so
+/// that a rollback that comes due while that configuration is still
+/// being tested waits for the test to finish, and a test that gets
+/// retried waits for a rollback that already started.
+fn rollback_ordering(derivation: &Path) -> Result<String, anyhow::Error> {
+    Ok(format!(
+        "--property=After={} {}",
+        activation_unit_name(Verb::Test, derivation)?,
+        activation_unit_name(Verb::Switch, derivation)?
+    ))
+}
+
+/// Returns the version of the package `pname` from a path into its
+/// store path, like `6.6.8` for
+/// `/nix/store/...-linux-6.6.8/bzImage`. Qualifiers between the name
+/// and the version (like in `systemd-minimal-254.6`) get skipped.
+fn package_version(path: &Path, pname: &str) -> Option<String> {
+    let name = path
+        .strip_prefix("/nix/store")
+        .ok()?
+        .components()
+        .next()?
+        .as_os_str()
+        .to_str()?;
+    let (_hash, name) = name.split_once('-')?;
+    let rest = name.strip_prefix(pname)?.strip_prefix('-')?;
+    let start = rest
+        .split('-')
+        .position(|part| part.starts_with(|c: char| c.is_ascii_digit()))?;
+    Some(rest.split('-').skip(start).collect::<Vec<_>>().join("-"))
+}
+
+/// The script that prints the components of the configuration given
+/// as its second argument that differ from those of the booted system
+/// given as its first, and take a reboot to take effect.
+const REBOOT_REASONS_SCRIPT: &str = r#"for component in kernel initrd kernel-modules systemd; do
+  [ "$(readlink -e "$1/$component")" = "$(readlink -e "$2/$component")" ] || echo "$component"
+done"#;
+
+/// The script that prints the identifier of the current boot: the
+/// kernel's boot ID, along with when the boot reached sysinit.target,
+/// which changes with a soft reboot, too.
+const BOOT_ID_SCRIPT: &str = r#"echo "$(cat /proc/sys/kernel/random/boot_id) $(systemctl show -P ActiveEnterTimestampMonotonic sysinit.target)""#;
+
+/// The script that loads the kernel and initrd of the configuration
+/// given as its argument with kexec, and boots into it from a timer,
+/// so that the script can return before the connection goes down.
+const KEXEC_SCRIPT: &str = r#"kexec --load "$1/kernel" --initrd="$1/initrd" --append="init=$1/init $(cat "$1/kernel-params")" && systemd-run --on-active=1 systemctl kexec"#;
+
+/// The first systemd version that can soft-reboot.
+const SOFT_REBOOT_SYSTEMD_VERSION: u64 = 254;
+
+/// Returns the version of systemd from the output of `systemctl
+/// --version`, like 254 for `systemd 254 (254.6)`.
+fn systemd_version_from_output(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .next()?
+        .strip_prefix("systemd ")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The experimental features that the nix commands deploy-flake runs
+/// on a system rely on.
+const NIX_FEATURES: &[&str] = &["nix-command", "flakes"];
+
+/// Returns the arguments that enable those [`NIX_FEATURES`] for a
+/// nix command that its configuration doesn't enable already.
+fn feature_args(enabled: &[String]) -> Vec<String> {
+    let missing: Vec<&str> = NIX_FEATURES
+        .iter()
+        .copied()
+        .filter(|feature| !enabled.iter().any(|enabled| enabled == feature))
+        .collect();
+    if missing.is_empty() {
+        return vec![];
+    }
+    vec![
+        "--extra-experimental-features".to_string(),
+        missing.join(" "),
+    ]
+}
+
+/// Returns the next line of a facts script's output.
+fn next_fact<'a>(
+    lines: &mut impl Iterator<Item = &'a str>,
+    what: &str,
+) -> Result<&'a str, anyhow::Error> {
+    lines
+        .next()
+        .map(str::trim)
+        .with_context(|| format!("Missing {what} in host facts"))
+}
+
+/// Parses the output of [`STABLE_FACTS_SCRIPT`] followed by
+/// [`CURRENT_FACTS_SCRIPT`].
+fn facts_from_output(output: &str) -> Result<HostFacts, anyhow::Error> {
+    let mut lines = output.lines();
+    let hostname = next_fact(&mut lines, "hostname")?.to_string();
+    let nix_version = next_fact(&mut lines, "nix version")?
+        .rsplit(' ')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let architecture = next_fact(&mut lines, "architecture")?.to_string();
+    let nix_features = next_fact(&mut lines, "nix features")?
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    current_facts_from_output(hostname, nix_version, architecture, nix_features, lines)
+}
+
+/// Parses the output of [`CURRENT_FACTS_SCRIPT`], completing the
+/// host facts with the given stable ones.
+fn current_facts_from_output<'a>(
+    hostname: String,
+    nix_version: String,
+    architecture: String,
+    nix_features: Vec<String>,
+    mut lines: impl Iterator<Item = &'a str>,
+) -> Result<HostFacts, anyhow::Error> {
+    let mut next = |what: &str| next_fact(&mut lines, what);
+    let free_store_bytes = next("free disk space")?
+        .parse()
+        .context("Could not parse free disk space")?;
+    let free_boot_bytes = Some(next("free disk space on /boot")?)
+        .filter(|free| !free.is_empty())
+        .map(str::parse)
+        .transpose()
+        .context("Could not parse free disk space on /boot")?;
+    let current_system = Some(next("current system")?)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from);
+    let current_generation = next("current generation")?
+        .strip_prefix("system-")
+        .and_then(|link| link.strip_suffix("-link"))
+        .and_then(|number| number.parse().ok());
+    let nixos_version = Some(next("NixOS version")?)
+        .filter(|version| !version.is_empty())
+        .map(String::from);
+    Ok(HostFacts {
+        hostname,
+        nix_version,
+        architecture,
+        nix_features,
+        free_store_bytes,
+        free_boot_bytes,
+        current_system,
+        current_generation,
+        nixos_version,
+    })
+}
+
+/// Returns the number of the generation before the current one from
+/// the output of `nix-env --list-generations`.
+fn previous_generation_from_output(output: &str) -> Option<u64> {
+    let generations: Vec<(u64, bool)> = output
+        .lines()
+        .filter_map(|line| {
+            let number = line.split_whitespace().next()?.parse().ok()?;
+            Some((number, line.contains("(current)")))
+        })
+        .collect();
+    let current = generations.iter().position(|(_, current)| *current)?;
+    current
+        .checked_sub(1)
+        .map(|previous| generations[previous].0)
+}
+
+/// Extracts the names of units that
+/// `switch-to-configuration` reports as failed from its output.
+fn failed_units_from_output(lines: &[String]) -> Vec<String> {
+    const MARKER: &str = "the following units failed: ";
+    lines
+        .iter()
+        .filter_map(|line| line.split_once(MARKER).map(|(_, units)| units))
+        .flat_map(|units| units.split(", "))
+        .map(|unit| unit.trim().to_string())
+        .filter(|unit| !unit.is_empty())
+        .collect()
+}
+
+/// Extracts the store paths of derivations that nix reports as
+/// having failed to build from its output, each once, in the order
+/// that nix first reports them.
+fn failed_derivations_from_output(lines: &[String]) -> Vec<String> {
+    const MARKERS: &[&str] = &["error: builder for '", "error: Cannot build '"];
+    let mut seen = HashSet::new();
+    lines
+        .iter()
+        .filter_map(|line| {
+            MARKERS
+                .iter()
+                .find_map(|marker| line.split_once(marker).map(|(_, rest)| rest))
+        })
+        .filter_map(|rest| rest.split_once('\'').map(|(drv, _)| drv.to_string()))
+        .filter(|drv| seen.insert(drv.clone()))
+        .collect()
+}
+
+/// The states that `systemctl is-system-running` reports.
+const SYSTEM_STATES: &[&str] = &[
+    "initializing",
+    "starting",
+    "running",
+    "degraded",
+    "maintenance",
+    "stopping",
+    "offline",
+    "unknown",
+];
+
+/// The exit status of `timeout` when the command it ran timed out.
+const TIMED_OUT_STATUS: i32 = 124;
+
+/// Parses the jobs out of the output of `systemctl list-jobs --plain
+/// --no-legend`, as "UNIT (TYPE, STATE)".
+fn jobs_from_list_output(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [_id, unit, job_type, state, ..] => Some(format!("{unit} ({job_type}, {state})")),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Returns the system configuration that a boot loader entry boots:
+/// the one whose `init` the first kernel command line in the entry
+/// runs.
+fn boot_system_from_entry(entry: &str) -> Option<PathBuf> {
+    entry.split_whitespace().find_map(|word| {
+        let init = Path::new(word.strip_prefix("init=")?);
+        Some(init.strip_prefix("/").ok()?.parent()?).map(|system| Path::new("/").join(system))
+    })
+}
+
+/// Returns the last deploy that deploy-flake made, according to the
+/// provenance records in the journal.
+fn deploy_from_output(output: &str) -> Option<Deployed> {
+    output.lines().rev().find_map(|line| {
+        let rest = line.strip_prefix("Activating ")?.strip_suffix(')')?;
+        let (path, rest) = rest.split_once(" (deploy ")?;
+        let (deploy_id, source) = rest.split_once(", source ")?;
+        Some(Deployed {
+            path: PathBuf::from(path),
+            deploy_id: deploy_id.to_string(),
+            source: source.to_string(),
+        })
+    })
+}
+
+/// Parses the names of the units out of the output of `systemctl
+/// list-units --plain --no-legend`.
+fn units_from_list_output(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect()
+}
+
+/// Parses the unit changes out of the output of
+/// `switch-to-configuration dry-activate`.
+fn unit_changes_from_output(output: &[String]) -> UnitChanges {
+    let mut changes = UnitChanges::default();
+    for line in output {
+        let (units, rest) = if let Some(rest) =
+            line.strip_prefix("would stop the following units: ")
+        {
+            (&mut changes.stop, rest)
+        } else if let Some(rest) = line.strip_prefix("would NOT stop the following changed units: ")
+        {
+            (&mut changes.not_stopped, rest)
+        } else if let Some(rest) = line.strip_prefix("would restart the following units: ") {
+            (&mut changes.restart, rest)
+        } else if let Some(rest) = line.strip_prefix("would reload the following units: ") {
+            (&mut changes.reload, rest)
+        } else if let Some(rest) = line.strip_prefix("would start the following units: ") {
+            (&mut changes.start, rest)
+        } else {
+            continue;
+        };
+        units.extend(rest.split(", ").map(|unit| unit.trim().to_string()));
     }
+    changes
 }
 
 impl Nixos {
-    /// Setup a new Nixos connection
-    pub(crate) fn new(host: String, session: openssh::Session) -> Self {
-        Self { host, session }
+    /// Wraps an established ssh session to the host, so that a
+    /// library user can share one session between several
+    /// operations.
+    pub fn from_session(host: String, session: openssh::Session, options: RemoteOptions) -> Self {
+        Self {
+            host,
+            port: None,
+            session,
+            master: None,
+            options,
+            flavor: Flavor::Nixos,
+            facts: Default::default(),
+            elevation_needs_password: Default::default(),
+            channels: Semaphore::new(MAX_CHANNELS),
+        }
+    }
+
+    /// Returns the system with its flavor set to `flavor`.
+    pub fn with_flavor(self, flavor: Flavor) -> Self {
+        Self { flavor, ..self }
+    }
+
+    /// Returns the flavor of operating system that the host runs.
+    pub fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    /// Returns the operating system that the host runs, according to
+    /// its flavor.
+    pub(crate) fn os(&self) -> Box<dyn NixOperatingSystem + '_> {
+        match self.flavor {
+            Flavor::Nixos => Box::new(NixosSystem(self)),
+            Flavor::Darwin => Box::new(Darwin(self)),
+        }
+    }
+
+    /// Connects to the host (on the given ssh port, if any),
+    /// checking its key against the known hosts, unless the system's
+    /// ssh configuration (with [`RemoteOptions::system_ssh`]) says
+    /// otherwise.
+    pub async fn connect(
+        host: &str,
+        port: Option<u16>,
+        options: RemoteOptions,
+    ) -> Result<Self, anyhow::Error> {
+        if options.system_ssh {
+            if let Some(jump_host) = &options.jump_host {
+                anyhow::bail!("With the system's ssh, reach {host:?} via {jump_host:?} with ProxyJump in the ssh configuration");
+            }
+            let (master, session) =
+                crate::system_ssh::Master::connect(host, port, &options).await?;
+            let system = Self {
+                port,
+                master: Some(master),
+                ..Self::from_session(host.to_string(), session, options)
+            };
+            system.elevation_needs_password().await?;
+            return Ok(system);
+        }
+        let mut builder = openssh::SessionBuilder::default();
+        builder.known_hosts_check(options.host_key_policy.unwrap_or_default().known_hosts());
+        if let Some(keep_alive) = options.keep_alive {
+            builder.server_alive_interval(keep_alive);
+        }
+        if let Some(port) = port {
+            builder.port(port);
+        }
+        if let Some(jump_host) = &options.jump_host {
+            builder.config_file(options.jump_hosts.config_for(jump_host, &options).await?);
+        } else if let Some(config) = &options.ssh_config {
+            builder.config_file(config.file());
+        }
+        let session = builder
+            .connect(host)
+            .await
+            .with_context(|| format!("Connecting to {host:?}"))?;
+        let system = Self {
+            port,
+            ..Self::from_session(host.to_string(), session, options)
+        };
+        system.elevation_needs_password().await?;
+        Ok(system)
+    }
+
+    /// Closes the ssh session to the host, waiting for it to shut
+    /// down. Fails if the system is still shared with other
+    /// operations; its session then gets closed once the last of
+    /// them drops it.
+    pub async fn close(self: Arc<Self>) -> Result<(), anyhow::Error> {
+        let system = Arc::try_unwrap(self)
+            .map_err(|system| anyhow::anyhow!("The connection to {system:?} is still in use"))?;
+        let host = system.host;
+        let closed = system
+            .session
+            .close()
+            .await
+            .with_context(|| format!("Closing the connection to {host:?}"));
+        drop(system.master);
+        closed
+    }
+
+    /// Returns the host name that the system was connected to.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the ssh port that the system was connected to, unless
+    /// it is the default one.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Returns the control socket of the ssh connection to the
+    /// system, which other ssh invocations can multiplex over.
+    pub fn control_socket(&self) -> &Path {
+        self.session.control_socket()
+    }
+
+    /// Returns the key that the facts about the system get cached
+    /// under: hosts behind the same address may differ by port.
+    fn cache_key(&self) -> Cow<'_, str> {
+        match self.port {
+            Some(port) => Cow::Owned(format!("{}:{port}", self.host)),
+            None => Cow::Borrowed(&self.host),
+        }
+    }
+
+    /// Returns the options that commands run on the system with.
+    pub fn options(&self) -> &RemoteOptions {
+        &self.options
+    }
+
+    /// Returns the URI of the system's nix store for `nix copy`, over
+    /// the `ssh-ng` protocol.
+    pub fn ssh_ng_store(&self) -> String {
+        let uri = format!("ssh-ng://{}", bracketed_host(&self.host));
+        match &self.options.remote_store {
+            Some(root) => format!("{uri}?remote-store={}", root.display()),
+            None => uri,
+        }
+    }
+
+    /// Returns the arguments that make nix commands on the system use
+    /// its alternate store, if it has one.
+    pub fn store_args(&self) -> Vec<String> {
+        match &self.options.remote_store {
+            Some(root) => vec!["--store".to_string(), root.to_string_lossy().into_owned()],
+            None => vec![],
+        }
+    }
+
+    /// Returns where a path of the system's nix state (like a profile
+    /// or a store path) actually lives on the system.
+    fn in_store_root(&self, path: &Path) -> PathBuf {
+        match &self.options.remote_store {
+            Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Returns a command that runs a program from a system
+    /// configuration with superuser privileges: inside the root of
+    /// the system's alternate store (with `nixos-enter`) if it has
+    /// one, so that the configuration's store paths resolve. It gets
+    /// killed once `timeout` (if any) is up, like with
+    /// [`Nixos::elevated_until`].
+    fn elevated_in_store_root(&self, timeout: Option<Duration>) -> RemoteCommand<'_> {
+        let mut cmd = self.elevated_until(timeout);
+        if let Some(root) = &self.options.remote_store {
+            cmd.args(["nixos-enter", "--root"])
+                .arg(root.to_string_lossy())
+                .arg("--");
+        }
+        cmd
+    }
+
+    /// Waits until a command may run on the system. The command must
+    /// finish before the returned permit is dropped.
+    async fn channel(&self) -> Result<SemaphorePermit<'_>, anyhow::Error> {
+        Ok(self.channels.acquire().await?)
+    }
+
+    /// Runs a command to completion, returning its output.
+    pub(super) async fn output<'s>(
+        &self,
+        cmd: impl Into<RemoteCommand<'s>>,
+    ) -> Result<Output, anyhow::Error> {
+        let mut cmd = cmd.into();
+        let Some(password) = self.password_for(&cmd).await? else {
+            let _channel = self.channel().await?;
+            return Ok(cmd.output().await?);
+        };
+        self.output_feeding(cmd, password.into_bytes()).await
+    }
+
+    /// Runs a command to completion with `input` on its stdin (after
+    /// the password, if the command needs one), returning its output.
+    pub(super) async fn output_with_input<'s>(
+        &self,
+        cmd: impl Into<RemoteCommand<'s>>,
+        input: &[u8],
+    ) -> Result<Output, anyhow::Error> {
+        let cmd = cmd.into();
+        let mut stdin = self
+            .password_for(&cmd)
+            .await?
+            .unwrap_or_default()
+            .into_bytes();
+        stdin.extend_from_slice(input);
+        self.output_feeding(cmd, stdin).await
+    }
+
+    /// Runs a command to completion, writing `input` to its stdin.
+    async fn output_feeding(
+        &self,
+        mut cmd: RemoteCommand<'_>,
+        input: Vec<u8>,
+    ) -> Result<Output, anyhow::Error> {
+        let _channel = self.channel().await?;
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = cmd.spawn().await?;
+        let mut stdin = child.stdin().take().unwrap();
+        let write = async move {
+            stdin.write_all(&input).await?;
+            stdin.shutdown().await
+        };
+        let (output, written) = futures::join!(child.wait_with_output(), write);
+        written.context("Could not write to the command's stdin")?;
+        Ok(output?)
+    }
+
+    /// Returns the password that the command needs fed to its stdin,
+    /// if any, followed by a newline.
+    async fn password_for(&self, cmd: &RemoteCommand<'_>) -> Result<Option<String>, anyhow::Error> {
+        let Some(prompt) = self
+            .options
+            .elevation_password
+            .clone()
+            .filter(|_| cmd.needs_password)
+        else {
+            return Ok(None);
+        };
+        if !self.elevation_needs_password().await? {
+            return Ok(None);
+        }
+        let host = self.host.clone();
+        let password = tokio::task::spawn_blocking(move || prompt.password(&host)).await??;
+        Ok(Some(format!("{password}\n")))
+    }
+
+    /// Returns whether elevating privileges on the system takes a
+    /// password that has to be asked for, finding out (by elevating
+    /// them without one, which sudo allows for commands that it
+    /// doesn't need a password for, or while it remembers the last
+    /// one) on first use.
+    async fn elevation_needs_password(&self) -> Result<bool, anyhow::Error> {
+        if self.options.elevation_password.is_none()
+            || self.options.elevation.password_prefix().is_none()
+        {
+            return Ok(false);
+        }
+        let needs_password = self
+            .elevation_needs_password
+            .get_or_try_init(|| async {
+                let probe = elevation_probe(&self.options);
+                let mut cmd = self.session.command(&probe[0]);
+                cmd.args(&probe[1..])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null());
+                let status = cmd
+                    .status()
+                    .await
+                    .context("Could not check whether elevating privileges takes a password")?;
+                Ok::<_, anyhow::Error>(!status.success())
+            })
+            .await?;
+        log::event!(
+            log::Level::DEBUG,
+            needs_password,
+            "Checked for an elevation password"
+        );
+        Ok(*needs_password)
+    }
+
+    /// Returns the facts about the system, gathering them on first
+    /// use and reusing them for the rest of the connection.
+    pub async fn facts(&self) -> Result<&HostFacts, anyhow::Error> {
+        self.facts.get_or_try_init(|| self.gather_facts()).await
+    }
+
+    /// Returns whether the system currently runs the configuration at
+    /// `path` (or one that it is a symlink to).
+    pub(crate) async fn runs(&self, path: &Path) -> Result<bool, anyhow::Error> {
+        if self.options.remote_store.is_some() {
+            // The system in an alternate store isn't running yet:
+            return Ok(false);
+        }
+        let running = self.resolve_link(Path::new(CURRENT_SYSTEM)).await?;
+        Ok(running.is_some() && running == self.resolve_link(path).await?)
+    }
+
+    /// Returns the arguments that make a nix command on the system
+    /// support flakes, if its configuration doesn't enable them.
+    pub(crate) async fn feature_args(&self) -> Result<Vec<String>, anyhow::Error> {
+        let enabled = &self.facts().await?.nix_features;
+        let args = feature_args(enabled);
+        if !args.is_empty() {
+            log::event!(
+                log::Level::DEBUG,
+                ?enabled,
+                "Enabling experimental nix features"
+            );
+        }
+        Ok(args)
+    }
+
+    /// Returns whether the system was booted into the configuration
+    /// at `path` (or one that it is a symlink to).
+    pub(crate) async fn booted(&self, path: &Path) -> Result<bool, anyhow::Error> {
+        let booted = self.resolve_link(Path::new(BOOTED_SYSTEM)).await?;
+        Ok(booted.is_some() && booted == self.resolve_link(path).await?)
+    }
+
+    /// Returns the platform (like `x86_64-linux`) that the system
+    /// configuration at `path` on the system was built for, if it
+    /// says.
+    pub async fn built_platform(&self, path: &Path) -> Result<Option<String>, anyhow::Error> {
+        let mut cmd = self.session.command("cat");
+        cmd.arg(self.in_store_root(&path.join("system")).to_string_lossy())
+            .stderr(Stdio::null());
+        let output = self.output(cmd).await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let platform = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(Some(platform).filter(|platform| !platform.is_empty()))
+    }
+
+    /// Returns the path that the file `name` (like `kernel`) of the
+    /// running system ultimately points to, if it has one.
+    pub(crate) async fn running_file(&self, name: &str) -> Result<Option<PathBuf>, anyhow::Error> {
+        self.resolve_link(&Path::new(CURRENT_SYSTEM).join(name))
+            .await
+    }
+
+    /// Returns the path that a symlink ultimately points to, or
+    /// `None` if it doesn't exist.
+    async fn resolve_link(&self, link: &Path) -> Result<Option<PathBuf>, anyhow::Error> {
+        let mut cmd = match &self.options.remote_store {
+            None => {
+                let mut cmd = self.session.command("readlink");
+                cmd.arg("-e");
+                cmd
+            }
+            // Links in an alternate store point at paths relative to
+            // its root, so they need resolving one at a time:
+            Some(root) => {
+                let mut cmd = self.session.command("sh");
+                cmd.args(["-c", RESOLVE_IN_ROOT_SCRIPT, "sh"])
+                    .arg(root.to_string_lossy());
+                cmd
+            }
+        };
+        cmd.arg(link.to_string_lossy()).stderr(Stdio::null());
+        let output = self.output(cmd).await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let target = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(Some(PathBuf::from(target)).filter(|path| !path.as_os_str().is_empty()))
+    }
+
+    /// Returns the versions of the [`NOTABLE_COMPONENTS`] of a system
+    /// configuration, along with their names. Components that the
+    /// configuration doesn't have get no version.
+    pub(crate) async fn component_versions(
+        &self,
+        system: &Path,
+    ) -> Result<Vec<(&'static str, Option<String>)>, anyhow::Error> {
+        futures::future::try_join_all(NOTABLE_COMPONENTS.iter().map(
+            |(component, path, pname)| async move {
+                let target = self.resolve_link(&system.join(path)).await?;
+                let version = target.and_then(|target| package_version(&target, pname));
+                Ok::<_, anyhow::Error>((*component, version))
+            },
+        ))
+        .await
+    }
+
+    /// Returns the units that are in the failed state on the system.
+    async fn failed_units(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut cmd = self.session.command("systemctl");
+        cmd.args(["list-units", "--failed", "--plain", "--no-legend"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = self.output(cmd).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Could not list failed units: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let units = String::from_utf8_lossy(&output.stdout);
+        Ok(units_from_list_output(&units)
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+
+    /// Returns a description of how the running system differs from
+    /// the system profile's current generation, if it does.
+    pub(super) async fn profile_inconsistency(&self) -> Result<Option<String>, anyhow::Error> {
+        let current = self.facts().await?.current_system.clone();
+        let profile = self.os().current_generation().await?;
+        Ok((current != profile).then(|| {
+            format!(
+                "The system profile points to {profile:?}, but the running system is {current:?}"
+            )
+        }))
+    }
+
+    /// Returns the system configuration that the default boot loader
+    /// entry boots, if the system uses a boot loader that
+    /// deploy-flake knows.
+    async fn default_boot_system(&self) -> Result<Option<PathBuf>, anyhow::Error> {
+        let mut cmd = self.elevated();
+        cmd.args(["sh", "-c", DEFAULT_BOOT_ENTRY_SCRIPT])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let output = self.output(cmd).await?;
+        Ok(boot_system_from_entry(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    /// Returns the last deploy that deploy-flake made to the system,
+    /// according to its journal.
+    async fn journal_last_deploy(&self) -> Result<Option<Deployed>, anyhow::Error> {
+        let mut cmd = self.elevated();
+        cmd.args([
+            "journalctl",
+            "--identifier=deploy-flake",
+            "--lines=1",
+            "--output=cat",
+            "--no-pager",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+        let output = self.output(cmd).await?;
+        Ok(deploy_from_output(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Returns when the "system" profile was last pointed at its
+    /// current generation, as long as that generation is what the
+    /// system runs. `stat_args` are as for [`Nixos::link_modified`].
+    ///
+    /// Unlike /run/current-system, which gets made anew at every
+    /// boot, the profile link only changes when a generation gets
+    /// installed or rolled back to.
+    pub(super) async fn profile_since(
+        &self,
+        stat_args: &[&str],
+    ) -> Result<Option<SystemTime>, anyhow::Error> {
+        let profile = Path::new(SYSTEM_PROFILE);
+        if !self.runs(profile).await? {
+            return Ok(None);
+        }
+        self.link_modified(profile, stat_args).await
+    }
+
+    /// Returns when a symlink (not its target) was last modified,
+    /// or `None` if it doesn't exist. `stat_args` make `stat` print
+    /// the modification time in seconds since the UNIX epoch.
+    pub(super) async fn link_modified(
+        &self,
+        link: &Path,
+        stat_args: &[&str],
+    ) -> Result<Option<SystemTime>, anyhow::Error> {
+        let mut cmd = self.session.command("stat");
+        cmd.args(stat_args)
+            .arg(link.to_string_lossy())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let output = self.output(cmd).await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let seconds: u64 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .with_context(|| format!("Could not parse the modification time of {link:?}"))?;
+        Ok(Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)))
+    }
+
+    /// Runs an arbitrary command on the system, logging its output
+    /// as it runs. If `input` is given, the command reads its stdin
+    /// from it. Fails if the command exits unsuccessfully.
+    pub async fn exec(
+        &self,
+        command: &[String],
+        input: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+    ) -> Result<(), anyhow::Error> {
+        self.exec_forwarding(command, input, None).await
+    }
+
+    /// Like [`Nixos::exec`], but also forwards every line the
+    /// command prints to `output` as it runs.
+    pub async fn exec_forwarding(
+        &self,
+        command: &[String],
+        input: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+        output: Option<tokio::sync::mpsc::UnboundedSender<OutputLine>>,
+    ) -> Result<(), anyhow::Error> {
+        let (program, args) = command.split_first().context("No command given")?;
+        let mut cmd = self.session.command(program);
+        cmd.args(args);
+        self.run_command_with_stdin(cmd, input, output).await
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn gather_facts(&self) -> Result<HostFacts, anyhow::Error> {
+        let cache = self.options.facts_cache.as_ref();
+        if let Some(cached) = cache.and_then(|cache| cache.load(&self.cache_key())) {
+            let output = self.run_facts_script(CURRENT_FACTS_SCRIPT).await?;
+            let facts = current_facts_from_output(
+                cached.hostname,
+                cached.nix_version,
+                cached.architecture,
+                cached.nix_features,
+                output.lines(),
+            )?;
+            if facts.current_system == cached.current_system {
+                log::event!(
+                    log::Level::DEBUG,
+                    ?facts,
+                    "Gathered host facts, using cached ones"
+                );
+                return Ok(facts);
+            }
+        }
+        let output = self
+            .run_facts_script(&format!("{STABLE_FACTS_SCRIPT}\n{CURRENT_FACTS_SCRIPT}"))
+            .await?;
+        let facts = facts_from_output(&output)?;
+        log::event!(log::Level::DEBUG, ?facts, "Gathered host facts");
+        if let Some(cache) = cache {
+            cache.store(&self.cache_key(), &facts);
+        }
+        Ok(facts)
+    }
+
+    /// Runs a script gathering host facts, returning its output.
+    async fn run_facts_script(&self, script: &str) -> Result<String, anyhow::Error> {
+        let mut cmd = self.session.command("sh");
+        cmd.args(["-c", script]).stderr(Stdio::inherit());
+        let output = self.output(cmd).await?;
+        if !output.status.success() {
+            anyhow::bail!("Could not gather host facts: {:?}", output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Returns a command that runs the program given as its first
+    /// argument in `/tmp`, so that nix doesn't pick up a flake from
+    /// the remote user's home directory. Unlike `env -C`, this works
+    /// with the BSD userland of macOS build hosts, too.
+    fn command_in_tmp(&self) -> Command<'_> {
+        let mut cmd = self.session.command("sh");
+        cmd.args(["-c", IN_TMP_SCRIPT, "sh"]);
+        cmd
+    }
+
+    /// Like [`Nixos::elevated`], but the command gets killed on the
+    /// system once `timeout` (if any) is up, so that it doesn't keep
+    /// running after deploy-flake gave up waiting for it.
+    pub(super) fn elevated_until(&self, timeout: Option<Duration>) -> RemoteCommand<'_> {
+        let mut cmd = self.elevated();
+        if let Some(timeout) = timeout {
+            cmd.args(timeout_args(timeout));
+        }
+        cmd
+    }
+
+    /// Returns a command that runs with superuser privileges (see
+    /// [`RemoteOptions::elevation`]). In non-interactive mode, it
+    /// fails instead of prompting for a password; with
+    /// [`RemoteOptions::elevation_password`], it gets the password
+    /// fed to its stdin. When auditing, the command gets logged to
+    /// the system's journal before it runs.
+    pub(super) fn elevated(&self) -> RemoteCommand<'_> {
+        let (command_line, needs_password) =
+            elevated_command_line(&self.options, self.elevation_needs_password.get().copied());
+        let (program, args) = command_line
+            .split_first()
+            .expect("Elevating privileges takes a program");
+        let mut cmd = RemoteCommand {
+            cmd: self.session.command(program),
+            needs_password,
+        };
+        cmd.args(args);
+        cmd
+    }
+
+    /// Makes the command run the rest of its command line with only
+    /// the environment variables in [`RemoteOptions::activation_env`],
+    /// so that whatever the destination's ssh server and the remote
+    /// user's environment files set can't affect activating a
+    /// configuration.
+    pub(super) fn clean_env(&self, cmd: &mut Command<'_>) {
+        let allowed = self.options.activation_env();
+        cmd.args(["sh", "-c", CLEAN_ENV_SCRIPT])
+            .arg(allowed.join(" "));
+    }
+
+    /// Logs the environment that commands run with after
+    /// [`Nixos::clean_env`], when debug logging is on.
+    pub(super) async fn log_activation_env(&self) {
+        if !log::enabled!(log::Level::DEBUG) {
+            return;
+        }
+        let mut cmd = self.elevated();
+        self.clean_env(&mut cmd);
+        cmd.arg("env").stdout(Stdio::piped()).stderr(Stdio::null());
+        match self.output(cmd).await {
+            Ok(output) => {
+                let environment = String::from_utf8_lossy(&output.stdout);
+                log::event!(log::Level::DEBUG, environment = %environment.trim(), "Activation environment");
+            }
+            Err(error) => {
+                log::event!(log::Level::DEBUG, error = %format!("{error:#}"), "Could not determine the activation environment");
+            }
+        }
+    }
+
+    /// Returns what remote commands should get as their stdin: the
+    /// terminal, unless we must never prompt for input.
+    fn stdin(&self) -> Stdio {
+        if self.options.non_interactive {
+            Stdio::null()
+        } else {
+            Stdio::inherit()
+        }
     }
 
     fn activation_command_line<'a>(
@@ -56,39 +1176,30 @@ impl Nixos {
         use super::Verb::*;
         match verb {
             Test => "test",
+            Switch => "switch",
             Build => "build",
             Boot => "boot",
+            DryActivate => "dry-activate",
         }
     }
 
-    async fn hostname(&self) -> Result<String, anyhow::Error> {
-        let output = self
-            .session
-            .command("hostname")
-            .stderr(Stdio::inherit())
-            .output()
-            .await?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!(
-                "Could not query for hostname: {:?}",
-                output.status
-            ));
-        }
-        Ok(strip_shell_output(output))
-    }
-
     #[instrument(level = "DEBUG", fields(pathname), err)]
     async fn test_file_existence<'s>(&self, path: &Path) -> Result<bool, anyhow::Error> {
         let mut cmd = self.session.command("test");
         cmd.arg("-f").raw_arg(path);
         cmd.stdout(Stdio::null())
             .stderr(Stdio::piped())
-            .stdin(Stdio::inherit());
+            .stdin(self.stdin());
         log::event!(log::Level::DEBUG, command=?cmd, "Running");
+        let _channel = self.channel().await?;
         let mut child = cmd.spawn().await?;
         let stderr_read = tokio::task::spawn(
-            read_and_log_messages("E", child.stderr().take().unwrap())
-                .instrument(log::Span::current()),
+            read_and_log_messages(
+                Stream::Stderr,
+                child.stderr().take().unwrap(),
+                &self.options,
+            )
+            .instrument(log::Span::current()),
         );
         let status = futures::join!(child.wait(), stderr_read);
         let exit_status = status.0?;
@@ -97,24 +1208,70 @@ impl Nixos {
     }
 
     #[instrument(level = "DEBUG", fields(cmd), err)]
-    async fn run_command<'s>(&self, mut cmd: Command<'s>) -> Result<(), anyhow::Error> {
-        cmd.stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .stdin(Stdio::inherit());
+    pub(super) async fn run_command<'s>(
+        &self,
+        cmd: impl Into<RemoteCommand<'s>> + fmt::Debug,
+    ) -> Result<(), anyhow::Error> {
+        self.run_command_with_stdin(cmd, None, None).await
+    }
 
-        log::event!(log::Level::DEBUG, command=?cmd, "Running");
+    /// Like [`Nixos::run_command`], but if `input` is given, the
+    /// command reads its stdin from it instead of from the terminal.
+    #[instrument(level = "DEBUG", fields(cmd), skip(input), err)]
+    async fn run_command_with_stdin<'s>(
+        &self,
+        cmd: impl Into<RemoteCommand<'s>> + fmt::Debug,
+        input: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+        output: Option<tokio::sync::mpsc::UnboundedSender<OutputLine>>,
+    ) -> Result<(), anyhow::Error> {
+        let mut cmd = cmd.into();
+        let password = self.password_for(&cmd).await?;
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        if input.is_some() || password.is_some() {
+            cmd.stdin(Stdio::piped());
+        } else {
+            cmd.stdin(self.stdin());
+        }
+
+        log::event!(log::Level::DEBUG, command=?cmd, "Running");
+        let _channel = self.channel().await?;
         let mut child = cmd.spawn().await?;
         // Read stdout/stderr line-by-line and emit them as log messages:
         let stdout_read = tokio::task::spawn(
-            read_and_log_messages("O", child.stdout().take().unwrap())
-                .instrument(log::Span::current()),
+            read_log_and_forward_messages(
+                Stream::Stdout,
+                child.stdout().take().unwrap(),
+                &self.options,
+                output.clone(),
+            )
+            .instrument(log::Span::current()),
         );
         let stderr_read = tokio::task::spawn(
-            read_and_log_messages("E", child.stderr().take().unwrap())
-                .instrument(log::Span::current()),
+            read_log_and_forward_messages(
+                Stream::Stderr,
+                child.stderr().take().unwrap(),
+                &self.options,
+                output,
+            )
+            .instrument(log::Span::current()),
         );
+        // Feed the password and the input to the command, closing
+        // its stdin at the end:
+        let stdin = child.stdin().take();
+        let stdin_write = async move {
+            if let Some(mut stdin) = stdin {
+                if let Some(password) = password {
+                    stdin.write_all(password.as_bytes()).await?;
+                }
+                if let Some(input) = input {
+                    tokio::io::copy(input, &mut stdin).await?;
+                }
+                stdin.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
         // Now, wait for it all to finish:
-        let status = futures::join!(child.wait(), stdout_read, stderr_read);
+        let status = futures::join!(child.wait(), stdout_read, stderr_read, stdin_write);
         let exit_status = status.0?;
         log::event!(log::Level::DEBUG, command=?cmd, ?exit_status, "Finished");
         if !exit_status.success() {
@@ -124,108 +1281,305 @@ impl Nixos {
                 exit_status
             );
         }
+        status.3.context("Could not write to the command's stdin")?;
         Ok(())
     }
-}
 
-impl NixOperatingSystem for Nixos {
-    #[instrument(level = "INFO", err)]
-    async fn preflight_check_system(&self) -> Result<(), anyhow::Error> {
-        let mut cmd = self.session.command("sudo");
-        cmd.stdout(Stdio::piped());
-        cmd.args(["systemctl", "is-system-running", "--wait"]);
-        let health = cmd.output().await?;
-        let health_data = String::from_utf8_lossy(&health.stdout);
-        let status = health_data.strip_suffix('\n').unwrap_or("");
-        if !health.status.success() {
-            log::error!(
-                ?status,
-                "System is not healthy. List of broken units follows:"
-            );
-            let output = self
-                .session
-                .command("sudo")
-                .args(["systemctl", "list-units", "--failed"])
-                .stdout(Stdio::piped())
-                .output()
-                .await?;
-            log::event!(
-                log::Level::WARN,
-                "Failed units:\n{}",
-                String::from_utf8_lossy(&output.stdout)
-            );
-            anyhow::bail!("Can not deploy to an unhealthy system");
+    /// Like [`Nixos::run_command`], but returns the exit status
+    /// along with every line the command printed (stdout and stderr
+    /// interleaved by stream), leaving the interpretation of failure
+    /// to the caller.
+    #[instrument(level = "DEBUG", fields(cmd), err)]
+    pub(super) async fn run_command_collecting<'s>(
+        &self,
+        cmd: impl Into<RemoteCommand<'s>> + fmt::Debug,
+    ) -> Result<(ExitStatus, Vec<String>), anyhow::Error> {
+        let mut cmd = cmd.into();
+        let password = self.password_for(&cmd).await?;
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        if password.is_some() {
+            cmd.stdin(Stdio::piped());
+        } else {
+            cmd.stdin(self.stdin());
         }
-        log::event!(log::Level::DEBUG, ?status, "System is healthy");
-        Ok(())
+
+        log::event!(log::Level::DEBUG, command=?cmd, "Running");
+        let _channel = self.channel().await?;
+        let mut child = cmd.spawn().await?;
+        if let (Some(password), Some(mut stdin)) = (password, child.stdin().take()) {
+            stdin
+                .write_all(password.as_bytes())
+                .await
+                .context("Could not write the password to the command's stdin")?;
+            stdin.shutdown().await?;
+        }
+        let stdout_read = tokio::task::spawn(
+            read_log_and_collect_messages(
+                Stream::Stdout,
+                child.stdout().take().unwrap(),
+                &self.options,
+            )
+            .instrument(log::Span::current()),
+        );
+        let stderr_read = tokio::task::spawn(
+            read_log_and_collect_messages(
+                Stream::Stderr,
+                child.stderr().take().unwrap(),
+                &self.options,
+            )
+            .instrument(log::Span::current()),
+        );
+        let (exit_status, stdout, stderr) = futures::join!(child.wait(), stdout_read, stderr_read);
+        let exit_status = exit_status?;
+        log::event!(log::Level::DEBUG, command=?cmd, ?exit_status, "Finished");
+        let mut lines = stdout??;
+        lines.extend(stderr??);
+        Ok((exit_status, lines))
     }
 
-    #[instrument(level = "INFO", err)]
-    async fn preflight_check_closure(
+    /// Returns the name of a configuration's store path, which the
+    /// units that activate it are named after.
+    fn flake_base_name(derivation: &Path) -> Result<&str, anyhow::Error> {
+        Ok(derivation
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Built path has a weird format: {:?}", derivation))?
+            .to_str()
+            .expect("Nix path must be utf-8 clean"))
+    }
+
+    /// Runs `switch-to-configuration` with a verb in a transient
+    /// systemd unit, so that it survives the ssh connection dropping
+    /// and runs in a clean environment, returning its exit status and
+    /// output. If it runs longer than `timeout`, systemd stops it.
+    async fn run_in_activation_unit(
         &self,
+        verb: Verb,
         derivation: &Path,
-        script: Option<&Path>,
+        timeout: Option<Duration>,
+    ) -> Result<(ExitStatus, Vec<String>), anyhow::Error> {
+        let mut cmd = self.elevated();
+        let unit_name = activation_unit_name(verb, derivation)?;
+
+        cmd.args([
+            "systemd-run",
+            "--working-directory=/tmp",
+            "--service-type=oneshot",
+            "--send-sighup",
+            "--unit",
+            &unit_name,
+            "--wait",
+            "--quiet",
+            "--collect",
+            "--pipe",
+            // Fix perl complaining about bad locale settings:
+            "--setenv=LC_ALL=C",
+        ]);
+        if let Some(timeout) = timeout {
+            cmd.arg(format!(
+                "--property=RuntimeMaxSec={}",
+                timeout.as_secs().max(1)
+            ));
+        }
+        cmd.args(self.activation_command_line(verb, derivation));
+        log::event!(
+            log::Level::DEBUG,
+            ?unit_name,
+            "Running switch-to-configuration {} in background",
+            Self::verb_command(verb)
+        );
+        self.run_command_collecting(cmd).await
+    }
+
+    /// Runs `switch-to-configuration` with a verb that changes the
+    /// running system, reporting the units that failed to start.
+    async fn activate_in_unit(
+        &self,
+        verb: Verb,
+        derivation: &Path,
+        timeout: Option<Duration>,
     ) -> Result<(), anyhow::Error> {
-        let script_path = if script.is_none() {
-            // Try to use the default pre-activation script name emitted by preflight-safety:
-            let script_path = derivation.join(DEFAULT_PREFLIGHT_SCRIPT_NAME);
-            log::event!(log::Level::DEBUG, dest=?self.host, script=?script_path.file_name(), "Checking for existence of inferred pre-activation script");
-            if !self.test_file_existence(&script_path).await? {
-                return Ok(());
-            }
-            script_path
-        } else {
-            derivation.join(script.unwrap())
+        let what = match verb {
+            Verb::Switch => "switching to",
+            _ => "testing",
         };
-        log::event!(log::Level::INFO, dest=?self.host, script=?script_path.file_name(), "Running pre-activation script");
-        let mut cmd = self.session.command("sudo");
-        cmd.raw_arg(script_path);
-        self.run_command(cmd)
+        if let Some(root) = &self.options.remote_store {
+            anyhow::bail!(
+                "Can not activate {derivation:?} from the store in {root:?} on the running system, skip testing it"
+            );
+        }
+        let (exit_status, output) = self
+            .run_in_activation_unit(verb, derivation, timeout)
             .await
-            .context("System closure self-checks failed")?;
+            .with_context(|| format!("{what} the system closure {derivation:?} failed"))?;
+        if !exit_status.success() {
+            let failed_units = failed_units_from_output(&output);
+            if failed_units.is_empty() {
+                match timeout {
+                    Some(timeout) => anyhow::bail!(
+                        "{what} the system closure {derivation:?} failed with status {exit_status:?}, possibly because it took longer than {}",
+                        humantime::format_duration(timeout)
+                    ),
+                    None => anyhow::bail!(
+                        "{what} the system closure {derivation:?} failed with status {exit_status:?}"
+                    ),
+                }
+            }
+            log::event!(log::Level::WARN, ?failed_units, "Units failed to start");
+            let details = match self.failed_unit_details(&failed_units).await {
+                Ok(details) => details,
+                Err(e) => format!("Could not retrieve details on failed units: {e:?}"),
+            };
+            return Err(anyhow::Error::new(UnitsFailed {
+                units: failed_units,
+                details,
+            })
+            .context(format!(
+                "{what} the system closure {derivation:?} failed with status {exit_status:?}"
+            )));
+        }
         Ok(())
     }
 
-    #[instrument(level = "DEBUG", err, skip(build_cmdline))]
-    async fn build_flake(
+    /// Runs `nix build --dry-run` for an installable on the system,
+    /// returning its output.
+    #[instrument(level = "DEBUG", skip(options), err)]
+    pub(crate) async fn dry_build(
+        &self,
+        installable: &str,
+        options: &crate::BuildOptions,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let mut cmd = self.command_in_tmp();
+        cmd.arg("nix")
+            .args(self.feature_args().await?)
+            .args(["build", "--dry-run", "--no-link"])
+            .args(self.store_args())
+            .args(options.nix_args())
+            .arg(installable);
+        let (exit_status, output) = self
+            .run_command_collecting(cmd)
+            .await
+            .context("Could not dry-run the build")?;
+        if !exit_status.success() {
+            anyhow::bail!("nix build --dry-run failed with status {exit_status:?}");
+        }
+        Ok(output)
+    }
+
+    /// Returns whether the system can fetch the flake with the given
+    /// (locked) reference itself, fetching it into its store if so.
+    #[instrument(level = "DEBUG", skip(options))]
+    pub async fn can_fetch_flake(&self, reference: &str, options: &crate::BuildOptions) -> bool {
+        let features = match self.feature_args().await {
+            Ok(features) => features,
+            Err(error) => {
+                log::event!(log::Level::DEBUG, %error, "Can not fetch the flake");
+                return false;
+            }
+        };
+        let mut cmd = self.session.command("nix");
+        cmd.args(features)
+            .args(["flake", "metadata", "--json"])
+            .args(self.store_args())
+            .args(&options.cmdline)
+            .arg(reference)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        match self.output(cmd).await {
+            Ok(output) if output.status.success() => true,
+            Ok(output) => {
+                log::event!(
+                    log::Level::DEBUG,
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "Can not fetch the flake"
+                );
+                false
+            }
+            Err(error) => {
+                log::event!(log::Level::DEBUG, %error, "Can not fetch the flake");
+                false
+            }
+        }
+    }
+
+    /// Builds the configuration with the given name (or the host's
+    /// name) on the system, with `installable` returning the flake
+    /// attribute to build for that name.
+    #[instrument(level = "DEBUG", err, skip(options, installable))]
+    pub(super) async fn build_config(
         &self,
-        flake: &crate::Flake,
         config_name: Option<&str>,
-        build_cmdline: Vec<String>,
+        options: &crate::BuildOptions,
+        installable: impl FnOnce(&str) -> String,
     ) -> Result<(PathBuf, String), anyhow::Error> {
         let hostname = match config_name {
-            None => self.hostname().await?,
+            None => self.facts().await?.hostname.clone(),
             Some(name) => name.to_owned(),
         };
+        let installable = installable(&hostname);
 
         // We run this twice: Once to get progress to the user & see
         // output; and the second time to get the actual derivation
         // path, which thankfully happens fast because the build
         // result will be cached already.
-        let build_args = ["nix", Self::verb_command(Verb::Build), "-L", "--no-link"];
-        let mut cmd = self.session.command("env");
-        cmd.args(["-C", "/tmp"])
+        let features = self.feature_args().await?;
+        let build_args = [Self::verb_command(Verb::Build), "--no-link"];
+        let build_cmdline = options.nix_args();
+        let mut cmd = self.command_in_tmp();
+        cmd.arg("nix")
+            .args(&features)
             .args(build_args)
+            .args(self.store_args())
             .args(&build_cmdline)
-            .arg(flake.nixos_system_config(&hostname));
-        self.run_command(cmd)
+            .arg(&installable);
+        let (exit_status, output) = self
+            .run_command_collecting(cmd)
             .await
+            .map_err(|e| match e.downcast_ref::<openssh::Error>() {
+                Some(openssh::Error::Disconnected | openssh::Error::RemoteProcessTerminated) => {
+                    anyhow::Error::new(TransientFailure(e.to_string()))
+                }
+                _ => e,
+            })
             .context("Could not build the flake")?;
+        if !exit_status.success() {
+            if let Some(line) = transient_failure_from_output(&output) {
+                return Err(anyhow::Error::new(TransientFailure(line.to_string()))
+                    .context("Could not build the flake"));
+            }
+            let failed = failed_derivations_from_output(&output);
+            if failed.is_empty() {
+                anyhow::bail!(
+                    "Could not build the flake: nix build failed with status {exit_status:?}"
+                );
+            }
+            anyhow::bail!(
+                "Could not build the flake: {} derivation(s) failed to build:\n  {}",
+                failed.len(),
+                failed.join("\n  ")
+            );
+        }
 
-        let mut cmd = self.session.command("env");
+        let mut cmd = self.command_in_tmp();
         cmd.stderr(Stdio::piped())
             .stdout(Stdio::piped())
-            .stdin(Stdio::inherit());
-        cmd.args(["-C", "/tmp"])
+            .stdin(self.stdin());
+        cmd.arg("nix")
+            .args(&features)
             .args(build_args)
+            .args(self.store_args())
             .args(&build_cmdline)
             .arg("--json")
-            .arg(flake.nixos_system_config(&hostname));
+            .arg(&installable);
+        let _channel = self.channel().await?;
         let mut child = cmd.spawn().await?;
-        let stderr_log = tokio::task::spawn(read_and_log_messages(
-            "E",
-            child.stderr().take().expect("should have stderr"),
-        ));
+        let stderr_log = tokio::task::spawn(
+            read_and_log_messages(
+                Stream::Stderr,
+                child.stderr().take().expect("should have stderr"),
+                &self.options,
+            )
+            .instrument(log::Span::current()),
+        );
         let mut child_stdout = child.stdout().take().expect("should have stdout");
         let mut stdout = vec![];
         let all = futures::join!(
@@ -249,59 +1603,684 @@ impl NixOperatingSystem for Nixos {
         }
     }
 
+    /// Describes what keeps the system from settling: its current
+    /// state, the jobs that systemd still has queued and the units
+    /// that failed.
+    async fn unsettled_state(&self) -> Result<String, anyhow::Error> {
+        let mut state = self.session.command("systemctl");
+        state
+            .arg("is-system-running")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let mut jobs = self.session.command("systemctl");
+        jobs.args(["list-jobs", "--plain", "--no-legend"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let mut failed = self.session.command("systemctl");
+        failed
+            .args(["list-units", "--failed", "--plain", "--no-legend"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let (state, jobs, failed) =
+            futures::try_join!(self.output(state), self.output(jobs), self.output(failed))?;
+        let jobs = jobs_from_list_output(&String::from_utf8_lossy(&jobs.stdout));
+        let failed = String::from_utf8_lossy(&failed.stdout);
+        let failed = units_from_list_output(&failed);
+        Ok(format!(
+            "it is {:?}, with {} queued jobs ({}) and {} failed units ({})",
+            String::from_utf8_lossy(&state.stdout).trim(),
+            jobs.len(),
+            jobs.join(", "),
+            failed.len(),
+            failed.join(", ")
+        ))
+    }
+
+    /// Gathers `systemctl status` and the most recent journal
+    /// entries for the given units, for inclusion in error messages.
+    async fn failed_unit_details(&self, units: &[String]) -> Result<String, anyhow::Error> {
+        let mut cmd = self.elevated();
+        cmd.args(["systemctl", "status", "--no-pager", "--full"])
+            .args(units)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let mut journalctl = self.elevated();
+        journalctl.args(["journalctl", "--no-pager", "--lines=50"]);
+        for unit in units {
+            journalctl.arg("-u").arg(unit);
+        }
+        journalctl.stdout(Stdio::piped()).stderr(Stdio::null());
+        let (status, journal) = futures::try_join!(self.output(cmd), self.output(journalctl))?;
+        Ok(format!(
+            "Status of failed units:\n{}\nRecent journal entries:\n{}",
+            String::from_utf8_lossy(&status.stdout),
+            String::from_utf8_lossy(&journal.stdout)
+        ))
+    }
+}
+
+/// A NixOS system, reached over the connection of a [`Nixos`] with
+/// the [`Nixos`](crate::Flavor::Nixos) flavor.
+#[derive(Debug)]
+pub(crate) struct NixosSystem<'a>(pub(crate) &'a Nixos);
+
+#[async_trait]
+impl NixOperatingSystem for NixosSystem<'_> {
+    #[instrument(level = "INFO", err)]
+    async fn preflight_check_system(
+        &self,
+        method: HealthCheck,
+        timeout: Duration,
+    ) -> Result<(), anyhow::Error> {
+        if method != HealthCheck::FailedUnits {
+            let mut cmd = self.0.elevated();
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+            cmd.arg("timeout")
+                .arg(timeout.as_secs().max(1).to_string())
+                .args(["systemctl", "is-system-running", "--wait"]);
+            let health = self.0.output(cmd).await?;
+            if health.status.code() == Some(TIMED_OUT_STATUS) {
+                let unsettled = self.0.unsettled_state().await?;
+                anyhow::bail!(
+                    "System did not settle within {}: {unsettled}",
+                    humantime::format_duration(timeout)
+                );
+            }
+            let health_data = String::from_utf8_lossy(&health.stdout);
+            let status = health_data.strip_suffix('\n').unwrap_or("");
+            if SYSTEM_STATES.contains(&status) {
+                if !health.status.success() {
+                    log::error!(
+                        ?status,
+                        "System is not healthy. List of broken units follows:"
+                    );
+                    let mut cmd = self.0.elevated();
+                    cmd.args(["systemctl", "list-units", "--failed"])
+                        .stdout(Stdio::piped());
+                    let output = self.0.output(cmd).await?;
+                    log::event!(
+                        log::Level::WARN,
+                        "Failed units:\n{}",
+                        String::from_utf8_lossy(&output.stdout)
+                    );
+                    anyhow::bail!("Can not deploy to an unhealthy system");
+                }
+                log::event!(log::Level::DEBUG, ?status, "System is healthy");
+                return Ok(());
+            }
+            let error = String::from_utf8_lossy(&health.stderr);
+            if method == HealthCheck::IsSystemRunning {
+                anyhow::bail!(
+                    "Could not determine the system state ({:?}): {}",
+                    health.status,
+                    error.trim()
+                );
+            }
+            log::warn!(
+                error = %error.trim(),
+                "`systemctl is-system-running --wait` is not supported, checking for failed units instead"
+            );
+        }
+
+        let failed = self.0.failed_units().await?;
+        if !failed.is_empty() {
+            log::error!(?failed, "System is not healthy, some units failed");
+            anyhow::bail!("Can not deploy to an unhealthy system");
+        }
+        log::event!(log::Level::DEBUG, "System is healthy, no units failed");
+        Ok(())
+    }
+
+    #[instrument(level = "INFO", err)]
+    async fn preflight_check_closure(
+        &self,
+        derivation: &Path,
+        script: Option<&Path>,
+    ) -> Result<(), anyhow::Error> {
+        let script_path = if let Some(script) = script {
+            derivation.join(script)
+        } else {
+            // Try to use the default pre-activation script name emitted by preflight-safety:
+            let script_path = derivation.join(DEFAULT_PREFLIGHT_SCRIPT_NAME);
+            log::event!(log::Level::DEBUG, dest=?self.0.host, script=?script_path.file_name(), "Checking for existence of inferred pre-activation script");
+            if !self
+                .0
+                .test_file_existence(&self.0.in_store_root(&script_path))
+                .await?
+            {
+                return Ok(());
+            }
+            script_path
+        };
+        log::event!(log::Level::INFO, dest=?self.0.host, script=?script_path.file_name(), "Running pre-activation script");
+        self.0.log_activation_env().await;
+        let mut cmd = self.0.elevated_in_store_root(None);
+        self.0.clean_env(&mut cmd);
+        cmd.raw_arg(script_path);
+        self.0
+            .run_command(cmd)
+            .await
+            .context("System closure self-checks failed")?;
+        Ok(())
+    }
+
+    #[instrument(level = "DEBUG", err, skip(options))]
+    async fn build_flake(
+        &self,
+        flake: &crate::Flake,
+        config_name: Option<&str>,
+        options: &crate::BuildOptions,
+    ) -> Result<(PathBuf, String), anyhow::Error> {
+        self.0
+            .build_config(config_name, options, |hostname| {
+                flake.nixos_system_config(hostname)
+            })
+            .await
+    }
+
+    #[instrument(level = "DEBUG", skip(paths), err)]
+    async fn missing_store_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, anyhow::Error> {
+        if paths.is_empty() {
+            return Ok(vec![]);
+        }
+        // A closure's paths don't fit on one commandline, so xargs
+        // splits them up:
+        let mut cmd = self.0.session.command("xargs");
+        cmd.args(["nix-store", "--check-validity", "--print-invalid"])
+            .args(self.0.store_args());
+        let input: String = paths
+            .iter()
+            .map(|path| format!("{}\n", path.display()))
+            .collect();
+        let output = self.0.output_with_input(cmd, input.as_bytes()).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Could not check store path validity: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            .collect())
+    }
+
     #[instrument(level = "DEBUG", err)]
-    async fn set_as_current_generation(&self, derivation: &Path) -> Result<(), anyhow::Error> {
-        let mut cmd = self.session.command("sudo");
-        cmd.args(["nix-env", "-p", "/nix/var/nix/profiles/system", "--set"])
+    async fn add_gc_root(&self, derivation: &Path, name: &str) -> Result<(), anyhow::Error> {
+        let roots_dir = self.0.in_store_root(Path::new(GC_ROOTS_DIR));
+        let mut cmd = self.0.elevated();
+        cmd.args(["mkdir", "-p"]).arg(roots_dir.to_string_lossy());
+        self.0
+            .run_command(cmd)
+            .await
+            .context("Could not create the GC roots directory")?;
+        let mut cmd = self.0.elevated();
+        cmd.arg("nix-store")
+            .args(self.0.store_args())
+            .args(["--realise", "--add-root"])
+            .arg(roots_dir.join(name).to_string_lossy())
             .arg(derivation.to_string_lossy());
-        self.run_command(cmd)
+        self.0
+            .run_command(cmd)
             .await
-            .with_context(|| format!("Could not set {derivation:?} as the current generation"))?;
+            .with_context(|| format!("Could not register {derivation:?} as a GC root"))?;
         Ok(())
     }
 
-    #[instrument(level = "DEBUG", skip(self), fields(host=self.host), err)]
-    async fn test_config(&self, derivation: &Path) -> Result<(), anyhow::Error> {
-        let mut cmd = self.session.command("sudo");
-        let flake_base_name = derivation
-            .file_name()
-            .ok_or_else(|| anyhow::anyhow!("Built path has a weird format: {:?}", derivation))?
-            .to_str()
-            .expect("Nix path must be utf-8 clean");
-        let unit_name = format!("{}--{}", Self::verb_command(Verb::Test), flake_base_name);
+    #[instrument(level = "DEBUG", err)]
+    async fn gc_root(&self, name: &str) -> Result<Option<PathBuf>, anyhow::Error> {
+        self.0
+            .resolve_link(&Path::new(GC_ROOTS_DIR).join(name))
+            .await
+    }
 
-        cmd.args([
-            "systemd-run",
-            "--working-directory=/tmp",
-            "--service-type=oneshot",
-            "--send-sighup",
-            "--unit",
-            &unit_name,
-            "--wait",
-            "--quiet",
-            "--collect",
-            "--pipe",
-            // Fix perl complaining about bad locale settings:
-            "--setenv=LC_ALL=C",
-        ]);
-        cmd.args(self.activation_command_line(Verb::Test, derivation));
-        log::event!(
-            log::Level::DEBUG,
-            ?unit_name,
-            "Running nixos-rebuild test in background"
-        );
-        self.run_command(cmd)
+    #[instrument(level = "DEBUG", err)]
+    async fn remove_gc_root(&self, name: &str) -> Result<(), anyhow::Error> {
+        let root = self.0.in_store_root(&Path::new(GC_ROOTS_DIR).join(name));
+        let mut cmd = self.0.elevated();
+        cmd.args(["rm", "-f"]).arg(root.to_string_lossy());
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not remove the GC root {name:?}"))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn current_generation(&self) -> Result<Option<PathBuf>, anyhow::Error> {
+        self.0.resolve_link(Path::new(SYSTEM_PROFILE)).await
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn current_system_since(&self) -> Result<Option<SystemTime>, anyhow::Error> {
+        self.0.profile_since(&["-c", "%Y"]).await
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn previous_generation(&self) -> Result<Option<(u64, PathBuf)>, anyhow::Error> {
+        let mut cmd = self.0.session.command("nix-env");
+        cmd.args(self.0.store_args())
+            .arg("-p")
+            .arg(
+                self.0
+                    .in_store_root(Path::new(SYSTEM_PROFILE))
+                    .to_string_lossy(),
+            )
+            .arg("--list-generations")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = self.0.output(cmd).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Could not list the system generations: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let Some(number) =
+            previous_generation_from_output(&String::from_utf8_lossy(&output.stdout))
+        else {
+            return Ok(None);
+        };
+        let link = PathBuf::from(format!("{SYSTEM_PROFILE}-{number}-link"));
+        Ok(self.0.resolve_link(&link).await?.map(|path| (number, path)))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn switch_generation(
+        &self,
+        number: u64,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        let mut cmd = self.0.elevated_until(timeout);
+        cmd.arg("nix-env")
+            .args(self.0.store_args())
+            .arg("-p")
+            .arg(
+                self.0
+                    .in_store_root(Path::new(SYSTEM_PROFILE))
+                    .to_string_lossy(),
+            )
+            .arg("--switch-generation")
+            .arg(number.to_string());
+        self.0
+            .run_command(cmd)
             .await
-            .with_context(|| format!("testing the system closure {derivation:?} failed"))?;
+            .with_context(|| format!("Could not switch to system generation {number}"))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn delete_generations(
+        &self,
+        profile_name: Option<&str>,
+        pruning: crate::Pruning,
+    ) -> Result<(), anyhow::Error> {
+        let profile = match profile_name {
+            None => self.0.in_store_root(Path::new(SYSTEM_PROFILE)),
+            Some(name) => self
+                .0
+                .in_store_root(&Path::new(SYSTEM_PROFILES_DIR).join(name)),
+        };
+        let mut cmd = self.0.elevated();
+        cmd.arg("nix-env")
+            .args(self.0.store_args())
+            .arg("-p")
+            .arg(profile.to_string_lossy())
+            .arg("--delete-generations")
+            .arg(pruning.generations());
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not delete old generations of {profile:?}"))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn reboot_reasons(&self, derivation: &Path) -> Result<Vec<String>, anyhow::Error> {
+        if self.0.options.remote_store.is_some() {
+            // Nothing runs from an alternate store yet:
+            return Ok(vec![]);
+        }
+        let mut cmd = self.0.session.command("sh");
+        cmd.args(["-c", REBOOT_REASONS_SCRIPT, "sh", BOOTED_SYSTEM])
+            .arg(derivation.to_string_lossy());
+        let output = self.0.output(cmd).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Could not compare {derivation:?} to the booted system: {:?}",
+                output.status
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(String::from)
+            .collect())
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn boot_id(&self) -> Result<String, anyhow::Error> {
+        let mut cmd = self.0.session.command("sh");
+        cmd.args(["-c", BOOT_ID_SCRIPT]);
+        let output = self.0.output(cmd).await?;
+        if !output.status.success() {
+            anyhow::bail!("Could not determine the boot ID: {:?}", output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn reboot(
+        &self,
+        method: crate::RebootMethod,
+        derivation: &Path,
+    ) -> Result<(), anyhow::Error> {
+        let mut cmd = self.0.elevated();
+        match method {
+            // Rebooting from a timer lets the command return before
+            // the connection goes down:
+            crate::RebootMethod::Reboot => {
+                cmd.args(["systemd-run", "--on-active=1", "systemctl", "reboot"]);
+            }
+            crate::RebootMethod::SoftReboot => {
+                let mut version = self.0.session.command("systemctl");
+                version.arg("--version");
+                let output = self.0.output(version).await?;
+                if !output.status.success() {
+                    anyhow::bail!(
+                        "Could not determine the version of systemd: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+                let version = systemd_version_from_output(&String::from_utf8_lossy(&output.stdout))
+                    .context("Could not determine the version of systemd")?;
+                if version < SOFT_REBOOT_SYSTEMD_VERSION {
+                    anyhow::bail!("Soft reboots need systemd {SOFT_REBOOT_SYSTEMD_VERSION} or later, but {} runs systemd {version}", self.0.host);
+                }
+                cmd.args(["systemd-run", "--on-active=1", "systemctl", "soft-reboot"]);
+            }
+            crate::RebootMethod::Kexec => {
+                cmd.args(["sh", "-c", KEXEC_SCRIPT, "sh"])
+                    .arg(derivation.to_string_lossy());
+            }
+        }
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not reboot with {method:?}"))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn collect_garbage(&self) -> Result<(), anyhow::Error> {
+        let mut cmd = self.0.elevated();
+        cmd.arg("nix-store").args(self.0.store_args()).arg("--gc");
+        self.0
+            .run_command(cmd)
+            .await
+            .context("Could not collect garbage")
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn record_provenance(
+        &self,
+        derivation: &Path,
+        source: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let deploy_id = self.0.options.deploy_id.as_deref().unwrap_or("unknown");
+        let source = source.unwrap_or("unknown");
+        let mut cmd = self.0.session.command("logger");
+        cmd.args(["-t", "deploy-flake"]).arg(format!(
+            "Activating {} (deploy {deploy_id}, source {source})",
+            derivation.display()
+        ));
+        self.0
+            .run_command(cmd)
+            .await
+            .context("Could not record the deploy in the journal")
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn take_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        name: &str,
+    ) -> Result<String, anyhow::Error> {
+        let (command, snapshot_name) = snapshot.command(name);
+        let mut cmd = self.0.elevated();
+        cmd.args(&command);
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not take snapshot {snapshot_name:?}"))?;
+        Ok(snapshot_name)
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn nixos_version(&self, derivation: &Path) -> Result<Option<String>, anyhow::Error> {
+        let mut cmd = self.0.session.command("cat");
+        cmd.arg(derivation.join("nixos-version").to_string_lossy())
+            .stderr(Stdio::null());
+        let output = self.0.output(cmd).await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(Some(version).filter(|version| !version.is_empty()))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn set_as_current_generation(
+        &self,
+        derivation: &Path,
+        profile_name: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        let profile = match profile_name {
+            None => self.0.in_store_root(Path::new(SYSTEM_PROFILE)),
+            Some(name) => {
+                let profiles_dir = self.0.in_store_root(Path::new(SYSTEM_PROFILES_DIR));
+                let mut cmd = self.0.elevated();
+                cmd.args(["mkdir", "-p"])
+                    .arg(profiles_dir.to_string_lossy());
+                self.0
+                    .run_command(cmd)
+                    .await
+                    .context("Could not create the system profiles directory")?;
+                profiles_dir.join(name)
+            }
+        };
+        let mut cmd = self.0.elevated_until(timeout);
+        cmd.arg("nix-env")
+            .args(self.0.store_args())
+            .arg("-p")
+            .arg(profile.to_string_lossy())
+            .arg("--set")
+            .arg(derivation.to_string_lossy());
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not set {derivation:?} as the current generation"))?;
         Ok(())
     }
 
     #[instrument(level = "DEBUG", err)]
-    async fn update_boot_for_config(&self, derivation: &Path) -> Result<(), anyhow::Error> {
-        let mut cmd = self.session.command("sudo");
-        cmd.args(self.activation_command_line(Verb::Boot, derivation))
+    async fn schedule_rollback(
+        &self,
+        previous: &Path,
+        from: &Path,
+        name: &str,
+        delay: Duration,
+    ) -> Result<(), anyhow::Error> {
+        let mut cmd = self.0.elevated();
+        cmd.args(["systemd-run", "--unit", name])
+            .arg(format!("--on-active={}s", delay.as_secs().max(1)))
+            .args([
+                "--timer-property=AccuracySec=1s",
+                "--service-type=oneshot",
+                "--setenv=LC_ALL=C",
+            ])
+            .arg(rollback_ordering(from)?)
+            .args(self.0.activation_command_line(Verb::Test, previous));
+        self.0
+            .run_command(cmd)
+            .await
+            .context("Could not schedule the rollback")
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn cancel_rollback(&self, name: &str) -> Result<(), anyhow::Error> {
+        let mut cmd = self.0.elevated();
+        cmd.args(["systemctl", "stop"]).arg(format!("{name}.timer"));
+        self.0
+            .run_command(cmd)
+            .await
+            .context("Could not cancel the scheduled rollback")
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn trigger_rollback(&self, name: &str) -> Result<(), anyhow::Error> {
+        let mut cmd = self.0.elevated();
+        cmd.args(["systemctl", "start"])
+            .arg(format!("{name}.service"));
+        self.0.run_command(cmd).await.context("Could not roll back")
+    }
+
+    #[instrument(level = "DEBUG", skip(self), fields(host=self.0.host), err)]
+    async fn test_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        self.0
+            .activate_in_unit(Verb::Test, derivation, timeout)
+            .await
+    }
+
+    #[instrument(level = "DEBUG", skip(self), fields(host=self.0.host), err)]
+    async fn switch_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        self.0
+            .activate_in_unit(Verb::Switch, derivation, timeout)
+            .await
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn dry_activate(&self, derivation: &Path) -> Result<UnitChanges, anyhow::Error> {
+        if let Some(root) = &self.0.options.remote_store {
+            anyhow::bail!(
+                "Can not dry-activate {derivation:?} from the store in {root:?} on the running system"
+            );
+        }
+        let (exit_status, output) = self
+            .0
+            .run_in_activation_unit(Verb::DryActivate, derivation, None)
+            .await
+            .with_context(|| format!("Dry activation of {derivation:?} failed"))?;
+        if !exit_status.success() {
+            anyhow::bail!("Dry activation of {derivation:?} failed with status {exit_status:?}");
+        }
+        Ok(unit_changes_from_output(&output))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn package_diff(&self, derivation: &Path) -> Result<Option<String>, anyhow::Error> {
+        if self.0.options.remote_store.is_some() {
+            // nvd only looks at the default store:
+            return Ok(None);
+        }
+        let mut cmd = self.0.session.command("nvd");
+        cmd.args(["diff", CURRENT_SYSTEM])
+            .arg(derivation.to_string_lossy())
+            .stderr(Stdio::piped());
+        let output = self.0.output(cmd).await?;
+        match output.status.code() {
+            Some(0) => Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned())),
+            Some(127) => {
+                log::event!(
+                    log::Level::DEBUG,
+                    "nvd is not installed, skipping the package diff"
+                );
+                Ok(None)
+            }
+            _ => anyhow::bail!(
+                "nvd diff failed with status {:?}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        }
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn inconsistencies(&self) -> Result<Vec<String>, anyhow::Error> {
+        if let Some(root) = &self.0.options.remote_store {
+            anyhow::bail!("Can not verify the running system against the store in {root:?}");
+        }
+        let mut problems: Vec<String> = self.0.profile_inconsistency().await?.into_iter().collect();
+        let current = self.0.facts().await?.current_system.clone();
+        let profile = self.0.os().current_generation().await?;
+        match self.0.default_boot_system().await? {
+            Some(boot) if Some(&boot) != profile.as_ref() => problems.push(format!(
+                "The default boot entry boots {boot:?}, but the system profile points to {profile:?}"
+            )),
+            Some(_) => {}
+            None => log::event!(
+                log::Level::DEBUG,
+                "Could not find the default boot entry, not checking it"
+            ),
+        }
+        let failed = self.0.failed_units().await?;
+        if !failed.is_empty() {
+            problems.push(format!("Units failed: {}", failed.join(", ")));
+        }
+        if let Some(Deployed {
+            path: activated, ..
+        }) = self.0.os().last_deploy().await?
+        {
+            if self.0.resolve_link(&activated).await?.is_none() {
+                problems.push(format!(
+                    "deploy-flake last activated {activated:?}, which is no longer in the store"
+                ));
+            } else if Some(&activated) != current.as_ref() {
+                problems.push(format!(
+                    "deploy-flake last activated {activated:?}, but the running system is {current:?}"
+                ));
+            }
+        }
+        Ok(problems)
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn stop_activation(&self, derivation: &Path) -> Result<(), anyhow::Error> {
+        // Patterns only match loaded units, so this succeeds when no
+        // activation is running:
+        let mut cmd = self.0.elevated();
+        cmd.args(["systemctl", "stop"]).arg(format!(
+            "*--{}.service",
+            Nixos::flake_base_name(derivation)?
+        ));
+        self.0
+            .run_command(cmd)
+            .await
+            .with_context(|| format!("Could not stop activating {derivation:?}"))
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn last_deploy(&self) -> Result<Option<Deployed>, anyhow::Error> {
+        self.0.journal_last_deploy().await
+    }
+
+    #[instrument(level = "DEBUG", err)]
+    async fn update_boot_for_config(
+        &self,
+        derivation: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        self.0.log_activation_env().await;
+        let mut cmd = self.0.elevated_in_store_root(timeout);
+        self.0.clean_env(&mut cmd);
+        cmd.args(self.0.activation_command_line(Verb::Boot, derivation))
             .arg(derivation.to_string_lossy());
-        self.run_command(cmd)
+        self.0
+            .run_command(cmd)
             .await
             .with_context(|| format!("Could not set {:?} up as the boot system", derivation))?;
         Ok(())
@@ -326,3 +2305,354 @@ struct NixBuildResult {
 struct NixOutput {
     out: PathBuf,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        activation_unit_name, boot_system_from_entry, deploy_from_output, elevated_command_line,
+        elevation_probe, facts_from_output, failed_derivations_from_output,
+        failed_units_from_output, feature_args, jobs_from_list_output, package_version,
+        previous_generation_from_output, rollback_ordering, systemd_version_from_output,
+        timeout_args, unit_changes_from_output, units_from_list_output, AUDIT_SCRIPT,
+        CLEAN_ENV_SCRIPT, KEXEC_SCRIPT, REBOOT_REASONS_SCRIPT,
+    };
+    use crate::{
+        elevate::{Elevation, PasswordPrompt},
+        RemoteOptions,
+    };
+    use std::{path::Path, sync::Arc, time::Duration};
+    use test_case::test_case;
+
+    #[derive(Debug)]
+    struct Unasked;
+
+    impl PasswordPrompt for Unasked {
+        fn password(&self, _host: &str) -> Result<String, anyhow::Error> {
+            anyhow::bail!("Nobody should ask for a password here")
+        }
+    }
+
+    #[test_case(false, None => ("sudo".to_string(), false); "no password prompt")]
+    #[test_case(true, None => ("sudo -S -p ".to_string(), true); "not checked yet")]
+    #[test_case(true, Some(true) => ("sudo -S -p ".to_string(), true); "password needed")]
+    #[test_case(true, Some(false) => ("sudo".to_string(), false); "no password needed")]
+    fn elevation_passwords(ask: bool, needs_password: Option<bool>) -> (String, bool) {
+        let options = RemoteOptions {
+            elevation_password: Some(Arc::new(Unasked) as Arc<dyn PasswordPrompt>).filter(|_| ask),
+            ..Default::default()
+        };
+        let (command_line, reads_password) = elevated_command_line(&options, needs_password);
+        (command_line.join(" "), reads_password)
+    }
+
+    #[test_case("sudo", false, false => vec!["sudo"]; "sudo")]
+    #[test_case("sudo", true, false => vec!["sudo", "-n"]; "non-interactive")]
+    #[test_case("doas", false, true => vec!["doas", "sh", "-c", AUDIT_SCRIPT, "01hdeploy"]; "audited")]
+    #[test_case("none", true, true => vec!["env", "sh", "-c", AUDIT_SCRIPT, "01hdeploy"]; "audited as root")]
+    #[test_case("pfexec -P all", true, false => vec!["pfexec", "-P", "all"]; "custom")]
+    fn elevated_command_lines(elevation: &str, non_interactive: bool, audit: bool) -> Vec<String> {
+        let options = RemoteOptions {
+            elevation: elevation.parse().unwrap(),
+            non_interactive,
+            audit,
+            deploy_id: Some("01hdeploy".to_string()),
+            ..Default::default()
+        };
+        elevated_command_line(&options, None).0
+    }
+
+    #[test_case(Duration::from_secs(90) => vec!["timeout", "--kill-after=10", "90"]; "whole seconds")]
+    #[test_case(Duration::from_millis(200) => vec!["timeout", "--kill-after=10", "1"]; "less than a second")]
+    fn timeouts(timeout: Duration) -> Vec<String> {
+        timeout_args(timeout)
+    }
+
+    #[test]
+    fn elevating_with_an_empty_command_line() {
+        let options = RemoteOptions {
+            elevation: Elevation::Custom(vec![]),
+            ..Default::default()
+        };
+        assert_eq!(elevated_command_line(&options, None).0, vec!["env"]);
+    }
+
+    #[test]
+    fn elevation_probing() {
+        assert_eq!(
+            elevation_probe(&RemoteOptions::default()),
+            vec!["sudo", "-n", "true"]
+        );
+    }
+
+    #[test]
+    fn cleaning_the_environment() {
+        let output = std::process::Command::new("sh")
+            .args(["-c", CLEAN_ENV_SCRIPT, "PATH KEPT", "env"])
+            .env("KEPT", "yes")
+            .env("DROPPED", "no")
+            .output()
+            .unwrap();
+        let environment = String::from_utf8_lossy(&output.stdout);
+        let mut names: Vec<&str> = environment
+            .lines()
+            .filter_map(|line| Some(line.split_once('=')?.0))
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["KEPT", "PATH"]);
+    }
+
+    #[test]
+    fn rollbacks_wait_for_activations() {
+        let new = Path::new("/nix/store/bbbb-nixos-system-host-24.05");
+        let ordering = rollback_ordering(new).unwrap();
+        let after = ordering.strip_prefix("--property=After=").unwrap();
+        let units: Vec<&str> = after.split(' ').collect();
+        for verb in [crate::os::Verb::Test, crate::os::Verb::Switch] {
+            let unit = activation_unit_name(verb, new).unwrap();
+            assert!(
+                units.contains(&unit.as_str()),
+                "{} not in {:?}",
+                unit,
+                units
+            );
+        }
+    }
+
+    #[test_case("systemd 254 (254.6)\n+PAM +AUDIT\n" => Some(254); "release")]
+    #[test_case("systemd 256 (256~rc3)\n" => Some(256); "release candidate")]
+    #[test_case("" => None; "no systemd")]
+    fn systemd_versions(output: &str) -> Option<u64> {
+        systemd_version_from_output(output)
+    }
+
+    #[test]
+    fn reboot_reasons() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (booted, new) = (dir.join("booted"), dir.join("new"));
+        std::fs::create_dir_all(dir.join("systemd-254")).unwrap();
+        for (system, kernel) in [(&booted, "linux-6.6.8"), (&new, "linux-6.6.9")] {
+            std::fs::create_dir_all(system).unwrap();
+            std::fs::create_dir_all(dir.join(kernel)).unwrap();
+            std::os::unix::fs::symlink(dir.join(kernel), system.join("kernel")).unwrap();
+            std::os::unix::fs::symlink(dir.join("systemd-254"), system.join("systemd")).unwrap();
+        }
+        let output = std::process::Command::new("sh")
+            .args(["-c", REBOOT_REASONS_SCRIPT, "sh"])
+            .args([&booted, &new])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "kernel\n");
+    }
+
+    #[test]
+    fn kexec_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let (bin, system) = (dir.path().join("bin"), dir.path().join("system"));
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::create_dir_all(&system).unwrap();
+        for program in ["kexec", "systemd-run"] {
+            let path = bin.join(program);
+            std::fs::write(
+                &path,
+                "#!/bin/sh\nprintf '%s' \"${0##*/}\"\nprintf ' [%s]' \"$@\"\necho\n",
+            )
+            .unwrap();
+            std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+                .unwrap();
+        }
+        std::fs::write(system.join("kernel-params"), "loglevel=4 quiet\n").unwrap();
+        let output = std::process::Command::new("sh")
+            .args(["-c", KEXEC_SCRIPT, "sh"])
+            .arg(&system)
+            .env(
+                "PATH",
+                format!(
+                    "{}:{}",
+                    bin.display(),
+                    std::env::var("PATH").unwrap_or_default()
+                ),
+            )
+            .output()
+            .unwrap();
+        let system = system.display();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("kexec [--load] [{system}/kernel] [--initrd={system}/initrd] [--append=init={system}/init loglevel=4 quiet]\nsystemd-run [--on-active=1] [systemctl] [kexec]\n")
+        );
+    }
+
+    #[test_case("/nix/store/abc-linux-6.6.8/bzImage", "linux" => Some("6.6.8".to_string()); "kernel")]
+    #[test_case("/nix/store/abc-systemd-minimal-254.6/bin/systemctl", "systemd" => Some("254.6".to_string()); "qualified")]
+    #[test_case("/nix/store/abc-openssh-9.6p1/bin/ssh", "openssh" => Some("9.6p1".to_string()); "openssh")]
+    #[test_case("/nix/store/abc-openssh-with-gssapi-9.6p1/bin/ssh", "openssh" => Some("9.6p1".to_string()); "variant")]
+    #[test_case("/nix/store/abc-ssh-wrapper/bin/ssh", "openssh" => None; "other package")]
+    #[test_case("/usr/bin/ssh", "openssh" => None; "outside the store")]
+    fn package_versions(path: &str, pname: &str) -> Option<String> {
+        package_version(Path::new(path), pname)
+    }
+
+    #[test]
+    fn boot_entry_parsing() {
+        let systemd_boot = "title NixOS\nversion Generation 42 NixOS 24.05, Linux Kernel 6.6.8, Built on 2024-01-01\nlinux /efi/nixos/abc-linux-6.6.8-bzImage.efi\noptions init=/nix/store/abc-nixos-system-db1-24.05/init loglevel=4\n";
+        assert_eq!(
+            boot_system_from_entry(systemd_boot),
+            Some(Path::new("/nix/store/abc-nixos-system-db1-24.05").to_path_buf())
+        );
+        let grub = "menuentry \"NixOS - Default\" {\n  linux /nix/store/abc-linux-6.6.8/bzImage init=/nix/store/def-nixos-system-db1-24.05/init loglevel=4\n}\nmenuentry \"NixOS - Generation 41\" {\n  linux /nix/store/abc-linux-6.6.8/bzImage init=/nix/store/ghi-nixos-system-db1-24.05/init\n}\n";
+        assert_eq!(
+            boot_system_from_entry(grub),
+            Some(Path::new("/nix/store/def-nixos-system-db1-24.05").to_path_buf())
+        );
+        assert_eq!(boot_system_from_entry(""), None);
+    }
+
+    #[test]
+    fn provenance_parsing() {
+        let deployed = deploy_from_output(
+            "Activating /nix/store/abc-nixos-system-db1 (deploy 01HQ, source git revision abc, narHash sha256-x=)\n",
+        )
+        .unwrap();
+        assert_eq!(
+            deployed.path,
+            Path::new("/nix/store/abc-nixos-system-db1").to_path_buf()
+        );
+        assert_eq!(deployed.deploy_id, "01HQ");
+        assert_eq!(deployed.source, "git revision abc, narHash sha256-x=");
+        assert_eq!(deploy_from_output("-- No entries --\n"), None);
+    }
+
+    #[test_case(&[] => vec!["--extra-experimental-features", "nix-command flakes"]; "none enabled")]
+    #[test_case(&["nix-command", "ca-derivations"] => vec!["--extra-experimental-features", "flakes"]; "some enabled")]
+    #[test_case(&["flakes", "nix-command"] => Vec::<String>::new(); "all enabled")]
+    fn enabling_features(enabled: &[&str]) -> Vec<String> {
+        let enabled: Vec<String> = enabled.iter().map(|feature| feature.to_string()).collect();
+        feature_args(&enabled)
+    }
+
+    #[test]
+    fn facts_parsing() {
+        let facts = facts_from_output(
+            "db1\nnix (Nix) 2.18.1\nx86_64\nnix-command flakes\n12345678\n4096\n/nix/store/aaa-nixos-system-db1\nsystem-42-link\n23.11.20240115.b8dd8be (Tapir)\n",
+        )
+        .unwrap();
+        assert_eq!(facts.hostname, "db1");
+        assert_eq!(facts.nix_version, "2.18.1");
+        assert_eq!(facts.architecture, "x86_64");
+        assert_eq!(facts.nix_features, vec!["nix-command", "flakes"]);
+        assert_eq!(facts.free_store_bytes, 12345678);
+        assert_eq!(facts.free_boot_bytes, Some(4096));
+        assert_eq!(
+            facts.current_system.as_deref(),
+            Some(Path::new("/nix/store/aaa-nixos-system-db1"))
+        );
+        assert_eq!(facts.current_generation, Some(42));
+        assert_eq!(
+            facts.nixos_version.as_deref(),
+            Some("23.11.20240115.b8dd8be (Tapir)")
+        );
+
+        let facts = facts_from_output("db1\nnix (Nix) 2.18.1\naarch64\n\n0\n\n\n\n\n").unwrap();
+        assert!(facts.nix_features.is_empty());
+        assert_eq!(facts.free_boot_bytes, None);
+        assert_eq!(facts.current_system, None);
+        assert_eq!(facts.current_generation, None);
+        assert!(facts_from_output("db1\nnix (Nix) 2.18.1\n").is_err());
+    }
+
+    #[test]
+    fn failed_derivations_parsing() {
+        let output: Vec<String> = [
+            "error: builder for '/nix/store/aaa-foo.drv' failed with exit code 1;",
+            "       last 10 log lines:",
+            "error: Cannot build '/nix/store/bbb-bar.drv'.",
+            "error: 1 dependencies of derivation '/nix/store/ccc-system.drv' failed to build",
+            "error: Cannot build '/nix/store/aaa-foo.drv'.",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            failed_derivations_from_output(&output),
+            vec!["/nix/store/aaa-foo.drv", "/nix/store/bbb-bar.drv"]
+        );
+    }
+
+    #[test]
+    fn unit_changes_parsing() {
+        let output: Vec<String> = [
+            "would stop the following units: old.service",
+            "would NOT stop the following changed units: getty@tty1.service",
+            "would activate the configuration...",
+            "would restart systemd",
+            "would restart the following units: nginx.service, sshd.service",
+            "would start the following units: new.service",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let changes = unit_changes_from_output(&output);
+        assert_eq!(changes.stop, vec!["old.service"]);
+        assert_eq!(changes.not_stopped, vec!["getty@tty1.service"]);
+        assert_eq!(changes.restart, vec!["nginx.service", "sshd.service"]);
+        assert!(changes.reload.is_empty());
+        assert_eq!(changes.start, vec!["new.service"]);
+        assert!(unit_changes_from_output(&output[2..4]).is_empty());
+    }
+
+    #[test]
+    fn job_list_parsing() {
+        let output = "1 multi-user.target start waiting
+                      87 network-online.target start waiting
+                      93 systemd-networkd-wait-online.service start running
+";
+        assert_eq!(
+            jobs_from_list_output(output),
+            vec![
+                "multi-user.target (start, waiting)",
+                "network-online.target (start, waiting)",
+                "systemd-networkd-wait-online.service (start, running)"
+            ]
+        );
+        assert!(jobs_from_list_output("").is_empty());
+    }
+
+    #[test]
+    fn unit_list_parsing() {
+        let output = "nginx.service loaded failed failed nginx\n\
+                      acme-example.com.service loaded failed failed Renew ACME certificate\n";
+        assert_eq!(
+            units_from_list_output(output),
+            vec!["nginx.service", "acme-example.com.service"]
+        );
+        assert!(units_from_list_output("").is_empty());
+    }
+
+    #[test]
+    fn failed_units_parsing() {
+        let output: Vec<String> = [
+            "restarting the following units: nginx.service",
+            "warning: the following units failed: nginx.service, acme-example.com.service",
+            "",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            failed_units_from_output(&output),
+            vec!["nginx.service", "acme-example.com.service"]
+        );
+        assert!(failed_units_from_output(&output[..1]).is_empty());
+    }
+
+    #[test]
+    fn previous_generation_parsing() {
+        let output = "  40   2024-01-10 12:00:00   \n  41   2024-01-12 09:30:00   \n  42   2024-01-15 18:45:00   (current)\n";
+        assert_eq!(previous_generation_from_output(output), Some(41));
+        assert_eq!(
+            previous_generation_from_output("  42   2024-01-15 18:45:00   (current)\n"),
+            None
+        );
+        assert_eq!(previous_generation_from_output(""), None);
+    }
+}
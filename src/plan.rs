@@ -0,0 +1,177 @@
+//! Plan files, which record the configurations that were staged on
+//! each destination so that they can be activated later on, possibly
+//! by a different invocation of deploy-flake.
+//!
+//! Plans can be signed with a [minisign](https://jedisct1.github.io/minisign/)
+//! key when they're written, so that activating them can check that
+//! they weren't tampered with in between.
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A system configuration that was built and registered as a GC root
+/// on a destination, waiting to be activated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StagedHost {
+    /// The destination, in the URL form accepted on the commandline.
+    pub destination: String,
+
+    /// The name of the system configuration that was built.
+    pub system_name: String,
+
+    /// The store path of the built system configuration.
+    pub configuration: PathBuf,
+
+    /// Whether the configuration has been activated on the destination.
+    #[serde(default)]
+    pub activated: bool,
+}
+
+/// The configurations staged across a set of destinations.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// The store path of the flake that the configurations were built from.
+    pub flake: String,
+
+    /// The source that the flake was built from (see
+    /// [`crate::Flake::provenance`]), recorded on each destination
+    /// when the plan gets activated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// The destinations that the configurations were staged on.
+    pub hosts: Vec<StagedHost>,
+
+    /// The minisign signature over the plan's staged configurations,
+    /// if it was signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Plan {
+    /// Reads a plan from a file.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents =
+            fs::read(path).with_context(|| format!("Could not read plan file {path:?}"))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("Could not parse plan file {path:?}"))
+    }
+
+    /// Writes the plan to a file, replacing it atomically so that an
+    /// interrupted write never leaves a truncated plan behind.
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Could not write plan file {tmp:?}"))?;
+        fs::rename(&tmp, path).with_context(|| format!("Could not replace plan file {path:?}"))
+    }
+
+    /// Returns the bytes that a plan's signature covers: everything
+    /// but the activation progress, which changes as the plan gets
+    /// activated.
+    fn signed_payload(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let mut plan = self.clone();
+        plan.signature = None;
+        for host in plan.hosts.iter_mut() {
+            host.activated = false;
+        }
+        Ok(serde_json::to_vec(&plan)?)
+    }
+
+    /// Signs the plan with a minisign secret key, using the
+    /// `minisign` tool (which asks for the key's password, if it has
+    /// one).
+    pub fn sign(&mut self, secret_key: &Path) -> Result<(), anyhow::Error> {
+        // A directory only we can access, so that nobody can swap out
+        // the payload or the signature while minisign works on them:
+        let dir = tempfile::Builder::new()
+            .prefix("deploy-flake-plan-")
+            .tempdir()
+            .context("Could not create a temporary directory")?;
+        let payload_file = dir.path().join("plan.json");
+        let signature_file = dir.path().join("plan.json.minisig");
+        fs::write(&payload_file, self.signed_payload()?)
+            .with_context(|| format!("Could not write {payload_file:?}"))?;
+        let status = Command::new("minisign")
+            .arg("-S")
+            .arg("-s")
+            .arg(secret_key)
+            .arg("-m")
+            .arg(&payload_file)
+            .arg("-x")
+            .arg(&signature_file)
+            .status()
+            .context("Could not execute minisign");
+        let signature = status.and_then(|status| {
+            if !status.success() {
+                bail!("minisign failed: {status}");
+            }
+            fs::read_to_string(&signature_file)
+                .with_context(|| format!("Could not read {signature_file:?}"))
+        });
+        self.signature = Some(signature.context("Could not sign the plan")?);
+        Ok(())
+    }
+
+    /// Checks that the plan carries a valid signature made with the
+    /// secret key belonging to the given minisign public key.
+    pub fn verify(&self, public_key: &Path) -> Result<(), anyhow::Error> {
+        let public_key = minisign_verify::PublicKey::from_file(public_key)
+            .with_context(|| format!("Could not read public key {public_key:?}"))?;
+        let signature = minisign_verify::Signature::decode(
+            self.signature
+                .as_deref()
+                .context("The plan is not signed")?,
+        )
+        .context("Could not parse the plan's signature")?;
+        public_key
+            .verify(&self.signed_payload()?, &signature, false)
+            .context("The plan's signature is invalid")
+    }
+
+    /// Returns the hosts that have not been activated yet.
+    pub fn pending(&self) -> impl Iterator<Item = &StagedHost> {
+        self.hosts.iter().filter(|host| !host.activated)
+    }
+
+    /// Records that the configuration on the given destination has
+    /// been activated.
+    pub fn mark_activated(&mut self, destination: &str) {
+        for host in self.hosts.iter_mut() {
+            if host.destination == destination {
+                host.activated = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Plan, StagedHost};
+
+    #[test]
+    fn signed_payload_ignores_activation() {
+        let mut plan = Plan {
+            flake: "/nix/store/aaa-source".to_string(),
+            source: None,
+            hosts: vec![StagedHost {
+                destination: "nixos://foo".to_string(),
+                system_name: "foo".to_string(),
+                configuration: "/nix/store/bbb-nixos-system-foo".into(),
+                activated: false,
+            }],
+            signature: None,
+        };
+        let payload = plan.signed_payload().unwrap();
+        plan.mark_activated("nixos://foo");
+        plan.signature = Some("signature".to_string());
+        assert_eq!(plan.signed_payload().unwrap(), payload);
+        plan.hosts[0].system_name = "bar".to_string();
+        assert_ne!(plan.signed_payload().unwrap(), payload);
+    }
+}
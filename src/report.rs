@@ -0,0 +1,288 @@
+//! Reports, which summarize what happened on each destination of a
+//! deployment, along with what deploy-flake learned about it.
+
+use crate::{Destination, HostFacts};
+use anyhow::Context;
+use serde::Serialize;
+use std::{fmt, fs, path::Path, path::PathBuf};
+use tracing as log;
+
+/// What happened on a destination.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// Deploying to the destination did not finish (or never started).
+    Pending,
+
+    /// The configuration was activated on the destination.
+    Succeeded,
+
+    /// The destination already ran the configuration, so it didn't
+    /// get activated again.
+    UpToDate,
+
+    /// The destination was skipped at the user's request.
+    Skipped,
+
+    /// Deploying to the destination failed.
+    Failed,
+}
+
+impl Outcome {
+    /// The outcome's name, as used in status events.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Pending => "pending",
+            Outcome::Succeeded => "succeeded",
+            Outcome::UpToDate => "up-to-date",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// A step of activating a configuration on a destination.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Step {
+    /// Activating the configuration on the running system.
+    Test,
+
+    /// Activating the configuration on the running system and
+    /// installing it as the boot configuration in one go.
+    Switch,
+
+    /// Confirming over a new connection that the destination is
+    /// still reachable after testing the configuration.
+    Confirm,
+
+    /// Taking filesystem snapshots, before anything gets activated.
+    Snapshot,
+
+    /// Taking the destination out of service (e.g. out of a load
+    /// balancer), before anything gets activated.
+    Drain,
+
+    /// Checking the system's health after testing the configuration.
+    HealthCheck,
+
+    /// Putting the destination back into service, once the
+    /// configuration is active and healthy.
+    Undrain,
+
+    /// Trying out the boot loader update, before changing the profile.
+    BootDryRun,
+
+    /// Making the configuration the current generation of the profile.
+    SetProfile,
+
+    /// Installing the configuration as the default boot entry.
+    UpdateBoot,
+
+    /// Deleting old generations of the profile, once the
+    /// configuration is installed as the boot configuration.
+    Prune,
+
+    /// Collecting garbage, after deleting old generations.
+    CollectGarbage,
+
+    /// Rebooting into the configuration, and waiting for the
+    /// destination to come back.
+    Reboot,
+
+    /// Checking the system's health after rebooting.
+    RebootHealthCheck,
+}
+
+impl Step {
+    /// The step's name, as used in log messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Snapshot => "snapshot",
+            Step::Test => "test",
+            Step::Switch => "switch",
+            Step::Confirm => "confirm",
+            Step::Drain => "drain",
+            Step::HealthCheck => "health-check",
+            Step::Undrain => "undrain",
+            Step::BootDryRun => "boot-dry-run",
+            Step::SetProfile => "set-profile",
+            Step::UpdateBoot => "update-boot",
+            Step::Prune => "prune",
+            Step::CollectGarbage => "collect-garbage",
+            Step::Reboot => "reboot",
+            Step::RebootHealthCheck => "reboot-health-check",
+        }
+    }
+
+    /// Describes the state that a destination is left in if this
+    /// step fails.
+    pub fn state_on_failure(&self) -> &'static str {
+        match self {
+            Step::Snapshot => "Some snapshots may have been taken, but the running system, profile and boot configuration are unchanged.",
+            Step::Test => "The new configuration may be partially active. The profile and boot configuration are unchanged, so rebooting returns to the previous configuration.",
+            Step::Switch => "The system profile points to the new configuration, which may be partially active, and the boot loader may not have been updated. Rebooting may or may not return to the previous configuration.",
+            Step::Confirm => "The host could not be reached after testing the new configuration, so it rolls back to the previous configuration on its own. The profile and boot configuration are unchanged.",
+            Step::HealthCheck => "The new configuration is active, but the system is unhealthy. The profile and boot configuration are unchanged, so rebooting returns to the previous configuration.",
+            Step::Drain => "The host may be partially drained, but the running system, profile and boot configuration are unchanged.",
+            Step::Undrain => "The new configuration is active, but the host is STILL DRAINED. Undrain it by hand once you've checked on it.",
+            Step::BootDryRun => "The profile and boot configuration are unchanged, so rebooting returns to the previous configuration.",
+            Step::SetProfile => "The system profile may point to the new configuration, but the boot configuration is unchanged.",
+            Step::UpdateBoot => "The system profile points to the new configuration, but the boot loader may not have been updated. Reset the system profile to clean up.",
+            Step::Prune => "The new configuration is active and installed as the boot configuration, but old generations may be left over, and the boot menu may still list deleted ones.",
+            Step::CollectGarbage => "The new configuration is active and installed as the boot configuration, and old generations are deleted, but their store paths may be left over.",
+            Step::Reboot => "The new configuration is installed as the boot configuration, but the host may not have come back from rebooting. Check on it through its console.",
+            Step::RebootHealthCheck => "The host rebooted into the new configuration, but is unhealthy. Roll back to return to the previous configuration.",
+        }
+    }
+}
+
+/// An activation step that completed successfully.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CompletedStep {
+    /// The step that completed.
+    pub step: Step,
+
+    /// How long the step took, in seconds.
+    pub seconds: f64,
+}
+
+/// A change in the version of a system component that often means
+/// that the destination needs a reboot, or that connections to it get
+/// dropped, like the kernel's.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct VersionChange {
+    /// The component, like "kernel" or "systemd".
+    pub component: String,
+
+    /// The component's version in the running system, if it has it.
+    pub from: Option<String>,
+
+    /// The component's version in the new configuration, if it has it.
+    pub to: Option<String>,
+}
+
+impl fmt::Display for VersionChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let from = self.from.as_deref().unwrap_or("none");
+        let to = self.to.as_deref().unwrap_or("none");
+        write!(f, "{} {from} -> {to}", self.component)
+    }
+}
+
+/// What happened on a single destination.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct HostReport {
+    /// The destination, in the URL form accepted on the commandline.
+    pub destination: String,
+
+    /// What happened on the destination.
+    pub outcome: Outcome,
+
+    /// The error that deploying to the destination failed with.
+    pub error: Option<String>,
+
+    /// The activation steps that completed, in order.
+    pub completed_steps: Vec<CompletedStep>,
+
+    /// The activation step that failed, if any.
+    pub failed_step: Option<Step>,
+
+    /// The state that the failed step left the destination in.
+    pub host_state: Option<String>,
+
+    /// The facts gathered about the destination, if it could be
+    /// connected to.
+    pub facts: Option<HostFacts>,
+
+    /// The name of the system configuration that was built.
+    pub system_name: Option<String>,
+
+    /// The store path of the built system configuration.
+    pub configuration: Option<PathBuf>,
+
+    /// The NixOS version of the built system configuration.
+    pub nixos_version: Option<String>,
+
+    /// The changes in the versions of the kernel, systemd and openssh
+    /// that activating the configuration makes.
+    pub version_changes: Vec<VersionChange>,
+
+    /// The filesystem snapshots taken before activating the
+    /// configuration.
+    pub snapshots: Vec<String>,
+
+    /// Problems that were found on the destination, but that didn't
+    /// stop the deploy (like failed preflight checks in warn mode).
+    pub warnings: Vec<String>,
+}
+
+impl HostReport {
+    /// Returns an empty report for a destination.
+    pub fn new(destination: &Destination) -> Self {
+        Self {
+            destination: destination.to_string(),
+            outcome: Outcome::Pending,
+            error: None,
+            completed_steps: vec![],
+            failed_step: None,
+            host_state: None,
+            facts: None,
+            system_name: None,
+            configuration: None,
+            nixos_version: None,
+            version_changes: vec![],
+            snapshots: vec![],
+            warnings: vec![],
+        }
+    }
+}
+
+/// What happened on every destination of a deployment.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Report {
+    /// The ID of the deploy run that the report is about.
+    pub deploy_id: Option<String>,
+
+    /// The destinations, in the order they were given in.
+    pub hosts: Vec<HostReport>,
+}
+
+impl Report {
+    /// Writes the report to a file as JSON.
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Could not write report file {path:?}"))
+    }
+
+    /// Logs one line per destination, saying what happened on it.
+    pub fn log_summary(&self) {
+        for host in &self.hosts {
+            let facts = host.facts.as_ref();
+            log::info!(
+                destination = host.destination,
+                outcome = ?host.outcome,
+                previous_system = ?facts.and_then(|facts| facts.current_system.as_ref()),
+                previous_generation = facts.and_then(|facts| facts.current_generation),
+                configuration = ?host.configuration,
+                version_changes = (!host.version_changes.is_empty()).then(|| {
+                    host.version_changes
+                        .iter()
+                        .map(VersionChange::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }),
+                error = host.error,
+                failed_step = host.failed_step.map(|step| step.name()),
+                host_state = host.host_state,
+                "Summary"
+            );
+            for warning in &host.warnings {
+                log::warn!(destination = host.destination, "{warning}");
+            }
+        }
+    }
+}
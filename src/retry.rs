@@ -0,0 +1,176 @@
+//! Retrying operations that fail for reasons that are likely to go
+//! away on their own, like network blips or copies that stall.
+
+use backon::{ExponentialBuilder, Retryable};
+use std::{fmt, future::Future, time::Duration};
+use tracing as log;
+
+/// How an operation gets retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times the operation gets retried after its first
+    /// attempt failed.
+    pub max_retries: usize,
+
+    /// How long a single attempt may take before it is abandoned
+    /// (and retried). Attempts may take arbitrarily long if unset.
+    pub attempt_timeout: Option<Duration>,
+
+    /// The delay before the first retry. Later retries wait
+    /// exponentially longer.
+    pub min_delay: Duration,
+
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            attempt_timeout: None,
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the policy with at most `max_retries` retries.
+    pub fn with_max_retries(self, max_retries: usize) -> Self {
+        Self {
+            max_retries,
+            ..self
+        }
+    }
+
+    /// Returns the policy with a timeout for each attempt.
+    pub fn with_attempt_timeout(self, attempt_timeout: Option<Duration>) -> Self {
+        Self {
+            attempt_timeout,
+            ..self
+        }
+    }
+}
+
+/// The error returned when an attempt took longer than the retry
+/// policy's attempt timeout.
+#[derive(Debug)]
+pub struct AttemptTimedOut(pub Duration);
+
+impl fmt::Display for AttemptTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out after {}", humantime::format_duration(self.0))
+    }
+}
+
+impl std::error::Error for AttemptTimedOut {}
+
+/// Runs the operation `f` until it succeeds, retrying it according
+/// to `policy` with exponential backoff. Attempts that time out
+/// always get retried; other failures only if `retry_if` returns
+/// true for their error. `what` names the operation in log messages.
+pub async fn retrying<T, F, Fut>(
+    what: &str,
+    policy: &RetryPolicy,
+    retry_if: impl Fn(&anyhow::Error) -> bool,
+    mut f: F,
+) -> Result<T, anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    let attempt_timeout = policy.attempt_timeout;
+    (|| {
+        let attempt = f();
+        async move {
+            match attempt_timeout {
+                None => attempt.await,
+                Some(timeout) => tokio::time::timeout(timeout, attempt)
+                    .await
+                    .map_err(|_| anyhow::Error::new(AttemptTimedOut(timeout)))?,
+            }
+        }
+    })
+    .retry(
+        ExponentialBuilder::default()
+            .with_min_delay(policy.min_delay)
+            .with_max_delay(policy.max_delay)
+            .with_max_times(policy.max_retries),
+    )
+    .when(|e| e.is::<AttemptTimedOut>() || retry_if(e))
+    .notify(|e, after| {
+        log::event!(log::Level::WARN, error=%e, retry_after=?after, "{what} failed, retrying");
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{retrying, AttemptTimedOut, RetryPolicy};
+    use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, time::Duration};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            attempt_timeout: Some(Duration::from_millis(50)),
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_only_matching_errors() {
+        let attempts = AtomicUsize::new(0);
+        let result = retrying(
+            "Testing",
+            &policy(),
+            |e| e.to_string() == "transient",
+            || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match attempt {
+                        0 => anyhow::bail!("transient"),
+                        _ => Ok(attempt),
+                    }
+                }
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap(), 1);
+
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), _> = retrying(
+            "Testing",
+            &policy(),
+            |_| false,
+            || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { anyhow::bail!("permanent") }
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = retrying(
+            "Testing",
+            &policy(),
+            |_| false,
+            || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    Ok(())
+                }
+            },
+        )
+        .await;
+        assert!(result.unwrap_err().is::<AttemptTimedOut>());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,144 @@
+//! Filesystem snapshots that get taken on a destination before a
+//! configuration gets activated, as a data-level rollback point.
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+/// The kind of filesystem (or volume manager) to take a snapshot with.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SnapshotKind {
+    /// A ZFS dataset, snapshotted with `zfs snapshot`.
+    Zfs,
+
+    /// A btrfs subvolume, snapshotted read-only next to itself with
+    /// `btrfs subvolume snapshot -r`.
+    Btrfs,
+
+    /// An LVM logical volume, snapshotted with `lvcreate --snapshot`.
+    Lvm,
+}
+
+/// A dataset, subvolume or logical volume to snapshot, written as
+/// `zfs:DATASET`, `btrfs:SUBVOLUME-PATH` or `lvm:VG/LV`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Snapshot {
+    pub kind: SnapshotKind,
+    pub source: String,
+}
+
+impl Snapshot {
+    /// Returns the command that takes a snapshot called `name`, and
+    /// the full name of the snapshot it creates.
+    pub fn command(&self, name: &str) -> (Vec<String>, String) {
+        let source = &self.source;
+        match self.kind {
+            SnapshotKind::Zfs => {
+                let snapshot = format!("{source}@{name}");
+                (
+                    vec!["zfs".into(), "snapshot".into(), snapshot.clone()],
+                    snapshot,
+                )
+            }
+            SnapshotKind::Btrfs => {
+                let snapshot = format!("{}/.{name}", source.trim_end_matches('/'));
+                (
+                    vec![
+                        "btrfs".into(),
+                        "subvolume".into(),
+                        "snapshot".into(),
+                        "-r".into(),
+                        source.clone(),
+                        snapshot.clone(),
+                    ],
+                    snapshot,
+                )
+            }
+            SnapshotKind::Lvm => {
+                let volume_group = source.split('/').next().unwrap_or_default();
+                let snapshot = format!("{volume_group}/{name}");
+                (
+                    vec![
+                        "lvcreate".into(),
+                        "--snapshot".into(),
+                        "--extents".into(),
+                        "10%ORIGIN".into(),
+                        "--name".into(),
+                        name.into(),
+                        source.clone(),
+                    ],
+                    snapshot,
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for Snapshot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, source) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Snapshot {s:?} must be of the form KIND:SOURCE"))?;
+        let kind = match kind {
+            "zfs" => SnapshotKind::Zfs,
+            "btrfs" => SnapshotKind::Btrfs,
+            "lvm" => SnapshotKind::Lvm,
+            kind => bail!("Unknown snapshot kind {kind:?} - must be zfs, btrfs or lvm"),
+        };
+        if source.is_empty() {
+            bail!("Snapshot {s:?} names no source");
+        }
+        if kind == SnapshotKind::Lvm && !source.contains('/') {
+            bail!("LVM snapshot source {source:?} must be of the form VG/LV");
+        }
+        Ok(Snapshot {
+            kind,
+            source: source.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            SnapshotKind::Zfs => "zfs",
+            SnapshotKind::Btrfs => "btrfs",
+            SnapshotKind::Lvm => "lvm",
+        };
+        write!(f, "{kind}:{}", self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Snapshot {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Snapshot;
+    use test_case::test_case;
+
+    #[test_case("zfs:rpool/root", "zfs snapshot rpool/root@df", "rpool/root@df" ; "zfs")]
+    #[test_case("btrfs:/home/", "btrfs subvolume snapshot -r /home/ /home/.df", "/home/.df" ; "btrfs")]
+    #[test_case("lvm:vg0/root", "lvcreate --snapshot --extents 10%ORIGIN --name df vg0/root", "vg0/df" ; "lvm")]
+    fn snapshot_commands(spec: &str, command: &str, name: &str) {
+        let snapshot: Snapshot = spec.parse().unwrap();
+        assert_eq!(snapshot.to_string(), spec);
+        let (cmd, snapshot_name) = snapshot.command("df");
+        assert_eq!(cmd.join(" "), command);
+        assert_eq!(snapshot_name, name);
+    }
+
+    #[test_case("rpool/root" ; "no kind")]
+    #[test_case("xfs:/" ; "unknown kind")]
+    #[test_case("zfs:" ; "no source")]
+    #[test_case("lvm:root" ; "lvm without volume group")]
+    fn snapshot_parsing_errors(spec: &str) {
+        assert!(spec.parse::<Snapshot>().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! ssh options and configuration files that every ssh that
+//! deploy-flake runs gets, without changing the user's
+//! `~/.ssh/config`.
+//!
+//! The [`openssh`] crate can only be given a configuration file, not
+//! individual options, so options get written to a configuration file
+//! of their own, which then includes the configuration that ssh would
+//! otherwise read. ssh uses the first value it finds for an option,
+//! so the options take precedence over that configuration, just like
+//! they would with `ssh -o`.
+
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// The ssh configuration for a run.
+#[derive(Debug)]
+pub struct SshConfig {
+    /// The configuration file that ssh gets pointed at with `-F`.
+    file: PathBuf,
+
+    /// The directory holding the generated configuration file, if
+    /// there is one; it gets removed along with the configuration.
+    _dir: Option<TempDir>,
+}
+
+impl SshConfig {
+    /// Returns the configuration that applies `options` (like
+    /// `IdentityFile=~/.ssh/deploy`, as given to `ssh -o`) on top of
+    /// the configuration file `config`, or the user's and the
+    /// system's configuration if there is none. Returns `None` if
+    /// there is nothing to change about ssh's defaults.
+    pub fn new(options: &[String], config: Option<&Path>) -> Result<Option<Self>, anyhow::Error> {
+        // Included files with relative paths are looked up in ~/.ssh,
+        // not in the current directory:
+        let config = config
+            .map(|config| {
+                std::fs::canonicalize(config)
+                    .with_context(|| format!("Could not find the ssh configuration {config:?}"))
+            })
+            .transpose()?;
+        if options.is_empty() {
+            return Ok(config.map(|file| SshConfig { file, _dir: None }));
+        }
+        let dir = tempfile::Builder::new()
+            .prefix("deploy-flake-ssh-config-")
+            .tempdir()
+            .context("Could not create a directory for the ssh configuration")?;
+        let ssh_config = SshConfig {
+            file: dir.path().join("config"),
+            _dir: Some(dir),
+        };
+        std::fs::write(&ssh_config.file, contents(options, config.as_deref())?)
+            .with_context(|| format!("Could not write {:?}", ssh_config.file))?;
+        Ok(Some(ssh_config))
+    }
+
+    /// Returns the configuration file that ssh gets pointed at.
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+}
+
+/// Returns the lines of an ssh configuration that include `config`,
+/// or if there is none, the configuration that ssh reads by default.
+pub(crate) fn includes(config: Option<&Path>) -> String {
+    match config {
+        Some(config) => format!("Include \"{}\"\n", config.display()),
+        None => "Include ~/.ssh/config\nInclude /etc/ssh/ssh_config\n".to_string(),
+    }
+}
+
+/// Returns an ssh configuration that sets `options` and otherwise
+/// uses `config`.
+fn contents(options: &[String], config: Option<&Path>) -> Result<String, anyhow::Error> {
+    let mut contents = String::new();
+    for option in options {
+        if option.trim().is_empty() || option.contains('\n') {
+            anyhow::bail!("{option:?} is not an ssh option");
+        }
+        contents.push_str(option.trim());
+        contents.push('\n');
+    }
+    contents.push_str(&includes(config));
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::contents;
+    use std::path::Path;
+
+    #[test]
+    fn options_come_first() {
+        let options = vec![
+            "IdentityFile=~/.ssh/deploy".to_string(),
+            "Ciphers aes256-gcm@openssh.com".to_string(),
+        ];
+        assert_eq!(
+            contents(&options, None).unwrap(),
+            "IdentityFile=~/.ssh/deploy\n\
+             Ciphers aes256-gcm@openssh.com\n\
+             Include ~/.ssh/config\n\
+             Include /etc/ssh/ssh_config\n"
+        );
+        assert_eq!(
+            contents(&options[..1], Some(Path::new("/etc/deploy/ssh config"))).unwrap(),
+            "IdentityFile=~/.ssh/deploy\n\
+             Include \"/etc/deploy/ssh config\"\n"
+        );
+        assert!(contents(&["Port 22\nHost *".to_string()], None).is_err());
+    }
+}
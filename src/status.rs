@@ -0,0 +1,189 @@
+//! Machine-readable progress events, written as line-delimited JSON
+//! to a side channel (like a file descriptor that a wrapper tool
+//! reads from), independently of the human-readable logs.
+//!
+//! Every event is a JSON object with an `event` field. Whenever a
+//! destination enters a phase of the deploy, a `phase` event gets
+//! written; events logged with the [`STATUS_TARGET`] target get
+//! written with their message as the `event` name. Events carry the
+//! run's `deploy_id`, and the `host` and `config` of the destination
+//! they concern, if any.
+
+use serde_json::{Map, Value};
+use std::{fmt, io::Write, sync::Mutex};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// The tracing target of events that get written to the status
+/// channel.
+pub const STATUS_TARGET: &str = "deploy_flake::status";
+
+/// The fields of spans and events that get copied into status events.
+const CONTEXT_FIELDS: &[&str] = &["deploy_id", "host", "config"];
+
+/// The name of the spans that [`phase`] returns.
+const PHASE_SPAN: &str = "phase";
+
+/// Returns a span for a phase of deploying to a destination, so that
+/// every log line from that phase carries its name. Phases run in
+/// [`in_phase`](crate::hooks::in_phase), which makes one.
+pub(crate) fn phase(name: &'static str) -> tracing::Span {
+    tracing::info_span!("phase", phase = name)
+}
+
+/// A tracing layer that writes status events to a writer.
+pub struct StatusLayer {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl StatusLayer {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Returns whether the layer needs to see spans or events with
+    /// this metadata.
+    pub fn is_interested(metadata: &tracing::Metadata<'_>) -> bool {
+        metadata.is_span() || metadata.target() == STATUS_TARGET
+    }
+
+    fn write(&self, event: Map<String, Value>) {
+        let mut writer = self.writer.lock().unwrap();
+        // The status channel is best-effort: if the reader went away,
+        // the deploy carries on regardless.
+        let _ = serde_json::to_writer(&mut *writer, &event);
+        let _ = writer.write_all(b"\n");
+        let _ = writer.flush();
+    }
+
+    /// Adds the context fields of the span and its ancestors to the
+    /// event, unless the event already has them.
+    fn add_context<S>(event: &mut Map<String, Value>, span: Option<span::Id>, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = span.and_then(|id| ctx.span(&id)) else {
+            return;
+        };
+        for span in span.scope() {
+            if let Some(Fields(fields)) = span.extensions().get::<Fields>() {
+                for (name, value) in fields {
+                    if !event.contains_key(name) {
+                        event.insert(name.clone(), value.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The context fields recorded on a span.
+struct Fields(Map<String, Value>);
+
+/// Collects the fields of a span or event into a JSON object.
+struct JsonVisitor<'a> {
+    fields: &'a mut Map<String, Value>,
+    only: Option<&'static [&'static str]>,
+}
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        if self.only.is_none_or(|only| only.contains(&field.name())) {
+            self.fields.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+impl<S> Layer<S> for StatusLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor {
+            fields: &mut fields,
+            only: Some(CONTEXT_FIELDS),
+        });
+        if !fields.is_empty() {
+            span.extensions_mut().insert(Fields(fields));
+        }
+        if span.name() == PHASE_SPAN {
+            let mut event = Map::new();
+            event.insert("event".to_string(), "phase".into());
+            attrs.record(&mut JsonVisitor {
+                fields: &mut event,
+                only: Some(&["phase"]),
+            });
+            Self::add_context(&mut event, Some(id.clone()), &ctx);
+            self.write(event);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<Fields>().is_none() {
+            extensions.insert(Fields(Map::new()));
+        }
+        let Fields(fields) = extensions.get_mut::<Fields>().unwrap();
+        values.record(&mut JsonVisitor {
+            fields,
+            only: Some(CONTEXT_FIELDS),
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != STATUS_TARGET {
+            return;
+        }
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor {
+            fields: &mut fields,
+            only: None,
+        });
+        let mut status = Map::new();
+        status.insert(
+            "event".to_string(),
+            fields.remove("message").unwrap_or(Value::Null),
+        );
+        status.extend(fields);
+        let parent = event
+            .parent()
+            .cloned()
+            .or_else(|| ctx.current_span().id().cloned());
+        Self::add_context(&mut status, parent, &ctx);
+        self.write(status);
+    }
+}
@@ -0,0 +1,335 @@
+//! Logging what subprocesses print, line by line, as they run.
+//!
+//! deploy-flake reads the stdout and stderr of every command it runs
+//! with a [`SubprocessLogger`], which hands each line to its sinks:
+//! by default, a [`TracingSink`] that logs it under
+//! [`SUBPROCESS_LOG_TARGET`](crate::SUBPROCESS_LOG_TARGET). Tools
+//! built on this crate can use it for the commands they spawn
+//! themselves, so that their output gets logged the same way, and
+//! add sinks of their own by implementing [`LineSink`]. Loggers made
+//! [for a destination's options](SubprocessLogger::for_options) hand
+//! the lines to the destination's phase hooks, too.
+
+use crate::{hooks::HookSink, OutputLine, RemoteOptions, SubprocessLogLevels};
+use anyhow::Context;
+use futures::future::BoxFuture;
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tracing as log;
+
+/// The stream that a subprocess prints a line to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    /// Returns the stream's name, like `stdout`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+
+    /// Returns the letter that logged lines from the stream start
+    /// with: `O` for stdout, `E` for stderr.
+    pub(crate) fn tag(self) -> &'static str {
+        match self {
+            Stream::Stdout => "O",
+            Stream::Stderr => "E",
+        }
+    }
+}
+
+/// Receives the lines that a subprocess prints.
+pub trait LineSink: fmt::Debug + Send {
+    /// Handles a line. A sink that can't keep up may make the
+    /// subprocess wait by not finishing right away: the subprocess
+    /// blocks once the pipe it prints to is full.
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+}
+
+/// Reads what a subprocess prints to one of its streams and hands
+/// every line to each of its sinks, in order.
+#[derive(Debug, Default)]
+pub struct SubprocessLogger {
+    sinks: Vec<Box<dyn LineSink>>,
+}
+
+impl SubprocessLogger {
+    /// Returns a logger that logs lines at the level that `levels`
+    /// assigns to them.
+    pub fn new(levels: SubprocessLogLevels) -> Self {
+        Self::default().with_sink(TracingSink(levels))
+    }
+
+    /// Returns a logger that logs lines at the level that `options`
+    /// assign to them, and hands them to the
+    /// [phase hooks](crate::hooks::PhaseHooks::on_subprocess_line)
+    /// in `options`, if any.
+    pub fn for_options(options: &RemoteOptions) -> Self {
+        let logger = Self::new(options.log_levels);
+        match &options.phase_hooks {
+            Some(hooks) => logger.with_sink(HookSink::new(hooks.clone())),
+            None => logger,
+        }
+    }
+
+    /// Adds a sink that every line gets handed to, after the sinks
+    /// that were added before.
+    pub fn with_sink(mut self, sink: impl LineSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Reads the lines that a subprocess prints to `stream` from `r`
+    /// until it ends, handing them to the sinks.
+    pub async fn read(
+        mut self,
+        stream: Stream,
+        r: impl AsyncRead + Unpin,
+    ) -> Result<(), anyhow::Error> {
+        let mut lines = BufReader::new(r).lines();
+        while let Some(line) = lines
+            .next_line()
+            .await
+            .context("Unable to read next line")?
+        {
+            self.handle(&OutputLine { stream, line }).await?;
+        }
+        Ok(())
+    }
+
+    /// Hands a line to each of the sinks, in order.
+    pub async fn handle(&mut self, line: &OutputLine) -> Result<(), anyhow::Error> {
+        for sink in &mut self.sinks {
+            sink.line(line).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs lines under [`SUBPROCESS_LOG_TARGET`](crate::SUBPROCESS_LOG_TARGET),
+/// at the level that the [`SubprocessLogLevels`] assign to them.
+#[derive(Debug, Clone, Copy)]
+pub struct TracingSink(pub SubprocessLogLevels);
+
+impl LineSink for TracingSink {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        self.0.log(line.stream, &line.line);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Keeps the last lines, e.g. to show them in an error message when
+/// the subprocess fails. Clones share the same lines.
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    capacity: usize,
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl RingBuffer {
+    /// Returns a buffer that keeps up to `capacity` lines.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: Default::default(),
+        }
+    }
+
+    /// Returns the lines that the buffer holds, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl LineSink for RingBuffer {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        let mut lines = self.lines.lock().unwrap();
+        if self.capacity > 0 {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line.line.clone());
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Keeps every line, e.g. to look through all of them once the
+/// subprocess is done. Clones share the same lines.
+#[derive(Debug, Clone, Default)]
+pub struct Collector {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl Collector {
+    /// Returns the lines that were collected, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+}
+
+impl LineSink for Collector {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        self.lines.lock().unwrap().push(line.line.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Appends lines to a file, each prefixed with the stream it was
+/// printed to.
+#[derive(Debug)]
+pub struct FileSink(tokio::fs::File);
+
+impl FileSink {
+    /// Opens `path` for appending, creating it if it doesn't exist.
+    pub async fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Could not open {path:?}"))?;
+        Ok(Self(file))
+    }
+}
+
+impl LineSink for FileSink {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            self.0
+                .write_all(format!("{} {}\n", line.stream.name(), line.line).as_bytes())
+                .await
+                .context("Could not write to the log file")?;
+            // Writes finish in the background; flushing waits for
+            // them, so that no line gets lost when the file is closed:
+            self.0
+                .flush()
+                .await
+                .context("Could not write to the log file")
+        })
+    }
+}
+
+/// What a [`ChannelSink`] does when its channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Wait until the receiver makes room, which makes the subprocess
+    /// wait, too.
+    #[default]
+    Wait,
+
+    /// Drop the line, so that a slow receiver never holds up the
+    /// subprocess. Dropped lines get counted and logged.
+    Drop,
+}
+
+/// Sends lines to a channel, as events for another task to handle. A
+/// receiver that goes away only means that nobody is interested in
+/// the lines any more.
+#[derive(Debug)]
+pub struct ChannelSink {
+    to: mpsc::Sender<OutputLine>,
+    backpressure: Backpressure,
+    dropped: usize,
+}
+
+impl ChannelSink {
+    /// Returns a sink that sends lines to `to`, doing what
+    /// `backpressure` says when the channel is full.
+    pub fn new(to: mpsc::Sender<OutputLine>, backpressure: Backpressure) -> Self {
+        Self {
+            to,
+            backpressure,
+            dropped: 0,
+        }
+    }
+}
+
+impl Drop for ChannelSink {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            log::warn!(
+                dropped = self.dropped,
+                "Dropped output lines that the receiver couldn't keep up with"
+            );
+        }
+    }
+}
+
+impl LineSink for ChannelSink {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            match self.backpressure {
+                Backpressure::Wait => {
+                    let _ = self.to.send(line.clone()).await;
+                }
+                Backpressure::Drop => {
+                    if let Err(mpsc::error::TrySendError::Full(_)) = self.to.try_send(line.clone())
+                    {
+                        self.dropped += 1;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sends lines to a channel that never fills up.
+impl LineSink for mpsc::UnboundedSender<OutputLine> {
+    fn line<'a>(&'a mut self, line: &'a OutputLine) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        let _ = self.send(line.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Backpressure, ChannelSink, Collector, FileSink, RingBuffer, Stream, SubprocessLogger,
+    };
+    use crate::OutputLine;
+
+    #[tokio::test]
+    async fn sinks() {
+        let ring = RingBuffer::new(2);
+        let collector = Collector::default();
+        let (to, mut received) = tokio::sync::mpsc::channel(1);
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("log");
+        SubprocessLogger::default()
+            .with_sink(ring.clone())
+            .with_sink(collector.clone())
+            .with_sink(ChannelSink::new(to, Backpressure::Drop))
+            .with_sink(FileSink::open(&file).await.unwrap())
+            .read(Stream::Stderr, &b"one\ntwo\nthree\n"[..])
+            .await
+            .unwrap();
+        assert_eq!(ring.lines(), vec!["two", "three"]);
+        assert_eq!(collector.lines(), vec!["one", "two", "three"]);
+        assert_eq!(
+            received.recv().await,
+            Some(OutputLine {
+                stream: Stream::Stderr,
+                line: "one".to_string()
+            })
+        );
+        // The rest didn't fit into the channel:
+        assert_eq!(received.recv().await, None);
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "stderr one\nstderr two\nstderr three\n"
+        );
+    }
+}
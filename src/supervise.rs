@@ -0,0 +1,250 @@
+//! Running an operation on many destinations at once, each in a task
+//! of its own.
+//!
+//! A [`Supervisor`] keeps track of what became of the task for each
+//! destination. A task that panics only fails its own destination,
+//! instead of taking down the whole run, and tasks that get cancelled
+//! count as failed, too.
+
+use futures::FutureExt;
+use std::{any::Any, fmt, future::Future, panic::AssertUnwindSafe};
+use tokio::task::{JoinError, JoinSet};
+use tracing as log;
+
+/// What became of the task for a destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostState {
+    /// The task is still running.
+    Running,
+
+    /// The task returned successfully.
+    Succeeded,
+
+    /// The task returned an error.
+    Failed,
+
+    /// The task panicked.
+    Panicked,
+
+    /// The task got cancelled before it finished.
+    Cancelled,
+}
+
+/// The error that a destination whose task panicked fails with.
+#[derive(Debug)]
+pub struct Panicked(pub String);
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Panicked: {}", self.0)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+impl Panicked {
+    /// Takes the message out of what a task panicked with.
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Self(message)
+    }
+}
+
+/// The error that a destination whose task got cancelled fails with.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// What a task returned, or what it panicked with.
+type Returned<T> = Result<Result<T, anyhow::Error>, Box<dyn Any + Send>>;
+
+/// The tasks working on a set of destinations.
+pub struct Supervisor<T> {
+    /// The tasks, each returning the index of its destination in
+    /// `hosts` along with its result.
+    tasks: JoinSet<(usize, Returned<T>)>,
+
+    /// The destinations, in the order their tasks were spawned, and
+    /// what became of them.
+    hosts: Vec<(String, HostState)>,
+}
+
+impl<T> Default for Supervisor<T> {
+    fn default() -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            hosts: vec![],
+        }
+    }
+}
+
+impl<T> fmt::Debug for Supervisor<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("hosts", &self.hosts)
+            .finish()
+    }
+}
+
+impl<T: Send + 'static> Supervisor<T> {
+    /// Starts working on `host` in a task of its own.
+    pub fn spawn(
+        &mut self,
+        host: impl Into<String>,
+        task: impl Future<Output = Result<T, anyhow::Error>> + Send + 'static,
+    ) {
+        let index = self.hosts.len();
+        self.hosts.push((host.into(), HostState::Running));
+        self.tasks
+            .spawn(async move { (index, AssertUnwindSafe(task).catch_unwind().await) });
+    }
+
+    /// Cancels the tasks that are still running. Their destinations
+    /// fail with [`Cancelled`].
+    pub fn cancel(&mut self) {
+        self.tasks.abort_all();
+    }
+
+    /// Waits for all tasks to finish, and returns their results in
+    /// the order they were spawned in. Must only be called once.
+    pub async fn join(&mut self) -> Vec<Result<T, anyhow::Error>> {
+        let mut results: Vec<Option<Result<T, anyhow::Error>>> = std::iter::repeat_with(|| None)
+            .take(self.hosts.len())
+            .collect();
+        while let Some(joined) = self.tasks.join_next().await {
+            // Tasks that got cancelled don't say which destination
+            // they were working on, and get left at `None`:
+            let Ok((index, returned)) = joined else {
+                continue;
+            };
+            let (state, result) = match returned {
+                Ok(Ok(value)) => (HostState::Succeeded, Ok(value)),
+                Ok(Err(e)) => (HostState::Failed, Err(e)),
+                Err(payload) => (
+                    HostState::Panicked,
+                    Err(Panicked::from_payload(payload).into()),
+                ),
+            };
+            self.hosts[index].1 = state;
+            results[index] = Some(result);
+        }
+        results
+            .into_iter()
+            .zip(&mut self.hosts)
+            .map(|(result, (host, state))| {
+                let result = result.unwrap_or_else(|| {
+                    *state = HostState::Cancelled;
+                    Err(Cancelled.into())
+                });
+                if let Err(e) = &result {
+                    if *state != HostState::Failed {
+                        log::error!(destination = host, error = %e, "Task failed");
+                    }
+                }
+                result
+            })
+            .collect()
+    }
+
+    /// Returns every destination, along with what became of its task.
+    pub fn states(&self) -> impl Iterator<Item = (&str, HostState)> {
+        self.hosts
+            .iter()
+            .map(|(host, state)| (host.as_str(), *state))
+    }
+}
+
+/// Turns the error that joining a task of its own returned into the
+/// error its destination fails with: [`Panicked`] or [`Cancelled`].
+pub fn join_error(e: JoinError) -> anyhow::Error {
+    match e.try_into_panic() {
+        Ok(payload) => Panicked::from_payload(payload).into(),
+        Err(_) => Cancelled.into(),
+    }
+}
+
+/// Works on every destination in a task of its own, and returns the
+/// results in the order of the destinations. See [`Supervisor`].
+pub async fn supervise<T, F>(
+    tasks: impl IntoIterator<Item = (String, F)>,
+) -> Vec<Result<T, anyhow::Error>>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T, anyhow::Error>> + Send + 'static,
+{
+    let mut supervisor = Supervisor::default();
+    for (host, task) in tasks {
+        supervisor.spawn(host, task);
+    }
+    supervisor.join().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{join_error, Cancelled, HostState, Panicked, Supervisor};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn supervision() {
+        let mut supervisor = Supervisor::default();
+        supervisor.spawn("slow", async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok(1)
+        });
+        supervisor.spawn("failing", async { anyhow::bail!("nope") });
+        supervisor.spawn("panicking", async { panic!("oops") });
+        let results = supervisor.join().await;
+        assert_eq!(results[0].as_ref().unwrap(), &1);
+        assert_eq!(results[1].as_ref().unwrap_err().to_string(), "nope");
+        let panicked = results[2].as_ref().unwrap_err();
+        assert_eq!(panicked.downcast_ref::<Panicked>().unwrap().0, "oops");
+        assert_eq!(
+            supervisor.states().collect::<Vec<_>>(),
+            vec![
+                ("slow", HostState::Succeeded),
+                ("failing", HostState::Failed),
+                ("panicking", HostState::Panicked)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancellation() {
+        let mut supervisor = Supervisor::<()>::default();
+        supervisor.spawn("stuck", std::future::pending());
+        assert_eq!(
+            supervisor.states().collect::<Vec<_>>(),
+            vec![("stuck", HostState::Running)]
+        );
+        supervisor.cancel();
+        let results = supervisor.join().await;
+        assert!(results[0].as_ref().unwrap_err().is::<Cancelled>());
+        assert_eq!(
+            supervisor.states().collect::<Vec<_>>(),
+            vec![("stuck", HostState::Cancelled)]
+        );
+    }
+
+    #[tokio::test]
+    async fn join_errors() {
+        let panicked = tokio::spawn(async { panic!("oops") }).await.unwrap_err();
+        let panicked = join_error(panicked);
+        assert_eq!(panicked.downcast_ref::<Panicked>().unwrap().0, "oops");
+
+        let stuck = tokio::spawn(std::future::pending::<()>());
+        stuck.abort();
+        let cancelled = join_error(stuck.await.unwrap_err());
+        assert!(cancelled.is::<Cancelled>());
+    }
+}
@@ -0,0 +1,155 @@
+//! Connections that defer entirely to the system's ssh and its
+//! configuration.
+//!
+//! The [`openssh`] crate starts its ssh connections with options of
+//! its own (like batch mode, a strict host key check and its own
+//! control socket), which take precedence over the user's ssh
+//! configuration. A [`Master`] instead runs the system's ssh with
+//! only the options needed to multiplex commands over it, so that
+//! everything else (ProxyCommand, Match blocks, GSSAPI, password
+//! prompts and so on) works just like it does for a plain `ssh`.
+
+use anyhow::Context;
+use std::{process::Stdio, time::Duration};
+use tempfile::TempDir;
+use tokio::process::{Child, Command};
+use tracing as log;
+
+/// How often to check whether the master connection is up yet.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A master connection, run by the system's ssh, that the commands
+/// on a destination get multiplexed over. Dropping it closes the
+/// connection.
+#[derive(Debug)]
+pub(crate) struct Master {
+    /// The ssh process; it gets killed when the master is dropped.
+    child: Child,
+
+    /// The directory holding the control socket and the log; it
+    /// gets removed once the ssh process is gone.
+    _dir: TempDir,
+}
+
+impl Master {
+    /// Connects to the host with the system's ssh, waiting until the
+    /// connection is established (which may involve prompting the
+    /// user on the terminal), and returns a session that runs
+    /// commands over it.
+    pub(crate) async fn connect(
+        host: &str,
+        port: Option<u16>,
+        options: &crate::RemoteOptions,
+    ) -> Result<(Self, openssh::Session), anyhow::Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("deploy-flake-ssh-")
+            .tempdir()
+            .context("Could not create a directory for the control socket")?;
+        let socket = dir.path().join("master");
+        let log = dir.path().join("log");
+        log::event!(log::Level::DEBUG, host, "Connecting with the system's ssh");
+        let child = Command::new("ssh")
+            .args(master_args(port, options))
+            .arg("-S")
+            .arg(&socket)
+            .arg("-E")
+            .arg(&log)
+            .arg(host)
+            .stdin(if options.non_interactive {
+                Stdio::null()
+            } else {
+                Stdio::inherit()
+            })
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Could not run ssh")?;
+        let mut master = Self { child, _dir: dir };
+        loop {
+            if let Some(status) = master.child.try_wait()? {
+                let log = std::fs::read_to_string(&log).unwrap_or_default();
+                anyhow::bail!(
+                    "Connecting to {host:?} failed with {status}: {}",
+                    log.trim()
+                );
+            }
+            let check = Command::new("ssh")
+                .arg("-S")
+                .arg(&socket)
+                .args(["-O", "check", host])
+                .stdin(Stdio::null())
+                .output()
+                .await
+                .context("Could not run ssh")?;
+            if check.status.success() {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        let session = openssh::Session::resume(socket.into(), Some(log.into()));
+        Ok((master, session))
+    }
+}
+
+/// Returns the options that a master connection runs with, other
+/// than its control socket, log file and destination.
+fn master_args(port: Option<u16>, options: &crate::RemoteOptions) -> Vec<String> {
+    let mut args = vec!["-M".to_string(), "-N".to_string()];
+    if let Some(port) = port {
+        args.extend(["-p".to_string(), port.to_string()]);
+    }
+    if let Some(config) = &options.ssh_config {
+        args.extend([
+            "-F".to_string(),
+            config.file().to_string_lossy().into_owned(),
+        ]);
+    }
+    // These only get passed if they were asked for explicitly:
+    if options.non_interactive {
+        args.extend(["-o".to_string(), "BatchMode=yes".to_string()]);
+    }
+    if let Some(policy) = options.host_key_policy {
+        args.extend(["-o".to_string(), policy.ssh_option()]);
+    }
+    if let Some(keep_alive) = options.keep_alive {
+        args.extend([
+            "-o".to_string(),
+            format!("ServerAliveInterval={}", keep_alive.as_secs().max(1)),
+        ]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::master_args;
+    use crate::RemoteOptions;
+    use std::time::Duration;
+
+    #[test]
+    fn defers_to_the_configuration() {
+        assert_eq!(
+            master_args(None, &RemoteOptions::default()),
+            vec!["-M", "-N"]
+        );
+        let options = RemoteOptions {
+            non_interactive: true,
+            keep_alive: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert_eq!(
+            master_args(Some(2222), &options),
+            vec![
+                "-M",
+                "-N",
+                "-p",
+                "2222",
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ServerAliveInterval=30"
+            ]
+        );
+    }
+}
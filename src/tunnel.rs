@@ -0,0 +1,226 @@
+//! Destinations that can't be connected to directly, like devices
+//! behind NAT: they dial in to the machine that deploy-flake runs on
+//! instead, forwarding a port there to their own ssh server (a
+//! reverse tunnel, as with `ssh -R PORT:localhost:22`). deploy-flake
+//! then connects to them through that port, once they have dialed
+//! in.
+//!
+//! Alternatively, a [`Broker`] lets them dial in to deploy-flake
+//! itself, with plain TCP connections to their ssh server, so they
+//! need no account on the machine that deploy-flake runs on.
+
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::OnceCell,
+    task::JoinHandle,
+};
+use tracing as log;
+
+/// The host that reverse tunnels listen on.
+pub const TUNNEL_HOST: &str = "localhost";
+
+/// How often to check whether a destination has dialed in.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waits for up to `timeout` until a destination has dialed in and
+/// its reverse tunnel listens on `port`.
+pub async fn wait_for_tunnel(port: u16, timeout: Duration) -> Result<(), anyhow::Error> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut waiting = false;
+    loop {
+        if TcpStream::connect((TUNNEL_HOST, port)).await.is_ok() {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!(
+                "Nothing has dialed in on port {port} (waited {})",
+                humantime::format_duration(timeout)
+            );
+        }
+        if !waiting {
+            log::info!(port, "Waiting for the destination to dial in");
+            waiting = true;
+        }
+        tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + POLL_INTERVAL)).await;
+    }
+}
+
+/// Accepts the connections that destinations dial in with, shared
+/// between all destinations of a run.
+///
+/// A destination dials in on its tunnel port with a connection to its
+/// own ssh server (e.g. with `socat`), and every ssh connection to
+/// it gets handed the next connection that it dialed in with, so it
+/// has to dial in again once a connection is done.
+#[derive(Debug)]
+pub struct Broker {
+    /// The address to listen on for dial-ins.
+    address: IpAddr,
+
+    tunnels: Mutex<HashMap<u16, Arc<OnceCell<Tunnel>>>>,
+}
+
+/// A tunnel port that dial-ins get accepted on.
+#[derive(Debug)]
+struct Tunnel {
+    /// The local port that ssh connects to the destination on.
+    local_port: u16,
+
+    /// Hands ssh connections to dial-ins.
+    relay: JoinHandle<()>,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.relay.abort();
+    }
+}
+
+impl Broker {
+    pub fn new(address: IpAddr) -> Self {
+        Self {
+            address,
+            tunnels: Default::default(),
+        }
+    }
+
+    /// Starts accepting dial-ins on `port`, unless that already
+    /// happens, and waits for up to `timeout` until the destination
+    /// has dialed in. Returns the local port that ssh connects to the
+    /// destination on.
+    pub async fn open(&self, port: u16, timeout: Duration) -> Result<u16, anyhow::Error> {
+        let tunnel = self
+            .tunnels
+            .lock()
+            .unwrap()
+            .entry(port)
+            .or_default()
+            .clone();
+        let tunnel = tunnel
+            .get_or_try_init(|| Tunnel::listen(self.address, port, timeout))
+            .await?;
+        Ok(tunnel.local_port)
+    }
+}
+
+impl Tunnel {
+    async fn listen(address: IpAddr, port: u16, timeout: Duration) -> Result<Self, anyhow::Error> {
+        let dial_ins = TcpListener::bind((address, port))
+            .await
+            .with_context(|| format!("Listening for dial-ins on {address} port {port}"))?;
+        let local = TcpListener::bind((TUNNEL_HOST, 0))
+            .await
+            .context("Listening for ssh connections")?;
+        let local_port = local.local_addr()?.port();
+        log::info!(port, "Waiting for the destination to dial in");
+        let (first, _) = tokio::time::timeout(timeout, dial_ins.accept())
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Nothing has dialed in on port {port} (waited {})",
+                    humantime::format_duration(timeout)
+                )
+            })?
+            .context("Accepting a dial-in")?;
+        Ok(Self {
+            local_port,
+            relay: tokio::spawn(relay(dial_ins, local, first)),
+        })
+    }
+}
+
+/// Hands each ssh connection on `local` the next connection that
+/// came in on `dial_ins`, starting with `first`.
+async fn relay(dial_ins: TcpListener, local: TcpListener, first: TcpStream) {
+    let mut next = Some(first);
+    loop {
+        let mut ssh = match local.accept().await {
+            Ok((ssh, _)) => ssh,
+            Err(e) => {
+                log::warn!(error = %e, "Could not accept an ssh connection");
+                continue;
+            }
+        };
+        let mut dialed_in = match next.take() {
+            Some(dialed_in) => dialed_in,
+            None => match dial_ins.accept().await {
+                Ok((dialed_in, _)) => dialed_in,
+                Err(e) => {
+                    log::warn!(error = %e, "Could not accept a dial-in");
+                    continue;
+                }
+            },
+        };
+        tokio::spawn(async move {
+            if let Err(e) = tokio::io::copy_bidirectional(&mut ssh, &mut dialed_in).await {
+                log::debug!(error = %e, "Tunneled connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{wait_for_tunnel, Broker, TUNNEL_HOST};
+    use std::{net::Ipv4Addr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn waiting() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        wait_for_tunnel(port, Duration::ZERO).await.unwrap();
+        drop(listener);
+        assert!(wait_for_tunnel(port, Duration::ZERO).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn brokering() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let broker = Broker::new(Ipv4Addr::LOCALHOST.into());
+        let device = tokio::spawn(async move {
+            for greeting in [&b"first"[..], b"second"] {
+                let mut dial_in = loop {
+                    match TcpStream::connect(("127.0.0.1", port)).await {
+                        Ok(dial_in) => break dial_in,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+                dial_in.write_all(greeting).await.unwrap();
+                dial_in.shutdown().await.unwrap();
+            }
+        });
+        let local_port = broker.open(port, Duration::from_secs(10)).await.unwrap();
+        for greeting in ["first", "second"] {
+            assert_eq!(broker.open(port, Duration::ZERO).await.unwrap(), local_port);
+            let mut ssh = TcpStream::connect((TUNNEL_HOST, local_port)).await.unwrap();
+            let mut received = String::new();
+            ssh.read_to_string(&mut received).await.unwrap();
+            assert_eq!(received, greeting);
+        }
+        device.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn nothing_dials_in() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let broker = Broker::new(Ipv4Addr::LOCALHOST.into());
+        assert!(broker.open(port, Duration::ZERO).await.is_err());
+    }
+}